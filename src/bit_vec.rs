@@ -16,6 +16,15 @@ impl BitVec {
         }
     }
 
+    // `n_bits` bits, all set.
+    pub(crate) fn full(n_bits: usize) -> Self {
+        let mut bit_vec = Self::new(n_bits);
+        for index in 0..n_bits {
+            bit_vec.set(index).expect("within the bitfield");
+        }
+        bit_vec
+    }
+
     pub fn from_vec(data: Vec<u8>) -> Self {
         Self {
             bytes: data,
@@ -23,8 +32,22 @@ impl BitVec {
         }
     }
 
+    // Takes raw bitfield bytes (high bit first) holding `n_bits` bits.
+    pub(crate) fn from_bytes(bytes: Vec<u8>, n_bits: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            bytes.len() == n_bits.div_ceil(8),
+            "{} bytes can't hold exactly {n_bits} bits",
+            bytes.len()
+        );
+        Ok(Self { bytes, n_bits })
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
     pub(crate) fn set(&mut self, index: usize) -> anyhow::Result<()> {
-        if index >= self.n_bits {
+        if index >= self.len() {
             return Err(anyhow!("bit index is out of range"));
         }
         let byte_i = index / 8;
@@ -34,7 +57,7 @@ impl BitVec {
     }
 
     pub(crate) fn unset(&mut self, index: usize) -> anyhow::Result<()> {
-        if index >= self.n_bits {
+        if index >= self.len() {
             return Err(anyhow!("bit index is out of range"));
         }
         let byte_i = index / 8;
@@ -44,7 +67,7 @@ impl BitVec {
     }
 
     pub(crate) fn toggle(&mut self, index: usize) -> anyhow::Result<()> {
-        if index >= self.n_bits {
+        if index >= self.len() {
             return Err(anyhow!("bit index is out of range"));
         }
         let byte_i = index / 8;
@@ -100,8 +123,51 @@ impl BitVec {
         }
         false
     }
+
+    // Bits that count; the rest of the last byte is padding. A bitfield
+    // straight off the wire (`from_vec`) doesn't know its length, so all of
+    // its bits count.
+    fn len(&self) -> usize {
+        if self.n_bits == 0 {
+            self.bytes.len() * 8
+        } else {
+            self.n_bits
+        }
+    }
+
+    // Whether `other` has every bit we have, e.g. a peer having all the
+    // pieces we're missing.
+    pub fn is_subset_of(&self, other: &BitVec) -> bool {
+        let len = self.len();
+        self.ones()
+            .take_while(|&index| index < len)
+            .all(|index| other.has(index))
+    }
 }
 
+// Padding bits are ignored.
+impl PartialEq for BitVec {
+    fn eq(&self, other: &Self) -> bool {
+        let len = self.len();
+        if len != other.len() {
+            return false;
+        }
+        let full = len / 8;
+        if self.bytes[..full] != other.bytes[..full] {
+            return false;
+        }
+        let padding = len % 8;
+        if padding == 0 {
+            return true;
+        }
+        let mask = !(0xff >> padding);
+        let last = |bv: &BitVec| bv.bytes.get(full).map_or(0, |byte| byte & mask);
+        last(self) == last(other)
+    }
+}
+
+impl Eq for BitVec {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -149,6 +215,32 @@ mod tests {
         assert_eq!(ones.next(), None);
     }
 
+    #[test]
+    fn bit_vec_subset() {
+        let mut some = BitVec::new(10);
+        let mut more = BitVec::new(10);
+        for index in [1, 9] {
+            some.set(index).unwrap();
+            more.set(index).unwrap();
+        }
+        more.set(4).unwrap();
+        assert!(some.is_subset_of(&more));
+        assert!(!more.is_subset_of(&some));
+        assert!(some.is_subset_of(&some));
+        assert!(BitVec::new(10).is_subset_of(&some));
+    }
+
+    #[test]
+    fn bit_vec_eq_ignores_padding() {
+        let a = BitVec::from_bytes(vec![0b1010_1010, 0b1100_0000], 10).unwrap();
+        let b = BitVec::from_bytes(vec![0b1010_1010, 0b1101_0111], 10).unwrap();
+        assert_eq!(a, b);
+        let c = BitVec::from_bytes(vec![0b1010_1010, 0b1000_0000], 10).unwrap();
+        assert_ne!(a, c);
+        let longer = BitVec::from_bytes(vec![0b1010_1010, 0b1100_0000], 11).unwrap();
+        assert_ne!(a, longer);
+    }
+
     #[test]
     fn bit_vec_zeros() {
         let bv = BitVec::new(3);
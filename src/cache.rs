@@ -1,8 +1,17 @@
-use std::collections::{HashMap, VecDeque};
+use crate::BLOCK_SIZE;
+use crate::lru_cache::LruCache;
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::mpsc::Receiver;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{Notify, mpsc};
 
 type PieceKey = (PathBuf, usize);
 
@@ -41,4 +50,667 @@ impl Cache {
     fn receive_pieces() {
 
     }
-}
\ No newline at end of file
+}
+
+// Assembles pieces from blocks while bounding how much memory the partial
+// pieces take up together. Once `cap` bytes are buffered, the least recently
+// touched partial pieces are dropped and have to be downloaded again. The
+// piece being written to is never dropped, so a single piece may exceed `cap`.
+// Pieces are keyed by the info hash of their torrent too, so one cache can be
+// shared by all running torrents.
+pub struct BlockCache {
+    shared: std::sync::Mutex<BlockCacheShared>,
+}
+
+struct BlockCacheShared {
+    len: usize,
+    cap: usize,
+    pieces: LruCache<PartialKey, PartialPiece>,
+}
+
+// (info hash, piece index)
+type PartialKey = ([u8; 20], usize);
+
+struct PartialPiece {
+    length: usize,
+    received: usize,
+    // block offset -> block
+    blocks: BTreeMap<usize, Bytes>,
+}
+
+impl BlockCache {
+    pub fn new(cap: usize) -> Self {
+        // every partial piece holds at least one block
+        let max_pieces = NonZeroUsize::new(cap / BLOCK_SIZE + 1).expect("not zero");
+        Self {
+            shared: std::sync::Mutex::new(BlockCacheShared {
+                len: 0,
+                cap,
+                pieces: LruCache::new(max_pieces),
+            }),
+        }
+    }
+
+    // Bytes currently buffered across all partial pieces.
+    pub fn len(&self) -> usize {
+        self.shared.lock().expect("not poisoned").len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn cap(&self) -> usize {
+        self.shared.lock().expect("not poisoned").cap
+    }
+
+    // Changes the bound, dropping least recently touched partial pieces
+    // until the buffered bytes fit.
+    pub fn resize(&self, cap: usize) {
+        let mut shared = self.shared.lock().expect("not poisoned");
+        let max_pieces = cap / BLOCK_SIZE + 1;
+        while shared.len > cap || shared.pieces.len() > max_pieces {
+            let (_, evicted) = shared.pieces.pop_lru().expect("over the bound");
+            shared.len -= evicted.received;
+        }
+        shared
+            .pieces
+            .resize(NonZeroUsize::new(max_pieces).expect("not zero"));
+        shared.cap = cap;
+    }
+
+    // Stores a block of the piece `piece_i` of the torrent `info_hash` that is
+    // `piece_length` bytes long. Returns the assembled piece once its last
+    // block arrives.
+    pub fn put_block(
+        &self,
+        info_hash: [u8; 20],
+        piece_i: usize,
+        begin: usize,
+        block: Bytes,
+        piece_length: usize,
+    ) -> Option<Bytes> {
+        let mut shared = self.shared.lock().expect("not poisoned");
+        let shared = &mut *shared;
+        let key = (info_hash, piece_i);
+        if !shared.pieces.contains(&key) {
+            let piece = PartialPiece {
+                length: piece_length,
+                received: 0,
+                blocks: BTreeMap::new(),
+            };
+            if let Some((_, evicted)) = shared.pieces.push(key, piece) {
+                shared.len -= evicted.received;
+            }
+        }
+        let piece = shared.pieces.get_mut(&key).expect("just inserted");
+        if begin + block.len() > piece.length || piece.blocks.contains_key(&begin) {
+            // out of bounds or a duplicate (e.g. from an endgame request)
+            return None;
+        }
+        piece.received += block.len();
+        shared.len += block.len();
+        piece.blocks.insert(begin, block);
+
+        if piece.received == piece.length {
+            let piece = shared.pieces.pop(&key).expect("present");
+            shared.len -= piece.received;
+            let mut assembled = BytesMut::with_capacity(piece.length);
+            for block in piece.blocks.into_values() {
+                assembled.extend_from_slice(&block);
+            }
+            return Some(assembled.freeze());
+        }
+
+        while shared.len > shared.cap {
+            let Some((&lru, _)) = shared.pieces.peek_lru() else {
+                break;
+            };
+            if lru == key {
+                break;
+            }
+            let (_, evicted) = shared.pieces.pop_lru().expect("present");
+            shared.len -= evicted.received;
+        }
+        None
+    }
+
+    // Removes the blocks of `piece_i` of the torrent `info_hash` buffered so
+    // far, each with its offset in the piece.
+    pub fn take_blocks(&self, info_hash: [u8; 20], piece_i: usize) -> Vec<(usize, Bytes)> {
+        let mut shared = self.shared.lock().expect("not poisoned");
+        let Some(piece) = shared.pieces.pop(&(info_hash, piece_i)) else {
+            return Vec::new();
+        };
+        shared.len -= piece.received;
+        piece.blocks.into_iter().collect()
+    }
+
+    // Whether blocks of `piece_i` of the torrent `info_hash` are currently
+    // buffered.
+    pub fn contains(&self, info_hash: [u8; 20], piece_i: usize) -> bool {
+        self.shared
+            .lock()
+            .expect("not poisoned")
+            .pieces
+            .contains(&(info_hash, piece_i))
+    }
+}
+
+// Keeps a `BlockCache` at `fraction` of the available memory, checked every
+// `period`, but never below `min` or above `max` bytes.
+#[derive(Debug, Clone)]
+pub struct AdaptiveCap {
+    pub fraction: f64,
+    pub min: usize,
+    pub max: usize,
+    pub period: Duration,
+}
+
+impl AdaptiveCap {
+    fn target(&self, available: usize) -> usize {
+        ((available as f64 * self.fraction) as usize).clamp(self.min, self.max)
+    }
+}
+
+pub trait MemoryProbe: Send + Sync + 'static {
+    // Bytes of memory that can still be used, if known.
+    fn available(&self) -> Option<usize>;
+}
+
+// Reads `MemAvailable` from `/proc/meminfo`, so only knows anything on Linux.
+pub struct SystemMemory;
+
+impl MemoryProbe for SystemMemory {
+    fn available(&self) -> Option<usize> {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+        let line = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix("MemAvailable:"))?;
+        let kib: usize = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+        Some(kib * 1024)
+    }
+}
+
+// Resizes `cache` as available memory changes. Runs until dropped.
+pub async fn adapt(cache: Arc<BlockCache>, cap: AdaptiveCap, probe: impl MemoryProbe) {
+    let mut interval = tokio::time::interval(cap.period);
+    loop {
+        interval.tick().await;
+        if let Some(available) = probe.available() {
+            cache.resize(cap.target(available));
+        }
+    }
+}
+
+// More threads than this only make the disk seek around.
+const MAX_DISK_WORKERS: usize = 4;
+
+// Well below the usual soft limit of 1024 file descriptors.
+const MAX_OPEN_FILES: usize = 256;
+
+// A few pieces worth of blocks.
+const COALESCE_LIMIT: usize = 64 * BLOCK_SIZE;
+
+#[derive(Debug, Clone)]
+pub struct DiskOptions {
+    // Threads doing the writing.
+    pub workers: usize,
+    // Writes that may wait for a worker before `DiskWriter::write` blocks.
+    pub queue_depth: usize,
+    // Files kept open across all workers. Least recently written ones are
+    // closed to stay under it and reopened when written to again.
+    pub max_open_files: usize,
+    // Bytes of writes held back so adjacent blocks can go to disk as one
+    // sequential write. 0 hands every write straight to the workers.
+    pub coalesce_limit: usize,
+}
+
+impl Default for DiskOptions {
+    fn default() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self {
+            workers: cores.min(MAX_DISK_WORKERS),
+            queue_depth: 64,
+            max_open_files: MAX_OPEN_FILES,
+            coalesce_limit: COALESCE_LIMIT,
+        }
+    }
+}
+
+struct DiskJob {
+    path: PathBuf,
+    offset: u64,
+    data: Bytes,
+}
+
+// Writes blocks to disk on a fixed number of worker threads. The queue in
+// front of them is bounded, so a slow disk makes producers wait instead of
+// piling up blocks in memory. With a single worker, writes land in the
+// order they were made. Writes are buffered up to `coalesce_limit` first,
+// so only `flush` guarantees they reach the workers.
+pub struct DiskWriter {
+    tx: mpsc::Sender<DiskJob>,
+    queue_depth: usize,
+    coalesce_limit: usize,
+    buffer: std::sync::Mutex<WriteBuffer>,
+    shared: Arc<DiskShared>,
+}
+
+#[derive(Default)]
+struct WriteBuffer {
+    jobs: Vec<DiskJob>,
+    bytes: usize,
+}
+
+impl WriteBuffer {
+    fn overlaps(&self, job: &DiskJob) -> bool {
+        let end = job.offset + job.data.len() as u64;
+        self.jobs.iter().any(|buffered| {
+            buffered.path == job.path
+                && buffered.offset < end
+                && job.offset < buffered.offset + buffered.data.len() as u64
+        })
+    }
+
+    fn take(&mut self) -> Vec<DiskJob> {
+        self.bytes = 0;
+        coalesce(std::mem::take(&mut self.jobs))
+    }
+}
+
+// Merges writes that continue one another into a single one. The buffer
+// never holds overlapping writes, so their order doesn't matter.
+fn coalesce(mut jobs: Vec<DiskJob>) -> Vec<DiskJob> {
+    jobs.sort_by(|a, b| (&a.path, a.offset).cmp(&(&b.path, b.offset)));
+    let mut merged: Vec<(PathBuf, u64, BytesMut)> = Vec::new();
+    for job in jobs {
+        match merged.last_mut() {
+            Some((path, offset, data))
+                if *path == job.path && *offset + data.len() as u64 == job.offset =>
+            {
+                data.extend_from_slice(&job.data);
+            }
+            _ => merged.push((job.path, job.offset, BytesMut::from(&job.data[..]))),
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(path, offset, data)| DiskJob {
+            path,
+            offset,
+            data: data.freeze(),
+        })
+        .collect()
+}
+
+struct DiskShared {
+    // queued or being written
+    pending: AtomicUsize,
+    open_files: AtomicUsize,
+    // write calls made to files
+    writes: AtomicUsize,
+    idle: Notify,
+    // first failed write, reported by `flush`
+    error: std::sync::Mutex<Option<std::io::Error>>,
+}
+
+impl DiskWriter {
+    pub fn new(options: DiskOptions) -> Self {
+        let queue_depth = options.queue_depth.max(1);
+        let (tx, rx) = mpsc::channel(queue_depth);
+        let rx = Arc::new(std::sync::Mutex::new(rx));
+        let shared = Arc::new(DiskShared {
+            pending: AtomicUsize::new(0),
+            open_files: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            idle: Notify::new(),
+            error: std::sync::Mutex::new(None),
+        });
+        let workers = options.workers.max(1);
+        // every worker gets a share, so a single path may be open on each
+        let max_open =
+            NonZeroUsize::new((options.max_open_files / workers).max(1)).expect("not zero");
+        for _ in 0..workers {
+            let rx = rx.clone();
+            let shared = shared.clone();
+            std::thread::spawn(move || disk_worker(&rx, &shared, max_open));
+        }
+        Self {
+            tx,
+            queue_depth,
+            coalesce_limit: options.coalesce_limit,
+            buffer: Default::default(),
+            shared,
+        }
+    }
+
+    // Queues `data` to be written at `offset` of `path`, waiting while the queue is full.
+    pub async fn write(&self, path: PathBuf, offset: u64, data: Bytes) {
+        let job = DiskJob { path, offset, data };
+        let (before, after) = {
+            let mut buffer = self.buffer.lock().expect("not poisoned");
+            // an overlapping write has to land after the one it overlaps
+            let full = buffer.bytes + job.data.len() > self.coalesce_limit;
+            let before = if full || buffer.overlaps(&job) {
+                buffer.take()
+            } else {
+                Vec::new()
+            };
+            buffer.bytes += job.data.len();
+            buffer.jobs.push(job);
+            let after = if buffer.bytes >= self.coalesce_limit {
+                buffer.take()
+            } else {
+                Vec::new()
+            };
+            (before, after)
+        };
+        for job in before.into_iter().chain(after) {
+            self.send(job).await;
+        }
+    }
+
+    async fn send(&self, job: DiskJob) {
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        self.tx
+            .send(job)
+            .await
+            .expect("workers live as long as the writer");
+    }
+
+    // Writes waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.queue_depth - self.tx.capacity()
+    }
+
+    // Files the workers currently hold open.
+    pub fn open_files(&self) -> usize {
+        self.shared.open_files.load(Ordering::SeqCst)
+    }
+
+    // Write calls the workers made, coalesced writes counting once.
+    pub fn disk_writes(&self) -> usize {
+        self.shared.writes.load(Ordering::SeqCst)
+    }
+
+    // Waits for every write made so far to hit the disk.
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let buffered = self.buffer.lock().expect("not poisoned").take();
+        for job in buffered {
+            self.send(job).await;
+        }
+        loop {
+            let idle = self.shared.idle.notified();
+            if self.shared.pending.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            idle.await;
+        }
+        match self.shared.error.lock().expect("not poisoned").take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+fn disk_worker(
+    rx: &std::sync::Mutex<mpsc::Receiver<DiskJob>>,
+    shared: &DiskShared,
+    max_open: NonZeroUsize,
+) {
+    let mut files = LruCache::new(max_open);
+    loop {
+        let job = rx.lock().expect("not poisoned").blocking_recv();
+        let Some(job) = job else {
+            // the writer is gone
+            return;
+        };
+        let result = write_at(&mut files, shared, &job);
+        if let Err(err) = result {
+            shared.error.lock().expect("not poisoned").get_or_insert(err);
+        }
+        if shared.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            shared.idle.notify_waiters();
+        }
+    }
+}
+
+fn write_at(
+    files: &mut LruCache<PathBuf, File>,
+    shared: &DiskShared,
+    job: &DiskJob,
+) -> std::io::Result<()> {
+    if !files.contains(&job.path) {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&job.path)?;
+        shared.open_files.fetch_add(1, Ordering::SeqCst);
+        if let Some((_, closed)) = files.push(job.path.clone(), file) {
+            drop(closed);
+            shared.open_files.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    let file = files.get_mut(&job.path).expect("just opened");
+    // every write seeks, so a reopened file picks up where it should
+    file.seek(SeekFrom::Start(job.offset))?;
+    shared.writes.fetch_add(1, Ordering::SeqCst);
+    file.write_all(&job.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 20] = [0; 20];
+
+    fn block(len: usize) -> Bytes {
+        Bytes::from(vec![0; len])
+    }
+
+    #[test]
+    fn assembles_pieces_from_out_of_order_blocks() {
+        let cache = BlockCache::new(1 << 20);
+        let piece_length = BLOCK_SIZE + 100;
+        assert!(
+            cache
+                .put_block(HASH, 0, BLOCK_SIZE, Bytes::from(vec![2; 100]), piece_length)
+                .is_none()
+        );
+        let piece = cache
+            .put_block(HASH, 0, 0, Bytes::from(vec![1; BLOCK_SIZE]), piece_length)
+            .unwrap();
+        assert_eq!(piece.len(), piece_length);
+        assert!(piece[..BLOCK_SIZE].iter().all(|&b| b == 1));
+        assert!(piece[BLOCK_SIZE..].iter().all(|&b| b == 2));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn concurrent_pieces_stay_within_the_bound() {
+        let cap = 2 * BLOCK_SIZE;
+        let cache = BlockCache::new(cap);
+        let piece_length = 4 * BLOCK_SIZE;
+        // interleave blocks of 4 pieces as several peers would
+        for begin in [0, BLOCK_SIZE, 2 * BLOCK_SIZE] {
+            for piece_i in 0..4 {
+                cache.put_block(HASH, piece_i, begin, block(BLOCK_SIZE), piece_length);
+                assert!(cache.len() <= cap);
+            }
+        }
+        // older partial pieces were dropped to make room
+        assert!(!cache.contains(HASH, 0));
+        assert!(cache.contains(HASH, 3));
+    }
+
+    struct FakeMemory(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl MemoryProbe for FakeMemory {
+        fn available(&self) -> Option<usize> {
+            Some(self.0.load(std::sync::atomic::Ordering::Relaxed))
+        }
+    }
+
+    #[tokio::test]
+    async fn adaptive_cap_follows_available_memory() {
+        let cache = Arc::new(BlockCache::new(4 * BLOCK_SIZE));
+        for piece_i in 0..4 {
+            cache.put_block(HASH, piece_i, 0, block(BLOCK_SIZE), 2 * BLOCK_SIZE);
+        }
+        let available = Arc::new(std::sync::atomic::AtomicUsize::new(1 << 30));
+        let cap = AdaptiveCap {
+            fraction: 0.5,
+            min: BLOCK_SIZE,
+            max: 64 * BLOCK_SIZE,
+            period: Duration::from_millis(10),
+        };
+        tokio::spawn(adapt(cache.clone(), cap, FakeMemory(available.clone())));
+        let settles_at = |target: usize| {
+            let cache = cache.clone();
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while cache.cap() != target {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+        };
+
+        // plenty of headroom, grow up to the max
+        settles_at(64 * BLOCK_SIZE).await.unwrap();
+        assert_eq!(cache.len(), 4 * BLOCK_SIZE);
+
+        // under pressure, half of what's left
+        available.store(4 * BLOCK_SIZE, std::sync::atomic::Ordering::Relaxed);
+        settles_at(2 * BLOCK_SIZE).await.unwrap();
+        assert_eq!(cache.len(), 2 * BLOCK_SIZE);
+        assert!(!cache.contains(HASH, 0) && cache.contains(HASH, 3));
+
+        // but never below the min
+        available.store(0, std::sync::atomic::Ordering::Relaxed);
+        settles_at(BLOCK_SIZE).await.unwrap();
+        assert!(cache.len() <= BLOCK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn single_disk_worker_writes_in_order() {
+        let path = std::env::temp_dir().join("bittorrent_disk_writer_test.bin");
+        let _ = std::fs::remove_file(&path);
+        let writer = DiskWriter::new(DiskOptions {
+            workers: 1,
+            queue_depth: 2,
+            coalesce_limit: 0,
+            ..Default::default()
+        });
+        for i in 0..200u8 {
+            // every write rewrites the start, so only the last one may be left there
+            writer.write(path.clone(), 0, Bytes::from(vec![i; 4])).await;
+            writer
+                .write(path.clone(), 4 + i as u64, Bytes::from(vec![i]))
+                .await;
+        }
+        writer.flush().await.unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written[..4], [199; 4]);
+        assert!(written[4..].iter().copied().eq(0..200u8));
+    }
+
+    #[test]
+    fn ignores_duplicate_blocks() {
+        let cache = BlockCache::new(1 << 20);
+        cache.put_block(HASH, 0, 0, block(BLOCK_SIZE), 2 * BLOCK_SIZE);
+        cache.put_block(HASH, 0, 0, block(BLOCK_SIZE), 2 * BLOCK_SIZE);
+        assert_eq!(cache.len(), BLOCK_SIZE);
+    }
+
+    #[test]
+    fn same_piece_of_different_torrents_does_not_collide() {
+        let cache = BlockCache::new(1 << 20);
+        let (one, other) = ([1; 20], [2; 20]);
+        let piece_length = 2 * BLOCK_SIZE;
+        let ones = Bytes::from(vec![1; BLOCK_SIZE]);
+        let twos = Bytes::from(vec![2; BLOCK_SIZE]);
+        let put = |info_hash, begin, block: &Bytes| {
+            cache.put_block(info_hash, 0, begin, block.clone(), piece_length)
+        };
+        assert!(put(one, 0, &ones).is_none());
+        // not a duplicate of the first torrent's block
+        assert!(put(other, 0, &twos).is_none());
+        assert_eq!(cache.len(), 2 * BLOCK_SIZE);
+
+        let piece = put(one, BLOCK_SIZE, &ones).unwrap();
+        assert!(piece.iter().all(|&b| b == 1));
+        assert!(!cache.contains(one, 0) && cache.contains(other, 0));
+        let piece = put(other, BLOCK_SIZE, &twos).unwrap();
+        assert!(piece.iter().all(|&b| b == 2));
+    }
+
+    #[tokio::test]
+    async fn open_files_stay_under_the_limit() {
+        let dir = std::env::temp_dir().join("bittorrent_open_files_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let writer = DiskWriter::new(DiskOptions {
+            workers: 2,
+            queue_depth: 4,
+            max_open_files: 4,
+            coalesce_limit: 0,
+        });
+        let mut max_open = 0;
+        // round robin over more files than may be open, so all get reopened
+        for round in 0..3u8 {
+            for file_i in 0..20 {
+                let path = dir.join(file_i.to_string());
+                writer
+                    .write(path, round as u64, Bytes::from(vec![round]))
+                    .await;
+                max_open = max_open.max(writer.open_files());
+            }
+        }
+        writer.flush().await.unwrap();
+        max_open = max_open.max(writer.open_files());
+        assert!(max_open <= 4, "{max_open} files open");
+
+        for file_i in 0..20 {
+            let written = std::fs::read(dir.join(file_i.to_string())).unwrap();
+            assert_eq!(written, [0, 1, 2]);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn adjacent_blocks_are_written_at_once() {
+        let path = std::env::temp_dir().join("bittorrent_coalesce_test.bin");
+        let _ = std::fs::remove_file(&path);
+        let writer = DiskWriter::new(DiskOptions {
+            workers: 1,
+            coalesce_limit: 4 * BLOCK_SIZE,
+            ..Default::default()
+        });
+        let write_blocks = async |blocks: &[u8]| {
+            // out of order, sorted before writing
+            for &i in blocks {
+                let offset = i as u64 * BLOCK_SIZE as u64;
+                writer
+                    .write(path.clone(), offset, Bytes::from(vec![i; BLOCK_SIZE]))
+                    .await;
+            }
+        };
+        write_blocks(&[2, 0, 3, 1]).await;
+        writer.flush().await.unwrap();
+        assert_eq!(writer.disk_writes(), 1);
+
+        // the first four hit the limit and go out without waiting for the flush
+        write_blocks(&[4, 5, 6, 7, 8, 9]).await;
+        writer.flush().await.unwrap();
+        assert_eq!(writer.disk_writes(), 3);
+
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.len(), 10 * BLOCK_SIZE);
+        for (i, block) in written.chunks(BLOCK_SIZE).enumerate() {
+            assert!(block.iter().all(|&b| b == i as u8));
+        }
+    }
+}
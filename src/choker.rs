@@ -0,0 +1,182 @@
+use crate::peer::PeerId;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Peers uploaded to at once, as in the reference client.
+pub const UNCHOKE_SLOTS: usize = 4;
+
+// Time between choke rounds. Shorter and peers get choked before a transfer
+// gets going, so rates never have a chance to show.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChokeStrategy {
+    // Tit-for-tat: the peers we download from fastest, plus one optimistic
+    // unchoke so newcomers get a chance to prove themselves.
+    #[default]
+    Download,
+    // the peers we upload to fastest, so pieces spread quickly
+    SeedFastest,
+    // every interested peer gets a turn
+    SeedRoundRobin,
+}
+
+// What a choke round needs to know about a connected peer.
+#[derive(Debug, Clone)]
+pub struct ChokeCandidate {
+    pub peer_id: PeerId,
+    // only interested peers are unchoked
+    pub interested: bool,
+    // bytes per second from and to the peer
+    pub download_rate: f64,
+    pub upload_rate: f64,
+}
+
+// Picks the peers to upload to every round. While downloading that's
+// `ChokeStrategy::Download`, once the torrent is complete the seed strategy
+// takes over, since there's nothing to get back from peers.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    seed_strategy: ChokeStrategy,
+    round: u64,
+    // round each peer was last unchoked in
+    last_unchoked: HashMap<PeerId, u64>,
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new(UNCHOKE_SLOTS)
+    }
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: slots.max(1),
+            seed_strategy: ChokeStrategy::SeedFastest,
+            round: 0,
+            last_unchoked: HashMap::new(),
+        }
+    }
+
+    pub fn with_seed_strategy(mut self, strategy: ChokeStrategy) -> Self {
+        self.seed_strategy = strategy;
+        self
+    }
+
+    pub fn strategy(&self, complete: bool) -> ChokeStrategy {
+        if complete {
+            self.seed_strategy
+        } else {
+            ChokeStrategy::Download
+        }
+    }
+
+    // The peers to unchoke this round, everyone else gets choked.
+    pub fn unchoke(&mut self, peers: &[ChokeCandidate], complete: bool) -> Vec<PeerId> {
+        self.round += 1;
+        let mut interested: Vec<_> = peers.iter().filter(|peer| peer.interested).collect();
+        let unchoked: Vec<PeerId> = match self.strategy(complete) {
+            ChokeStrategy::Download => {
+                interested.sort_by(|a, b| b.download_rate.total_cmp(&a.download_rate));
+                let regular = (self.slots - 1).min(interested.len());
+                let (fastest, rest) = interested.split_at(regular);
+                // the one that's been waiting the longest
+                let optimistic = rest
+                    .iter()
+                    .min_by_key(|peer| self.last_unchoked.get(&peer.peer_id));
+                fastest
+                    .iter()
+                    .chain(optimistic)
+                    .map(|peer| peer.peer_id)
+                    .collect()
+            }
+            ChokeStrategy::SeedFastest => {
+                interested.sort_by(|a, b| b.upload_rate.total_cmp(&a.upload_rate));
+                interested
+                    .iter()
+                    .take(self.slots)
+                    .map(|peer| peer.peer_id)
+                    .collect()
+            }
+            ChokeStrategy::SeedRoundRobin => {
+                // never unchoked sorts first
+                interested.sort_by_key(|peer| self.last_unchoked.get(&peer.peer_id));
+                interested
+                    .iter()
+                    .take(self.slots)
+                    .map(|peer| peer.peer_id)
+                    .collect()
+            }
+        };
+        for peer_id in &unchoked {
+            self.last_unchoked.insert(*peer_id, self.round);
+        }
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: u8, interested: bool, download_rate: f64, upload_rate: f64) -> ChokeCandidate {
+        ChokeCandidate {
+            peer_id: [id; 20],
+            interested,
+            download_rate,
+            upload_rate,
+        }
+    }
+
+    fn ids(unchoked: Vec<PeerId>) -> Vec<u8> {
+        unchoked.into_iter().map(|peer_id| peer_id[0]).collect()
+    }
+
+    #[test]
+    fn seed_strategies_pick_their_peers() {
+        let peers = [
+            peer(1, true, 900.0, 10.0),
+            peer(2, true, 0.0, 500.0),
+            peer(3, false, 0.0, 9000.0),
+            peer(4, true, 0.0, 300.0),
+            peer(5, true, 800.0, 100.0),
+        ];
+
+        // while downloading, who gives us the most plus an optimistic unchoke
+        let mut choker = Choker::new(2);
+        assert_eq!(choker.strategy(false), ChokeStrategy::Download);
+        assert_eq!(ids(choker.unchoke(&peers, false)), [1, 5]);
+
+        // complete: the fastest uploads, the uninterested one left out
+        assert_eq!(choker.strategy(true), ChokeStrategy::SeedFastest);
+        assert_eq!(ids(choker.unchoke(&peers, true)), [2, 4]);
+        assert_eq!(ids(choker.unchoke(&peers, true)), [2, 4]);
+
+        let mut choker = Choker::new(2).with_seed_strategy(ChokeStrategy::SeedRoundRobin);
+        assert_eq!(ids(choker.unchoke(&peers, true)), [1, 2]);
+        assert_eq!(ids(choker.unchoke(&peers, true)), [4, 5]);
+        assert_eq!(ids(choker.unchoke(&peers, true)), [1, 2]);
+    }
+
+    #[test]
+    fn unchoked_peers_follow_the_rates() {
+        let mut choker = Choker::new(3);
+        let mut peers = [
+            peer(1, true, 900.0, 0.0),
+            peer(2, true, 800.0, 0.0),
+            peer(3, true, 10.0, 0.0),
+            peer(4, true, 0.0, 0.0),
+        ];
+        // the two fastest, and 3 gets the optimistic unchoke
+        assert_eq!(ids(choker.unchoke(&peers, false)), [1, 2, 3]);
+        // it rotates to the one that waited the longest
+        assert_eq!(ids(choker.unchoke(&peers, false)), [1, 2, 4]);
+
+        // 1 slowed down and 4 made good use of its chance
+        peers[0].download_rate = 5.0;
+        peers[3].download_rate = 1000.0;
+        assert_eq!(ids(choker.unchoke(&peers, false)), [4, 2, 3]);
+    }
+}
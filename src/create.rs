@@ -1,60 +1,374 @@
 use crate::dot_torrent::hashes::Hashes;
-use crate::dot_torrent::{Info, Key, DotTorrent};
+use crate::dot_torrent::{
+    DotTorrent, FileTree, FileTreeEntry, FileTreeNode, Info, Key, safe_component,
+};
 use anyhow::Context;
 use memmap2::Mmap;
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, BufReader};
 
 const PIECE_LENGTH: usize = 32768;
 
-pub async fn create_torrent(path: PathBuf) -> anyhow::Result<()> {
+// v2 hashes files in blocks of 16 KiB, whatever the piece length.
+const MERKLE_BLOCK_SIZE: usize = 1 << 14;
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // also add v2 metadata
+    pub hybrid: bool,
+    // goes into `info.source`, see `Info::source`
+    pub source: Option<String>,
+    // advertised instead of the source's own file name
+    pub name: Option<String>,
+    // read the file piece by piece instead of mapping it, see `create_streaming`
+    pub streaming: bool,
+}
+
+pub async fn create_torrent(path: PathBuf, options: &CreateOptions) -> anyhow::Result<()> {
+    let dot_torrent = if options.streaming {
+        anyhow::ensure!(!options.hybrid, "hybrid torrents can't be streamed yet");
+        let mut dot_torrent = create_streaming(&path).await?;
+        apply_options(&mut dot_torrent, &path, options)?;
+        dot_torrent
+    } else {
+        create_with_options(&path, options)?
+    };
+    let bencoded_dot_torrent = dot_torrent
+        .to_bytes()
+        .context("invalid data during encoding")?;
+    let mut path = PathBuf::from("./");
+    path.push(&dot_torrent.info.name);
+    path.set_extension("torrent");
+    tokio::fs::write(path, &bencoded_dot_torrent)
+        .await
+        .context("failed to write `.torrent` file")?;
+    Ok(())
+}
+
+pub fn create_with_options(path: &Path, options: &CreateOptions) -> anyhow::Result<DotTorrent> {
+    let mut dot_torrent = create(path)?;
+    apply_options(&mut dot_torrent, path, options)?;
+    Ok(dot_torrent)
+}
+
+fn apply_options(
+    dot_torrent: &mut DotTorrent,
+    path: &Path,
+    options: &CreateOptions,
+) -> anyhow::Result<()> {
+    if let Some(name) = &options.name {
+        // it ends up as a file or directory name wherever it's downloaded
+        safe_component(name).context("torrent name")?;
+        dot_torrent.info.name = name.clone();
+    }
+    // after the rename, the v2 file tree is keyed by the name too
+    if options.hybrid {
+        add_v2(dot_torrent, path)?;
+    }
+    dot_torrent.info.source = options.source.clone();
+    Ok(())
+}
+
+// Hashes the file at `path` into a torrent without writing it anywhere.
+pub fn create(path: &Path) -> anyhow::Result<DotTorrent> {
+    let mut dot_torrent = without_pieces(path)?;
+    let file = File::open(path).context("failed to open the file")?;
+    let mmap = unsafe { Mmap::map(&file).context("failed to map the file")? };
+    let file_length = mmap.len();
+    dot_torrent.info.key = Key::SingleFile {
+        length: file_length,
+    };
+    let n_pieces = (file_length + PIECE_LENGTH - 1) / PIECE_LENGTH;
+    for piece_i in 0..n_pieces {
+        let piece_size = if piece_i == n_pieces - 1 {
+            // calculate last piece's size
+            let modulo = file_length % PIECE_LENGTH;
+            if modulo == 0 { PIECE_LENGTH } else { modulo }
+        } else {
+            PIECE_LENGTH
+        };
+        let piece = &mmap[piece_i * PIECE_LENGTH..piece_i * PIECE_LENGTH + piece_size];
+        let mut hasher = Sha1::new();
+        hasher.update(piece);
+        let hash: [u8; 20] = hasher.finalize().into();
+        dot_torrent.info.pieces.0.push(hash);
+    }
+    Ok(dot_torrent)
+}
+
+// Like `create`, but reads the file a piece at a time, so it works for files
+// that don't fit in the address space (32-bit targets) and doesn't pin the
+// whole file in virtual memory. Slower than mapping it.
+pub async fn create_streaming(path: &Path) -> anyhow::Result<DotTorrent> {
+    let mut dot_torrent = without_pieces(path)?;
+    let file = tokio::fs::File::open(path)
+        .await
+        .context("failed to open the file")?;
+    let mut reader = BufReader::new(file);
+    let mut piece = vec![0; PIECE_LENGTH];
+    let mut file_length = 0;
+    loop {
+        // a read can come up short before the end of the file
+        let mut filled = 0;
+        while filled < PIECE_LENGTH {
+            let n = reader
+                .read(&mut piece[filled..])
+                .await
+                .context("failed to read the file")?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+        file_length += filled;
+        let hash: [u8; 20] = Sha1::digest(&piece[..filled]).into();
+        dot_torrent.info.pieces.0.push(hash);
+        if filled < PIECE_LENGTH {
+            break;
+        }
+    }
+    dot_torrent.info.key = Key::SingleFile {
+        length: file_length,
+    };
+    Ok(dot_torrent)
+}
+
+// A torrent for the file at `path` with everything but the hashes.
+fn without_pieces(path: &Path) -> anyhow::Result<DotTorrent> {
+    anyhow::ensure!(path.is_file(), "only single files are supported");
     let name = path
         .file_name()
         .and_then(|s| s.to_str())
         .map(|s| s.to_string())
         .context("couldn't get the final component of the Path")?;
-    let mut dot_torrent = DotTorrent {
+    let info = Info::new(
+        &name,
+        PIECE_LENGTH,
+        Hashes(Vec::new()),
+        Key::SingleFile { length: 0 },
+    );
+    Ok(DotTorrent {
         // URL for tests with a "real" tracker
         // http://bittorrent-test-tracker.codecrafters.io/announce
-        announce: "http://127.0.0.1:8000/announce".to_string(),
-        info: Info {
-            name,
-            piece_length: PIECE_LENGTH,
-            pieces: Hashes(Vec::new()),
-            key: Key::SingleFile { length: 0 },
-        },
+        announce: Some("http://127.0.0.1:8000/announce".to_string()),
+        ..DotTorrent::new(info)
+    })
+}
+
+// Like `create`, but also adds the v2 (BEP 52) file tree and piece layers, so
+// both v1 and v2 clients can use the torrent. The v1 info hash covers the
+// v2 keys too, so v1 clients just ignore them.
+pub fn create_hybrid(path: &Path) -> anyhow::Result<DotTorrent> {
+    let mut dot_torrent = create(path)?;
+    add_v2(&mut dot_torrent, path)?;
+    Ok(dot_torrent)
+}
+
+fn add_v2(dot_torrent: &mut DotTorrent, path: &Path) -> anyhow::Result<()> {
+    let file = File::open(path).context("failed to open the file")?;
+    let mmap = unsafe { Mmap::map(&file).context("failed to map the file")? };
+    let (root, piece_layer) = merkle(&mmap, PIECE_LENGTH);
+    let entry = FileTreeEntry {
+        length: mmap.len(),
+        pieces_root: root.map(Vec::from).unwrap_or_default(),
     };
-    if path.is_file() {
-        let file = File::open(path).context("failed to open the file")?;
-        let mmap = unsafe { Mmap::map(&file).context("failed to map the file")? };
-        let file_length = mmap.len();
-        dot_torrent.info.key = Key::SingleFile {
-            length: file_length,
+    dot_torrent.info.meta_version = Some(2);
+    dot_torrent.info.file_tree = Some(FileTree(BTreeMap::from([(
+        dot_torrent.info.name.clone(),
+        FileTreeNode::File { entry },
+    )])));
+    // files that fit in a piece are verified with their root alone
+    if let (Some(root), true) = (root, mmap.len() > PIECE_LENGTH) {
+        dot_torrent.piece_layers = Some(BTreeMap::from([(
+            ByteBuf::from(root.to_vec()),
+            ByteBuf::from(piece_layer.concat()),
+        )]));
+    }
+    Ok(())
+}
+
+// Builds the v2 merkle tree of `data`: SHA-256 of every block, padded with
+// zeroed hashes to a power of two and combined pairwise up to the root.
+// Returns the root (none for empty data) and the layer whose hashes each
+// cover one piece, cut off at the end of the data.
+fn merkle(data: &[u8], piece_length: usize) -> (Option<[u8; 32]>, Vec<[u8; 32]>) {
+    let mut layer: Vec<[u8; 32]> = data
+        .chunks(MERKLE_BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect();
+    if layer.is_empty() {
+        return (None, Vec::new());
+    }
+    let n_pieces = data.len().div_ceil(piece_length);
+    layer.resize(layer.len().next_power_of_two(), [0; 32]);
+    let mut span = MERKLE_BLOCK_SIZE;
+    let mut piece_layer = Vec::new();
+    loop {
+        if span == piece_length {
+            piece_layer = layer[..n_pieces].to_vec();
+        }
+        if layer.len() == 1 {
+            return (Some(layer[0]), piece_layer);
+        }
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+        span *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256_pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().into()
+    }
+
+    #[test]
+    fn hybrid_torrent_has_consistent_v1_and_v2_hashes() {
+        // 4 pieces, the last one short
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 247) as u8).collect();
+        let path = std::env::temp_dir().join("bittorrent_hybrid_test.bin");
+        std::fs::write(&path, &data).unwrap();
+        let created = create_hybrid(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // survives a trip through a `.torrent` file, info hash included
+        let bytes = serde_bencode::to_bytes(&created).unwrap();
+        let dot_torrent = DotTorrent::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            dot_torrent.info_hash().unwrap(),
+            created.info_hash().unwrap()
+        );
+
+        // v1
+        assert_eq!(dot_torrent.info.pieces.0.len(), 4);
+        for (piece, hash) in data.chunks(PIECE_LENGTH).zip(&dot_torrent.info.pieces.0) {
+            assert_eq!(<[u8; 20]>::from(Sha1::digest(piece)), *hash);
+        }
+
+        // v2
+        assert_eq!(dot_torrent.info.meta_version, Some(2));
+        let file_tree = dot_torrent.info.file_tree.as_ref().unwrap();
+        let Some(FileTreeNode::File { entry }) = file_tree.0.get("bittorrent_hybrid_test.bin")
+        else {
+            panic!("file missing from the file tree");
+        };
+        assert_eq!(entry.length, data.len());
+        let layers = dot_torrent.piece_layers.as_ref().unwrap();
+        let layer = &layers[&ByteBuf::from(entry.pieces_root.clone())];
+        assert_eq!(layer.len(), 4 * 32);
+
+        // the first piece's hash covers its two blocks
+        let blocks: Vec<[u8; 32]> = data[..PIECE_LENGTH]
+            .chunks(MERKLE_BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).into())
+            .collect();
+        assert_eq!(layer[..32], sha256_pair(blocks[0], blocks[1]));
+        // and the piece layer hashes up to the root
+        let layer: Vec<[u8; 32]> = layer.chunks(32).map(|h| h.try_into().unwrap()).collect();
+        let root = sha256_pair(
+            sha256_pair(layer[0], layer[1]),
+            sha256_pair(layer[2], layer[3]),
+        );
+        assert_eq!(entry.pieces_root, root);
+    }
+
+    #[test]
+    fn source_changes_the_info_hash() {
+        let path = std::env::temp_dir().join("bittorrent_source_test.bin");
+        std::fs::write(&path, [7; 1000]).unwrap();
+        let create = |source: Option<&str>| {
+            let options = CreateOptions {
+                source: source.map(String::from),
+                ..Default::default()
+            };
+            let created = create_with_options(&path, &options).unwrap();
+            // as other clients would see it
+            let bytes = serde_bencode::to_bytes(&created).unwrap();
+            DotTorrent::from_bytes(&bytes).unwrap()
         };
-        let n_pieces = (file_length + PIECE_LENGTH - 1) / PIECE_LENGTH;
-        for piece_i in 0..n_pieces {
-            let piece_size = if piece_i == n_pieces - 1 {
-                // calculate last piece's size
-                let modulo = file_length % PIECE_LENGTH;
-                if modulo == 0 { PIECE_LENGTH } else { modulo }
-            } else {
-                PIECE_LENGTH
+        let plain = create(None);
+        let first = create(Some("FIRST"));
+        let second = create(Some("SECOND"));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(first.info.source.as_deref(), Some("FIRST"));
+        assert_eq!(plain.info.source, None);
+        let hashes = [&plain, &first, &second].map(|t| t.info_hash().unwrap());
+        assert_ne!(hashes[0], hashes[1]);
+        assert_ne!(hashes[1], hashes[2]);
+        assert_ne!(hashes[0], hashes[2]);
+        // same content all the same
+        assert_eq!(first.info.pieces.0, second.info.pieces.0);
+    }
+
+    #[test]
+    fn custom_name_is_advertised() {
+        let path = std::env::temp_dir().join("bittorrent_name_test.bin");
+        std::fs::write(&path, [3; 50_000]).unwrap();
+        let create = |name: &str| {
+            let options = CreateOptions {
+                name: Some(name.to_string()),
+                hybrid: true,
+                ..Default::default()
             };
-            let piece = &mmap[piece_i * PIECE_LENGTH..piece_i * PIECE_LENGTH + piece_size];
-            let mut hasher = Sha1::new();
-            hasher.update(piece);
-            let hash: [u8; 20] = hasher.finalize().into();
-            dot_torrent.info.pieces.0.push(hash);
-        }
-        let bencoded_dot_torrent =
-            serde_bencode::to_bytes(&dot_torrent).context("invalid data during encoding")?;
-        let mut path = PathBuf::from("./");
-        path.push(&dot_torrent.info.name);
-        path.set_extension("torrent");
-        tokio::fs::write(path, &bencoded_dot_torrent)
-            .await
-            .context("failed to write `.torrent` file")?;
+            create_with_options(&path, &options)
+        };
+        let named = create("Holiday Photos").unwrap();
+        let bad = ["", "a/b", "a\\b", ".."].map(|name| create(name).is_err());
+        let plain = create_with_options(&path, &CreateOptions::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(named.info.name, "Holiday Photos");
+        let tree = named.info.file_tree.as_ref().unwrap();
+        assert!(tree.0.contains_key("Holiday Photos"));
+        // still the real data
+        assert_eq!(named.info.pieces.0, plain.info.pieces.0);
+        assert_eq!(bad, [true; 4]);
+    }
+
+    #[tokio::test]
+    async fn streaming_matches_mapping() {
+        let path = std::env::temp_dir().join("bittorrent_streaming_test.bin");
+        // several pieces with a short one at the end
+        let data: Vec<u8> = (0..5 * PIECE_LENGTH as u32 + 1234)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+        let streamed = create_streaming(&path).await.unwrap();
+        let mapped = create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(streamed.info.pieces.0.len(), 6);
+        assert_eq!(streamed.info.pieces.0, mapped.info.pieces.0);
+        assert_eq!(streamed.length(), data.len());
+        assert_eq!(streamed.info_hash().unwrap(), mapped.info_hash().unwrap());
+    }
+
+    #[test]
+    fn small_files_have_no_piece_layer() {
+        let (root, layer) = merkle(&[1; 100], PIECE_LENGTH);
+        assert_eq!(root, Some(Sha256::digest([1; 100]).into()));
+        assert!(layer.is_empty());
+        assert_eq!(merkle(&[], PIECE_LENGTH), (None, Vec::new()));
     }
-    Ok(())
 }
@@ -1,6 +1,6 @@
 use anyhow::Context;
 use sha2::{Digest, Sha256};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use serde::Deserialize;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
@@ -36,12 +36,13 @@ impl FileDB {
         let mut config_file = OpenOptions::new()
             .create(true)
             .read(true)
+            .write(true)
             .open(&config_path)
             .await
             .context(format!("couldn't open `{}`", config_path.display()))?;
 
         let mut buf = Vec::new();
-        config_file.read(&mut buf).await?;
+        config_file.read_to_end(&mut buf).await?;
         let mut config;
         let mut checksum_unset = false;
         if buf.len() == 0 {
@@ -54,13 +55,15 @@ impl FileDB {
         let mut file = OpenOptions::new()
             .create(true)
             .read(true)
+            .write(true)
             .open(&path)
             .await
             .context(format!("couldn't open `{}`", path.display()))?;
         buf.clear();
-        file.read(&mut buf).await?;
+        file.read_to_end(&mut buf).await?;
         if buf.len() == 0 {
-            buf.extend("{}\n".as_bytes());
+            // no torrents yet
+            buf.extend("[]\n".as_bytes());
         }
         if checksum_unset {
             config.checksum = Sha256::digest(&buf).into();
@@ -92,6 +95,10 @@ impl FileDB {
         Ok(())
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.data
     }
@@ -1,44 +1,242 @@
+use crate::bit_vec::BitVec;
 use crate::download::{Downloaded, all};
+use crate::error::BtError;
+use crate::magnet::{self, Magnet};
 use anyhow::Context;
 use hashes::Hashes;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
-use std::path::Path;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Position of a file in `DotTorrent::files`.
+pub type FileIndex = usize;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DotTorrent {
-    // The URL of the tracker.
-    pub announce: String,
+    // The URL of the tracker. Missing for trackerless (DHT-only) torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    // Tiers of backup trackers (BEP 12). When present, clients
+    // use it instead of `announce`.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    // Free-form notes from the author.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // Program that made the torrent.
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    // Seconds since the Unix epoch.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    // Servers handing out whole pieces over HTTP (BEP 17), see `HttpSeed`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub httpseeds: Vec<String>,
     pub info: Info,
+    // The `info` dictionary exactly as it appeared in the parsed file.
+    // Re-encoding `info` loses keys we don't model and any non-canonical
+    // ordering, which would change the info hash.
+    #[serde(skip)]
+    pub(crate) raw_info: Option<Vec<u8>>,
+    // Filled in by the first `info_hash` call, so `info` must not change afterwards.
+    #[serde(skip)]
+    pub(crate) cached_info_hash: OnceLock<InfoHash>,
+    // v2 hashes of every piece of the files larger than a piece, keyed by
+    // the file's `pieces root` (BEP 52).
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+}
+
+// Where a piece sits in the torrent's byte stream and what it should hash to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceInfo {
+    pub index: usize,
+    pub offset: usize,
+    // `piece_length`, except for a possibly truncated last piece
+    pub length: usize,
+    pub hash: [u8; 20],
 }
 
+// Identifies a torrent, e.g. as a map key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InfoHash(pub [u8; 20]);
+
 impl DotTorrent {
+    // A torrent of just `info`, without trackers or any of the optional keys.
+    pub fn new(info: Info) -> Self {
+        Self {
+            announce: None,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            httpseeds: Vec::new(),
+            info,
+            raw_info: None,
+            cached_info_hash: OnceLock::new(),
+            piece_layers: None,
+        }
+    }
+
+    // Gets just the `info` of a magnet link from peers (BEP 9), e.g. to look
+    // at the files, without downloading any of the data.
+    pub async fn fetch_metadata(magnet: &str) -> anyhow::Result<Info> {
+        magnet::fetch_info(&Magnet::parse(magnet)?).await
+    }
+
     pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        let bencoded_info = serde_bencode::to_bytes(&self.info).context("bencode info section")?;
+        if let Some(info_hash) = self.cached_info_hash.get() {
+            return Ok(info_hash.0);
+        }
         let mut hasher = Sha1::new();
-        hasher.update(&bencoded_info);
-        Ok(hasher.finalize().into())
+        match &self.raw_info {
+            Some(raw_info) => hasher.update(raw_info),
+            None => {
+                let bencoded_info =
+                    serde_bencode::to_bytes(&self.info).context("bencode info section")?;
+                hasher.update(&bencoded_info);
+            }
+        }
+        let info_hash = self
+            .cached_info_hash
+            .get_or_init(|| InfoHash(hasher.finalize().into()));
+        Ok(info_hash.0)
+    }
+
+    // Whether both describe the same content, regardless of trackers.
+    pub fn same_torrent(&self, other: &DotTorrent) -> bool {
+        match (self.info_hash(), other.info_hash()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
     }
 
-    pub async fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let dot_torrent = tokio::fs::read(path).await.context("open torrent file")?;
-        let torrent: DotTorrent =
-            serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+    // Adds trackers known from elsewhere, e.g. a magnet link's `tr`, as a
+    // new last tier. The existing tiers stay as they are (`announce` becomes
+    // the first one if there were none) and trackers already in them are
+    // skipped.
+    pub fn merge_trackers(&mut self, extra: &[String]) {
+        let mut tiers = match self.announce_list.take() {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers,
+            _ => self.announce.iter().map(|url| vec![url.clone()]).collect(),
+        };
+        let mut known: HashSet<String> = tiers.iter().flatten().cloned().collect();
+        let new: Vec<String> = extra
+            .iter()
+            .filter(|url| !url.is_empty() && known.insert(url.to_string()))
+            .cloned()
+            .collect();
+        if !new.is_empty() {
+            tiers.push(new);
+        }
+        if self.announce.is_none() {
+            // for clients that only look at `announce`
+            self.announce = tiers.first().and_then(|tier| tier.first()).cloned();
+        }
+        self.announce_list = (!tiers.is_empty()).then_some(tiers);
+    }
+
+    pub async fn read(path: impl AsRef<Path>) -> Result<Self, BtError> {
+        Self::read_with_limits(path, &Limits::default()).await
+    }
+
+    pub async fn read_with_limits(
+        path: impl AsRef<Path>,
+        limits: &Limits,
+    ) -> Result<Self, BtError> {
+        let dot_torrent = tokio::fs::read(path).await?;
+        Self::from_bytes_with_limits(&dot_torrent, limits).map_err(BtError::Parse)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Self::from_bytes_with_limits(bytes, &Limits::default())
+    }
+
+    // Parses a `.torrent` from untrusted input, rejecting metadata
+    // whose declared sizes exceed `limits`.
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: &Limits) -> anyhow::Result<Self> {
+        let info = raw_info(bytes)?;
+        anyhow::ensure!(
+            info.len() <= limits.max_info_size,
+            "torrent metadata is {} bytes, limit is {}",
+            info.len(),
+            limits.max_info_size
+        );
+        let mut torrent: DotTorrent =
+            serde_bencode::from_bytes(bytes).context("parse torrent file")?;
+        limits.check(&torrent.info)?;
+        // some clients write an empty string instead of leaving the key out
+        if torrent.announce.as_deref() == Some("") {
+            torrent.announce = None;
+        }
+        torrent.raw_info = Some(info.to_vec());
         Ok(torrent)
     }
 
+    // Bencodes the torrent for writing to a `.torrent` file. A parsed `info`
+    // goes out exactly as it came in, so the info hash doesn't change, and
+    // so do the v2 `piece layers` next to it.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = serde_bencode::to_bytes(self).context("bencode torrent")?;
+        if let Some(raw) = &self.raw_info {
+            let info = raw_info_range(&bytes)?;
+            bytes.splice(info, raw.iter().copied());
+        }
+        Ok(bytes)
+    }
+
     pub fn print_tree(&self) {
-        println!("torrent tree:");
+        print!("{}", self.summary());
+    }
+
+    // What the `info` subcommand shows. Optional fields are left out when
+    // the torrent doesn't have them.
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        let tracker = self.announce.as_deref().unwrap_or("(trackerless)");
+        summary += &format!("tracker: {tracker}\n");
+        if let Some(date) = self.creation_date {
+            summary += &format!("created on: {}\n", utc_timestamp(date));
+        }
+        if let Some(created_by) = &self.created_by {
+            summary += &format!("created by: {created_by}\n");
+        }
+        if let Some(comment) = &self.comment {
+            summary += &format!("comment: {comment}\n");
+        }
+        summary += "torrent tree:\n";
         match &self.info.key {
             Key::SingleFile { .. } => {
-                println!("{}", &self.info.name);
+                summary += &format!("{}\n", &self.info.name);
             }
             Key::MultipleFiles { files } => {
                 for file in files {
-                    println!("{}", file.path.join(std::path::MAIN_SEPARATOR_STR));
+                    let path = file.path.join(std::path::MAIN_SEPARATOR_STR);
+                    summary += &format!("{path}\n");
                 }
             }
         }
+        summary
     }
 
     pub fn length(&self) -> usize {
@@ -48,11 +246,332 @@ impl DotTorrent {
         }
     }
 
-    pub async fn download_all(&self) -> anyhow::Result<Downloaded> {
+    pub fn piece(&self, index: usize) -> Option<PieceInfo> {
+        let hash = *self.info.pieces.0.get(index)?;
+        let offset = index * self.info.piece_length;
+        Some(PieceInfo {
+            index,
+            offset,
+            length: self
+                .info
+                .piece_length
+                .min(self.length().saturating_sub(offset)),
+            hash,
+        })
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = PieceInfo> + '_ {
+        (0..self.info.pieces.0.len()).filter_map(|index| self.piece(index))
+    }
+
+    // Files in the order they are laid out in the torrent's byte stream.
+    // A single-file torrent is treated as one file named after the torrent.
+    pub fn files(&self) -> Vec<File> {
+        match &self.info.key {
+            Key::SingleFile { length } => vec![File {
+                length: *length,
+                path: vec![self.info.name.clone()],
+            }],
+            Key::MultipleFiles { files } => files.clone(),
+        }
+    }
+
+    // Where each of `files()` goes, relative to the download directory. Files
+    // of a multi-file torrent go in a directory named after it. The names come
+    // from whoever made the torrent, so anything that could end up outside
+    // that directory (`..`, absolute paths, separators inside a name) is an
+    // error, and names Windows reserves for devices get an underscore.
+    pub fn sanitize_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let root = safe_component(&self.info.name).context("torrent name")?;
+        match &self.info.key {
+            Key::SingleFile { .. } => Ok(vec![PathBuf::from(root)]),
+            Key::MultipleFiles { files } => files
+                .iter()
+                .map(|file| {
+                    anyhow::ensure!(!file.path.is_empty(), "file has an empty path");
+                    let mut path = PathBuf::from(&root);
+                    for component in &file.path {
+                        path.push(
+                            safe_component(component)
+                                .with_context(|| format!("file {:?}", file.path.join("/")))?,
+                        );
+                    }
+                    Ok(path)
+                })
+                .collect(),
+        }
+    }
+
+    // Where the file of a single-file torrent goes when it's saved as `name`
+    // instead of the torrent's name, e.g. to avoid a collision.
+    pub fn renamed_path(&self, name: &str) -> anyhow::Result<PathBuf> {
+        anyhow::ensure!(
+            matches!(self.info.key, Key::SingleFile { .. }),
+            "only single-file torrents can be renamed"
+        );
+        Ok(PathBuf::from(safe_component(name).context("new name")?))
+    }
+
+    // Like `sanitize_paths`, laid out as `mode` says.
+    pub fn layout_paths(&self, mode: LayoutMode) -> anyhow::Result<Vec<PathBuf>> {
+        let paths = self.sanitize_paths()?;
+        if mode == LayoutMode::Nested || matches!(self.info.key, Key::SingleFile { .. }) {
+            return Ok(paths);
+        }
+        let mut taken = HashSet::new();
+        Ok(paths
+            .into_iter()
+            .map(|path| {
+                let mut components = path.iter().map(|c| c.to_string_lossy());
+                let root = components.next().expect("starts with the torrent name");
+                let name = components.collect::<Vec<_>>().join(FLAT_SEPARATOR);
+                let mut flat = name.clone();
+                // e.g. "a/b_c" and "a_b/c" both end up as "a_b_c"
+                let (stem, ext) = match name.rfind('.') {
+                    Some(dot) if dot > 0 => name.split_at(dot),
+                    _ => (name.as_str(), ""),
+                };
+                let mut n = 1;
+                while !taken.insert(flat.clone()) {
+                    flat = format!("{stem} ({n}){ext}");
+                    n += 1;
+                }
+                Path::new(root.as_ref()).join(flat)
+            })
+            .collect())
+    }
+
+    // Indices of the pieces covering each file (empty for empty files).
+    pub fn file_pieces(&self) -> Vec<Range<usize>> {
+        let piece_length = self.info.piece_length;
+        let mut offset = 0;
+        self.files()
+            .iter()
+            .map(|file| {
+                let start = offset;
+                offset += file.length;
+                if file.length == 0 {
+                    return 0..0;
+                }
+                start / piece_length..(offset - 1) / piece_length + 1
+            })
+            .collect()
+    }
+
+    // Fraction of each file's covering pieces that are complete.
+    // A piece spanning two files counts towards both of them.
+    pub fn file_progress(&self, pieces: &BitVec) -> Vec<(File, f64)> {
+        self.files()
+            .into_iter()
+            .zip(self.file_pieces())
+            .map(|(file, covering)| {
+                if covering.is_empty() {
+                    return (file, 1.0);
+                }
+                let total = covering.len();
+                let complete = covering.filter(|&piece_i| pieces.has(piece_i)).count();
+                (file, complete as f64 / total as f64)
+            })
+            .collect()
+    }
+
+    pub async fn download_all(&self) -> Result<Downloaded, BtError> {
         all(self).await
     }
 }
 
+// Joins the path components of a file in `LayoutMode::Flat`.
+pub const FLAT_SEPARATOR: &str = "_";
+
+// How the files of a multi-file torrent are laid out in its directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LayoutMode {
+    // subdirectories and all, as the torrent has them
+    #[default]
+    Nested,
+    // every file right in the torrent's directory, named after its whole path
+    Flat,
+}
+
+// A single file or directory name that stays where it's put.
+pub(crate) fn safe_component(name: &str) -> anyhow::Result<String> {
+    anyhow::ensure!(
+        !name.is_empty() && name != "." && name != "..",
+        "unsafe path component {name:?}"
+    );
+    anyhow::ensure!(
+        !name.contains(['/', '\\', ':', '\0']),
+        "path component {name:?} isn't a plain name"
+    );
+    // device names are reserved with any extension, e.g. "nul.txt"
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    let reserved = ["CON", "PRN", "AUX", "NUL"]
+        .iter()
+        .any(|r| stem.eq_ignore_ascii_case(r))
+        || (stem.len() == 4
+            && stem
+                .get(..3)
+                .is_some_and(|p| ["COM", "LPT"].iter().any(|r| p.eq_ignore_ascii_case(r)))
+            && matches!(stem.as_bytes()[3], b'1'..=b'9'));
+    Ok(if reserved {
+        format!("_{name}")
+    } else {
+        name.to_string()
+    })
+}
+
+// Formats seconds since the Unix epoch as "YYYY-MM-DD hh:mm:ss UTC".
+fn utc_timestamp(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // civil date from days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Finds the bencoded value of the top-level `info` key.
+fn raw_info(bytes: &[u8]) -> anyhow::Result<&[u8]> {
+    Ok(&bytes[raw_info_range(bytes)?])
+}
+
+fn raw_info_range(bytes: &[u8]) -> anyhow::Result<Range<usize>> {
+    anyhow::ensure!(bytes.first() == Some(&b'd'), "torrent is not a dictionary");
+    let mut pos = 1;
+    while bytes.get(pos).context("unterminated dictionary")? != &b'e' {
+        let key_end = skip_value(bytes, pos)?;
+        let value_end = skip_value(bytes, key_end)?;
+        if &bytes[pos..key_end] == b"4:info" {
+            return Ok(key_end..value_end);
+        }
+        pos = value_end;
+    }
+    anyhow::bail!("torrent has no info dictionary")
+}
+
+// Returns the position right after the bencoded value starting at `pos`.
+// Peers send us these, so nesting is tracked with a counter rather than
+// recursion, any depth is fine without running out of stack.
+pub(crate) fn skip_value(bytes: &[u8], mut pos: usize) -> anyhow::Result<usize> {
+    // lists and dictionaries we're inside of
+    let mut depth = 0usize;
+    loop {
+        let byte = bytes.get(pos).with_context(|| {
+            if depth > 0 {
+                "unterminated list or dictionary"
+            } else {
+                "unexpected end of bencoded data"
+            }
+        })?;
+        match byte {
+            b'i' => {
+                let len = bytes[pos..]
+                    .iter()
+                    .position(|&b| b == b'e')
+                    .context("unterminated integer")?;
+                pos += len + 1;
+            }
+            b'l' | b'd' => {
+                depth += 1;
+                pos += 1;
+                continue;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = bytes[pos..]
+                    .iter()
+                    .position(|&b| b == b':')
+                    .context("unterminated string length")?;
+                let len: usize = std::str::from_utf8(&bytes[pos..pos + colon])?
+                    .parse()
+                    .context("invalid string length")?;
+                pos = (pos + colon + 1)
+                    .checked_add(len)
+                    .filter(|&end| end <= bytes.len())
+                    .context("string runs past the end of the data")?;
+            }
+            b => anyhow::bail!("unexpected byte {b:#x} in bencoded data"),
+        }
+        if depth == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+// Sanity limits for metadata coming from untrusted sources (`.torrent` files
+// or metadata sent by peers), so a hostile torrent can't make us allocate
+// arbitrary amounts of memory.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_piece_length: usize,
+    pub max_pieces: usize,
+    // applies to the bencoded info dictionary, as for metadata from peers
+    pub max_info_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // 64 MiB, well above the 16 MiB real-world maximum
+            max_piece_length: 1 << 26,
+            max_pieces: 1 << 20,
+            max_info_size: 1 << 24,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check(&self, info: &Info) -> anyhow::Result<()> {
+        anyhow::ensure!(info.piece_length > 0, "piece length is zero");
+        anyhow::ensure!(
+            info.piece_length <= self.max_piece_length,
+            "piece length {} exceeds limit of {}",
+            info.piece_length,
+            self.max_piece_length
+        );
+        anyhow::ensure!(
+            info.pieces.0.len() <= self.max_pieces,
+            "torrent has {} pieces, limit is {}",
+            info.pieces.0.len(),
+            self.max_pieces
+        );
+        // the content has to fit the pieces, we allocate by its length
+        let length = match &info.key {
+            Key::SingleFile { length } => Some(*length),
+            Key::MultipleFiles { files } => files
+                .iter()
+                .try_fold(0usize, |sum, file| sum.checked_add(file.length)),
+        }
+        .context("torrent length overflows")?;
+        let n_pieces = info.pieces.0.len();
+        let covered = n_pieces
+            .checked_mul(info.piece_length)
+            .context("torrent length overflows")?;
+        let short_of_last = n_pieces.saturating_sub(1) * info.piece_length;
+        anyhow::ensure!(
+            length <= covered && (n_pieces == 0 || length > short_of_last),
+            "torrent length {length} doesn't match its {n_pieces} pieces of {}",
+            info.piece_length
+        );
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Info {
     // The `name` key maps to a UTF-8 encoded string which is
@@ -76,10 +595,72 @@ pub struct Info {
     // which is the SHA1 hash of the piece at the corresponding index.
     pub pieces: Hashes,
 
+    // 2 for hybrid torrents, which carry v2 (BEP 52) metadata next to the
+    // v1 keys so clients of either version can use them.
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+
+    // v2 layout of the files, each with the merkle root of its blocks.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+
+    // Set by private trackers so the same content gets a different info hash
+    // on each of them (prevents cross-seeding).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
     #[serde(flatten)]
     pub key: Key,
 }
 
+impl Info {
+    // v1 info without any of the optional keys.
+    pub fn new(name: &str, piece_length: usize, pieces: Hashes, key: Key) -> Self {
+        Self {
+            name: name.to_string(),
+            piece_length,
+            pieces,
+            meta_version: None,
+            file_tree: None,
+            source: None,
+            key,
+        }
+    }
+}
+
+// v2 directory: maps names to files or subdirectories.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FileTree(pub BTreeMap<String, FileTreeNode>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    // files are a dictionary with a single empty key
+    File {
+        #[serde(rename = "")]
+        entry: FileTreeEntry,
+    },
+    Dir(FileTree),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTreeEntry {
+    pub length: usize,
+    // SHA-256 merkle root of the file's 16 KiB blocks, missing for empty files
+    #[serde(
+        rename = "pieces root",
+        with = "serde_bytes",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub pieces_root: Vec<u8>,
+}
+
 // There is also a key length or a key files, but not both or neither.
 // If length is present then the download represents a single file,
 // otherwise it represents a set of files which go in a directory structure.
@@ -147,3 +728,353 @@ pub mod hashes {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_file(piece_length: usize) -> DotTorrent {
+        DotTorrent {
+            announce: Some("http://127.0.0.1:8000/announce".to_string()),
+            ..DotTorrent::new(Info::new(
+                "sample.txt",
+                piece_length,
+                Hashes(vec![[0; 20]]),
+                Key::SingleFile { length: 1024 },
+            ))
+        }
+    }
+
+    #[test]
+    fn pieces_are_contiguous_with_a_truncated_last_one() {
+        let mut dot_torrent = single_file(300);
+        dot_torrent.info.pieces = Hashes((0..4).map(|i| [i; 20]).collect());
+        let pieces: Vec<_> = dot_torrent.pieces().collect();
+        assert_eq!(pieces.len(), 4);
+        let mut offset = 0;
+        for (i, piece) in pieces.iter().enumerate() {
+            assert_eq!(piece.index, i);
+            assert_eq!(piece.offset, offset);
+            assert_eq!(piece.hash, [i as u8; 20]);
+            offset += piece.length;
+        }
+        assert_eq!(offset, 1024);
+        assert_eq!(pieces[3].length, 1024 - 3 * 300);
+
+        // the last piece is whole when the length is an exact multiple
+        let mut dot_torrent = single_file(256);
+        dot_torrent.info.pieces = Hashes(vec![[0; 20]; 4]);
+        let last = dot_torrent.pieces().last().unwrap();
+        assert_eq!((last.offset, last.length), (768, 256));
+        assert_eq!(dot_torrent.piece(4), None);
+    }
+
+    #[test]
+    fn file_progress_counts_shared_pieces_for_both_files() {
+        let file = |length, name: &str| File {
+            length,
+            path: vec![name.to_string()],
+        };
+        let dot_torrent = DotTorrent::new(Info::new(
+            "pack",
+            10,
+            Hashes(vec![[0; 20]; 3]),
+            Key::MultipleFiles {
+                // pieces: [0, 10) [10, 20) [20, 30)
+                files: vec![file(15, "a"), file(10, "b"), file(5, "c")],
+            },
+        ));
+        let mut pieces = BitVec::new(3);
+        pieces.set(0).unwrap();
+        pieces.set(1).unwrap();
+        let progress: Vec<_> = dot_torrent
+            .file_progress(&pieces)
+            .into_iter()
+            .map(|(file, fraction)| (file.path[0].clone(), fraction))
+            .collect();
+        assert_eq!(
+            progress,
+            vec![
+                ("a".to_string(), 1.0),
+                ("b".to_string(), 0.5),
+                ("c".to_string(), 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn info_hash_uses_raw_info_bytes() {
+        // `private` isn't modelled by `Info`, so re-encoding would drop it
+        let info: &[u8] = b"d6:lengthi1024e4:name10:sample.txt6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+12:piece lengthi32768e7:privatei1ee";
+        let mut bytes = b"d8:announce30:http://127.0.0.1:8000/announce4:info".to_vec();
+        bytes.extend(info);
+        bytes.push(b'e');
+
+        let dot_torrent = DotTorrent::from_bytes(&bytes).unwrap();
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(dot_torrent.info_hash().unwrap(), expected);
+
+        // re-encoding drops `private`, which would give a different hash
+        let reencoded = serde_bencode::to_bytes(&dot_torrent.info).unwrap();
+        assert_ne!(reencoded, info);
+    }
+
+    #[test]
+    fn same_torrent_ignores_trackers() {
+        let a = single_file(1 << 15);
+        let mut b = single_file(1 << 15);
+        b.announce = Some("udp://tracker.example:6969".to_string());
+        b.announce_list = Some(vec![vec!["udp://tracker.example:6969".to_string()]]);
+        assert!(a.same_torrent(&b));
+        assert!(!a.same_torrent(&single_file(1 << 16)));
+
+        let mut by_hash = std::collections::HashMap::new();
+        by_hash.insert(InfoHash(a.info_hash().unwrap()), "a");
+        assert_eq!(by_hash.get(&InfoHash(b.info_hash().unwrap())), Some(&"a"));
+    }
+
+    #[test]
+    fn magnet_trackers_are_merged_in() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056\
+             &tr=udp%3A%2F%2Fb.example%3A6969&tr=udp%3A%2F%2Fd.example%3A6969\
+             &tr=udp%3A%2F%2Fd.example%3A6969&tr=http%3A%2F%2Fa.example%2Fannounce",
+        )
+        .unwrap();
+        let mut dot_torrent = single_file(1 << 15);
+        dot_torrent.announce = Some("http://a.example/announce".to_string());
+        dot_torrent.announce_list = Some(vec![
+            vec!["http://a.example/announce".to_string()],
+            vec![
+                "udp://b.example:6969".to_string(),
+                "udp://c.example:6969".to_string(),
+            ],
+        ]);
+        dot_torrent.merge_trackers(&magnet.trackers);
+        assert_eq!(
+            dot_torrent.announce_list.unwrap(),
+            [
+                vec!["http://a.example/announce"],
+                vec!["udp://b.example:6969", "udp://c.example:6969"],
+                vec!["udp://d.example:6969"],
+            ]
+        );
+
+        // a torrent with just `announce` keeps it as the first tier
+        let mut dot_torrent = single_file(1 << 15);
+        dot_torrent.merge_trackers(&magnet.trackers);
+        assert_eq!(
+            dot_torrent.announce_list.unwrap(),
+            [
+                vec!["http://127.0.0.1:8000/announce"],
+                vec![
+                    "udp://b.example:6969",
+                    "udp://d.example:6969",
+                    "http://a.example/announce"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_shows_creation_details() {
+        let info = serde_bencode::to_bytes(&single_file(1 << 15).info).unwrap();
+        let mut bytes = b"d8:announce14:http://tracker7:comment11:hello there\
+            10:created by13:mktorrent 1.1\
+            13:creation datei1700000000e4:info"
+            .to_vec();
+        bytes.extend(&info);
+        bytes.push(b'e');
+        let dot_torrent = DotTorrent::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            dot_torrent.summary(),
+            "tracker: http://tracker\n\
+             created on: 2023-11-14 22:13:20 UTC\n\
+             created by: mktorrent 1.1\n\
+             comment: hello there\n\
+             torrent tree:\n\
+             sample.txt\n"
+        );
+        assert_eq!(dot_torrent.to_bytes().unwrap(), bytes);
+
+        let plain = single_file(1 << 15).summary();
+        assert!(!plain.contains("created") && !plain.contains("comment"));
+        assert_eq!(utc_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(utc_timestamp(-1), "1969-12-31 23:59:59 UTC");
+    }
+
+    #[test]
+    fn trackerless_torrent_loads() {
+        let info = serde_bencode::to_bytes(&single_file(1 << 15).info).unwrap();
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend(&info);
+        bytes.push(b'e');
+        let dot_torrent = DotTorrent::from_bytes(&bytes).unwrap();
+        assert_eq!(dot_torrent.announce, None);
+        assert_eq!(dot_torrent.info.name, "sample.txt");
+
+        let mut bytes = b"d8:announce0:4:info".to_vec();
+        bytes.extend(&info);
+        bytes.push(b'e');
+        let dot_torrent = DotTorrent::from_bytes(&bytes).unwrap();
+        assert_eq!(dot_torrent.announce, None);
+    }
+
+    #[test]
+    fn info_hash_of_sample_torrent() {
+        let bytes = std::fs::read("sample.torrent").unwrap();
+        let dot_torrent = DotTorrent::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            hex::encode(dot_torrent.info_hash().unwrap()),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+    }
+
+    #[test]
+    fn from_bytes_rejects_absurd_piece_length() {
+        let bytes = serde_bencode::to_bytes(&single_file(1 << 40)).unwrap();
+        let err = DotTorrent::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("piece length"));
+
+        let bytes = serde_bencode::to_bytes(&single_file(1 << 15)).unwrap();
+        assert!(DotTorrent::from_bytes(&bytes).is_ok());
+    }
+
+    #[test]
+    fn from_bytes_rejects_length_the_pieces_dont_cover() {
+        let mut dot_torrent = single_file(1 << 15);
+        dot_torrent.info.key = Key::SingleFile { length: 1 << 62 };
+        let bytes = serde_bencode::to_bytes(&dot_torrent).unwrap();
+        let err = DotTorrent::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("doesn't match"), "{err}");
+
+        // nor can the last piece be left empty
+        dot_torrent.info.pieces = Hashes(vec![[0; 20]; 2]);
+        dot_torrent.info.key = Key::SingleFile { length: 1 << 15 };
+        let bytes = serde_bencode::to_bytes(&dot_torrent).unwrap();
+        assert!(DotTorrent::from_bytes(&bytes).is_err());
+
+        // and files can't add up past what fits in memory at all
+        let files = (0..4).map(|i| File {
+            length: 1 << 62,
+            path: vec![i.to_string()],
+        });
+        dot_torrent.info.key = Key::MultipleFiles {
+            files: files.collect(),
+        };
+        let bytes = serde_bencode::to_bytes(&dot_torrent).unwrap();
+        let err = DotTorrent::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("overflows"), "{err}");
+    }
+
+    #[test]
+    fn skipping_hostile_bencode_fails_cleanly() {
+        // far deeper than the stack would allow one frame per level
+        let depth = 1_000_000;
+        let mut nested = b"l".repeat(depth);
+        nested.extend(b"e".repeat(depth));
+        assert_eq!(skip_value(&nested, 0).unwrap(), nested.len());
+        nested.pop();
+        let err = skip_value(&nested, 0).unwrap_err();
+        assert_eq!(err.to_string(), "unterminated list or dictionary");
+
+        // a length that wraps around when added to the position
+        let huge = format!("l{}:xe", usize::MAX);
+        let err = skip_value(huge.as_bytes(), 0).unwrap_err();
+        assert_eq!(err.to_string(), "string runs past the end of the data");
+
+        assert_eq!(skip_value(b"d3:keyli1ei-2eee4:rest", 0).unwrap(), 16);
+    }
+
+    #[test]
+    fn from_bytes_rejects_oversized_metadata() {
+        let mut dot_torrent = single_file(1 << 15);
+        let bytes = serde_bencode::to_bytes(&dot_torrent).unwrap();
+        let limits = Limits {
+            max_info_size: raw_info(&bytes).unwrap().len(),
+            ..Limits::default()
+        };
+        assert!(DotTorrent::from_bytes_with_limits(&bytes, &limits).is_ok());
+        // what's around the info dictionary doesn't count
+        dot_torrent.comment = Some("x".repeat(10_000));
+        let bytes = serde_bencode::to_bytes(&dot_torrent).unwrap();
+        assert!(DotTorrent::from_bytes_with_limits(&bytes, &limits).is_ok());
+
+        let limits = Limits {
+            max_info_size: limits.max_info_size - 1,
+            ..limits
+        };
+        assert!(DotTorrent::from_bytes_with_limits(&bytes, &limits).is_err());
+    }
+
+    #[test]
+    fn unsafe_file_paths_are_rejected() {
+        let with_paths = |paths: &[&[&str]]| {
+            let files = paths
+                .iter()
+                .map(|path| File {
+                    length: 1,
+                    path: path.iter().map(|c| c.to_string()).collect(),
+                })
+                .collect();
+            DotTorrent::new(Info::new(
+                "pack",
+                10,
+                Hashes(vec![[0; 20]]),
+                Key::MultipleFiles { files },
+            ))
+        };
+
+        let paths = with_paths(&[&["dir", "a"], &["con.txt"], &["LPT1"], &["com10"]])
+            .sanitize_paths()
+            .unwrap();
+        assert_eq!(
+            paths,
+            [
+                Path::new("pack/dir/a"),
+                Path::new("pack/_con.txt"),
+                Path::new("pack/_LPT1"),
+                Path::new("pack/com10"),
+            ]
+        );
+
+        let traversal = with_paths(&[&["a"], &["..", "..", "etc", "passwd"]]);
+        let err = traversal.sanitize_paths().unwrap_err();
+        assert!(format!("{err:#}").contains("../../etc/passwd"));
+        for path in [&["/etc", "passwd"][..], &["a/../../b"], &["C:", "x"], &[]] {
+            assert!(with_paths(&[path]).sanitize_paths().is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn hybrid_torrent_round_trips_byte_for_byte() {
+        let root = [1; 32];
+        let mut info = b"d9:file treed10:sample.txtd0:d6:lengthi65536e11:pieces root32:".to_vec();
+        info.extend(root);
+        // `private` isn't modelled, so only the raw bytes keep it
+        info.extend(b"eee6:lengthi65536e12:meta versioni2e4:name10:sample.txt");
+        info.extend(b"12:piece lengthi32768e6:pieces40:");
+        info.extend([b'a'; 40]);
+        info.extend(b"7:privatei1ee");
+        let mut bytes = b"d8:announce30:http://127.0.0.1:8000/announce4:info".to_vec();
+        bytes.extend(&info);
+        bytes.extend(b"12:piece layersd32:");
+        bytes.extend(root);
+        bytes.extend(b"64:");
+        bytes.extend([2; 64]);
+        bytes.extend(b"ee");
+
+        let dot_torrent = DotTorrent::from_bytes(&bytes).unwrap();
+        let layers = dot_torrent.piece_layers.as_ref().unwrap();
+        assert_eq!(layers[&ByteBuf::from(root.to_vec())], [2; 64]);
+        assert_eq!(dot_torrent.to_bytes().unwrap(), bytes);
+        // a fresh parse of the output is still the same torrent
+        let again = DotTorrent::from_bytes(&dot_torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            again.info_hash().unwrap(),
+            <[u8; 20]>::from(Sha1::digest(&info))
+        );
+        assert!(again.piece_layers.is_some());
+    }
+}
@@ -1,25 +1,392 @@
 use crate::BLOCK_SIZE;
-use crate::dot_torrent::{DotTorrent, File, Key};
-use crate::peer::{MessageType, Peer, PieceResponse};
+use crate::bit_vec::BitVec;
+use crate::cache::{AdaptiveCap, BlockCache, DiskOptions, DiskWriter, SystemMemory, adapt};
+use crate::dot_torrent::{DotTorrent, File, FileIndex, LayoutMode, PieceInfo};
+use crate::error::BtError;
+use crate::ip_filter::IpFilter;
+use crate::peer::{
+    IDLE_TIMEOUT, MessageType, Peer, PeerId, PieceResponse, ProtocolCheck, RequestTimeout,
+    cancel_superseded, client_peer_id,
+};
 use crate::piece::Piece;
-use crate::tracker::query_tracker;
+use crate::piece_download::{PieceDownload, PieceDownloads};
+use crate::rate_limit::RateLimiter;
+use crate::recheck::{data_paths, read_blocks};
+use crate::state::PartialPiece;
+use crate::torrent::{DownloadRate, PieceMetric};
+use crate::tracker::{Progress, TrackerClient};
+use crate::verify::PieceVerifier;
+use crate::web_seed::{self, HttpSeed};
 use anyhow::Context;
+use bytes::Bytes;
 use futures_util::StreamExt;
 use futures_util::stream;
 use futures_util::stream::futures_unordered::FuturesUnordered;
-use kanal::bounded_async;
-use sha1::{Digest, Sha1};
-use std::collections::BinaryHeap;
-use tokio::sync::mpsc::channel;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, unbounded_channel};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until};
 
-pub(crate) async fn all(dot_torrent: &DotTorrent) -> anyhow::Result<Downloaded> {
-    let tracker_resp = query_tracker(dot_torrent)
-        .await
-        .context("query tracker for peer info")?;
+// A freshly created torrent often has no peers yet, so keep asking for a while.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const PEERS_DEADLINE: Duration = Duration::from_secs(5 * 60);
+
+// How close to its deadline a piece goes into endgame, see `DownloadOptions::deadlines`.
+pub const DEADLINE_ENDGAME: Duration = Duration::from_secs(2);
+
+pub const PIECE_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    // Upper bound on the bytes held by partially downloaded pieces.
+    pub assembly_buffer: usize,
+    // Adjust `assembly_buffer` to the memory available as the download goes on.
+    pub adaptive_buffer: Option<AdaptiveCap>,
+    // Requests each peer keeps outstanding, and blocks peers may hand over
+    // before waiting for assembly to catch up.
+    pub pipeline_depth: usize,
+    // Most of a piece's blocks (0 to 1) a single peer may claim while others
+    // are helping. `None` lets fast peers take as many as they can.
+    pub max_peer_share: Option<f64>,
+    // Stop as soon as these files are complete, leaving the rest undownloaded.
+    pub stop_after: Option<Vec<FileIndex>>,
+    // Peers silent for this long (not even a keep-alive) are dropped.
+    pub idle_timeout: Duration,
+    // How long each peer gets to answer a block request, see `RequestTimeout`.
+    pub request_timeout: RequestTimeout,
+    // Caps the download rate, usually a child of a limiter shared by all
+    // torrents (see `RateLimiter::child`).
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    // Hashes the downloaded pieces, usually shared by all torrents so only
+    // so many pieces are hashed at once.
+    pub verifier: Arc<PieceVerifier>,
+    // What we introduce ourselves to peers as.
+    pub peer_id: PeerId,
+    // Peers that aren't connected to.
+    pub ip_filter: Arc<IpFilter>,
+    // The pieces being downloaded, for someone else to look into or steer,
+    // e.g. the `Torrent` behind `Torrent::download_options`. Peers excluded
+    // there (see `PieceDownloads::exclude`) aren't asked for anything more.
+    pub downloads: Arc<std::sync::Mutex<PieceDownloads>>,
+    // How the latest attempt at each piece went, by index.
+    pub piece_metrics: Arc<std::sync::Mutex<BTreeMap<usize, PieceMetric>>>,
+    // Fed every block received, e.g. for `Torrent::eta`.
+    pub download_rate: Arc<std::sync::Mutex<DownloadRate>>,
+    // Pieces needed by a certain time (e.g. for streaming playback). They're
+    // downloaded before any other, the earliest deadline first, and their
+    // blocks are requested from every peer once the deadline is
+    // `DEADLINE_ENDGAME` away.
+    pub deadlines: HashMap<usize, Instant>,
+    // Blocks of unfinished pieces that are already on disk.
+    pub on_disk: Option<OnDisk>,
+    // Write the downloaded files under this directory (synced to disk)
+    // before returning.
+    pub save_to: Option<PathBuf>,
+    // How the files of a multi-file torrent are laid out under `save_to`.
+    pub layout: LayoutMode,
+    // Save the file of a single-file torrent under this name instead.
+    pub rename: Option<String>,
+    // How the files are written under `save_to`, see `DiskWriter`.
+    pub disk: DiskOptions,
+    // How many times a piece is tried (failing the hash check or running out
+    // of peers) before it's given up on. The rest of the torrent carries on
+    // without it, see `Downloaded::failed`.
+    pub piece_attempts: usize,
+    // Who the piece that completes the download is fetched from.
+    pub last_piece: LastPiece,
+    pub on_complete: Option<OnComplete>,
+    pub on_event: Option<OnEvent>,
+}
+
+// How the last piece left is downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LastPiece {
+    // like any other piece, split between the peers that have it
+    #[default]
+    Shared,
+    // Entirely from the peer we've downloaded from fastest, so the download
+    // isn't held up by a slow one. If that peer gives up, every other peer
+    // that has the piece is asked for any missing block (endgame).
+    FastestPeer,
+}
+
+// Things that happen during a download a UI may want to show.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadEvent {
+    // out of attempts, the piece won't be downloaded
+    PieceFailed { index: usize, reason: String },
+    // the peer claimed to have the piece but wouldn't send it, so it's no
+    // longer asked for it
+    PeerDistrusted { peer: SocketAddrV4, index: usize },
+}
+
+// Called with every `DownloadEvent` as it happens.
+#[derive(Clone)]
+pub struct OnEvent(Arc<dyn Fn(&DownloadEvent) + Send + Sync>);
+
+impl OnEvent {
+    pub fn new(hook: impl Fn(&DownloadEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for OnEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnEvent(..)")
+    }
+}
+
+// Runs once a download finishes, after its files are saved if `save_to` is
+// set, e.g. to move them to a library or extract an archive.
+#[derive(Clone)]
+pub struct OnComplete(Arc<dyn Fn(&Downloaded) + Send + Sync>);
+
+impl OnComplete {
+    pub fn new(hook: impl Fn(&Downloaded) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for OnComplete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnComplete(..)")
+    }
+}
+
+// Partially written pieces under `dir` (see `recheck::read_blocks`). Their
+// blocks are read back instead of being requested again.
+#[derive(Debug, Clone)]
+pub struct OnDisk {
+    pub dir: PathBuf,
+    pub pieces: Vec<PartialPiece>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            assembly_buffer: 16 << 20,
+            adaptive_buffer: None,
+            pipeline_depth: 5,
+            max_peer_share: None,
+            stop_after: None,
+            idle_timeout: IDLE_TIMEOUT,
+            request_timeout: RequestTimeout::default(),
+            rate_limit: None,
+            verifier: Arc::default(),
+            peer_id: client_peer_id(),
+            ip_filter: Arc::default(),
+            downloads: Arc::default(),
+            piece_metrics: Arc::default(),
+            download_rate: Arc::default(),
+            deadlines: HashMap::new(),
+            on_disk: None,
+            save_to: None,
+            layout: LayoutMode::default(),
+            rename: None,
+            disk: DiskOptions::default(),
+            piece_attempts: PIECE_ATTEMPTS,
+            last_piece: LastPiece::default(),
+            on_complete: None,
+            on_event: None,
+        }
+    }
+}
+
+pub(crate) async fn all(dot_torrent: &DotTorrent) -> Result<Downloaded, BtError> {
+    let peers = wait_for_peers(dot_torrent, FIRST_RETRY, PEERS_DEADLINE).await?;
+    from_peers(dot_torrent, &peers, &DownloadOptions::default()).await
+}
+
+// Re-announces until the tracker hands out at least one peer. The wait between
+// announces doubles each time, starting at `first_retry`, but never exceeds the
+// interval the tracker asked for.
+async fn wait_for_peers(
+    dot_torrent: &DotTorrent,
+    first_retry: Duration,
+    deadline: Duration,
+) -> Result<Vec<SocketAddrV4>, BtError> {
+    let Some(announce) = &dot_torrent.announce else {
+        // peers would have to come from DHT/PEX, which we don't speak yet
+        return Err(BtError::Tracker(anyhow::anyhow!(
+            "torrent is trackerless and DHT isn't supported"
+        )));
+    };
+    let client = TrackerClient::new();
+    let deadline = Instant::now() + deadline;
+    let mut retry = first_retry;
+    loop {
+        let tracker_resp = client
+            .announce(announce, dot_torrent, None, Progress::fresh(dot_torrent))
+            .await
+            .context("query tracker for peer info")
+            .map_err(BtError::Tracker)?;
+        if !tracker_resp.peers.0.is_empty() {
+            return Ok(tracker_resp.peers.0);
+        }
+        let wait = retry
+            .min(Duration::from_secs(tracker_resp.interval))
+            .max(first_retry);
+        if Instant::now() + wait > deadline {
+            return Err(BtError::Timeout(
+                "tracker has no peers for this torrent, giving up".to_string(),
+            ));
+        }
+        println!("tracker has no peers yet, asking again in {wait:?}");
+        tokio::time::sleep(wait).await;
+        retry *= 2;
+    }
+}
+
+// Downloads the whole torrent from (up to 5 of) the given peers.
+pub async fn from_peers(
+    dot_torrent: &DotTorrent,
+    peer_addrs: &[SocketAddrV4],
+    options: &DownloadOptions,
+) -> Result<Downloaded, BtError> {
+    // pieces covering the files we stop after, including ones shared with other files
+    let wanted: Option<HashSet<usize>> = match &options.stop_after {
+        Some(files) => {
+            let file_pieces = dot_torrent.file_pieces();
+            let mut wanted = HashSet::new();
+            for &file_i in files {
+                let covering = file_pieces
+                    .get(file_i)
+                    .with_context(|| format!("torrent has no file {file_i}"))?;
+                wanted.extend(covering.clone());
+            }
+            Some(wanted)
+        }
+        None => None,
+    };
+    let n_pieces = dot_torrent.info.pieces.0.len();
+    let (bytes, verified, failed) =
+        fetch(dot_torrent, peer_addrs, options, wanted, 0..n_pieces).await?;
+    let downloaded = Downloaded {
+        bytes,
+        failed,
+        files: dot_torrent.files(),
+        complete: dot_torrent
+            .file_progress(&verified)
+            .into_iter()
+            .map(|(_, progress)| progress == 1.0)
+            .collect(),
+    };
+    if let Some(dir) = &options.save_to {
+        let writer = DiskWriter::new(options.disk.clone());
+        save(dot_torrent, &downloaded, dir, &writer, options).await?;
+    }
+    if let Some(OnComplete(hook)) = &options.on_complete {
+        hook(&downloaded);
+    }
+    Ok(downloaded)
+}
+
+// Writes the complete files under `dir` through `writer` a block at a time
+// and waits until they're on disk. Adjacent blocks go out as one write, up
+// to `DiskOptions::coalesce_limit`.
+async fn save(
+    dot_torrent: &DotTorrent,
+    downloaded: &Downloaded,
+    dir: &Path,
+    writer: &DiskWriter,
+    options: &DownloadOptions,
+) -> Result<(), BtError> {
+    let paths = match &options.rename {
+        Some(name) => dot_torrent
+            .renamed_path(name)
+            .map(|path| vec![dir.join(path)]),
+        None => data_paths(dot_torrent, dir, options.layout),
+    }
+    .map_err(BtError::Parse)?;
+    for file in downloaded {
+        let path = &paths[file.index];
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // cuts off what's left of an earlier, longer file
+        tokio::fs::File::create(path).await?;
+        for (block_i, block) in file.bytes().chunks(BLOCK_SIZE).enumerate() {
+            let offset = (block_i * BLOCK_SIZE) as u64;
+            writer
+                .write(path.clone(), offset, Bytes::copy_from_slice(block))
+                .await;
+        }
+    }
+    writer.flush().await?;
+    for file in downloaded {
+        let handle = tokio::fs::File::open(&paths[file.index]).await?;
+        handle.sync_all().await?;
+    }
+    Ok(())
+}
+
+// Downloads the bytes `range` of the torrent's content, as if all its files
+// were laid out back to back. Pieces at the edges are fetched whole so they
+// can be verified, but only the requested bytes are returned.
+pub async fn range_from_peers(
+    dot_torrent: &DotTorrent,
+    peer_addrs: &[SocketAddrV4],
+    range: Range<usize>,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>, BtError> {
+    let length = dot_torrent.length();
+    if range.start > range.end || range.end > length {
+        return Err(BtError::Other(anyhow::anyhow!(
+            "byte range {range:?} is outside of the {length} bytes of the torrent"
+        )));
+    }
+    if range.is_empty() {
+        return Ok(Vec::new());
+    }
+    let piece_length = dot_torrent.info.piece_length;
+    let span = range.start / piece_length..(range.end - 1) / piece_length + 1;
+    let wanted = span.clone().collect();
+    let (mut bytes, _, failed) =
+        fetch(dot_torrent, peer_addrs, options, Some(wanted), span.clone()).await?;
+    if let Some(piece_i) = failed.first() {
+        return Err(BtError::Peer(anyhow::anyhow!(
+            "gave up on piece {piece_i} of the range"
+        )));
+    }
+    // the buffer starts with the first piece of the span
+    let base = span.start * piece_length;
+    bytes.truncate(range.end - base);
+    bytes.drain(..range.start - base);
+    Ok(bytes)
+}
+
+// Downloads the `wanted` pieces (all of them if `None`) into a buffer holding
+// the pieces in `span`, returning it along with the pieces it holds and the
+// ones given up on. Wanted pieces have to be within the span.
+async fn fetch(
+    dot_torrent: &DotTorrent,
+    peer_addrs: &[SocketAddrV4],
+    options: &DownloadOptions,
+    wanted: Option<HashSet<usize>>,
+    span: Range<usize>,
+) -> Result<(Vec<u8>, BitVec, BTreeSet<usize>), BtError> {
     let info_hash = dot_torrent.info_hash()?;
-    let mut stream = stream::iter(tracker_resp.peers.0.iter())
+    let (peer_addrs, filtered): (Vec<&SocketAddrV4>, Vec<_>) = peer_addrs
+        .iter()
+        .partition(|addr| options.ip_filter.allows(*addr.ip()));
+    for peer_addr in filtered {
+        println!("not connecting to filtered peer {peer_addr}");
+    }
+    let mut stream = stream::iter(peer_addrs)
         .map(|peer_addr| async move {
-            let peer = Peer::new(*peer_addr, info_hash).await;
+            let peer = Peer::connect(
+                *peer_addr,
+                info_hash,
+                options.peer_id,
+                ProtocolCheck::Strict,
+            )
+            .await;
             (peer_addr, peer)
         })
         .buffer_unordered(5);
@@ -27,7 +394,11 @@ pub(crate) async fn all(dot_torrent: &DotTorrent) -> anyhow::Result<Downloaded>
     let mut peers = Vec::new();
     while let Some((peer_addr, peer)) = stream.next().await {
         match peer {
-            Ok(peer) => {
+            Ok(mut peer) => {
+                peer.set_idle_timeout(options.idle_timeout);
+                peer.set_request_timeout(options.request_timeout);
+                peer.set_pipeline_depth(options.pipeline_depth);
+                peer.set_piece_count(dot_torrent.info.pieces.0.len());
                 peers.push(peer);
                 if peers.len() >= 5 {
                     break;
@@ -37,6 +408,16 @@ pub(crate) async fn all(dot_torrent: &DotTorrent) -> anyhow::Result<Downloaded>
         }
     }
     drop(stream);
+    let seeds: Vec<_> = dot_torrent
+        .httpseeds
+        .iter()
+        .map(|url| HttpSeed::new(url))
+        .collect();
+    if peers.is_empty() && seeds.is_empty() {
+        return Err(BtError::Peer(anyhow::anyhow!(
+            "couldn't connect to any peer"
+        )));
+    }
 
     // TODO: since it's stored in memory, should be implemented differently
     // write every piece to disk so we can resume downloads and seed later on
@@ -44,138 +425,414 @@ pub(crate) async fn all(dot_torrent: &DotTorrent) -> anyhow::Result<Downloaded>
     // pieces which peers don't have
     let mut unavailable_pieces = Vec::new();
     for piece_i in 0..dot_torrent.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, dot_torrent, &peers);
+        if wanted
+            .as_ref()
+            .is_some_and(|wanted| !wanted.contains(&piece_i))
+        {
+            // never queued, so the loop ends once the wanted pieces are verified
+            continue;
+        }
+        let piece = Piece::new(piece_i, dot_torrent, &peers)
+            .with_deadline(options.deadlines.get(&piece_i).copied());
         if piece.peers().is_empty() {
             unavailable_pieces.push(piece);
         } else {
             pieces_to_download.push(piece);
         }
     }
-    assert!(unavailable_pieces.is_empty());
+    if !unavailable_pieces.is_empty() && seeds.is_empty() {
+        return Err(BtError::Peer(anyhow::anyhow!(
+            "{} piece(s) aren't available from any connected peer",
+            unavailable_pieces.len()
+        )));
+    }
+
+    let cache = Arc::new(BlockCache::new(options.assembly_buffer));
+    // dropped (and so aborted) along with the download
+    let mut background = JoinSet::new();
+    if let Some(cap) = &options.adaptive_buffer {
+        background.spawn(adapt(cache.clone(), cap.clone(), SystemMemory));
+    }
+    let mut verified = BitVec::new(dot_torrent.info.pieces.0.len());
+    let mut attempts: HashMap<usize, usize> = HashMap::new();
+    let mut failed = BTreeSet::new();
+    // only as big as the span, so a small range of a big torrent stays small
+    let piece_length = dot_torrent.info.piece_length;
+    let base = span.start * piece_length;
+    let mut downloaded_pieces = vec![0; (span.end * piece_length).min(dot_torrent.length()) - base];
 
-    let mut downloaded_pieces = vec![0; dot_torrent.length()];
-    while let Some(piece) = pieces_to_download.pop() {
-        let peers: Vec<_> = peers
+    // what the peers can't give us, the web seeds may, while the peers carry on
+    let has_seeds = !seeds.is_empty();
+    let (seed_tx, seed_rx) = unbounded_channel();
+    let (seeded_tx, mut seeded_rx) = unbounded_channel();
+    background.spawn(web_seed::fetch_queued(
+        seeds,
+        info_hash,
+        options.verifier.clone(),
+        seed_rx,
+        seeded_tx,
+    ));
+    for piece in &unavailable_pieces {
+        let info = dot_torrent
+            .piece(piece.index())
+            .expect("within the torrent");
+        seed_tx.send(info).expect("web seeds outlive the download");
+    }
+    while let Some(mut piece) = pieces_to_download.pop() {
+        while let Ok((info, seeded)) = seeded_rx.try_recv() {
+            take_seeded(
+                info,
+                seeded,
+                &mut downloaded_pieces,
+                base,
+                &mut verified,
+                &mut failed,
+            )?;
+        }
+        // peers that flooded us with unrequested pieces, went quiet or were
+        // excluded are done
+        let excluded = |peer: &Peer| {
+            let downloads = options.downloads.lock().expect("mutex was poisoned");
+            downloads.is_excluded(peer.addr())
+        };
+        let usable = |peer_i: usize, peer: &Peer| {
+            piece.peers().contains(&peer_i)
+                && !peer.is_flooding()
+                && !peer.is_idle()
+                && !excluded(peer)
+        };
+        // slow peers only get to help when there's no one else
+        let any_fast = peers
+            .iter()
+            .enumerate()
+            .any(|(peer_i, peer)| usable(peer_i, peer) && !peer.is_slow());
+        let mut participating: Vec<_> = peers
             .iter_mut()
             .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
+            .filter(|(peer_i, peer)| usable(*peer_i, peer) && !(any_fast && peer.is_slow()))
+            .map(|(_, peer)| peer)
             .collect();
+        // held back unless the one participant gives up
+        let mut reserve = Vec::new();
+        if options.last_piece == LastPiece::FastestPeer
+            && pieces_to_download.is_empty()
+            && participating.len() > 1
+        {
+            participating
+                .sort_by(|a, b| b.stats().download_rate.total_cmp(&a.stats().download_rate));
+            reserve = participating.split_off(1);
+        }
 
+        let started = Instant::now();
+        let mut first_block = None;
         let piece_size = piece.length();
         // "+ BLOCK_SIZE - 1" rounds up the number
         let n_blocks = (piece_size + BLOCK_SIZE - 1) / BLOCK_SIZE;
-        let (job_tx, job_rx) = bounded_async(n_blocks);
-        for block_i in 0..n_blocks {
-            job_tx
-                .send(block_i)
-                .await
-                .expect("all peers already exited");
-        }
-
-        let (done_tx, mut done_rx) = channel(n_blocks);
-        let mut participants = FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                n_blocks,
-                job_tx.clone(),
-                job_rx.clone(),
-                done_tx.clone(),
-            ));
+        let mut download = PieceDownload::new(piece.index(), piece_size);
+        if let Some(share) = options.max_peer_share {
+            download = download.with_max_share(share);
         }
-        // drop our copies of handles
-        drop(job_tx);
-        drop(done_tx);
-        drop(job_rx);
+        let mut assembled = None;
+        let partial = options.on_disk.as_ref().and_then(|on_disk| {
+            let partial = on_disk.pieces.iter().find(|p| p.index == piece.index())?;
+            Some((&on_disk.dir, partial))
+        });
+        if let Some((dir, partial)) = partial {
+            let have =
+                BitVec::from_bytes(partial.blocks.clone(), n_blocks).map_err(BtError::Parse)?;
+            for (begin, block) in
+                read_blocks(dot_torrent, dir, options.layout, piece.index(), &have).await?
+            {
+                assembled = cache.put_block(info_hash, piece.index(), begin, block, piece_size);
+            }
+            download = download.with_blocks(&have);
+        }
+        let download = options
+            .downloads
+            .lock()
+            .expect("mutex was poisoned")
+            .insert(download);
 
-        let mut downloaded_blocks = vec![0u8; piece_size];
-        let mut bytes_received = 0;
-        loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
-                    // match joined {
-                    //     None => {
-                    //         // There are no peers.
-                    //         // This must mean we are about to get `None` from `done_rx.recv()`,
-                    //         // so we'll handle it there.
-                    //     }
-                    //     Some(Ok(_)) => {
-                    //         // The peer gave up because it timed out.
-                    //         // Nothing to do, except maybe to de-prioritize this peer
-                    //         // for later.
-                    //     }
-                    //     Some(Err(_)) => {
-                    //         // Peer failed and should be removed later.
-                    //         // It already isn't participating in this piece.
-                    //         // We should remove it from global peer list.
-                    //     }
-                    // }
-                }
-                msg = done_rx.recv() => {
-                    if let Some(msg) = msg {
-                        assert_eq!(msg.typ, MessageType::Piece);
-                        assert!(!msg.payload.is_empty());
-                        // keep track of the bytes in message
-                        let piece_response = PieceResponse::ref_from_bytes(&msg.payload)
-                            .expect("always get all `PieceResponse` fields from peer");
-                        downloaded_blocks[piece_response.begin() as usize..][..piece_response.block().len()]
-                            .copy_from_slice(piece_response.block());
-                        bytes_received += piece_response.block().len();
-                        if bytes_received == piece_size {
-                            // we got all the bytes
-                            // This must mean that all participants have either exited or
-                            // are waiting for more work. In either case, it's OK to drop
-                            // all the participant futures.
-                            break;
+        let endgame_at = piece
+            .deadline()
+            .map(|deadline| deadline.checked_sub(DEADLINE_ENDGAME).unwrap_or(deadline));
+        if assembled.is_none() {
+            assembled = download_blocks(
+                &download,
+                participating,
+                reserve,
+                endgame_at,
+                options,
+                |begin, block| {
+                    first_block.get_or_insert_with(|| started.elapsed());
+                    cache.put_block(info_hash, piece.index(), begin, block, piece_size)
+                },
+            )
+            .await;
+        }
+        cancel_superseded(&mut peers, piece.index(), piece_size, download.scheduler()).await;
+        options
+            .downloads
+            .lock()
+            .expect("mutex was poisoned")
+            .finish(piece.index());
+
+        // peers that kept rejecting requests dropped the piece from their bitfield
+        for (peer_i, peer) in peers.iter().enumerate() {
+            if !peer.has_piece(piece.index())
+                && piece.forget_peer(peer_i)
+                && let Some(OnEvent(hook)) = &options.on_event
+            {
+                hook(&DownloadEvent::PeerDistrusted {
+                    peer: peer.addr(),
+                    index: piece.index(),
+                });
+            }
+        }
+
+        let checked = match assembled {
+            Some(blocks) => Some(options.verifier.verified(blocks, piece.hash()).await),
+            None => None,
+        };
+        let metric = PieceMetric {
+            piece: piece.index(),
+            first_block,
+            complete: matches!(checked, Some(Some(_))).then(|| started.elapsed()),
+            peers: download.contributors(),
+            retries: attempts.get(&piece.index()).copied().unwrap_or(0),
+        };
+        options
+            .piece_metrics
+            .lock()
+            .expect("mutex was poisoned")
+            .insert(piece.index(), metric);
+
+        let reason = match checked {
+            Some(checked) => match checked {
+                Some(blocks) => {
+                    assert_eq!(blocks.len(), piece_size);
+                    verified.set(piece.index())?;
+                    for peer in &mut peers {
+                        if let Err(err) = peer.update_interest(&verified).await {
+                            println!("failed to update interest in peer {}: {err}", peer.addr());
                         }
-                    } else {
-                        // there are no peer left so we can't progress
-                        assert_eq!(bytes_received, piece_size);
-                        break;
                     }
+                    downloaded_pieces[piece.index() * piece_length - base..][..piece_size]
+                        .copy_from_slice(&blocks);
+                    continue;
                 }
-            }
+                None => BtError::Hash {
+                    piece: piece.index(),
+                }
+                .to_string(),
+            },
+            // TODO: connect to more peers that have the piece before giving up on it
+            None => format!("no peers left to get piece {}", piece.index()),
+        };
+        let index = piece.index();
+        retry_or_fail(
+            piece,
+            reason,
+            &mut pieces_to_download,
+            &mut attempts,
+            &mut failed,
+            options,
+        );
+        if has_seeds && failed.remove(&index) {
+            let info = dot_torrent.piece(index).expect("within the torrent");
+            seed_tx.send(info).expect("web seeds outlive the download");
         }
-        drop(participants);
+    }
 
-        if bytes_received == piece_size {
-            // we got all the bytes
-        } else {
-            // We'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the pieces we didn't get from them.
-            // Probably also stick this back onto the pieces_heap.
-            anyhow::bail!("no peers left to get piece {}", piece.index());
-        }
+    // the peers are done, wait for the web seeds
+    drop(seed_tx);
+    while let Some((info, seeded)) = seeded_rx.recv().await {
+        take_seeded(
+            info,
+            seeded,
+            &mut downloaded_pieces,
+            base,
+            &mut verified,
+            &mut failed,
+        )?;
+    }
+    Ok((downloaded_pieces, verified, failed))
+}
 
-        assert_eq!(downloaded_blocks.len(), piece_size);
-        let mut hasher = Sha1::new();
-        hasher.update(&downloaded_blocks);
-        let hash: [u8; 20] = hasher.finalize().into();
-        assert_eq!(hash, piece.hash());
+// Puts a piece from the web seeds in its place among the `downloaded` bytes,
+// which start at `base` of the torrent, or gives up on it.
+fn take_seeded(
+    piece: PieceInfo,
+    seeded: anyhow::Result<Vec<u8>>,
+    downloaded: &mut [u8],
+    base: usize,
+    verified: &mut BitVec,
+    failed: &mut BTreeSet<usize>,
+) -> Result<(), BtError> {
+    match seeded {
+        Ok(data) => {
+            verified.set(piece.index)?;
+            downloaded[piece.offset - base..][..piece.length].copy_from_slice(&data);
+        }
+        Err(err) => {
+            println!("{err}");
+            failed.insert(piece.index);
+        }
+    }
+    Ok(())
+}
 
-        downloaded_pieces[piece.index() * dot_torrent.info.piece_length..][..piece_size]
-            .copy_from_slice(&downloaded_blocks)
+// Queues `piece` again, unless it's out of attempts or peers to get it from,
+// in which case it's given up on and reported.
+fn retry_or_fail(
+    piece: Piece,
+    reason: String,
+    queue: &mut BinaryHeap<Piece>,
+    attempts: &mut HashMap<usize, usize>,
+    failed: &mut BTreeSet<usize>,
+    options: &DownloadOptions,
+) {
+    let index = piece.index();
+    let tried = attempts.entry(index).or_default();
+    *tried += 1;
+    if *tried < options.piece_attempts && !piece.peers().is_empty() {
+        println!("retrying piece {index}: {reason}");
+        queue.push(piece);
+        return;
+    }
+    failed.insert(index);
+    if let Some(OnEvent(hook)) = &options.on_event {
+        hook(&DownloadEvent::PieceFailed { index, reason });
     }
+}
 
-    let files = match &dot_torrent.info.key {
-        Key::SingleFile { length } => vec![File {
-            length: *length,
-            path: vec![dot_torrent.info.name.clone()],
-        }],
-        Key::MultipleFiles { files } => files.clone(),
-    };
+// Gets the missing blocks of `download` from the `participating` peers,
+// handing each to `on_block` until it returns the assembled piece. If the
+// participants all stop before that, the `reserve` peers are asked for the
+// rest (endgame). `None` if the piece couldn't be completed.
+pub(crate) async fn download_blocks(
+    download: &PieceDownload,
+    participating: Vec<&mut Peer>,
+    mut reserve: Vec<&mut Peer>,
+    endgame_at: Option<Instant>,
+    options: &DownloadOptions,
+    mut on_block: impl FnMut(usize, Bytes) -> Option<Bytes>,
+) -> Option<Bytes> {
+    let (index, piece_size) = (download.index(), download.length());
+    let n_blocks = piece_size.div_ceil(BLOCK_SIZE);
+    // small on purpose: a full channel makes peers wait instead of piling up blocks
+    let (done_tx, mut done_rx) = channel(options.pipeline_depth.max(1));
+    let mut participants = FuturesUnordered::new();
+    for peer in participating {
+        participants.push(peer.participate(
+            index,
+            piece_size,
+            n_blocks,
+            download.scheduler(),
+            done_tx.clone(),
+        ));
+    }
+    // kept for the reserve, dropped with it
+    let mut reserve_tx = (!reserve.is_empty()).then(|| done_tx.clone());
+    // drop our copy of the handle
+    drop(done_tx);
 
-    Ok(Downloaded {
-        bytes: downloaded_pieces,
-        files,
-    })
+    loop {
+        tokio::select! {
+            _ = sleep_until(endgame_at.unwrap_or_else(Instant::now)),
+                if endgame_at.is_some() && !download.scheduler().is_endgame() => {
+                download.scheduler().set_endgame();
+            }
+            joined = participants.next(), if !participants.is_empty() => {
+                if participants.is_empty()
+                    && download.scheduler().n_done() < n_blocks
+                    && let Some(done_tx) = reserve_tx.take()
+                {
+                    download.scheduler().set_endgame();
+                    for peer in reserve.drain(..) {
+                        participants.push(peer.participate(
+                            index,
+                            piece_size,
+                            n_blocks,
+                            download.scheduler(),
+                            done_tx.clone(),
+                        ));
+                    }
+                }
+                // if a participant ends early, it's either slow or failed
+                // match joined {
+                //     None => {
+                //         // There are no peers.
+                //         // This must mean we are about to get `None` from `done_rx.recv()`,
+                //         // so we'll handle it there.
+                //     }
+                //     Some(Ok(_)) => {
+                //         // The peer gave up because it timed out.
+                //         // Nothing to do, except maybe to de-prioritize this peer
+                //         // for later.
+                //     }
+                //     Some(Err(_)) => {
+                //         // Peer failed and should be removed later.
+                //         // It already isn't participating in this piece.
+                //         // We should remove it from global peer list.
+                //     }
+                // }
+            }
+            msg = done_rx.recv() => {
+                let Some(msg) = msg else {
+                    // there are no peer left so we can't progress
+                    return None;
+                };
+                assert_eq!(msg.typ, MessageType::Piece);
+                assert!(!msg.payload.is_empty());
+                // keep track of the bytes in message
+                let piece_response = PieceResponse::ref_from_bytes(&msg.payload)
+                    .expect("always get all `PieceResponse` fields from peer");
+                if let Some(limit) = &options.rate_limit {
+                    // peers wait on the channel meanwhile
+                    limit.acquire(piece_response.block().len()).await;
+                }
+                options
+                    .download_rate
+                    .lock()
+                    .expect("mutex was poisoned")
+                    .record(piece_response.block().len(), std::time::Instant::now());
+                let assembled = on_block(
+                    piece_response.begin() as usize,
+                    Bytes::copy_from_slice(piece_response.block()),
+                );
+                if assembled.is_some() {
+                    // we got all the bytes
+                    // This must mean that all participants have either exited or
+                    // are waiting for more work. In either case, it's OK to drop
+                    // all the participant futures.
+                    return assembled;
+                }
+            }
+        }
+    }
 }
 
 pub struct Downloaded {
     files: Vec<File>,
     bytes: Vec<u8>,
+    // pieces given up on, see `DownloadOptions::piece_attempts`
+    failed: BTreeSet<usize>,
+    // files left out by `stop_after` aren't handed out
+    complete: Vec<bool>,
+}
+
+impl Downloaded {
+    // Pieces that couldn't be downloaded, the files they touch are left out.
+    pub fn failed(&self) -> &BTreeSet<usize> {
+        &self.failed
+    }
+
+    // The file at `path` (as in the torrent, without its name), if it was
+    // downloaded.
+    pub fn file(&self, path: &[String]) -> Option<DownloadedFile<'_>> {
+        self.into_iter().find(|file| file.path() == path)
+    }
 }
 
 impl<'d> IntoIterator for &'d Downloaded {
@@ -189,7 +846,7 @@ impl<'d> IntoIterator for &'d Downloaded {
 
 pub struct DownloadedIter<'d> {
     downloaded: &'d Downloaded,
-    files_iter: std::slice::Iter<'d, File>,
+    files_iter: std::iter::Enumerate<std::slice::Iter<'d, File>>,
     offset: usize,
 }
 
@@ -197,7 +854,7 @@ impl<'d> DownloadedIter<'d> {
     fn new(downloaded: &'d Downloaded) -> Self {
         Self {
             downloaded,
-            files_iter: downloaded.files.iter(),
+            files_iter: downloaded.files.iter().enumerate(),
             offset: 0,
         }
     }
@@ -207,19 +864,36 @@ impl<'d> Iterator for DownloadedIter<'d> {
     type Item = DownloadedFile<'d>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let file = self.files_iter.next()?;
-        // slicing twice here
-        let bytes = &self.downloaded.bytes[self.offset..self.offset + file.length];
-        Some(DownloadedFile { file, bytes })
+        loop {
+            let (file_i, file) = self.files_iter.next()?;
+            let offset = self.offset;
+            self.offset += file.length;
+            if self.downloaded.complete[file_i] {
+                let bytes = &self.downloaded.bytes[offset..offset + file.length];
+                return Some(DownloadedFile {
+                    index: file_i,
+                    file,
+                    bytes,
+                });
+            }
+        }
     }
 }
 
 pub struct DownloadedFile<'d> {
+    // position among the torrent's files
+    index: usize,
     file: &'d File,
     bytes: &'d [u8],
 }
 
 impl<'d> DownloadedFile<'d> {
+    // Position among the torrent's files, also when files before it were
+    // left out.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
     pub fn path(&self) -> &'d [String] {
         &self.file.path
     }
@@ -228,3 +902,791 @@ impl<'d> DownloadedFile<'d> {
         self.bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::create;
+    use crate::dot_torrent::hashes::Hashes;
+    use crate::dot_torrent::{Info, Key};
+    use crate::testing::{MockPeer, MockTracker, full_bitfield, tracker_response};
+    use sha1::{Digest, Sha1};
+
+    // 4 pieces worth of data and a torrent for it
+    fn sample(name: &str) -> (DotTorrent, Vec<u8>) {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, &data).unwrap();
+        let dot_torrent = create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (dot_torrent, data)
+    }
+
+    #[tokio::test]
+    async fn downloads_created_torrent_from_two_peers() {
+        let (dot_torrent, data) = sample("bittorrent_download_test.bin");
+        let info_hash = dot_torrent.info_hash().unwrap();
+        let piece_length = dot_torrent.info.piece_length;
+        let n_pieces = dot_torrent.info.pieces.0.len();
+        assert_eq!(n_pieces, 4);
+
+        let seed = MockPeer::start(
+            info_hash,
+            data.clone(),
+            piece_length,
+            full_bitfield(n_pieces),
+        )
+        .await;
+        // only has the first two pieces
+        let partial =
+            MockPeer::start(info_hash, data.clone(), piece_length, vec![0b1100_0000]).await;
+
+        // a bound below one piece still lets pieces through one at a time
+        let options = DownloadOptions {
+            assembly_buffer: BLOCK_SIZE,
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[seed.addr, partial.addr], &options)
+            .await
+            .unwrap();
+        let file = downloaded.into_iter().next().unwrap();
+        assert_eq!(file.path(), ["bittorrent_download_test.bin"]);
+        assert!(file.bytes() == data);
+    }
+
+    #[tokio::test]
+    async fn stops_once_selected_file_is_complete() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+        let piece_length = 32768;
+        // "a" covers pieces 0 and 1, "b" covers pieces 1 to 3
+        let files = vec![
+            File {
+                length: 40_000,
+                path: vec!["a".to_string()],
+            },
+            File {
+                length: 60_000,
+                path: vec!["b".to_string()],
+            },
+        ];
+        let pieces = data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let dot_torrent = DotTorrent::new(Info::new(
+            "pack",
+            piece_length,
+            Hashes(pieces),
+            Key::MultipleFiles { files },
+        ));
+        let seed = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            piece_length,
+            full_bitfield(4),
+        )
+        .await;
+
+        let options = DownloadOptions {
+            stop_after: Some(vec![0]),
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[seed.addr], &options)
+            .await
+            .unwrap();
+        let files: Vec<_> = downloaded.into_iter().collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path(), ["a"]);
+        assert!(files[0].bytes() == &data[..40_000]);
+        // only pieces covering "a" were fetched
+        assert!(downloaded.bytes[2 * piece_length..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn files_are_looked_up_by_path() {
+        let file = |length, path: &[&str]| File {
+            length,
+            path: path.iter().map(|c| c.to_string()).collect(),
+        };
+        let downloaded = Downloaded {
+            files: vec![file(3, &["a"]), file(4, &["dir", "b"]), file(2, &["c"])],
+            bytes: b"aaabbbbcc".to_vec(),
+            failed: BTreeSet::new(),
+            complete: vec![true, true, false],
+        };
+        let path = |path: &[&str]| path.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let b = downloaded.file(&path(&["dir", "b"])).unwrap();
+        assert_eq!(b.path(), ["dir", "b"]);
+        assert_eq!(b.bytes(), b"bbbb");
+        assert_eq!(downloaded.file(&path(&["a"])).unwrap().bytes(), b"aaa");
+        // not downloaded, or not there at all
+        assert!(downloaded.file(&path(&["c"])).is_none());
+        assert!(downloaded.file(&path(&["b"])).is_none());
+    }
+
+    #[tokio::test]
+    async fn flat_layout_saves_files_side_by_side() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 239) as u8).collect();
+        let piece_length = 32768;
+        let file = |length, path: &[&str]| File {
+            length,
+            path: path.iter().map(|c| c.to_string()).collect(),
+        };
+        let files = vec![
+            file(40_000, &["dir", "a.txt"]),
+            // same name once flattened
+            file(30_000, &["dir_a.txt"]),
+            file(30_000, &["b", "c", "d"]),
+        ];
+        let pieces = data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let dot_torrent = DotTorrent::new(Info::new(
+            "pack",
+            piece_length,
+            Hashes(pieces),
+            Key::MultipleFiles { files },
+        ));
+        let seed = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            piece_length,
+            full_bitfield(4),
+        )
+        .await;
+
+        let dir = std::env::temp_dir().join("bittorrent_flat_layout_test");
+        let options = DownloadOptions {
+            save_to: Some(dir.clone()),
+            layout: LayoutMode::Flat,
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[seed.addr], &options).await;
+        let mut saved: Vec<_> = std::fs::read_dir(dir.join("pack"))
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let bytes = std::fs::read(entry.path()).unwrap();
+                (entry.file_name().into_string().unwrap(), bytes)
+            })
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(downloaded.is_ok());
+        saved.sort();
+        assert_eq!(
+            saved,
+            [
+                ("b_c_d".to_string(), data[70_000..].to_vec()),
+                ("dir_a (1).txt".to_string(), data[40_000..70_000].to_vec()),
+                ("dir_a.txt".to_string(), data[..40_000].to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn saving_more_files_than_may_be_open() {
+        let data: Vec<u8> = (0..80_000u32).map(|i| (i % 239) as u8).collect();
+        let files: Vec<_> = (0..8)
+            .map(|i| File {
+                length: 10_000,
+                path: vec![format!("{i}.bin")],
+            })
+            .collect();
+        let dot_torrent = DotTorrent::new(Info::new(
+            "many",
+            32768,
+            Hashes(vec![[0; 20]; 3]),
+            Key::MultipleFiles {
+                files: files.clone(),
+            },
+        ));
+        let downloaded = Downloaded {
+            files,
+            bytes: data.clone(),
+            failed: BTreeSet::new(),
+            complete: vec![true; 8],
+        };
+        let dir = std::env::temp_dir().join("bittorrent_save_open_files_test");
+        let writer = DiskWriter::new(DiskOptions {
+            workers: 1,
+            max_open_files: 2,
+            ..Default::default()
+        });
+        let saved = save(
+            &dot_torrent,
+            &downloaded,
+            &dir,
+            &writer,
+            &DownloadOptions::default(),
+        )
+        .await;
+        let written: Vec<_> = (0..8)
+            .map(|i| std::fs::read(dir.join("many").join(format!("{i}.bin"))).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        saved.unwrap();
+        assert_eq!(writer.open_files(), 2);
+        assert!(written.concat() == data);
+    }
+
+    #[tokio::test]
+    async fn saved_blocks_are_merged_into_sequential_writes() {
+        let (dot_torrent, data) = sample("bittorrent_save_coalesce_test.bin");
+        assert_eq!(data.len().div_ceil(BLOCK_SIZE), 7);
+        let downloaded = Downloaded {
+            files: dot_torrent.files(),
+            bytes: data.clone(),
+            failed: BTreeSet::new(),
+            complete: vec![true],
+        };
+        let dir = std::env::temp_dir().join("bittorrent_save_coalesce_test");
+        let writer = DiskWriter::new(DiskOptions {
+            workers: 1,
+            coalesce_limit: 4 * BLOCK_SIZE,
+            ..Default::default()
+        });
+        let saved = save(
+            &dot_torrent,
+            &downloaded,
+            &dir,
+            &writer,
+            &DownloadOptions::default(),
+        )
+        .await;
+        let written = std::fs::read(dir.join(&dot_torrent.info.name));
+        std::fs::remove_dir_all(&dir).unwrap();
+        saved.unwrap();
+        // the first four blocks, then the other three
+        assert_eq!(writer.disk_writes(), 2);
+        assert!(written.unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn single_file_is_saved_under_its_new_name() {
+        let (dot_torrent, data) = sample("bittorrent_download_rename_test.bin");
+        let seed = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            dot_torrent.info.piece_length,
+            full_bitfield(4),
+        )
+        .await;
+        assert!(dot_torrent.renamed_path("../escape").is_err());
+
+        let dir = std::env::temp_dir().join("bittorrent_rename_test");
+        let options = DownloadOptions {
+            save_to: Some(dir.clone()),
+            rename: Some("renamed.bin".to_string()),
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[seed.addr], &options).await;
+        let saved: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        let written = std::fs::read(dir.join("renamed.bin"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(downloaded.is_ok());
+        assert_eq!(saved, ["renamed.bin"]);
+        assert!(written.unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn waits_for_tracker_to_have_peers() {
+        let (mut dot_torrent, data) = sample("bittorrent_download_wait_test.bin");
+        let info_hash = dot_torrent.info_hash().unwrap();
+        let n_pieces = dot_torrent.info.pieces.0.len();
+        let seed = MockPeer::start(
+            info_hash,
+            data,
+            dot_torrent.info.piece_length,
+            full_bitfield(n_pieces),
+        )
+        .await;
+        let seed_addr = seed.addr;
+        let mut tracker = MockTracker::start(move |i, _| {
+            let peers = if i < 2 { vec![] } else { vec![seed_addr] };
+            (200, tracker_response(0, &peers))
+        })
+        .await;
+        dot_torrent.announce = Some(tracker.url.clone());
+
+        let peers = wait_for_peers(
+            &dot_torrent,
+            Duration::from_millis(10),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(peers, vec![seed.addr]);
+        for _ in 0..3 {
+            tracker.requests.recv().await.unwrap();
+        }
+        assert!(
+            from_peers(&dot_torrent, &peers, &DownloadOptions::default())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn byte_range_fetches_only_covering_pieces() {
+        let (dot_torrent, data) = sample("bittorrent_download_range_test.bin");
+        let piece_length = dot_torrent.info.piece_length;
+        let peer = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            piece_length,
+            full_bitfield(4),
+        )
+        .await;
+
+        // starts in piece 1 and ends in piece 2
+        let range = piece_length + 100..2 * piece_length + 5;
+        let bytes = range_from_peers(
+            &dot_torrent,
+            &[peer.addr],
+            range.clone(),
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(bytes, data[range]);
+        assert_eq!(peer.requested_pieces(), [1, 2]);
+        // and only those pieces are held in memory
+        let options = DownloadOptions::default();
+        let wanted = Some((1..3).collect());
+        let (held, _, _) = fetch(&dot_torrent, &[peer.addr], &options, wanted, 1..3)
+            .await
+            .unwrap();
+        assert_eq!(held.len(), 2 * piece_length);
+        assert!(held == data[piece_length..3 * piece_length]);
+
+        let err = range_from_peers(
+            &dot_torrent,
+            &[peer.addr],
+            0..data.len() + 1,
+            &DownloadOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("outside"));
+    }
+
+    #[tokio::test]
+    async fn half_written_piece_only_fetches_missing_blocks() {
+        let (dot_torrent, data) = sample("bittorrent_download_resume_test.bin");
+        let piece_length = dot_torrent.info.piece_length;
+        assert_eq!(piece_length, 2 * BLOCK_SIZE);
+        let peer = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            piece_length,
+            full_bitfield(4),
+        )
+        .await;
+
+        // the first block of piece 1 made it to disk before a restart
+        let dir = std::env::temp_dir().join("bittorrent_download_resume_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut on_disk = vec![0; data.len()];
+        let written = piece_length..piece_length + BLOCK_SIZE;
+        on_disk[written.clone()].copy_from_slice(&data[written]);
+        std::fs::write(dir.join(&dot_torrent.info.name), &on_disk).unwrap();
+
+        let options = DownloadOptions {
+            on_disk: Some(OnDisk {
+                dir: dir.clone(),
+                pieces: vec![PartialPiece {
+                    index: 1,
+                    blocks: vec![0b1000_0000],
+                }],
+            }),
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[peer.addr], &options).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        let downloaded = downloaded.unwrap();
+        let file = downloaded.into_iter().next().unwrap();
+        assert_eq!(file.bytes(), data);
+        assert_eq!(peer.requested_blocks(0), [0, BLOCK_SIZE]);
+        assert_eq!(peer.requested_blocks(1), [BLOCK_SIZE]);
+    }
+
+    #[tokio::test]
+    async fn completion_hook_runs_once_after_saving() {
+        let (dot_torrent, data) = sample("bittorrent_download_hook_test.bin");
+        let peer = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            dot_torrent.info.piece_length,
+            full_bitfield(4),
+        )
+        .await;
+        let dir = std::env::temp_dir().join("bittorrent_download_hook_test");
+        let saved = dir.join(&dot_torrent.info.name);
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let options = DownloadOptions {
+            save_to: Some(dir.clone()),
+            on_complete: Some(OnComplete::new({
+                let calls = calls.clone();
+                move |downloaded| {
+                    // the data is already where it belongs
+                    let on_disk = std::fs::read(&saved).unwrap();
+                    let file = downloaded.into_iter().next().unwrap();
+                    calls.lock().unwrap().push(on_disk == file.bytes());
+                }
+            })),
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[peer.addr], &options).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(downloaded.is_ok());
+        assert_eq!(*calls.lock().unwrap(), [true]);
+    }
+
+    #[tokio::test]
+    async fn deadline_piece_is_requested_first() {
+        let (dot_torrent, data) = sample("bittorrent_download_deadline_test.bin");
+        let info_hash = dot_torrent.info_hash().unwrap();
+        let piece_length = dot_torrent.info.piece_length;
+        let seed = MockPeer::start(info_hash, data.clone(), piece_length, full_bitfield(4)).await;
+        // piece 3 is only on the seed, so it'd normally come last
+        let partial =
+            MockPeer::start(info_hash, data.clone(), piece_length, vec![0b1110_0000]).await;
+
+        let options = DownloadOptions {
+            deadlines: HashMap::from([(3, Instant::now() + Duration::from_secs(1))]),
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[seed.addr, partial.addr], &options).await;
+        let downloaded = downloaded.unwrap();
+        let file = downloaded.into_iter().next().unwrap();
+        assert_eq!(file.bytes(), data);
+        assert_eq!(seed.request_order()[0], 3);
+        assert!(!partial.request_order().contains(&3));
+    }
+
+    #[tokio::test]
+    async fn corrupt_piece_is_given_up_on() {
+        let (dot_torrent, data) = sample("bittorrent_download_corrupt_test.bin");
+        let piece_length = dot_torrent.info.piece_length;
+        let mut corrupt = data.clone();
+        corrupt[2 * piece_length + 7] ^= 0xff;
+        let peer = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            corrupt,
+            piece_length,
+            full_bitfield(4),
+        )
+        .await;
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let options = DownloadOptions {
+            on_event: Some(OnEvent::new(move |event| {
+                seen.lock().unwrap().push(event.clone())
+            })),
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[peer.addr], &options)
+            .await
+            .unwrap();
+        assert_eq!(downloaded.failed().iter().collect::<Vec<_>>(), [&2]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [DownloadEvent::PieceFailed {
+                index: 2,
+                reason: "piece 2 failed the hash check".to_string(),
+            }]
+        );
+        // the other pieces made it, the file they're part of didn't
+        for piece_i in [0, 1, 3] {
+            let range = piece_i * piece_length..((piece_i + 1) * piece_length).min(data.len());
+            assert!(downloaded.bytes[range.clone()] == data[range]);
+        }
+        assert!(downloaded.into_iter().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn pieces_are_timed_with_their_retries() {
+        let (dot_torrent, data) = sample("bittorrent_download_metrics_test.bin");
+        let piece_length = dot_torrent.info.piece_length;
+        let mut corrupt = data.clone();
+        corrupt[piece_length] ^= 0xff;
+        let peer = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            corrupt,
+            piece_length,
+            full_bitfield(4),
+        )
+        .await;
+        let options = DownloadOptions::default();
+        from_peers(&dot_torrent, &[peer.addr], &options)
+            .await
+            .unwrap();
+        let metrics = options.piece_metrics.lock().unwrap();
+        assert_eq!(metrics.len(), 4);
+        for (&index, metric) in metrics.iter() {
+            assert_eq!(metric.piece, index);
+            assert_eq!(metric.peers, 1);
+            let first_block = metric.first_block.unwrap();
+            if index == 1 {
+                assert_eq!(metric.retries, PIECE_ATTEMPTS - 1);
+                assert_eq!(metric.complete, None);
+            } else {
+                assert_eq!(metric.retries, 0);
+                assert!(metric.complete.unwrap() >= first_block);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn files_after_a_failed_one_keep_their_index() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 233) as u8).collect();
+        let piece_length = 16384;
+        // piece 3 lies within "b" alone
+        let files = [("a", 30_000), ("b", 40_000), ("c", 30_000)]
+            .into_iter()
+            .map(|(name, length)| File {
+                length,
+                path: vec![name.to_string()],
+            })
+            .collect();
+        let pieces: Vec<_> = data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let n_pieces = pieces.len();
+        let dot_torrent = DotTorrent::new(Info::new(
+            "pack",
+            piece_length,
+            Hashes(pieces),
+            Key::MultipleFiles { files },
+        ));
+        let mut corrupt = data.clone();
+        corrupt[3 * piece_length + 1] ^= 0xff;
+        let peer = MockPeer::start(
+            dot_torrent.info_hash().unwrap(),
+            corrupt,
+            piece_length,
+            full_bitfield(n_pieces),
+        )
+        .await;
+
+        let downloaded = from_peers(&dot_torrent, &[peer.addr], &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(downloaded.failed().iter().collect::<Vec<_>>(), [&3]);
+        let paths = dot_torrent.sanitize_paths().unwrap();
+        let files: Vec<_> = downloaded.into_iter().collect();
+        assert_eq!(files.len(), 2);
+        // "c" still maps to its own path, not to the one "b" would have had
+        assert_eq!(files[1].index(), 2);
+        assert_eq!(files[1].path(), ["c"]);
+        assert!(paths[files[1].index()].ends_with("c"));
+        assert!(files[1].bytes() == &data[70_000..]);
+        assert_eq!(files[0].index(), 0);
+        assert!(paths[files[0].index()].ends_with("a"));
+    }
+
+    #[tokio::test]
+    async fn peer_rejecting_a_piece_it_has_is_no_longer_asked() {
+        let (dot_torrent, data) = sample("bittorrent_download_reject_test.bin");
+        let piece_length = dot_torrent.info.piece_length;
+        let peer = MockPeer::start_rejecting(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            piece_length,
+            full_bitfield(4),
+            &[1],
+        )
+        .await;
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let options = DownloadOptions {
+            on_event: Some(OnEvent::new(move |event| {
+                seen.lock().unwrap().push(event.clone())
+            })),
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[peer.addr], &options)
+            .await
+            .unwrap();
+        // it was the piece's only peer, so there's no one left to retry with
+        assert_eq!(downloaded.failed().iter().collect::<Vec<_>>(), [&1]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                DownloadEvent::PeerDistrusted {
+                    peer: peer.addr,
+                    index: 1,
+                },
+                DownloadEvent::PieceFailed {
+                    index: 1,
+                    reason: "no peers left to get piece 1".to_string(),
+                },
+            ]
+        );
+        for piece_i in [0, 2, 3] {
+            let range = piece_i * piece_length..((piece_i + 1) * piece_length).min(data.len());
+            assert!(downloaded.bytes[range.clone()] == data[range]);
+        }
+    }
+
+    #[tokio::test]
+    async fn excluded_peer_sits_out_the_download() {
+        let (dot_torrent, data) = sample("bittorrent_download_exclude_test.bin");
+        let info_hash = dot_torrent.info_hash().unwrap();
+        let piece_length = dot_torrent.info.piece_length;
+        let kicked = MockPeer::start(info_hash, data.clone(), piece_length, full_bitfield(4)).await;
+        let other = MockPeer::start(info_hash, data.clone(), piece_length, full_bitfield(4)).await;
+        let options = DownloadOptions::default();
+        // e.g. by `Torrent::disconnect`
+        options.downloads.lock().unwrap().exclude(kicked.addr);
+        let downloaded = from_peers(&dot_torrent, &[kicked.addr, other.addr], &options)
+            .await
+            .unwrap();
+        assert!(downloaded.bytes == data);
+        assert!(kicked.requested_pieces().is_empty());
+        assert_eq!(other.requested_pieces(), [0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn pieces_come_from_http_seeds_without_peers() {
+        let (mut dot_torrent, data) = sample("bittorrent_http_seed_test.bin");
+        let info_hash = dot_torrent.info_hash().unwrap();
+        let piece_length = dot_torrent.info.piece_length;
+        // piece 1 comes back corrupt from the first seed, so it has to be
+        // fetched again from the second
+        let seed = |corrupt: Option<usize>| {
+            let data = data.clone();
+            MockTracker::start(move |_, target| {
+                let piece: usize = target.rsplit_once("piece=").unwrap().1.parse().unwrap();
+                let mut piece_data = data.chunks(piece_length).nth(piece).unwrap().to_vec();
+                if corrupt == Some(piece) {
+                    piece_data[0] ^= 1;
+                }
+                (200, piece_data)
+            })
+        };
+        let mut first = seed(Some(1)).await;
+        let mut second = seed(None).await;
+        dot_torrent.httpseeds = vec![first.url.clone(), second.url.clone()];
+
+        let downloaded = from_peers(&dot_torrent, &[], &DownloadOptions::default())
+            .await
+            .unwrap();
+        assert!(downloaded.failed().is_empty());
+        assert!(downloaded.into_iter().next().unwrap().bytes() == data);
+
+        let hash = crate::tracker::url_encode(&info_hash);
+        for piece in 0..dot_torrent.info.pieces.0.len() {
+            let target = first.requests.recv().await.unwrap();
+            assert!(
+                target.ends_with(&format!("?info_hash={hash}&piece={piece}")),
+                "{target}"
+            );
+        }
+        let target = second.requests.recv().await.unwrap();
+        assert!(target.ends_with("&piece=1"), "{target}");
+        assert!(second.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn http_seeds_work_alongside_the_peers() {
+        let (mut dot_torrent, data) = sample("bittorrent_http_seed_alongside_test.bin");
+        let info_hash = dot_torrent.info_hash().unwrap();
+        let piece_length = dot_torrent.info.piece_length;
+        // takes a while for the three pieces it has
+        let peer = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            piece_length,
+            vec![0b1110_0000],
+            Duration::from_millis(100),
+        )
+        .await;
+        let last = data.chunks(piece_length).nth(3).unwrap().to_vec();
+        // busy at first, the piece is there a second later
+        let mut seed = MockTracker::start(move |i, _| match i {
+            0 => (503, b"1".to_vec()),
+            _ => (200, last.clone()),
+        })
+        .await;
+        dot_torrent.httpseeds = vec![seed.url.clone()];
+
+        let options = DownloadOptions::default();
+        let peers = [peer.addr];
+        let (downloaded, asked) = tokio::join!(
+            from_peers(&dot_torrent, &peers, &options),
+            tokio::time::timeout(Duration::from_millis(300), seed.requests.recv()),
+        );
+        // before the peer was done
+        assert!(asked.unwrap().unwrap().ends_with("&piece=3"));
+        let downloaded = downloaded.unwrap();
+        assert!(downloaded.failed().is_empty());
+        assert!(downloaded.bytes == data);
+        let again = seed.requests.recv().await.unwrap();
+        assert!(again.ends_with("&piece=3"), "{again}");
+        assert!(seed.requests.try_recv().is_err());
+        assert_eq!(peer.requested_pieces(), [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn last_piece_comes_from_the_fastest_peer() {
+        let (dot_torrent, data) = sample("bittorrent_last_piece_test.bin");
+        let info_hash = dot_torrent.info_hash().unwrap();
+        let piece_length = dot_torrent.info.piece_length;
+        let n_pieces = dot_torrent.info.pieces.0.len();
+        let fast = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            piece_length,
+            full_bitfield(n_pieces),
+            Duration::from_millis(5),
+        )
+        .await;
+        let slow = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            piece_length,
+            full_bitfield(n_pieces),
+            Duration::from_millis(100),
+        )
+        .await;
+
+        let options = DownloadOptions {
+            last_piece: LastPiece::FastestPeer,
+            ..Default::default()
+        };
+        let downloaded = from_peers(&dot_torrent, &[slow.addr, fast.addr], &options)
+            .await
+            .unwrap();
+        assert!(downloaded.into_iter().next().unwrap().bytes() == data);
+        // the slow peer helped with others, the last one was the fast peer's alone
+        let last = *fast.request_order().last().unwrap();
+        assert!(!slow.request_order().is_empty());
+        assert!(slow.requested_blocks(last).is_empty());
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_tracker_never_has_peers() {
+        let (mut dot_torrent, _) = sample("bittorrent_download_empty_test.bin");
+        let tracker = MockTracker::start(|_, _| (200, tracker_response(0, &[]))).await;
+        dot_torrent.announce = Some(tracker.url.clone());
+
+        let err = wait_for_peers(
+            &dot_torrent,
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, BtError::Timeout(_)));
+        assert!(err.to_string().contains("no peers"));
+    }
+}
@@ -0,0 +1,32 @@
+use thiserror::Error;
+
+// What the public entry points fail with, so applications can tell failures
+// apart. The details are still `anyhow` chains from inside the crate.
+#[derive(Debug, Error)]
+pub enum BtError {
+    #[error("tracker: {0:#}")]
+    Tracker(anyhow::Error),
+    #[error("peer: {0:#}")]
+    Peer(anyhow::Error),
+    #[error("piece {piece} failed the hash check")]
+    Hash { piece: usize },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid torrent: {0:#}")]
+    Parse(anyhow::Error),
+    #[error("timed out: {0}")]
+    Timeout(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+// Handshake failures a caller may want to react to, e.g. by retrying the
+// peer with encryption. `Peer::new` returns them inside its `anyhow` error,
+// so they're found with `downcast_ref`.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PeerError {
+    #[error("peer doesn't speak the BitTorrent protocol (got {0:?})")]
+    BadProtocol(String),
+}
+
+pub type Result<T> = std::result::Result<T, BtError>;
@@ -0,0 +1,182 @@
+use anyhow::Context;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+
+// Addresses `first` to `last`, both included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    pub first: Ipv4Addr,
+    pub last: Ipv4Addr,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        (self.first..=self.last).contains(&ip)
+    }
+}
+
+// "10.0.0.0/8", "10.0.0.1 - 10.0.0.9" or a single address.
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some((ip, prefix)) = s.split_once('/') {
+            let ip = u32::from(parse_ip(ip)?);
+            let prefix: u32 = prefix.trim().parse().context("parse prefix length")?;
+            anyhow::ensure!(prefix <= 32, "prefix length {prefix} is over 32");
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            return Ok(Self {
+                first: Ipv4Addr::from(ip & mask),
+                last: Ipv4Addr::from(ip | !mask),
+            });
+        }
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let (first, last) = (parse_ip(first)?, parse_ip(last)?);
+        anyhow::ensure!(first <= last, "range {first} - {last} is backwards");
+        Ok(Self { first, last })
+    }
+}
+
+// Unlike `Ipv4Addr::from_str` this takes zero-padded octets, which
+// blocklists are full of ("001.002.003.004").
+fn parse_ip(s: &str) -> anyhow::Result<Ipv4Addr> {
+    let octets: Vec<u8> = s
+        .trim()
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("invalid address {:?}", s.trim()))?;
+    let octets: [u8; 4] = octets
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid address {:?}", s.trim()))?;
+    Ok(Ipv4Addr::from(octets))
+}
+
+// Decides which peers we talk to, in either direction. Denied ranges always
+// win, and if any ranges are allowed only addresses in them get through.
+// Empty, it lets everyone through.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allowed: Vec<IpRange>,
+    denied: Vec<IpRange>,
+}
+
+impl IpFilter {
+    pub fn with_allowed(mut self, range: IpRange) -> Self {
+        self.allowed.push(range);
+        self
+    }
+
+    pub fn with_denied(mut self, range: IpRange) -> Self {
+        self.denied.push(range);
+        self
+    }
+
+    // Denies everything in a blocklist. Takes the P2P format
+    // ("description:1.2.3.0-1.2.3.255"), eMule's ipfilter.dat
+    // ("001.002.003.000 - 001.002.003.255 , 000 , description", where a
+    // level over 127 means the range isn't blocked) and plain ranges or
+    // CIDRs, one per line. Lines starting with `#` are comments.
+    pub fn with_blocklist(mut self, list: &str) -> anyhow::Result<Self> {
+        for (i, line) in list.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            // P2P descriptions may have commas too, it's eMule's only with a
+            // numeric level after the first one
+            let mut fields = line.split(',');
+            let range = fields.next().unwrap_or_default();
+            let level = fields.next().map(|level| level.trim().parse::<u32>());
+            let range = if let Some(Ok(level)) = level {
+                if level > 127 {
+                    continue;
+                }
+                range
+            } else if let Some((_description, range)) = line.rsplit_once(':') {
+                range
+            } else {
+                line
+            };
+            let range = range
+                .parse()
+                .with_context(|| format!("blocklist line {}", i + 1))?;
+            self.denied.push(range);
+        }
+        Ok(self)
+    }
+
+    // `with_blocklist` with the file at `path`.
+    pub fn with_blocklist_file(self, path: &Path) -> anyhow::Result<Self> {
+        let list = std::fs::read_to_string(path)
+            .with_context(|| format!("read blocklist {}", path.display()))?;
+        self.with_blocklist(&list)
+    }
+
+    pub fn allows(&self, ip: Ipv4Addr) -> bool {
+        if self.denied.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.allowed.is_empty() || self.allowed.iter().any(|range| range.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn blocklist_formats_are_read() {
+        let filter = IpFilter::default()
+            .with_blocklist(
+                "# honeypots\n\
+                 Some Company:10.1.0.0-10.1.255.255\n\
+                 Foo, Inc:10.4.0.0-10.4.0.255\n\
+                 010.002.000.000 - 010.002.000.255 , 000 , eMule style\n\
+                 010.003.000.000 - 010.003.000.255 , 200 , not actually blocked\n\
+                 192.168.0.0/16\n\
+                 \n\
+                 8.8.8.8\n",
+            )
+            .unwrap();
+        for blocked in [
+            "10.1.0.0",
+            "10.1.200.3",
+            "10.2.0.255",
+            "10.4.0.7",
+            "192.168.4.4",
+            "8.8.8.8",
+        ] {
+            assert!(!filter.allows(ip(blocked)), "{blocked}");
+        }
+        for allowed in ["10.0.255.255", "10.2.1.0", "10.3.0.1", "8.8.4.4"] {
+            assert!(filter.allows(ip(allowed)), "{allowed}");
+        }
+
+        let err = IpFilter::default()
+            .with_blocklist("1.2.3.4\nnonsense\n")
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("10.0.0.9-10.0.0.1".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn allowed_ranges_restrict_and_denied_ones_win() {
+        let filter = IpFilter::default()
+            .with_allowed("10.0.0.0/8".parse().unwrap())
+            .with_denied("10.9.0.0/16".parse().unwrap());
+        assert!(filter.allows(ip("10.1.2.3")));
+        assert!(!filter.allows(ip("10.9.2.3")));
+        assert!(!filter.allows(ip("11.0.0.1")));
+
+        let everyone = IpFilter::default().with_allowed("0.0.0.0/0".parse().unwrap());
+        assert!(everyone.allows(ip("255.255.255.255")));
+        assert!(IpFilter::default().allows(ip("1.1.1.1")));
+    }
+}
@@ -1,16 +1,29 @@
 pub mod bit_vec;
 pub mod cache;
+pub mod choker;
 pub mod client;
 pub mod create;
 pub mod db;
 pub mod dot_torrent;
 pub mod download;
+pub mod error;
+pub mod ip_filter;
 pub mod lru_cache;
+pub mod magnet;
 pub mod peer;
 pub mod piece;
+pub mod piece_download;
+pub mod rate_limit;
+pub mod recheck;
+pub(crate) mod scheduler;
 pub mod state;
+pub mod storage;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 pub mod torrent;
 pub mod torrent_list;
 pub mod tracker;
+pub mod verify;
+pub mod web_seed;
 
 pub(crate) const BLOCK_SIZE: usize = 1 << 14; // 16384 (16kb)
@@ -91,7 +91,7 @@ impl<K, V> Node<K, V> {
     }
 }
 
-struct LruCache<K, V> {
+pub struct LruCache<K, V> {
     map: HashMap<KeyRef<K>, NonNull<Node<K, V>>>,
     cap: NonZeroUsize,
     head: *mut Node<K, V>,
@@ -518,6 +518,21 @@ impl<K: Hash + Eq, V> LruCache<K, V> {
         Some((key, val))
     }
 
+    // Like `peek_lru`, but the value can be changed in place, e.g. to mark a
+    // block clean once it's been flushed.
+    pub fn peek_lru_mut(&mut self) -> Option<(&K, &mut V)> {
+        if self.is_empty() {
+            return None;
+        }
+        let (key, val);
+        unsafe {
+            let node = (*self.tail).prev;
+            key = &*(*node).key.as_ptr();
+            val = &mut *(*node).val.as_mut_ptr();
+        }
+        Some((key, val))
+    }
+
     // Returns the value corresponding to the most recently used item or `None` if the
     // cache is empty. Like `peek`, `peek_mru` does not update the LRU list so the item's
     // position will be unchanged.
@@ -576,6 +591,13 @@ impl<K: Hash + Eq, V> LruCache<K, V> {
         Some(unsafe { (key.assume_init(), val.assume_init()) })
     }
 
+    // Removes and returns the least recently used item only if `f` says so,
+    // so the tail can be inspected and evicted in one go.
+    pub fn pop_lru_if(&mut self, f: impl FnOnce(&K, &V) -> bool) -> Option<(K, V)> {
+        let (key, val) = self.peek_lru()?;
+        if f(key, val) { self.pop_lru() } else { None }
+    }
+
     // Removes and returns the key and value corresponding to the most recently
     // used item or `None` if the cache is empty.
     pub fn pop_mru(&mut self) -> Option<(K, V)> {
@@ -957,3 +979,52 @@ impl<K: Hash + Eq, V> fmt::Debug for LruCache<K, V> {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> LruCache<&'static str, (u32, bool)> {
+        let mut cache = LruCache::new(NonZeroUsize::new(3).unwrap());
+        cache.put("a", (1, true));
+        cache.put("b", (2, true));
+        cache.put("c", (3, true));
+        cache
+    }
+
+    #[test]
+    fn lru_value_is_mutated_in_place() {
+        let mut cache = cache();
+        let (key, val) = cache.peek_lru_mut().unwrap();
+        assert_eq!(*key, "a");
+        // flushed, so no longer dirty
+        val.1 = false;
+        assert_eq!(cache.peek_lru(), Some((&"a", &(1, false))));
+        // still the least recently used one
+        assert_eq!(cache.pop_lru(), Some(("a", (1, false))));
+        assert_eq!(cache.len(), 2);
+
+        let mut empty: LruCache<u8, u8> = LruCache::new(NonZeroUsize::new(1).unwrap());
+        assert!(empty.peek_lru_mut().is_none());
+    }
+
+    #[test]
+    fn lru_is_popped_only_if_the_predicate_holds() {
+        let mut cache = cache();
+        // dirty, so it stays
+        assert_eq!(cache.pop_lru_if(|_, (_, dirty)| !dirty), None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.peek_lru(), Some((&"a", &(1, true))));
+
+        cache.peek_lru_mut().unwrap().1.1 = false;
+        assert_eq!(
+            cache.pop_lru_if(|_, (_, dirty)| !dirty),
+            Some(("a", (1, false)))
+        );
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.peek_lru(), Some((&"b", &(2, true))));
+
+        cache.clear();
+        assert_eq!(cache.pop_lru_if(|_, _| true), None);
+    }
+}
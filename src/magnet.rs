@@ -0,0 +1,502 @@
+use crate::dot_torrent::hashes::Hashes;
+use crate::dot_torrent::{DotTorrent, Info, InfoHash, Key, Limits, skip_value};
+use crate::peer::{
+    EXTENSION_BIT, Handshake, Message, MessageFramer, MessageType, client_peer_id, read_handshake,
+};
+use crate::tracker::{Progress, TrackerClient};
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddrV4;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, FramedParts};
+
+// BEP 9 sends the metadata in blocks of 16 KiB, the last one may be shorter.
+pub(crate) const METADATA_BLOCK: usize = 1 << 14;
+
+// The id peers should use for `ut_metadata` messages they send us.
+pub(crate) const UT_METADATA: u8 = 1;
+
+// `msg_type` of `ut_metadata` messages.
+pub(crate) const METADATA_REQUEST: u8 = 0;
+pub(crate) const METADATA_DATA: u8 = 1;
+pub(crate) const METADATA_REJECT: u8 = 2;
+
+// How long a single peer gets to hand over the whole metadata.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// What a magnet link tells us: the info hash and where to start looking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    // display name, until we have the metadata
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // peers to try right away (`x.pe`)
+    pub peers: Vec<SocketAddrV4>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> anyhow::Result<Self> {
+        let query = uri.strip_prefix("magnet:?").context("not a magnet link")?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).context("parse magnet link parameters")?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in params {
+            match key.as_str() {
+                // other URNs (e.g. v2's btmh) are skipped
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(btih(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid peer address {value:?}"))?,
+                ),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            info_hash: info_hash.context("magnet link has no BitTorrent info hash")?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    // Stands in for the torrent when announcing, trackers only need the hash.
+    fn stub(&self) -> DotTorrent {
+        let name = self.name.as_deref().unwrap_or_default();
+        let info = Info::new(name, 0, Hashes(Vec::new()), Key::SingleFile { length: 0 });
+        DotTorrent {
+            cached_info_hash: OnceLock::from(InfoHash(self.info_hash)),
+            ..DotTorrent::new(info)
+        }
+    }
+}
+
+// The info hash is either 40 hex digits or 32 base32 ones.
+fn btih(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("invalid hex info hash")?,
+        32 => {
+            let mut bytes = Vec::with_capacity(20);
+            let (mut buf, mut bits) = (0u64, 0);
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => anyhow::bail!("invalid base32 info hash"),
+                };
+                buf = buf << 5 | value as u64;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    bytes.push((buf >> bits) as u8);
+                }
+            }
+            bytes
+        }
+        n => anyhow::bail!("info hash has {n} characters"),
+    };
+    Ok(bytes.try_into().expect("20 bytes either way"))
+}
+
+// Payload of the extension handshake, the first extended message each side
+// sends.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ExtendedHandshake {
+    // extension name to the id to use for it
+    pub m: BTreeMap<String, u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+// Header of `ut_metadata` messages, a data message has the block right after.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MetadataMessage {
+    pub msg_type: u8,
+    pub piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+pub(crate) fn extended(id: u8, body: &impl Serialize) -> anyhow::Result<Message> {
+    let mut payload = vec![id];
+    payload.extend(serde_bencode::to_bytes(body).context("bencode extended message")?);
+    Ok(Message {
+        typ: MessageType::Extended,
+        payload,
+    })
+}
+
+// Asks the magnet's peers, and the ones its trackers know of, for the
+// metadata until one of them sends it. No data is requested.
+pub async fn fetch_info(magnet: &Magnet) -> anyhow::Result<Info> {
+    let mut peers = magnet.peers.clone();
+    let client = TrackerClient::new();
+    let stub = magnet.stub();
+    // the size is unknown, anything but zero so we aren't taken for a seed
+    let progress = Progress {
+        left: 1,
+        ..Default::default()
+    };
+    for tracker in &magnet.trackers {
+        match client.announce(tracker, &stub, None, progress).await {
+            Ok(response) => peers.extend(response.peers.0),
+            Err(err) => println!("tracker {tracker} failed: {err}"),
+        }
+    }
+    let limits = Limits::default();
+    let mut tried = HashSet::new();
+    // carried over from peer to peer
+    let mut assembly = None;
+    for peer in peers {
+        if !tried.insert(peer) {
+            continue;
+        }
+        let fetched = tokio::time::timeout(
+            PEER_TIMEOUT,
+            fetch_from(peer, magnet.info_hash, &limits, &mut assembly),
+        )
+        .await;
+        let bytes = match fetched {
+            Ok(Ok(bytes)) => bytes,
+            Ok(Err(err)) => {
+                println!("peer {peer} didn't send the metadata: {err}");
+                continue;
+            }
+            Err(_) => {
+                println!("peer {peer} took too long to send the metadata");
+                continue;
+            }
+        };
+        let info: Info = serde_bencode::from_bytes(&bytes).context("parse metadata")?;
+        limits.check(&info)?;
+        return Ok(info);
+    }
+    anyhow::bail!("no peer sent the metadata")
+}
+
+// Metadata collected so far. It's kept across peers, so one that rejects a
+// piece or hangs up only costs us the pieces it didn't send.
+struct MetadataAssembly {
+    size: usize,
+    // each piece and the peer that sent it
+    pieces: Vec<Option<(Vec<u8>, SocketAddrV4)>>,
+    // fed the pieces in order as they come in
+    hasher: Sha1,
+    hashed: usize,
+}
+
+impl MetadataAssembly {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            pieces: vec![None; size.div_ceil(METADATA_BLOCK)],
+            hasher: Sha1::new(),
+            hashed: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pieces.iter().all(Option::is_none)
+    }
+
+    fn missing(&self) -> Vec<usize> {
+        (0..self.pieces.len())
+            .filter(|&piece| self.pieces[piece].is_none())
+            .collect()
+    }
+
+    fn insert(&mut self, piece: usize, block: &[u8], from: SocketAddrV4) -> anyhow::Result<()> {
+        anyhow::ensure!(piece < self.pieces.len(), "no metadata piece {piece}");
+        let begin = piece * METADATA_BLOCK;
+        let end = (begin + METADATA_BLOCK).min(self.size);
+        anyhow::ensure!(
+            block.len() == end - begin,
+            "metadata piece {piece} has the wrong length"
+        );
+        if self.pieces[piece].is_none() {
+            self.pieces[piece] = Some((block.to_vec(), from));
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self) {
+        while let Some(Some((block, _))) = self.pieces.get(self.hashed) {
+            self.hasher.update(block);
+            self.hashed += 1;
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.hashed == self.pieces.len()
+    }
+
+    fn matches(&self, info_hash: [u8; 20]) -> bool {
+        <[u8; 20]>::from(self.hasher.clone().finalize()) == info_hash
+    }
+
+    // Throws away the pieces peers other than `from` sent, returning them.
+    fn forget_others(&mut self, from: SocketAddrV4) -> Vec<usize> {
+        let mut forgotten = Vec::new();
+        for (piece, slot) in self.pieces.iter_mut().enumerate() {
+            if slot.as_ref().is_some_and(|(_, peer)| *peer != from) {
+                *slot = None;
+                forgotten.push(piece);
+            }
+        }
+        if !forgotten.is_empty() {
+            self.hasher = Sha1::new();
+            self.hashed = 0;
+            self.advance();
+        }
+        forgotten
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.pieces
+            .into_iter()
+            .flat_map(|piece| piece.expect("complete").0)
+            .collect()
+    }
+}
+
+async fn request_metadata(
+    stream: &mut Framed<TcpStream, MessageFramer>,
+    ut_metadata: u8,
+    pieces: &[usize],
+) -> anyhow::Result<()> {
+    for &piece in pieces {
+        let request = MetadataMessage {
+            msg_type: METADATA_REQUEST,
+            piece,
+            total_size: None,
+        };
+        stream.send(extended(ut_metadata, &request)?).await?;
+    }
+    Ok(())
+}
+
+// Does the extension handshake with `addr` and asks for the metadata pieces
+// `assembly` is missing, then hangs up. The result is checked against
+// `info_hash`.
+async fn fetch_from(
+    addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    limits: &Limits,
+    assembly: &mut Option<MetadataAssembly>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await.context("connect to peer")?;
+    let mut handshake = Handshake::new(info_hash, client_peer_id());
+    handshake.reserved[5] |= EXTENSION_BIT;
+    stream
+        .write_all(&handshake.to_bytes())
+        .await
+        .context("write handshake")?;
+    let (handshake_bytes, rest) = read_handshake(&mut stream)
+        .await
+        .context("read handshake")?;
+    let their_handshake = Handshake::from_bytes(&handshake_bytes)?;
+    anyhow::ensure!(
+        their_handshake.info_hash == info_hash,
+        "peer has another torrent"
+    );
+    anyhow::ensure!(
+        their_handshake.reserved[5] & EXTENSION_BIT != 0,
+        "peer doesn't speak the extension protocol"
+    );
+    let mut parts = FramedParts::new::<Message>(stream, MessageFramer::default());
+    parts.read_buf = rest;
+    let mut stream = Framed::from_parts(parts);
+    let ours = ExtendedHandshake {
+        m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA)]),
+        metadata_size: None,
+    };
+    stream.send(extended(0, &ours)?).await?;
+
+    let mut ut_metadata = None;
+    loop {
+        let msg = stream
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer message was invalid")?;
+        // bitfield, have and the like don't matter, we never ask for data
+        if msg.typ != MessageType::Extended {
+            continue;
+        }
+        let (&id, payload) = msg
+            .payload
+            .split_first()
+            .context("extended message without an id")?;
+        if id == 0 {
+            let theirs: ExtendedHandshake =
+                serde_bencode::from_bytes(payload).context("parse extension handshake")?;
+            let id = theirs
+                .m
+                .get("ut_metadata")
+                .copied()
+                .filter(|&id| id != 0)
+                .context("peer can't send metadata")?;
+            let size = theirs
+                .metadata_size
+                .context("peer didn't say the metadata size")?;
+            anyhow::ensure!(
+                size > 0 && size <= limits.max_info_size,
+                "metadata size {size} is out of bounds"
+            );
+            if let Some(parts) = assembly.as_ref()
+                && parts.size != size
+            {
+                anyhow::ensure!(
+                    parts.is_empty(),
+                    "peer says the metadata is {size} bytes, not {}",
+                    parts.size
+                );
+                *assembly = None;
+            }
+            let parts = assembly.get_or_insert_with(|| MetadataAssembly::new(size));
+            request_metadata(&mut stream, id, &parts.missing()).await?;
+            ut_metadata = Some(id);
+        } else if id == UT_METADATA {
+            let (Some(parts), Some(ut_metadata)) = (assembly.as_mut(), ut_metadata) else {
+                anyhow::bail!("metadata before the extension handshake");
+            };
+            let header_end = skip_value(payload, 0)?;
+            let header: MetadataMessage = serde_bencode::from_bytes(&payload[..header_end])
+                .context("parse metadata message")?;
+            match header.msg_type {
+                METADATA_DATA => {
+                    parts.insert(header.piece, &payload[header_end..], addr)?;
+                    if !parts.is_complete() {
+                        continue;
+                    }
+                    if parts.matches(info_hash) {
+                        return Ok(assembly.take().expect("just completed").into_bytes());
+                    }
+                    // Someone sent a bad piece. If it can't have been only
+                    // this peer, it sends the others' pieces too.
+                    let forgotten = parts.forget_others(addr);
+                    if forgotten.is_empty() {
+                        *assembly = None;
+                        anyhow::bail!("metadata doesn't match the info hash");
+                    }
+                    request_metadata(&mut stream, ut_metadata, &forgotten).await?;
+                }
+                METADATA_REJECT => anyhow::bail!("peer rejected metadata piece {}", header.piece),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockPeer;
+
+    #[test]
+    fn parses_magnet_links() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056\
+             &dn=Cosmos+Laundromat&tr=udp%3A%2F%2Ftracker.example.org%3A6969\
+             &x.pe=10.0.0.1:6881",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "c9e15763f722f23e98a29decdfae341b98d53056"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("Cosmos Laundromat"));
+        assert_eq!(magnet.trackers, ["udp://tracker.example.org:6969"]);
+        assert_eq!(magnet.peers, ["10.0.0.1:6881".parse().unwrap()]);
+
+        // the same hash in base32
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+
+        assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+        assert!(Magnet::parse("http://example.org").is_err());
+    }
+
+    #[tokio::test]
+    async fn fetches_only_the_metadata() {
+        // a bit over two metadata blocks worth of piece hashes
+        let info = Info::new(
+            "big.iso",
+            1 << 18,
+            Hashes((0..1700u32).map(|i| [i as u8; 20]).collect()),
+            Key::SingleFile { length: 1700 << 18 },
+        );
+        let bytes = serde_bencode::to_bytes(&info).unwrap();
+        assert!(bytes.len() > 2 * METADATA_BLOCK);
+        let info_hash: [u8; 20] = Sha1::digest(&bytes).into();
+        let peer = MockPeer::start_metadata(info_hash, bytes.clone()).await;
+
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&x.pe={}",
+            hex::encode(info_hash),
+            peer.addr
+        );
+        let fetched = DotTorrent::fetch_metadata(&uri).await.unwrap();
+        assert_eq!(serde_bencode::to_bytes(&fetched).unwrap(), bytes);
+        assert_eq!(fetched.name, "big.iso");
+        assert!(peer.requested_pieces().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tampered_metadata_loses_to_the_real_thing() {
+        let info = Info::new(
+            "two-blocks.iso",
+            1 << 18,
+            Hashes((0..1000u32).map(|i| [i as u8; 20]).collect()),
+            Key::SingleFile { length: 1000 << 18 },
+        );
+        let bytes = serde_bencode::to_bytes(&info).unwrap();
+        assert_eq!(bytes.len().div_ceil(METADATA_BLOCK), 2);
+        let info_hash: [u8; 20] = Sha1::digest(&bytes).into();
+        // sends a doctored first piece and refuses the second
+        let mut tampered = bytes.clone();
+        tampered[METADATA_BLOCK - 1] ^= 1;
+        let liar = MockPeer::start_metadata_rejecting(info_hash, tampered, &[1]).await;
+        let honest = MockPeer::start_metadata(info_hash, bytes.clone()).await;
+
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&x.pe={}&x.pe={}",
+            hex::encode(info_hash),
+            liar.addr,
+            honest.addr
+        );
+        let fetched = DotTorrent::fetch_metadata(&uri).await.unwrap();
+        assert_eq!(serde_bencode::to_bytes(&fetched).unwrap(), bytes);
+
+        // on its own, the liar's metadata is turned down
+        let liar = MockPeer::start_metadata(info_hash, {
+            let mut tampered = bytes.clone();
+            tampered[0] ^= 1;
+            tampered
+        })
+        .await;
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&x.pe={}",
+            hex::encode(info_hash),
+            liar.addr
+        );
+        assert!(DotTorrent::fetch_metadata(&uri).await.is_err());
+    }
+}
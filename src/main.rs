@@ -1,5 +1,5 @@
 use std::io::Write;
-use bittorrent::create::create_torrent;
+use bittorrent::create::{CreateOptions, create_torrent};
 use bittorrent::dot_torrent::DotTorrent;
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
@@ -13,8 +13,28 @@ pub struct Args {
 #[derive(Debug, Subcommand)]
 #[clap(rename_all = "snake_case")]
 pub enum Command {
-    Download { path: PathBuf },
-    Create { path: PathBuf },
+    Download {
+        path: PathBuf,
+        /// Save a single-file torrent's file under this name
+        #[arg(long)]
+        rename: Option<String>,
+    },
+    Create {
+        path: PathBuf,
+        /// Also add v2 metadata
+        #[arg(long)]
+        hybrid: bool,
+        /// Tracker tag that makes the info hash unique to it
+        #[arg(long)]
+        source: Option<String>,
+        /// Name to advertise instead of the file's own
+        #[arg(long)]
+        name: Option<String>,
+        /// Read the file piece by piece instead of mapping it
+        #[arg(long)]
+        streaming: bool,
+    },
+    Info { path: PathBuf },
     Test,
 }
 
@@ -22,18 +42,45 @@ pub enum Command {
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     match args.command {
-        Command::Download { mut path } => {
+        Command::Download { mut path, rename } => {
             path.set_extension("torrent");
             let dot_torrent = DotTorrent::read(path).await?;
+            // checked before downloading anything
+            let paths = match rename {
+                Some(name) => vec![dot_torrent.renamed_path(&name)?],
+                None => dot_torrent.sanitize_paths()?,
+            };
             let files = dot_torrent.download_all().await?;
-            let output = dot_torrent.info.name;
-            tokio::fs::write(
-                output,
-                files.into_iter().next().expect("always one file").bytes(),
-            )
-            .await?
+            // files touching a failed piece are left out, so go by index
+            for file in &files {
+                let path = &paths[file.index()];
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(path, file.bytes()).await?
+            }
+            anyhow::ensure!(
+                files.failed().is_empty(),
+                "couldn't download pieces {:?}, the files they're part of weren't saved",
+                files.failed()
+            );
         }
-        Command::Create { path } => create_torrent(path).await?,
+        Command::Create {
+            path,
+            hybrid,
+            source,
+            name,
+            streaming,
+        } => {
+            let options = CreateOptions {
+                hybrid,
+                source,
+                name,
+                streaming,
+            };
+            create_torrent(path, &options).await?
+        }
+        Command::Info { path } => DotTorrent::read(path).await?.print_tree(),
         Command::Test => {
 
         },
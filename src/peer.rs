@@ -1,60 +1,589 @@
 use crate::BLOCK_SIZE;
 use crate::bit_vec::BitVec;
+use crate::error::PeerError;
+use crate::scheduler::BlockScheduler;
+use crate::torrent::BlockRequest;
 use anyhow::Context;
 use bytes::{Buf, BufMut, BytesMut};
-use futures_util::{SinkExt, StreamExt};
-use kanal::{AsyncReceiver, AsyncSender};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddrV4;
+use std::sync::OnceLock;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::Sender;
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+
+// Pieces can legitimately arrive after we stopped caring about them (another
+// peer was faster, or we got choked mid-request), but a peer sending lots of
+// them is wasting our bandwidth.
+const MAX_UNSOLICITED_PIECES: usize = 16;
+
+// Peers are supposed to send keep-alives every couple of minutes, so one that
+// stays silent for longer than this while we wait on it is gone.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+// How long we go without sending anything before sending a keep-alive, a
+// bit under the two minutes peers wait for one.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+// Block requests we keep outstanding with a peer, so it always has the next
+// one before it's done sending the last.
+pub const PIPELINE_DEPTH: usize = 5;
+
+// Requests of an inbound peer waiting to be served. Any more are dropped.
+const MAX_QUEUED_REQUESTS: usize = 250;
+
+// Largest block an inbound peer may ask for, as most clients allow.
+const MAX_REQUEST_LENGTH: usize = 1 << 17;
+
+// Requests for a piece a peer may reject or leave unanswered before we stop
+// believing it has the piece.
+const MAX_REFUSALS: usize = 2;
+
+// How many of a peer's latest block latencies its request timeout is based on.
+const LATENCY_SAMPLES: usize = 20;
+
+// Sizes a peer's block-request timeout from how quickly it has answered so
+// far: `multiple` times its average latency, kept within `min..=max`, so a
+// stall on a fast peer is noticed quickly while slow peers get more time.
+// `initial` is used until the peer has sent a block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestTimeout {
+    pub multiple: f64,
+    pub min: Duration,
+    pub max: Duration,
+    pub initial: Duration,
+}
+
+impl Default for RequestTimeout {
+    fn default() -> Self {
+        Self {
+            multiple: 4.0,
+            min: Duration::from_secs(2),
+            max: Duration::from_secs(60),
+            initial: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RequestTimeout {
+    pub fn for_latency(&self, latency: &Latency) -> Duration {
+        match latency.average() {
+            Some(average) => average.mul_f64(self.multiple).clamp(self.min, self.max),
+            None => self.initial,
+        }
+    }
+}
+
+// Rolling window of how long a peer took to answer block requests.
+#[derive(Debug, Clone, Default)]
+pub struct Latency {
+    samples: VecDeque<Duration>,
+}
+
+impl Latency {
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        let n = u32::try_from(self.samples.len()).ok().filter(|&n| n > 0)?;
+        Some(self.samples.iter().sum::<Duration>() / n)
+    }
+}
+
+// The 20 bytes a peer introduces itself with in the handshake.
+pub type PeerId = [u8; 20];
+
+// Client code and version starting our peer ids, Azureus style.
+pub const PEER_ID_PREFIX: [u8; 8] = *b"-RS0001-";
+
+// A fresh peer id: `PEER_ID_PREFIX` and 12 random bytes, so trackers and
+// peers can tell two instances of the client apart.
+pub fn generate_peer_id() -> PeerId {
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(&PEER_ID_PREFIX);
+    peer_id[8..].copy_from_slice(&rand::random::<[u8; 12]>());
+    peer_id
+}
+
+// The id this process goes by unless it's given another one, generated on
+// first use.
+pub fn client_peer_id() -> PeerId {
+    static PEER_ID: OnceLock<PeerId> = OnceLock::new();
+    *PEER_ID.get_or_init(generate_peer_id)
+}
 
 // so that we can respond from request from other side, also choking and unchoking other side
-pub(crate) struct Peer {
+pub struct Peer {
     addr: SocketAddrV4,
+    // as sent in the peer's handshake
+    peer_id: [u8; 20],
     stream: Framed<TcpStream, MessageFramer>,
     pieces: BitVec,
-    chocked: bool,
+    // the torrent's, see `set_piece_count`
+    n_pieces: Option<usize>,
+    // sent `HaveAll` before we knew how many pieces there are to have
+    have_all: bool,
+    // both sides speak the fast extension (BEP 6), so requests we won't
+    // serve are rejected instead of dropped
+    fast: bool,
+    // Connections start out choked and not interested on both sides.
+    // we refuse to upload to the peer
+    am_choking: bool,
+    // we want pieces the peer has
+    am_interested: bool,
+    // the peer refuses to upload to us
+    peer_choking: bool,
+    // the peer wants pieces we have
+    peer_interested: bool,
+    // `Piece` messages that didn't match what we asked for, less the ones
+    // that did, so the odd late block of a healthy peer is forgiven
+    unsolicited_pieces: usize,
+    // how long we wait for any message (keep-alives included) before giving up
+    idle_timeout: Duration,
+    // the peer sent nothing within `idle_timeout`
+    went_idle: bool,
+    keep_alive_interval: Duration,
+    // when we last sent anything, keep-alives included
+    last_sent: Instant,
+    connected_at: Instant,
+    // when `recent_rates` was last asked, with the byte counts back then
+    rates_since: (Instant, usize, usize),
+    // block bytes received from and sent to the peer
+    downloaded: usize,
+    uploaded: usize,
+    // requests the peer hasn't answered yet
+    requests_in_flight: usize,
+    // how many of ours it may have at once, see `request_blocks`
+    pipeline_depth: usize,
+    latency: Latency,
+    request_timeout: RequestTimeout,
+    // requests rejected or left unanswered, by piece
+    refusals: HashMap<usize, usize>,
+    // let a block request time out
+    slow: bool,
+    // our pieces as of the last `update_interest`, so a `Have` can make us
+    // interested again
+    our_pieces: Option<BitVec>,
+}
+
+// Snapshot of a connection for peer lists and diagnostics. Rates are bytes
+// per second averaged over the whole connection.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub addr: SocketAddrV4,
+    pub peer_id: [u8; 20],
+    pub client_name: Option<String>,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub am_choking: bool,
+    pub peer_choking: bool,
+    pub pieces_have: usize,
+    pub requests_in_flight: usize,
+}
+
+// How picky to be about the protocol string in the peer's handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProtocolCheck {
+    #[default]
+    Strict,
+    // Also accept the protocol string in another case, as some clients send
+    // it. Its length still has to be right, a handshake that's off there is
+    // not a BitTorrent one at all.
+    Lenient,
 }
 
 impl Peer {
     pub async fn new(addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
+        Self::connect(addr, info_hash, client_peer_id(), ProtocolCheck::Strict).await
+    }
+
+    // Like `new`, introducing ourselves as `our_id`.
+    pub async fn connect(
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        our_id: PeerId,
+        check: ProtocolCheck,
+    ) -> anyhow::Result<Self> {
         let mut stream = TcpStream::connect(addr).await.context("connect to peer")?;
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-        // TODO: remove unsafe and implement serde instead
-        // drop handshake_bytes
-        // Safety: Handshake is POD with repr(C)
-        let handshake_bytes = handshake.as_bytes_mut();
+        let handshake = Handshake::new(info_hash, our_id);
         stream
-            .write_all(handshake_bytes)
+            .write_all(&handshake.to_bytes())
             .await
             .context("write handshake")?;
-        stream
-            .read_exact(handshake_bytes)
+        let (handshake_bytes, rest) = read_handshake(&mut stream)
             .await
             .context("read handshake")?;
-        let handshake = Handshake::ref_from_bytes(handshake_bytes);
-        anyhow::ensure!(handshake.length == 19);
-        anyhow::ensure!(handshake.bittorrent == *b"BitTorrent protocol");
-        let mut stream = Framed::new(stream, MessageFramer);
+        let handshake = Handshake::parse(&handshake_bytes, check)?;
+        // the peer may have sent its first messages right after the handshake
+        let mut parts = FramedParts::new::<Message>(stream, MessageFramer::default());
+        parts.read_buf = rest;
+        let mut stream = Framed::from_parts(parts);
         let msg = stream
             .next()
             .await
             .expect("peer always sends a bitfield")
             .context("peer message was invalid")?;
-        anyhow::ensure!(msg.typ == MessageType::Bitfield);
-        Ok(Self {
+        let mut peer = Self::from_stream(addr, &handshake, stream, BitVec::new(0));
+        match msg.typ {
+            MessageType::Bitfield => peer.pieces = BitVec::from_vec(msg.payload),
+            // fast extension peers may say it in one byte instead
+            MessageType::HaveAll if peer.fast => peer.set_have_all(),
+            MessageType::HaveNone if peer.fast => {}
+            typ => anyhow::bail!("peer sent {typ:?} instead of a bitfield"),
+        }
+        Ok(peer)
+    }
+
+    // Takes over a connection the peer opened: reads its handshake and
+    // answers with ours if it's after `info_hash`. Unlike `connect`, nothing
+    // is waited for after that, the peer may well skip its bitfield and go
+    // straight to `Interested` and requests, see `serve`.
+    pub async fn accept(
+        mut stream: TcpStream,
+        info_hash: [u8; 20],
+        our_id: PeerId,
+        check: ProtocolCheck,
+    ) -> anyhow::Result<Self> {
+        let std::net::SocketAddr::V4(addr) = stream.peer_addr()? else {
+            anyhow::bail!("IPv6 peers aren't supported");
+        };
+        let (handshake_bytes, rest) = read_handshake(&mut stream)
+            .await
+            .context("read handshake")?;
+        let their_handshake = Handshake::parse(&handshake_bytes, check)?;
+        anyhow::ensure!(
+            their_handshake.info_hash == info_hash,
+            "peer {addr} asked for another torrent"
+        );
+        let handshake = Handshake::new(info_hash, our_id);
+        stream
+            .write_all(&handshake.to_bytes())
+            .await
+            .context("write handshake")?;
+        let mut parts = FramedParts::new::<Message>(stream, MessageFramer::default());
+        parts.read_buf = rest;
+        let stream = Framed::from_parts(parts);
+        // until it tells us otherwise
+        Ok(Self::from_stream(
             addr,
+            &their_handshake,
             stream,
-            pieces: BitVec::from_vec(msg.payload),
-            chocked: true,
-        })
+            BitVec::new(0),
+        ))
+    }
+
+    // `handshake` is the peer's.
+    fn from_stream(
+        addr: SocketAddrV4,
+        handshake: &Handshake,
+        stream: Framed<TcpStream, MessageFramer>,
+        pieces: BitVec,
+    ) -> Self {
+        Self {
+            addr,
+            peer_id: handshake.peer_id,
+            stream,
+            pieces,
+            n_pieces: None,
+            have_all: false,
+            // ours always has the bit set
+            fast: handshake.reserved[7] & FAST_BIT != 0,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            unsolicited_pieces: 0,
+            idle_timeout: IDLE_TIMEOUT,
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+            last_sent: Instant::now(),
+            went_idle: false,
+            connected_at: Instant::now(),
+            rates_since: (Instant::now(), 0, 0),
+            downloaded: 0,
+            uploaded: 0,
+            requests_in_flight: 0,
+            pipeline_depth: PIPELINE_DEPTH,
+            latency: Latency::default(),
+            request_timeout: RequestTimeout::default(),
+            refusals: HashMap::new(),
+            slow: false,
+            our_pieces: None,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
     }
 
     pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
-        self.pieces.has(piece_i)
+        self.have_all || self.pieces.has(piece_i)
+    }
+
+    // Lets a `HaveAll` from the peer be turned into a bitfield, and gives
+    // `Have`s room in the bitfield of a peer that didn't send one.
+    pub(crate) fn set_piece_count(&mut self, n_pieces: usize) {
+        self.n_pieces = Some(n_pieces);
+        if std::mem::take(&mut self.have_all) {
+            self.set_have_all();
+        } else if self.pieces.as_bytes().is_empty() {
+            self.pieces = BitVec::new(n_pieces);
+        }
+    }
+
+    fn set_have_all(&mut self) {
+        match self.n_pieces {
+            Some(n_pieces) => self.pieces = BitVec::full(n_pieces),
+            None => self.have_all = true,
+        }
+    }
+
+    pub(crate) fn pieces(&self) -> &BitVec {
+        &self.pieces
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    // Whether the peer sent too many pieces we didn't ask for and should be dropped.
+    pub fn is_flooding(&self) -> bool {
+        self.unsolicited_pieces > MAX_UNSOLICITED_PIECES
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn set_keep_alive_interval(&mut self, interval: Duration) {
+        self.keep_alive_interval = interval;
+    }
+
+    pub fn set_request_timeout(&mut self, request_timeout: RequestTimeout) {
+        self.request_timeout = request_timeout;
+    }
+
+    pub fn set_pipeline_depth(&mut self, depth: usize) {
+        self.pipeline_depth = depth.max(1);
+    }
+
+    // How long a block request may go unanswered before the block goes to
+    // another peer.
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout.for_latency(&self.latency)
+    }
+
+    // Tells the peer whether we're interested, if that changed: we are as
+    // long as it has a piece missing from `have`. Called whenever either
+    // side got a new piece.
+    pub(crate) async fn update_interest(&mut self, have: &BitVec) -> anyhow::Result<()> {
+        let interested = have.zeros().any(|piece_i| self.has_piece(piece_i));
+        self.our_pieces = Some(have.clone());
+        if interested == self.am_interested {
+            return Ok(());
+        }
+        let typ = if interested {
+            MessageType::Interested
+        } else {
+            MessageType::NotInterested
+        };
+        self.send(Message {
+            typ,
+            payload: Vec::new(),
+        })
+        .await
+    }
+
+    // Whether the peer let a block request time out, so other peers should
+    // be asked first.
+    pub fn is_slow(&self) -> bool {
+        self.slow
+    }
+
+    // Whether the peer went silent for longer than the idle timeout and should be dropped.
+    pub fn is_idle(&self) -> bool {
+        self.went_idle
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        let secs = self.connected_at.elapsed().as_secs_f64().max(f64::EPSILON);
+        PeerStats {
+            addr: self.addr,
+            peer_id: self.peer_id,
+            client_name: client_name(&self.peer_id),
+            download_rate: self.downloaded as f64 / secs,
+            upload_rate: self.uploaded as f64 / secs,
+            am_choking: self.am_choking,
+            peer_choking: self.peer_choking,
+            pieces_have: self.pieces.ones().count(),
+            requests_in_flight: self.requests_in_flight,
+        }
+    }
+
+    // Download and upload rate since the last call, or since connecting.
+    // Choke rounds go by these: what the peer did for us lately matters,
+    // not what it did an hour ago.
+    pub fn recent_rates(&mut self) -> (f64, f64) {
+        let (since, downloaded, uploaded) = self.rates_since;
+        let secs = since.elapsed().as_secs_f64().max(f64::EPSILON);
+        self.rates_since = (Instant::now(), self.downloaded, self.uploaded);
+        (
+            (self.downloaded - downloaded) as f64 / secs,
+            (self.uploaded - uploaded) as f64 / secs,
+        )
+    }
+
+    // The peer turned down (or never answered) a request for a piece it
+    // claims to have. Once it has done so `MAX_REFUSALS` times, its claim is
+    // dropped from the bitfield and it's no longer asked for the piece.
+    fn refused(&mut self, piece_i: usize) {
+        let refusals = self.refusals.entry(piece_i).or_default();
+        *refusals += 1;
+        if *refusals >= MAX_REFUSALS {
+            let _ = self.pieces.unset(piece_i);
+        }
+    }
+
+    fn unsolicited_piece(&mut self) -> anyhow::Result<()> {
+        self.unsolicited_pieces += 1;
+        anyhow::ensure!(
+            !self.is_flooding(),
+            "peer {} sent more than {MAX_UNSOLICITED_PIECES} unrequested pieces",
+            self.addr
+        );
+        Ok(())
+    }
+
+    // Sends a message, keeping track of the choke/interest state we announce.
+    pub(crate) async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        let typ = msg.typ;
+        let block_len = msg.payload.len().saturating_sub(8);
+        self.stream
+            .send(msg)
+            .await
+            .with_context(|| format!("send {typ:?} message"))?;
+        self.last_sent = Instant::now();
+        match typ {
+            MessageType::Choke => self.am_choking = true,
+            MessageType::Unchoke => self.am_choking = false,
+            MessageType::Interested => self.am_interested = true,
+            MessageType::NotInterested => self.am_interested = false,
+            MessageType::Request => self.requests_in_flight += 1,
+            MessageType::Cancel => {
+                self.requests_in_flight = self.requests_in_flight.saturating_sub(1)
+            }
+            MessageType::Piece => self.uploaded += block_len,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Sends a keep-alive, so a peer we have nothing to say to (e.g. while
+    // it's choking us) doesn't take us for gone.
+    pub(crate) async fn send_keep_alive(&mut self) -> anyhow::Result<()> {
+        self.stream
+            .send(KeepAlive)
+            .await
+            .context("send keep-alive")?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    // Receives the next message, keeping track of the peer's choke/interest
+    // state. Sends keep-alives while waiting.
+    pub(crate) async fn recv(&mut self) -> anyhow::Result<Message> {
+        let waiting_since = Instant::now();
+        let msg = loop {
+            // keep-alives never make it out of the decoder, but they still count
+            let heard = self
+                .stream
+                .codec()
+                .last_keep_alive
+                .map_or(waiting_since, |at| at.max(waiting_since));
+            let keep_alive_at = self.last_sent + self.keep_alive_interval;
+            let give_up_at = heard + self.idle_timeout;
+            match tokio::time::timeout_at(keep_alive_at.min(give_up_at), self.stream.next()).await {
+                Ok(msg) => break msg,
+                Err(_) if keep_alive_at < give_up_at => self.send_keep_alive().await?,
+                Err(_)
+                    if self
+                        .stream
+                        .codec()
+                        .last_keep_alive
+                        .is_some_and(|at| at > heard) => {}
+                Err(_) => {
+                    self.went_idle = true;
+                    anyhow::bail!(
+                        "peer {} sent nothing for {:?}",
+                        self.addr,
+                        self.idle_timeout
+                    );
+                }
+            }
+        };
+        let msg = msg
+            .context("peer closed the connection")?
+            .context("peer message was invalid")?;
+        match msg.typ {
+            MessageType::Choke => {
+                self.peer_choking = true;
+                // choking discards whatever we asked for
+                self.requests_in_flight = 0;
+            }
+            MessageType::Unchoke => self.peer_choking = false,
+            MessageType::Have => {
+                let index = <[u8; 4]>::try_from(&msg.payload[..])
+                    .context("have message without a piece index")?;
+                let index = u32::from_be_bytes(index) as usize;
+                // a piece past the end of the bitfield is ignored
+                let _ = self.pieces.set(index);
+                if let Some(have) = self.our_pieces.take() {
+                    if !have.has(index) {
+                        self.update_interest(&have).await?;
+                    }
+                    self.our_pieces = Some(have);
+                }
+            }
+            MessageType::HaveAll => self.set_have_all(),
+            MessageType::HaveNone => {
+                self.pieces = BitVec::new(self.n_pieces.unwrap_or(0));
+                self.have_all = false;
+            }
+            MessageType::Interested => self.peer_interested = true,
+            MessageType::NotInterested => self.peer_interested = false,
+            MessageType::Piece | MessageType::RejectRequest => {
+                if msg.typ == MessageType::Piece {
+                    self.downloaded += msg.payload.len().saturating_sub(8);
+                }
+                self.requests_in_flight = self.requests_in_flight.saturating_sub(1);
+            }
+            _ => {}
+        }
+        Ok(msg)
     }
 
     pub(crate) async fn participate(
@@ -62,34 +591,52 @@ impl Peer {
         piece_i: usize,
         piece_size: usize,
         n_blocks: usize,
-        job_tx: AsyncSender<usize>,
-        job_rx: AsyncReceiver<usize>,
+        scheduler: &BlockScheduler,
+        done_tx: Sender<Message>,
+    ) -> anyhow::Result<()> {
+        let result = self
+            .request_blocks(piece_i, piece_size, n_blocks, scheduler, done_tx)
+            .await;
+        // whatever we were still waiting for has to go to other peers
+        scheduler.release(self.addr);
+        if self.went_idle {
+            // not an error, the peer is just gone
+            return Ok(());
+        }
+        result
+    }
+
+    // Keeps up to `pipeline_depth` block requests outstanding with the peer,
+    // so it isn't left waiting on us for a round trip between blocks. The
+    // blocks that come back are matched to our requests by their offset.
+    async fn request_blocks(
+        &mut self,
+        piece_i: usize,
+        piece_size: usize,
+        n_blocks: usize,
+        scheduler: &BlockScheduler,
         done_tx: Sender<Message>,
     ) -> anyhow::Result<()> {
         anyhow::ensure!(self.has_piece(piece_i));
-        self.stream
-            .send(Message {
+        if !self.am_interested {
+            self.send(Message {
                 typ: MessageType::Interested,
                 payload: Vec::new(),
             })
-            .await
-            .context("send interested message")?;
+            .await?;
+        }
 
-        // TODO: timeout, error and return block to submit if next() timed out
+        // requested blocks with their size and when they were asked for,
+        // oldest first
+        let mut in_flight: VecDeque<(usize, usize, Instant)> = VecDeque::new();
         'job: loop {
-            while self.chocked {
-                let msg = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends an unchoke")
-                    .context("peer message was invalid")?;
+            while self.peer_choking {
+                let msg = self.recv().await?;
                 match msg.typ {
                     MessageType::Choke => {
-                        anyhow::bail!("peer sent unchoke while unchoked")
+                        // already choked
                     }
                     MessageType::Unchoke => {
-                        self.chocked = false;
                         assert!(msg.payload.is_empty());
                         break;
                     }
@@ -99,100 +646,409 @@ impl Peer {
                     | MessageType::Cancel => {
                         // not allowing requests for now
                     }
-                    MessageType::Have => {
-                        // TODO: update bitfield
+                    MessageType::Extended => {
+                        // we don't advertise any extensions
+                    }
+                    MessageType::RejectRequest => {
+                        // for a request the choke already requeued
+                    }
+                    MessageType::Have | MessageType::HaveAll | MessageType::HaveNone => {
+                        // already in the bitfield, see `recv`
                         // TODO: add to list of peers for relevant piece
                     }
+                    MessageType::SuggestPiece | MessageType::AllowedFast => {
+                        // we pick pieces ourselves
+                    }
                     MessageType::Bitfield => {
                         anyhow::bail!("peer sent bitfield after handshake")
                     }
                     MessageType::Piece => {
                         // piece that we no longer need/are responsible for
+                        self.unsolicited_piece()?;
                     }
                 }
             }
 
-            let Ok(block_i) = job_rx.recv().await else {
+            if scheduler.n_done() == n_blocks {
+                // whatever we're still waiting on came from other peers
                 break;
-            };
+            }
+            while in_flight.len() < self.pipeline_depth {
+                // only wait for a block when there's nothing else to wait for
+                let block_i = if in_flight.is_empty() {
+                    scheduler.next(self.addr).await
+                } else {
+                    match scheduler.next(self.addr).now_or_never() {
+                        Some(block_i) => block_i,
+                        None => break,
+                    }
+                };
+                let Some(block_i) = block_i else {
+                    break 'job;
+                };
 
-            let block_size = if block_i == n_blocks - 1 {
-                // calculate last block's size
-                let modulo = piece_size % BLOCK_SIZE;
-                if modulo == 0 { BLOCK_SIZE } else { modulo }
-            } else {
-                BLOCK_SIZE
-            };
-            let mut request = PieceRequest::new(
-                piece_i as u32,
-                (block_i * BLOCK_SIZE) as u32,
-                block_size as u32,
-            );
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.stream
-                .send(Message {
+                let block_size = if block_i == n_blocks - 1 {
+                    // calculate last block's size
+                    let modulo = piece_size % BLOCK_SIZE;
+                    if modulo == 0 { BLOCK_SIZE } else { modulo }
+                } else {
+                    BLOCK_SIZE
+                };
+                let mut request = PieceRequest::new(
+                    piece_i as u32,
+                    (block_i * BLOCK_SIZE) as u32,
+                    block_size as u32,
+                );
+                let request_bytes = Vec::from(request.as_bytes_mut());
+                self.send(Message {
                     typ: MessageType::Request,
                     payload: request_bytes,
                 })
                 .await
                 .with_context(|| format!("send request for block: {block_i}"))?;
-            // TODO: timeout and return block to submit if timed out
-            let mut msg;
-            loop {
-                msg = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends an unchoke")
-                    .context("peer message was invalid")?;
-                match msg.typ {
-                    MessageType::Choke => {
-                        assert!(msg.payload.is_empty());
-                        self.chocked = true;
-                        job_tx
-                            .send(block_i)
-                            .await
-                            .expect("we still have a receiver");
-                        continue 'job;
+                in_flight.push_back((block_i, block_size, Instant::now()));
+            }
+
+            let (oldest, _, sent_at) = in_flight[0];
+            let timeout = self.request_timeout();
+            let addr = self.addr;
+            let received = tokio::select! {
+                received = tokio::time::timeout_at(sent_at + timeout, self.recv()) => received,
+                superseded = scheduler.superseded(addr) => {
+                    // in endgame, another peer sent blocks we asked for too
+                    for block_i in superseded {
+                        if let Some(slot) = in_flight.iter().position(|&(b, _, _)| b == block_i) {
+                            let (_, block_size, _) = in_flight.remove(slot).expect("found it");
+                            self.cancel_block(piece_i, block_i, block_size).await?;
+                        }
                     }
-                    MessageType::Unchoke => {
-                        anyhow::bail!("peer sent unchoke while unchoked")
+                    continue;
+                }
+            };
+            let msg = match received {
+                Ok(msg) => msg?,
+                Err(_) => {
+                    // someone else may pick it up, we try again otherwise
+                    in_flight.pop_front();
+                    scheduler.requeue(self.addr, oldest);
+                    self.slow = true;
+                    self.refused(piece_i);
+                    anyhow::ensure!(
+                        self.has_piece(piece_i),
+                        "peer {} didn't send block {oldest} within {timeout:?}",
+                        self.addr
+                    );
+                    continue;
+                }
+            };
+            // which of our requests a block or rejection is for
+            let slot = |index: u32, begin: u32| {
+                in_flight.iter().position(|&(block_i, _, _)| {
+                    index as usize == piece_i && begin as usize == block_i * BLOCK_SIZE
+                })
+            };
+            match msg.typ {
+                MessageType::Choke => {
+                    assert!(msg.payload.is_empty());
+                    // the peer drops our requests when it chokes us
+                    for (block_i, _, _) in in_flight.drain(..) {
+                        scheduler.requeue(self.addr, block_i);
                     }
-                    MessageType::Interested
-                    | MessageType::NotInterested
-                    | MessageType::Request
-                    | MessageType::Cancel => {
-                        // not allowing request for now
+                }
+                MessageType::Unchoke => {
+                    // already unchoked
+                }
+                MessageType::Interested
+                | MessageType::NotInterested
+                | MessageType::Request
+                | MessageType::Cancel => {
+                    // not allowing request for now
+                }
+                MessageType::Extended => {
+                    // we don't advertise any extensions
+                }
+                MessageType::RejectRequest => {
+                    let rejected = PieceRequest::from_bytes(&msg.payload)?;
+                    if let Some(slot) = slot(rejected.index(), rejected.begin()) {
+                        let (block_i, _, _) = in_flight.remove(slot).expect("found it");
+                        scheduler.requeue(self.addr, block_i);
+                        self.refused(piece_i);
+                        anyhow::ensure!(
+                            self.has_piece(piece_i),
+                            "peer {} keeps rejecting requests for piece {piece_i}",
+                            self.addr
+                        );
                     }
-                    MessageType::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
+                }
+                MessageType::Have | MessageType::HaveAll | MessageType::HaveNone => {
+                    // already in the bitfield, see `recv`
+                    // TODO: add to list of peers for relevant piece
+                }
+                MessageType::SuggestPiece | MessageType::AllowedFast => {
+                    // we pick pieces ourselves
+                }
+                MessageType::Bitfield => {
+                    anyhow::bail!("peer sent bitfield after handshake")
+                }
+                MessageType::Piece => {
+                    let piece_response = PieceResponse::ref_from_bytes(&msg.payload[..])
+                        .expect("always get all `PieceResponse` fields from peer");
+                    let Some(slot) = slot(piece_response.index(), piece_response.begin()) else {
+                        // piece that we no longer need/are responsible for
+                        self.unsolicited_piece()?;
+                        continue;
+                    };
+                    let (block_i, block_size, sent_at) = in_flight.remove(slot).expect("found it");
+                    assert_eq!(piece_response.block().len(), block_size);
+                    self.latency.record(sent_at.elapsed());
+                    self.unsolicited_pieces = self.unsolicited_pieces.saturating_sub(1);
+                    // otherwise another peer beat us to it
+                    if scheduler.complete(self.addr, block_i) {
+                        done_tx.send(msg).await
+                            .expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
                     }
-                    MessageType::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Withdraws our request for `block_i` of `piece_i`, e.g. because another
+    // peer sent it first.
+    pub(crate) async fn cancel(
+        &mut self,
+        piece_i: usize,
+        block_i: usize,
+        piece_size: usize,
+    ) -> anyhow::Result<()> {
+        let length = (piece_size - block_i * BLOCK_SIZE).min(BLOCK_SIZE);
+        self.cancel_block(piece_i, block_i, length).await
+    }
+
+    // Same as `cancel` when the block's size is already known. The peer
+    // matches it to the request by all three fields, so they are the ones
+    // the request was sent with.
+    pub(crate) async fn cancel_block(
+        &mut self,
+        piece_i: usize,
+        block_i: usize,
+        block_size: usize,
+    ) -> anyhow::Result<()> {
+        let mut request = PieceRequest::new(
+            piece_i as u32,
+            (block_i * BLOCK_SIZE) as u32,
+            block_size as u32,
+        );
+        self.send(Message {
+            typ: MessageType::Cancel,
+            payload: Vec::from(request.as_bytes_mut()),
+        })
+        .await?;
+        self.requests_in_flight = self.requests_in_flight.saturating_sub(1);
+        Ok(())
+    }
+
+    // Tells the peer we won't answer its request, if it speaks the fast
+    // extension. Other peers have to figure that out themselves.
+    async fn reject(&mut self, request: BlockRequest) -> anyhow::Result<()> {
+        if !self.fast {
+            return Ok(());
+        }
+        let mut rejected = PieceRequest::new(
+            request.piece as u32,
+            request.begin as u32,
+            request.length as u32,
+        );
+        self.send(Message {
+            typ: MessageType::RejectRequest,
+            payload: Vec::from(rejected.as_bytes_mut()),
+        })
+        .await
+    }
+
+    // Uploads to the peer until it goes away: sends our bitfield, unchokes it
+    // once it's interested and answers its requests for pieces we `have` with
+    // the blocks `read` returns. Requests are queued, so one the peer cancels
+    // before we got to it is never sent. A request running past the end of
+    // its piece (`piece_length` long) ends the connection.
+    pub async fn serve(
+        &mut self,
+        have: &BitVec,
+        piece_length: impl Fn(usize) -> usize,
+        read: impl AsyncFn(BlockRequest) -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        self.send(Message {
+            typ: MessageType::Bitfield,
+            payload: have.as_bytes().to_vec(),
+        })
+        .await?;
+        let mut queue = VecDeque::new();
+        let mut heard_from = false;
+        loop {
+            // whatever the peer already sent goes first, so a cancel still
+            // catches its request in the queue
+            let msg = if queue.is_empty() {
+                Some(self.recv().await?)
+            } else {
+                self.recv().now_or_never().transpose()?
+            };
+            let Some(msg) = msg else {
+                let request: BlockRequest = queue.pop_front().expect("not empty");
+                let block = read(request).await?;
+                anyhow::ensure!(
+                    block.len() == request.length,
+                    "read {} bytes for a {} byte block",
+                    block.len(),
+                    request.length
+                );
+                let payload =
+                    PieceResponse::to_bytes(request.piece as u32, request.begin as u32, &block);
+                self.send(Message {
+                    typ: MessageType::Piece,
+                    payload,
+                })
+                .await?;
+                continue;
+            };
+            let first = !std::mem::replace(&mut heard_from, true);
+            match msg.typ {
+                MessageType::Interested if self.am_choking => {
+                    self.send(Message {
+                        typ: MessageType::Unchoke,
+                        payload: Vec::new(),
+                    })
+                    .await?
+                }
+                MessageType::NotInterested if !self.am_choking => {
+                    self.send(Message {
+                        typ: MessageType::Choke,
+                        payload: Vec::new(),
+                    })
+                    .await?;
+                    // choking discards its requests
+                    for request in std::mem::take(&mut queue) {
+                        self.reject(request).await?;
                     }
-                    MessageType::Piece => {
-                        let piece_response = PieceResponse::ref_from_bytes(&msg.payload[..])
-                            .expect("always get all `PieceResponse` fields from peer");
-                        if piece_response.index() as usize != piece_i
-                            || piece_response.begin() as usize != block_i * BLOCK_SIZE
-                        {
-                            // piece that we no longer need/are responsible for
-                        } else {
-                            assert_eq!(piece_response.block().len(), block_size);
-                            break;
-                        }
+                }
+                MessageType::Request => {
+                    let request = block_request(&msg.payload)?;
+                    anyhow::ensure!(
+                        have.has(request.piece)
+                            && request.length <= MAX_REQUEST_LENGTH
+                            && request.begin + request.length <= piece_length(request.piece),
+                        "peer {} asked for a block we can't send: {request:?}",
+                        self.addr
+                    );
+                    // requests of a choked peer are ignored
+                    if !self.am_choking && queue.len() < MAX_QUEUED_REQUESTS {
+                        queue.push_back(request);
+                    } else {
+                        self.reject(request).await?;
                     }
                 }
+                MessageType::Cancel => {
+                    let request = block_request(&msg.payload)?;
+                    queue.retain(|queued| *queued != request);
+                }
+                MessageType::Bitfield if first => {
+                    self.pieces = BitVec::from_vec(msg.payload);
+                }
+                MessageType::Bitfield => {
+                    anyhow::bail!("peer sent bitfield after other messages")
+                }
+                MessageType::Piece => self.unsolicited_piece()?,
+                _ => {}
             }
-            done_tx.send(msg).await
-                .expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
         }
-        Ok(())
     }
 }
 
-#[repr(C)]
+// Once a piece is complete, tells the peers still working on blocks of it
+// that others delivered (as happens in endgame) not to bother, instead of
+// having them send data that would be thrown away.
+pub(crate) async fn cancel_superseded(
+    peers: &mut [Peer],
+    piece_i: usize,
+    piece_size: usize,
+    scheduler: &BlockScheduler,
+) {
+    for (addr, block_i) in scheduler.take_superseded() {
+        let Some(peer) = peers.iter_mut().find(|peer| peer.addr == addr) else {
+            continue;
+        };
+        if let Err(err) = peer.cancel(piece_i, block_i, piece_size).await {
+            println!("failed to cancel block {block_i} of piece {piece_i} from peer {addr}: {err}");
+        }
+    }
+}
+
+fn block_request(payload: &[u8]) -> anyhow::Result<BlockRequest> {
+    let request = PieceRequest::from_bytes(payload)?;
+    Ok(BlockRequest {
+        piece: request.index() as usize,
+        begin: request.begin() as usize,
+        length: request.length() as usize,
+    })
+}
+
+// Names the client behind an Azureus-style peer id, e.g. `-qB4500-` is
+// qBittorrent 4.5.0. The fourth version digit only shows up if it's not 0.
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let name = match &peer_id[1..3] {
+        b"qB" => "qBittorrent",
+        b"TR" => "Transmission",
+        b"UT" => "µTorrent",
+        b"LT" => "libtorrent",
+        b"lt" => "rTorrent",
+        b"DE" => "Deluge",
+        b"AZ" => "Vuze",
+        b"BC" => "BitComet",
+        b"KT" => "KTorrent",
+        _ => return None,
+    };
+    let version = &peer_id[3..7];
+    if !version.iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let mut parts: Vec<_> = version.iter().map(|&c| (c as char).to_string()).collect();
+    if parts[3] == "0" {
+        parts.pop();
+    }
+    Some(format!("{name} {}", parts.join(".")))
+}
+
+pub(crate) const HANDSHAKE_LEN: usize = 68;
+
+// Reads the peer's handshake into its own buffer. Peers may pipeline their
+// first messages (e.g. the bitfield) in the same segment as the handshake,
+// so any bytes read past it are returned to be fed to the message decoder.
+pub(crate) async fn read_handshake(
+    stream: &mut TcpStream,
+) -> anyhow::Result<([u8; HANDSHAKE_LEN], BytesMut)> {
+    let mut buf = BytesMut::with_capacity(4096);
+    while buf.len() < HANDSHAKE_LEN {
+        let n = stream.read_buf(&mut buf).await?;
+        anyhow::ensure!(n != 0, "peer closed the connection during handshake");
+    }
+    let handshake = buf
+        .split_to(HANDSHAKE_LEN)
+        .as_ref()
+        .try_into()
+        .expect("split exactly HANDSHAKE_LEN bytes");
+    Ok((handshake, buf))
+}
+
+// Set in `Handshake::reserved[5]` by peers speaking the extension protocol
+// (BEP 10).
+pub(crate) const EXTENSION_BIT: u8 = 0x10;
+
+// Set in `Handshake::reserved[7]` by peers speaking the fast extension (BEP 6).
+pub(crate) const FAST_BIT: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
     pub length: u8,
     pub bittorrent: [u8; 19],
@@ -206,20 +1062,52 @@ impl Handshake {
         Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved: [0, 0, 0, 0, 0, 0, 0, FAST_BIT],
             info_hash,
             peer_id,
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = unsafe { self as *mut Self as *mut [u8; size_of::<Self>()] };
-        unsafe { &mut *bytes }
+    pub fn validate(&self, check: ProtocolCheck) -> Result<(), PeerError> {
+        let protocol = b"BitTorrent protocol";
+        let matches = match check {
+            ProtocolCheck::Strict => self.bittorrent == *protocol,
+            ProtocolCheck::Lenient => self.bittorrent.eq_ignore_ascii_case(protocol),
+        };
+        if self.length == 19 && matches {
+            return Ok(());
+        }
+        let protocol = &self.bittorrent[..(self.length as usize).min(19)];
+        Err(PeerError::BadProtocol(
+            String::from_utf8_lossy(protocol).into_owned(),
+        ))
     }
 
-    pub fn ref_from_bytes(data: &[u8]) -> &Self {
-        let handshake = data as *const [u8] as *const Self;
-        unsafe { &*handshake }
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[0] = self.length;
+        bytes[1..20].copy_from_slice(&self.bittorrent);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(data: &[u8; HANDSHAKE_LEN]) -> anyhow::Result<Self> {
+        Self::parse(data, ProtocolCheck::Strict)
+    }
+
+    // Like `from_bytes`, but only as picky about the protocol as `check`.
+    pub fn parse(data: &[u8; HANDSHAKE_LEN], check: ProtocolCheck) -> anyhow::Result<Self> {
+        let handshake = Self {
+            length: data[0],
+            bittorrent: data[1..20].try_into().expect("19 bytes"),
+            reserved: data[20..28].try_into().expect("8 bytes"),
+            info_hash: data[28..48].try_into().expect("20 bytes"),
+            peer_id: data[48..].try_into().expect("20 bytes"),
+        };
+        handshake.validate(check)?;
+        Ok(handshake)
     }
 }
 
@@ -254,6 +1142,20 @@ impl PieceRequest {
         u32::from_be_bytes(self.length)
     }
 
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            data.len() == size_of::<Self>(),
+            "request payload is {} bytes, expected {}",
+            data.len(),
+            size_of::<Self>()
+        );
+        Ok(Self {
+            index: data[0..4].try_into().expect("4 bytes"),
+            begin: data[4..8].try_into().expect("4 bytes"),
+            length: data[8..12].try_into().expect("4 bytes"),
+        })
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = unsafe { self as *mut Self as *mut [u8; size_of::<Self>()] };
         unsafe { &mut *bytes }
@@ -287,6 +1189,15 @@ impl PieceResponse {
         &self.block
     }
 
+    // Payload of a `Piece` message carrying `block`.
+    pub fn to_bytes(index: u32, begin: u32, block: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::LEAD + block.len());
+        payload.extend(index.to_be_bytes());
+        payload.extend(begin.to_be_bytes());
+        payload.extend(block);
+        payload
+    }
+
     const LEAD: usize = size_of::<PieceResponse<()>>();
     pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
         let n = data.len();
@@ -310,12 +1221,22 @@ impl PieceResponse {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
 pub struct Message {
     pub typ: MessageType,
+    // serialized as a single byte string (length + bytes) rather than a list of integers
+    #[cfg_attr(feature = "serde-messages", serde(with = "payload"))]
     pub payload: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
 pub enum MessageType {
     Choke = 0,
     // permission to download
@@ -327,6 +1248,17 @@ pub enum MessageType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    // BEP 6 (fast extension): a piece worth getting from this peer
+    SuggestPiece = 13,
+    // in place of a bitfield with every piece, or none
+    HaveAll = 14,
+    HaveNone = 15,
+    // the peer won't answer a request
+    RejectRequest = 16,
+    // a piece we may request even while choked
+    AllowedFast = 17,
+    // BEP 10, the first payload byte says which extension it's for
+    Extended = 20,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -344,13 +1276,68 @@ impl TryFrom<u8> for MessageType {
             6 => Ok(Request),
             7 => Ok(Piece),
             8 => Ok(Cancel),
+            13 => Ok(SuggestPiece),
+            14 => Ok(HaveAll),
+            15 => Ok(HaveNone),
+            16 => Ok(RejectRequest),
+            17 => Ok(AllowedFast),
+            20 => Ok(Extended),
             _ => Err(Error::new(ErrorKind::InvalidData, "Invalid message type")),
         }
     }
 }
 
+#[cfg(feature = "serde-messages")]
+mod payload {
+    use serde::de::{Error, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(payload)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(PayloadVisitor)
+    }
+
+    struct PayloadVisitor;
+
+    impl<'de> Visitor<'de> for PayloadVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a byte string")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: Error,
+        {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+        where
+            E: Error,
+        {
+            Ok(v)
+        }
+    }
+}
+
 // Message form: <length prefix><message ID><payload>.
-pub struct MessageFramer;
+#[derive(Default)]
+pub struct MessageFramer {
+    // when the last keep-alive arrived
+    last_keep_alive: Option<Instant>,
+}
 
 const MAX: usize = 1 << 16;
 
@@ -372,6 +1359,7 @@ impl Decoder for MessageFramer {
         if length == 0 {
             // This is a keep-alive message which should be discarded.
             src.advance(4);
+            self.last_keep_alive = Some(Instant::now());
             // Try again in case buffer has more messages.
             return self.decode(src);
         }
@@ -417,6 +1405,18 @@ impl Decoder for MessageFramer {
     }
 }
 
+// The empty frame peers send to show they're still there.
+pub struct KeepAlive;
+
+impl Encoder<KeepAlive> for MessageFramer {
+    type Error = Error;
+
+    fn encode(&mut self, _: KeepAlive, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&0u32.to_be_bytes());
+        Ok(())
+    }
+}
+
 impl Encoder<Message> for MessageFramer {
     type Error = Error;
 
@@ -444,3 +1444,1058 @@ impl Encoder<Message> for MessageFramer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::channel;
+
+    // Connects a `Peer` to a fake remote that sends `bitfield` after the
+    // handshake, returning the remote's end of the connection.
+    async fn connect(bitfield: Vec<u8>) -> (Peer, Framed<TcpStream, MessageFramer>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let remote = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&handshake).await.unwrap();
+            let mut stream = Framed::new(stream, MessageFramer::default());
+            stream
+                .send(Message {
+                    typ: MessageType::Bitfield,
+                    payload: bitfield,
+                })
+                .await
+                .unwrap();
+            stream
+        });
+        let peer = Peer::new(addr, [7; 20]).await.unwrap();
+        (peer, remote.await.unwrap())
+    }
+
+    fn message(typ: MessageType) -> Message {
+        Message {
+            typ,
+            payload: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn tracks_choke_and_interest_in_both_directions() {
+        let (mut peer, mut remote) = connect(vec![0b1000_0000]).await;
+        assert!(peer.am_choking() && !peer.am_interested());
+        assert!(peer.peer_choking() && !peer.peer_interested());
+
+        for (typ, peer_choking, peer_interested) in [
+            (MessageType::Unchoke, false, false),
+            (MessageType::Interested, false, true),
+            (MessageType::Choke, true, true),
+            (MessageType::NotInterested, true, false),
+        ] {
+            remote.send(message(typ)).await.unwrap();
+            assert_eq!(peer.recv().await.unwrap().typ, typ);
+            assert_eq!(peer.peer_choking(), peer_choking, "after {typ:?}");
+            assert_eq!(peer.peer_interested(), peer_interested, "after {typ:?}");
+        }
+
+        for (typ, am_choking, am_interested) in [
+            (MessageType::Interested, true, true),
+            (MessageType::Unchoke, false, true),
+            (MessageType::NotInterested, false, false),
+            (MessageType::Choke, true, false),
+        ] {
+            peer.send(message(typ)).await.unwrap();
+            assert_eq!(remote.next().await.unwrap().unwrap().typ, typ);
+            assert_eq!(peer.am_choking(), am_choking, "after {typ:?}");
+            assert_eq!(peer.am_interested(), am_interested, "after {typ:?}");
+        }
+    }
+
+    #[test]
+    fn peer_ids_share_the_prefix_only() {
+        let (first, second) = (generate_peer_id(), generate_peer_id());
+        assert_eq!(first[..8], *b"-RS0001-");
+        assert_eq!(second[..8], first[..8]);
+        assert_ne!(first[8..], second[8..]);
+        // while the client's own stays put
+        assert_eq!(client_peer_id(), client_peer_id());
+        assert_eq!(client_peer_id()[..8], PEER_ID_PREFIX);
+    }
+
+    #[test]
+    fn handshake_round_trips() {
+        let mut handshake = Handshake::new([7; 20], *b"-MK0001-000000000000");
+        handshake.reserved[5] |= EXTENSION_BIT;
+        let bytes = handshake.to_bytes();
+        assert_eq!(bytes[0], 19);
+        assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+        assert_eq!(bytes[25], EXTENSION_BIT);
+        assert_eq!(bytes[27], FAST_BIT);
+        assert_eq!(&bytes[28..48], &[7; 20]);
+        assert_eq!(&bytes[48..], b"-MK0001-000000000000");
+        assert_eq!(Handshake::from_bytes(&bytes).unwrap(), handshake);
+    }
+
+    #[test]
+    fn handshake_with_a_corrupt_length_is_rejected() {
+        let mut bytes = Handshake::new([7; 20], [1; 20]).to_bytes();
+        bytes[0] = 20;
+        let err = Handshake::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PeerError>(),
+            Some(PeerError::BadProtocol(_))
+        ));
+        // leniency is only about the protocol string
+        assert!(Handshake::parse(&bytes, ProtocolCheck::Lenient).is_err());
+
+        let mut bytes = Handshake::new([7; 20], [1; 20]).to_bytes();
+        bytes[1..20].copy_from_slice(b"BitTorrent protocoI");
+        assert!(Handshake::from_bytes(&bytes).is_err());
+        assert!(Handshake::parse(&bytes, ProtocolCheck::Lenient).is_err());
+        bytes[1..20].copy_from_slice(b"BITTORRENT PROTOCOL");
+        assert!(Handshake::from_bytes(&bytes).is_err());
+        let handshake = Handshake::parse(&bytes, ProtocolCheck::Lenient).unwrap();
+        assert_eq!(handshake.peer_id, [1; 20]);
+    }
+
+    #[test]
+    fn keep_alive_is_an_empty_frame() {
+        let mut buf = BytesMut::new();
+        MessageFramer::default()
+            .encode(KeepAlive, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], [0, 0, 0, 0]);
+        // and the decoder takes it as one
+        let mut framer = MessageFramer::default();
+        assert!(framer.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty() && framer.last_keep_alive.is_some());
+    }
+
+    #[tokio::test]
+    async fn keep_alives_are_sent_while_waiting() {
+        let (mut peer, mut remote) = connect(vec![0b1000_0000]).await;
+        peer.set_keep_alive_interval(Duration::from_millis(50));
+        let unchoke = async {
+            tokio::time::sleep(Duration::from_millis(180)).await;
+            remote.send(message(MessageType::Unchoke)).await.unwrap();
+            // only the keep-alives are waiting to be read
+            let next = tokio::time::timeout(Duration::from_millis(50), remote.next()).await;
+            assert!(next.is_err());
+            remote.codec().last_keep_alive
+        };
+        let (msg, keep_alive) = tokio::join!(peer.recv(), unchoke);
+        assert_eq!(msg.unwrap().typ, MessageType::Unchoke);
+        assert!(keep_alive.is_some());
+    }
+
+    #[tokio::test]
+    async fn have_adds_to_the_bitfield() {
+        let (mut peer, mut remote) = connect(vec![0b1100_0000]).await;
+        assert!(!peer.has_piece(3));
+        let have = |index: u32| Message {
+            typ: MessageType::Have,
+            payload: index.to_be_bytes().to_vec(),
+        };
+        remote.send(have(3)).await.unwrap();
+        assert_eq!(peer.recv().await.unwrap().typ, MessageType::Have);
+        assert!(peer.has_piece(3));
+
+        // out of range is ignored, a missing index isn't
+        remote.send(have(1000)).await.unwrap();
+        peer.recv().await.unwrap();
+        remote.send(message(MessageType::Have)).await.unwrap();
+        assert!(peer.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn interest_follows_what_the_peer_has_left_for_us() {
+        let (mut peer, mut remote) = connect(vec![0b1100_0000]).await;
+        let mut have = BitVec::new(3);
+        peer.update_interest(&have).await.unwrap();
+        assert_eq!(
+            remote.next().await.unwrap().unwrap().typ,
+            MessageType::Interested
+        );
+        assert!(peer.am_interested());
+
+        // one piece left to get from it, then nothing
+        have.set(0).unwrap();
+        peer.update_interest(&have).await.unwrap();
+        assert!(peer.am_interested());
+        have.set(1).unwrap();
+        peer.update_interest(&have).await.unwrap();
+        assert_eq!(
+            remote.next().await.unwrap().unwrap().typ,
+            MessageType::NotInterested
+        );
+        assert!(!peer.am_interested());
+
+        // until it announces a piece we're missing
+        remote
+            .send(Message {
+                typ: MessageType::Have,
+                payload: 2u32.to_be_bytes().to_vec(),
+            })
+            .await
+            .unwrap();
+        peer.recv().await.unwrap();
+        assert!(peer.has_piece(2) && peer.am_interested());
+        assert_eq!(
+            remote.next().await.unwrap().unwrap().typ,
+            MessageType::Interested
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_peer_flooding_unrequested_pieces() {
+        let (mut peer, mut remote) = connect(vec![0b1100_0000]).await;
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = remote.next().await {
+                match msg.typ {
+                    MessageType::Interested => {
+                        remote.send(message(MessageType::Unchoke)).await.unwrap()
+                    }
+                    MessageType::Request => {
+                        // pad every real response with as many bogus ones as we get away with
+                        let mut bogus = vec![0, 0, 0, 9, 0, 0, 0, 0];
+                        bogus.extend([0; 10]);
+                        for _ in 0..MAX_UNSOLICITED_PIECES {
+                            let piece = Message {
+                                typ: MessageType::Piece,
+                                payload: bogus.clone(),
+                            };
+                            remote.send(piece).await.unwrap();
+                        }
+                        let mut payload = msg.payload[..8].to_vec();
+                        payload.extend([1; 10]);
+                        let piece = Message {
+                            typ: MessageType::Piece,
+                            payload,
+                        };
+                        remote.send(piece).await.unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        // the requested block still makes it through
+        let (done_tx, mut done_rx) = channel(1);
+        let scheduler = BlockScheduler::new(1, 1);
+        peer.participate(0, 10, 1, &scheduler, done_tx.clone())
+            .await
+            .unwrap();
+        assert_eq!(done_rx.recv().await.unwrap().payload[8..], [1; 10]);
+        assert!(!peer.is_flooding());
+
+        // but one more bogus piece is too many
+        let scheduler = BlockScheduler::new(1, 1);
+        let err = peer
+            .participate(1, 10, 1, &scheduler, done_tx)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unrequested"));
+        assert!(peer.is_flooding());
+    }
+
+    #[tokio::test]
+    async fn late_blocks_of_a_long_download_are_forgiven() {
+        let (mut peer, mut remote) = connect(vec![0b1000_0000]).await;
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = remote.next().await {
+                match msg.typ {
+                    MessageType::Interested => {
+                        remote.send(message(MessageType::Unchoke)).await.unwrap()
+                    }
+                    MessageType::Request => {
+                        let mut payload = msg.payload[..8].to_vec();
+                        payload.extend([1; BLOCK_SIZE]);
+                        let piece = Message {
+                            typ: MessageType::Piece,
+                            payload,
+                        };
+                        remote.send(piece).await.unwrap();
+                        // like one we stopped waiting for earlier
+                        let mut late = vec![0, 0, 0, 9, 0, 0, 0, 0];
+                        late.extend([0; 10]);
+                        let late = Message {
+                            typ: MessageType::Piece,
+                            payload: late,
+                        };
+                        remote.send(late).await.unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let n_blocks = 4 * MAX_UNSOLICITED_PIECES;
+        let (done_tx, mut done_rx) = channel(n_blocks);
+        let scheduler = BlockScheduler::new(n_blocks, usize::MAX);
+        peer.participate(0, n_blocks * BLOCK_SIZE, n_blocks, &scheduler, done_tx)
+            .await
+            .unwrap();
+        let mut n_done = 0;
+        while done_rx.recv().await.is_some() {
+            n_done += 1;
+        }
+        assert_eq!(n_done, n_blocks);
+        assert!(!peer.is_flooding());
+    }
+
+    #[tokio::test]
+    async fn requests_are_pipelined() {
+        let (mut peer, mut remote) = connect(vec![0b1000_0000]).await;
+        let n_blocks = 6;
+        let remote = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            while let Some(Ok(msg)) = remote.next().await {
+                match msg.typ {
+                    MessageType::Interested => {
+                        remote.send(message(MessageType::Unchoke)).await.unwrap()
+                    }
+                    MessageType::Request => requests.push(msg.payload),
+                    _ => {}
+                }
+                if requests.len() == PIPELINE_DEPTH {
+                    break;
+                }
+            }
+            // the pipeline is full, so no more until a block arrives
+            let more = tokio::time::timeout(Duration::from_millis(100), remote.next()).await;
+            assert!(more.is_err(), "{more:?}");
+            // answered last to first, the blocks still land in the right place
+            let mut n_requests = requests.len();
+            while let Some(request) = requests.pop() {
+                let length = u32::from_be_bytes(request[8..].try_into().unwrap());
+                let mut payload = request[..8].to_vec();
+                payload.extend(vec![1; length as usize]);
+                let piece = Message {
+                    typ: MessageType::Piece,
+                    payload,
+                };
+                remote.send(piece).await.unwrap();
+                if requests.is_empty()
+                    && n_requests < n_blocks
+                    && let Some(Ok(msg)) = remote.next().await
+                {
+                    requests.push(msg.payload);
+                    n_requests += 1;
+                }
+            }
+            n_requests
+        });
+
+        let (done_tx, mut done_rx) = channel(n_blocks);
+        let scheduler = BlockScheduler::new(n_blocks, usize::MAX);
+        peer.participate(0, n_blocks * BLOCK_SIZE, n_blocks, &scheduler, done_tx)
+            .await
+            .unwrap();
+        assert_eq!(remote.await.unwrap(), n_blocks);
+        let mut begins = Vec::new();
+        while let Some(msg) = done_rx.recv().await {
+            let response = PieceResponse::ref_from_bytes(&msg.payload).unwrap();
+            assert_eq!(response.block().len(), BLOCK_SIZE);
+            begins.push(response.begin() as usize / BLOCK_SIZE);
+        }
+        // the last block was only asked for once the first answer came in
+        assert_eq!(begins, [4, 3, 2, 1, 0, 5]);
+    }
+
+    #[tokio::test]
+    async fn slow_assembly_throttles_the_peer() {
+        let (mut peer, mut remote) = connect(vec![0b1000_0000]).await;
+        tokio::spawn(async move {
+            let mut n_requests = 0;
+            while let Some(Ok(msg)) = remote.next().await {
+                match msg.typ {
+                    MessageType::Interested => {
+                        remote.send(message(MessageType::Unchoke)).await.unwrap()
+                    }
+                    MessageType::Request => {
+                        n_requests += 1;
+                        if n_requests == 5 {
+                            // the block has to be asked for again after this
+                            remote.send(message(MessageType::Choke)).await.unwrap();
+                            remote.send(message(MessageType::Unchoke)).await.unwrap();
+                            continue;
+                        }
+                        let length = u32::from_be_bytes(msg.payload[8..].try_into().unwrap());
+                        let mut payload = msg.payload[..8].to_vec();
+                        payload.extend(vec![1; length as usize]);
+                        let piece = Message {
+                            typ: MessageType::Piece,
+                            payload,
+                        };
+                        remote.send(piece).await.unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let n_blocks = 32;
+        let depth = 2;
+        let (done_tx, mut done_rx) = channel(depth);
+        let scheduler = BlockScheduler::new(n_blocks, 1);
+        let assembler = async {
+            // let the peer run ahead of us
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(done_rx.len(), depth);
+            let mut n_received = 0;
+            while done_rx.recv().await.is_some() {
+                n_received += 1;
+                assert!(done_rx.len() <= depth);
+            }
+            n_received
+        };
+        let participant = peer.participate(0, n_blocks * BLOCK_SIZE, n_blocks, &scheduler, done_tx);
+        let (result, n_received) = tokio::join!(participant, assembler);
+        result.unwrap();
+        assert_eq!(n_received, n_blocks);
+    }
+
+    #[tokio::test]
+    async fn drops_silent_peer_after_idle_window() {
+        let (mut peer, mut remote) = connect(vec![0b1000_0000]).await;
+        peer.set_idle_timeout(Duration::from_millis(200));
+        tokio::spawn(async move {
+            // unchoke once, then only keep-alives for a while before going quiet
+            while let Some(Ok(msg)) = remote.next().await {
+                if msg.typ == MessageType::Interested {
+                    break;
+                }
+            }
+            remote.send(message(MessageType::Unchoke)).await.unwrap();
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                remote.get_mut().write_all(&[0; 4]).await.unwrap();
+            }
+            // hold the connection open without a word
+            while remote.next().await.is_some() {}
+        });
+
+        let (done_tx, _done_rx) = channel(1);
+        let scheduler = BlockScheduler::new(1, 1);
+        let started = Instant::now();
+        peer.participate(0, 10, 1, &scheduler, done_tx)
+            .await
+            .unwrap();
+        // the keep-alives bought it more than one window
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert!(peer.is_idle());
+        // the block is up for grabs again
+        assert_eq!(
+            scheduler.next("127.0.0.1:1".parse().unwrap()).await,
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn request_timeout_follows_each_peers_latency() {
+        let config = RequestTimeout {
+            multiple: 4.0,
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            initial: Duration::from_secs(5),
+        };
+        let history = |millis: &[u64]| {
+            let mut latency = Latency::default();
+            for &ms in millis {
+                latency.record(Duration::from_millis(ms));
+            }
+            config.for_latency(&latency)
+        };
+        assert_eq!(history(&[]), config.initial);
+        let fast = history(&[40, 60, 50]);
+        let slow = history(&[800, 1200, 1000]);
+        assert_eq!(fast, Duration::from_millis(200));
+        assert_eq!(slow, Duration::from_millis(4000));
+        // clamped on both ends
+        assert_eq!(history(&[1, 2, 3]), config.min);
+        assert_eq!(history(&[5000, 5000]), config.max);
+        // only the latest samples count
+        let mut recovered = vec![5000; LATENCY_SAMPLES];
+        recovered.extend([50; LATENCY_SAMPLES]);
+        assert_eq!(history(&recovered), Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn stalled_request_times_out_and_requeues_the_block() {
+        let (mut peer, mut remote) = connect(vec![0b1000_0000]).await;
+        peer.set_request_timeout(RequestTimeout {
+            initial: Duration::from_millis(100),
+            ..Default::default()
+        });
+        tokio::spawn(async move {
+            // unchokes, then ignores the request
+            while let Some(Ok(msg)) = remote.next().await {
+                if msg.typ == MessageType::Interested {
+                    remote.send(message(MessageType::Unchoke)).await.unwrap();
+                }
+            }
+        });
+
+        let (done_tx, _done_rx) = channel(1);
+        let scheduler = BlockScheduler::new(1, 1);
+        let err = peer
+            .participate(0, 10, 1, &scheduler, done_tx)
+            .await
+            .unwrap_err();
+        // asked twice before giving up on it
+        assert!(err.to_string().contains("didn't send block 0"));
+        assert!(peer.is_slow() && !peer.has_piece(0));
+        assert_eq!(
+            scheduler.next("127.0.0.1:1".parse().unwrap()).await,
+            Some(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_others_answered_are_cancelled() {
+        let (first, mut first_remote) = connect(vec![0b1000_0000]).await;
+        let (second, mut second_remote) = connect(vec![0b1000_0000]).await;
+        let (a, b) = (first.addr(), second.addr());
+        // both blocks get asked of both peers, and the first one sends both
+        let scheduler = BlockScheduler::new(2, 2);
+        scheduler.set_endgame();
+        assert_eq!(scheduler.next(a).await, Some(0));
+        assert_eq!(scheduler.next(b).await, Some(1));
+        assert_eq!(scheduler.next(b).await, Some(0));
+        assert_eq!(scheduler.next(a).await, Some(1));
+        assert!(scheduler.complete(a, 0) && scheduler.complete(a, 1));
+
+        let piece_size = BLOCK_SIZE + 100;
+        let mut peers = [first, second];
+        cancel_superseded(&mut peers, 3, piece_size, &scheduler).await;
+        for (begin, length) in [(0, BLOCK_SIZE), (BLOCK_SIZE, 100)] {
+            let msg = second_remote.next().await.unwrap().unwrap();
+            assert_eq!(msg.typ, MessageType::Cancel);
+            let cancel = PieceRequest::from_bytes(&msg.payload).unwrap();
+            assert_eq!(
+                (cancel.index(), cancel.begin(), cancel.length()),
+                (3, begin as u32, length as u32)
+            );
+        }
+        // the peer that delivered is left alone, and nothing is cancelled twice
+        cancel_superseded(&mut peers, 3, piece_size, &scheduler).await;
+        let quiet = Duration::from_millis(50);
+        assert!(
+            tokio::time::timeout(quiet, first_remote.next())
+                .await
+                .is_err()
+        );
+        assert!(
+            tokio::time::timeout(quiet, second_remote.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn endgame_request_is_cancelled_once_another_peer_delivers() {
+        let (mut fast, mut fast_remote) = connect(vec![0b1000_0000]).await;
+        let (mut slow, mut slow_remote) = connect(vec![0b1000_0000]).await;
+        let piece_size = 100;
+        let fast_remote = tokio::spawn(async move {
+            while let Some(Ok(msg)) = fast_remote.next().await {
+                match msg.typ {
+                    MessageType::Interested => fast_remote
+                        .send(message(MessageType::Unchoke))
+                        .await
+                        .unwrap(),
+                    MessageType::Request => {
+                        // long enough for the slow peer to ask as well
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        let mut payload = msg.payload[..8].to_vec();
+                        payload.extend(vec![1; piece_size]);
+                        let piece = Message {
+                            typ: MessageType::Piece,
+                            payload,
+                        };
+                        fast_remote.send(piece).await.unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        });
+        let slow_remote = tokio::spawn(async move {
+            let mut frames = Vec::new();
+            while let Some(Ok(msg)) = slow_remote.next().await {
+                match msg.typ {
+                    MessageType::Interested => slow_remote
+                        .send(message(MessageType::Unchoke))
+                        .await
+                        .unwrap(),
+                    MessageType::Request | MessageType::Cancel => {
+                        let mut frame = BytesMut::new();
+                        MessageFramer::default().encode(msg, &mut frame).unwrap();
+                        frames.push(frame);
+                        if frames.len() == 2 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            frames
+        });
+
+        let scheduler = BlockScheduler::new(1, usize::MAX);
+        scheduler.set_endgame();
+        let (done_tx, mut done_rx) = channel(1);
+        let (fast_result, slow_result) = tokio::join!(
+            fast.participate(0, piece_size, 1, &scheduler, done_tx.clone()),
+            slow.participate(0, piece_size, 1, &scheduler, done_tx),
+        );
+        fast_result.unwrap();
+        slow_result.unwrap();
+        assert!(done_rx.recv().await.is_some());
+        drop(fast);
+        fast_remote.await.unwrap();
+
+        // the cancel repeats the request, only the message id differs
+        let frames = slow_remote.await.unwrap();
+        let (request, cancel) = (&frames[0], &frames[1]);
+        assert_eq!(request[4], MessageType::Request as u8);
+        assert_eq!(cancel[4], MessageType::Cancel as u8);
+        assert_eq!(request.len(), cancel.len());
+        assert_eq!(request[..4], cancel[..4]);
+        assert_eq!(request[5..], cancel[5..]);
+        assert!(scheduler.take_superseded().is_empty());
+    }
+
+    #[test]
+    fn names_clients_from_peer_ids() {
+        let name = |prefix: &[u8; 8]| {
+            let mut peer_id = [b'0'; 20];
+            peer_id[..8].copy_from_slice(prefix);
+            client_name(&peer_id)
+        };
+        assert_eq!(name(b"-qB4500-").as_deref(), Some("qBittorrent 4.5.0"));
+        assert_eq!(name(b"-TR3000-").as_deref(), Some("Transmission 3.0.0"));
+        assert_eq!(name(b"-LT1234-").as_deref(), Some("libtorrent 1.2.3.4"));
+        assert_eq!(name(b"-XX1000-"), None);
+        assert_eq!(name(b"M7-2-2--"), None);
+    }
+
+    #[tokio::test]
+    async fn stats_snapshot_tracks_the_connection() {
+        let (mut peer, mut remote) = connect(vec![0b1010_0000]).await;
+        remote.send(message(MessageType::Unchoke)).await.unwrap();
+        peer.recv().await.unwrap();
+        let request = PieceRequest::new(0, 0, 10).as_bytes_mut().to_vec();
+        for _ in 0..2 {
+            peer.send(Message {
+                typ: MessageType::Request,
+                payload: request.clone(),
+            })
+            .await
+            .unwrap();
+        }
+        let mut payload = vec![0; 8];
+        payload.extend([1; 10]);
+        remote
+            .send(Message {
+                typ: MessageType::Piece,
+                payload,
+            })
+            .await
+            .unwrap();
+        peer.recv().await.unwrap();
+
+        let stats = peer.stats();
+        // our own, echoed back
+        assert_eq!(stats.peer_id, client_peer_id());
+        assert_eq!(stats.client_name, None);
+        assert!(stats.download_rate > 0.0);
+        assert_eq!(stats.upload_rate, 0.0);
+        assert!(stats.am_choking && !stats.peer_choking);
+        assert_eq!(stats.pieces_have, 2);
+        assert_eq!(stats.requests_in_flight, 1);
+
+        // and only what came since counts next time
+        let (download_rate, upload_rate) = peer.recent_rates();
+        assert!(download_rate > 0.0 && upload_rate == 0.0);
+        assert_eq!(peer.recent_rates(), (0.0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn inbound_peer_is_served_what_it_requests() {
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seeder = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut peer = Peer::accept(stream, [7; 20], client_peer_id(), ProtocolCheck::Strict)
+                .await
+                .unwrap();
+            let mut have = BitVec::new(1);
+            have.set(0).unwrap();
+            let result = peer
+                .serve(
+                    &have,
+                    |_| data.len(),
+                    async |request: BlockRequest| {
+                        let begin = request.begin;
+                        Ok(data[begin..begin + request.length].to_vec())
+                    },
+                )
+                .await;
+            (peer.stats(), result)
+        };
+        let remote = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let handshake = Handshake::new([7; 20], *b"-MK0001-000000000000");
+            let mut sent = handshake.to_bytes().to_vec();
+            // straight to business, before even hearing back
+            let mut buf = BytesMut::new();
+            for msg in [
+                message(MessageType::Interested),
+                Message {
+                    typ: MessageType::Request,
+                    payload: Vec::from(PieceRequest::new(0, BLOCK_SIZE as u32, 100).as_bytes_mut()),
+                },
+            ] {
+                MessageFramer::default().encode(msg, &mut buf).unwrap();
+            }
+            sent.extend(buf);
+            stream.write_all(&sent).await.unwrap();
+
+            let mut handshake = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            assert_eq!(
+                Handshake::from_bytes(&handshake).unwrap().info_hash,
+                [7; 20]
+            );
+            let mut remote = Framed::new(stream, MessageFramer::default());
+            let mut next = async || remote.next().await.unwrap().unwrap();
+            let bitfield = next().await;
+            assert_eq!(bitfield.typ, MessageType::Bitfield);
+            assert_eq!(bitfield.payload, [0b1000_0000]);
+            assert_eq!(next().await.typ, MessageType::Unchoke);
+            let piece = next().await;
+            assert_eq!(piece.typ, MessageType::Piece);
+            let response = PieceResponse::ref_from_bytes(&piece.payload).unwrap();
+            assert_eq!((response.index(), response.begin()), (0, BLOCK_SIZE as u32));
+            assert_eq!(response.block(), &data[BLOCK_SIZE..BLOCK_SIZE + 100]);
+            let block = &data[BLOCK_SIZE..BLOCK_SIZE + 100];
+            assert_eq!(
+                piece.payload,
+                PieceResponse::to_bytes(0, BLOCK_SIZE as u32, block)
+            );
+        };
+
+        let ((stats, result), ()) = tokio::join!(seeder, remote);
+        // the peer hanging up ends it
+        assert!(result.is_err());
+        assert!(stats.upload_rate > 0.0);
+    }
+
+    #[tokio::test]
+    async fn inbound_peer_without_a_bitfield_keeps_its_haves() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let handshake = Handshake::new([7; 20], *b"99887766554433221100");
+            stream.write_all(&handshake.to_bytes()).await.unwrap();
+            let mut reply = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut reply).await.unwrap();
+            let mut remote = Framed::new(stream, MessageFramer::default());
+            let have = Message {
+                typ: MessageType::Have,
+                payload: 3u32.to_be_bytes().to_vec(),
+            };
+            remote.send(have).await.unwrap();
+            remote
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut peer = Peer::accept(stream, [7; 20], client_peer_id(), ProtocolCheck::Strict)
+            .await
+            .unwrap();
+        peer.set_piece_count(10);
+        let _remote = remote.await.unwrap();
+        assert_eq!(peer.recv().await.unwrap().typ, MessageType::Have);
+        assert_eq!(peer.pieces().ones().collect::<Vec<_>>(), [3]);
+        assert!(!peer.has_piece(4));
+    }
+
+    #[tokio::test]
+    async fn request_past_the_piece_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seeder = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut peer = Peer::accept(stream, [7; 20], client_peer_id(), ProtocolCheck::Strict)
+                .await
+                .unwrap();
+            let mut have = BitVec::new(1);
+            have.set(0).unwrap();
+            peer.serve(
+                &have,
+                |_| 100,
+                async |request: BlockRequest| Ok(vec![0; request.length]),
+            )
+            .await
+        };
+        let remote = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let handshake = Handshake::new([7; 20], *b"-MK0001-000000000000");
+            stream.write_all(&handshake.to_bytes()).await.unwrap();
+            let mut handshake = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let mut remote = Framed::new(stream, MessageFramer::default());
+            remote.send(message(MessageType::Interested)).await.unwrap();
+            // the piece is only 100 bytes long
+            let request = PieceRequest::new(0, 64, 64).as_bytes_mut().to_vec();
+            remote
+                .send(Message {
+                    typ: MessageType::Request,
+                    payload: request,
+                })
+                .await
+                .unwrap();
+            // bitfield and unchoke, then nothing
+            let mut types = Vec::new();
+            while let Some(Ok(msg)) = remote.next().await {
+                types.push(msg.typ);
+            }
+            types
+        };
+        let (result, types) = tokio::join!(seeder, remote);
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("can't send"), "{err}");
+        assert!(!types.contains(&MessageType::Piece), "{types:?}");
+    }
+
+    #[test]
+    fn fast_extension_messages_round_trip() {
+        use MessageType::*;
+        for (typ, id) in [
+            (SuggestPiece, 0x0d),
+            (HaveAll, 0x0e),
+            (HaveNone, 0x0f),
+            (RejectRequest, 0x10),
+            (AllowedFast, 0x11),
+        ] {
+            let payload = match typ {
+                HaveAll | HaveNone => Vec::new(),
+                RejectRequest => PieceRequest::new(1, 0, 10).as_bytes_mut().to_vec(),
+                _ => 3u32.to_be_bytes().to_vec(),
+            };
+            let msg = Message {
+                typ,
+                payload: payload.clone(),
+            };
+            let mut buf = BytesMut::new();
+            MessageFramer::default().encode(msg, &mut buf).unwrap();
+            assert_eq!(buf[4], id);
+            let decoded = MessageFramer::default().decode(&mut buf).unwrap().unwrap();
+            assert_eq!((decoded.typ, decoded.payload), (typ, payload));
+        }
+    }
+
+    #[tokio::test]
+    async fn have_all_is_a_full_bitfield() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let info_hash = [7; 20];
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut their_handshake = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut their_handshake).await.unwrap();
+            assert_eq!(their_handshake[27] & FAST_BIT, FAST_BIT);
+            let handshake = Handshake::new(info_hash, *b"99887766554433221100");
+            let mut reply = handshake.to_bytes().to_vec();
+            // no bitfield, just "everything"
+            reply.extend([0, 0, 0, 1, MessageType::HaveAll as u8]);
+            stream.write_all(&reply).await.unwrap();
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+
+        let mut peer = Peer::new(addr, info_hash).await.unwrap();
+        // before we know how many pieces there are it has any of them
+        assert!(peer.has_piece(0) && peer.has_piece(1000));
+        peer.set_piece_count(10);
+        assert_eq!(
+            peer.pieces().ones().collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert!(peer.pieces().is_full());
+        assert!(!peer.has_piece(10));
+        assert_eq!(peer.stats().pieces_have, 10);
+    }
+
+    #[tokio::test]
+    async fn fast_peer_is_told_about_ignored_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seeder = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut peer = Peer::accept(stream, [7; 20], client_peer_id(), ProtocolCheck::Strict)
+                .await
+                .unwrap();
+            let mut have = BitVec::new(1);
+            have.set(0).unwrap();
+            peer.serve(
+                &have,
+                |_| 100,
+                async |request: BlockRequest| Ok(vec![0; request.length]),
+            )
+            .await
+        };
+        let remote = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let handshake = Handshake::new([7; 20], *b"-MK0001-000000000000");
+            stream.write_all(&handshake.to_bytes()).await.unwrap();
+            let mut handshake = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let mut remote = Framed::new(stream, MessageFramer::default());
+            // still choked, so it won't be answered
+            let request = PieceRequest::new(0, 0, 10).as_bytes_mut().to_vec();
+            remote
+                .send(Message {
+                    typ: MessageType::Request,
+                    payload: request.clone(),
+                })
+                .await
+                .unwrap();
+            let mut next = async || remote.next().await.unwrap().unwrap();
+            assert_eq!(next().await.typ, MessageType::Bitfield);
+            let rejected = next().await;
+            assert_eq!(rejected.typ, MessageType::RejectRequest);
+            assert_eq!(rejected.payload, request);
+        };
+        tokio::select! {
+            result = seeder => panic!("stopped serving: {result:?}"),
+            () = remote => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn bitfield_pipelined_with_handshake_is_parsed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let info_hash = [7; 20];
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut their_handshake = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut their_handshake).await.unwrap();
+            let handshake = Handshake::new(info_hash, *b"99887766554433221100");
+            let mut reply = handshake.to_bytes().to_vec();
+            // bitfield message with pieces 0 and 3
+            reply.extend([0, 0, 0, 2, MessageType::Bitfield as u8, 0b1001_0000]);
+            stream.write_all(&reply).await.unwrap();
+            // keep the connection open until the client is done
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+
+        let peer = Peer::new(addr, info_hash).await.unwrap();
+        assert!(peer.has_piece(0));
+        assert!(!peer.has_piece(1));
+        assert!(peer.has_piece(3));
+    }
+
+    #[tokio::test]
+    async fn wrong_protocol_string_is_a_typed_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let info_hash = [7; 20];
+        tokio::spawn(async move {
+            // once for each attempt
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut their_handshake = [0; HANDSHAKE_LEN];
+                stream.read_exact(&mut their_handshake).await.unwrap();
+                let mut handshake = Handshake::new(info_hash, *b"99887766554433221100");
+                handshake.bittorrent = *b"Bittorrent Protocol";
+                let mut reply = handshake.to_bytes().to_vec();
+                reply.extend([0, 0, 0, 2, MessageType::Bitfield as u8, 0b1000_0000]);
+                stream.write_all(&reply).await.unwrap();
+                let _ = stream.read(&mut [0; 1]).await;
+            }
+        });
+
+        let err = Peer::new(addr, info_hash).await.err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<PeerError>(),
+            Some(&PeerError::BadProtocol("Bittorrent Protocol".to_string()))
+        );
+        let peer = Peer::connect(addr, info_hash, client_peer_id(), ProtocolCheck::Lenient)
+            .await
+            .unwrap();
+        assert!(peer.has_piece(0));
+    }
+
+    #[cfg(feature = "serde-messages")]
+    #[test]
+    fn message_serde_round_trip() {
+        use MessageType::*;
+        for typ in [
+            Choke,
+            Unchoke,
+            Interested,
+            NotInterested,
+            Have,
+            Bitfield,
+            Request,
+            Piece,
+            Cancel,
+            SuggestPiece,
+            HaveAll,
+            HaveNone,
+            RejectRequest,
+            AllowedFast,
+            Extended,
+        ] {
+            let bytes = serde_bencode::to_bytes(&typ).unwrap();
+            let decoded: MessageType = serde_bencode::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, typ);
+        }
+
+        let msg = Message {
+            typ: Piece,
+            payload: vec![0, 0, 0, 1, 0, 0, 0, 0, 0xde, 0xad],
+        };
+        let bytes = serde_bencode::to_bytes(&msg).unwrap();
+        // payload is stored as a length-prefixed byte string
+        assert!(bytes.windows(3).any(|w| w == b"10:"));
+        let decoded: Message = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.typ, msg.typ);
+        assert_eq!(decoded.payload, msg.payload);
+
+        // extended messages carry their sub-id and a bencoded dictionary
+        let msg = Message {
+            typ: Extended,
+            payload: b"\x00d1:md11:ut_metadatai1eee".to_vec(),
+        };
+        let bytes = serde_bencode::to_bytes(&msg).unwrap();
+        let decoded: Message = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.typ, Extended);
+        assert_eq!(decoded.payload, msg.payload);
+        // and go over the wire with id 20
+        let mut frame = BytesMut::new();
+        MessageFramer::default().encode(msg, &mut frame).unwrap();
+        assert_eq!(frame[4], 20);
+        let decoded = MessageFramer::default()
+            .decode(&mut frame)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.typ, Extended);
+        assert_eq!(decoded.payload, b"\x00d1:md11:ut_metadatai1eee");
+    }
+}
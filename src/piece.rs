@@ -2,6 +2,7 @@ use crate::dot_torrent::DotTorrent;
 use crate::peer::Peer;
 use std::cmp::Ordering;
 use std::collections::HashSet;
+use tokio::time::Instant;
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Piece {
@@ -9,30 +10,48 @@ pub struct Piece {
     length: usize,
     hash: [u8; 20],
     peers: HashSet<usize>,
+    // must be downloaded by then, e.g. for streaming playback
+    deadline: Option<Instant>,
 }
 
 impl Piece {
     pub(crate) fn new(index: usize, dot_torrent: &DotTorrent, peers: &[Peer]) -> Self {
-        let length = if index == dot_torrent.info.pieces.0.len() - 1 {
-            // calculates last piece's size
-            dot_torrent.length() % dot_torrent.info.piece_length
-        } else {
-            dot_torrent.info.piece_length
-        };
-        let hash = dot_torrent.info.pieces.0[index];
-        let peers = peers
-            .iter()
+        let has = peers.iter().map(|peer| peer.has_piece(index));
+        Self::held_by(index, dot_torrent, has)
+    }
+
+    // Like `new`, with the peers given by whether each of them has the piece.
+    pub(crate) fn held_by(
+        index: usize,
+        dot_torrent: &DotTorrent,
+        has: impl IntoIterator<Item = bool>,
+    ) -> Self {
+        let info = dot_torrent
+            .piece(index)
+            .expect("piece index within the torrent");
+        let peers = has
+            .into_iter()
             .enumerate()
-            .filter_map(|(peer_i, peer)| peer.has_piece(index).then_some(peer_i))
+            .filter_map(|(peer_i, has)| has.then_some(peer_i))
             .collect();
         Self {
             index,
-            length,
-            hash,
+            length: info.length,
+            hash: info.hash,
             peers,
+            deadline: None,
         }
     }
 
+    pub(crate) fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
     pub(crate) fn index(&self) -> usize {
         self.index
     }
@@ -48,13 +67,24 @@ impl Piece {
     pub(crate) fn peers(&self) -> &HashSet<usize> {
         &self.peers
     }
+
+    // Stops asking `peer_i` for the piece, returning whether it was asked before.
+    pub(crate) fn forget_peer(&mut self, peer_i: usize) -> bool {
+        self.peers.remove(&peer_i)
+    }
 }
 
 impl Ord for Piece {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.peers
-            .len()
-            .cmp(&other.peers.len())
+        // pieces with a deadline come first, the most urgent of them first
+        let by_deadline = match (self.deadline, other.deadline) {
+            (Some(ours), Some(theirs)) => theirs.cmp(&ours),
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (None, None) => Ordering::Equal,
+        };
+        by_deadline
+            .then(self.peers.len().cmp(&other.peers.len()))
             // tie-break by random ordering of HashSet to avoid deterministic contention
             .then(self.peers.iter().cmp(other.peers.iter()))
     }
@@ -65,3 +95,34 @@ impl PartialOrd for Piece {
         Some(self.cmp(other))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BinaryHeap;
+    use std::time::Duration;
+
+    fn piece(index: usize, n_peers: usize, deadline: Option<Instant>) -> Piece {
+        Piece {
+            index,
+            length: 1,
+            hash: [0; 20],
+            peers: (0..n_peers).collect(),
+            deadline,
+        }
+    }
+
+    #[test]
+    fn deadline_pieces_go_first() {
+        let now = Instant::now();
+        let mut heap = BinaryHeap::from([
+            piece(0, 3, None),
+            piece(1, 1, Some(now + Duration::from_secs(10))),
+            piece(2, 2, None),
+            piece(3, 1, Some(now + Duration::from_secs(1))),
+        ]);
+        let order: Vec<_> = std::iter::from_fn(|| heap.pop().map(|p| p.index())).collect();
+        // then the usual order among the rest
+        assert_eq!(order, [3, 1, 0, 2]);
+    }
+}
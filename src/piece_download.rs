@@ -0,0 +1,251 @@
+use crate::BLOCK_SIZE;
+use crate::bit_vec::BitVec;
+use crate::scheduler::BlockScheduler;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Instant;
+
+// A piece that is being downloaded. It can be paused to let a more urgent
+// piece (e.g. one a stream is waiting on) have the peers, without losing the
+// blocks it already got: they stay in the `BlockCache` and only the missing
+// ones are handed out after `resume`.
+pub struct PieceDownload {
+    index: usize,
+    length: usize,
+    scheduler: BlockScheduler,
+}
+
+impl PieceDownload {
+    pub fn new(index: usize, length: usize) -> Self {
+        Self {
+            index,
+            length,
+            // peers hold as many blocks as their pipeline allows
+            scheduler: BlockScheduler::new(length.div_ceil(BLOCK_SIZE), usize::MAX),
+        }
+    }
+
+    // See `DownloadOptions::max_peer_share`.
+    pub fn with_max_share(mut self, share: f64) -> Self {
+        self.scheduler = self.scheduler.with_max_share(share);
+        self
+    }
+
+    // Starts with the blocks in `have` (see `blocks`) already there, e.g. on
+    // disk from before a restart.
+    pub fn with_blocks(mut self, have: &BitVec) -> Self {
+        self.scheduler = self.scheduler.with_done(have);
+        self
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn pause(&self) {
+        self.scheduler.pause();
+    }
+
+    pub fn resume(&self) {
+        self.scheduler.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.scheduler.is_paused()
+    }
+
+    pub fn blocks_done(&self) -> usize {
+        self.scheduler.n_done()
+    }
+
+    // Peers that sent at least one of the blocks.
+    pub fn contributors(&self) -> usize {
+        self.scheduler.contributors()
+    }
+
+    // Bitmap of the blocks received so far, to be persisted with the resume
+    // data so a restart only fetches the missing ones.
+    pub fn blocks(&self) -> BitVec {
+        self.scheduler.done()
+    }
+
+    // The first block that still has to be fetched.
+    pub fn next_missing(&self) -> Option<usize> {
+        let done = self.blocks();
+        (0..self.length.div_ceil(BLOCK_SIZE)).find(|&block_i| !done.has(block_i))
+    }
+
+    // Requested blocks that haven't arrived: block index, peer and since when.
+    pub fn inflight(&self) -> Vec<(usize, SocketAddrV4, Instant)> {
+        self.scheduler.in_flight()
+    }
+
+    // Requeues a requested block, e.g. to get a stall moving.
+    pub fn cancel(&self, block_i: usize) -> bool {
+        self.scheduler.cancel(block_i)
+    }
+
+    pub(crate) fn scheduler(&self) -> &BlockScheduler {
+        &self.scheduler
+    }
+}
+
+// Pieces with a download in progress, paused or not. Starting a piece that
+// is already here picks the existing download back up rather than starting
+// over, so a paused piece never turns into a fresh one.
+#[derive(Default)]
+pub struct PieceDownloads {
+    active: HashMap<usize, Arc<PieceDownload>>,
+    // peers no piece gets blocks from, see `exclude`
+    excluded: HashSet<SocketAddrV4>,
+}
+
+impl PieceDownloads {
+    pub fn start(&mut self, index: usize, length: usize) -> Arc<PieceDownload> {
+        self.insert(PieceDownload::new(index, length))
+    }
+
+    // Like `start`, for a download set up with the `with_*` options. If the
+    // piece is already being downloaded, that download is kept instead.
+    pub fn insert(&mut self, download: PieceDownload) -> Arc<PieceDownload> {
+        let excluded = &self.excluded;
+        self.active
+            .entry(download.index)
+            .or_insert_with(|| {
+                for &peer in excluded {
+                    download.scheduler.exclude(peer);
+                }
+                Arc::new(download)
+            })
+            .clone()
+    }
+
+    // Takes `peer` off every piece, the ones started later included. What
+    // it holds is requeued for the other peers.
+    pub fn exclude(&mut self, peer: SocketAddrV4) {
+        for download in self.active.values() {
+            download.scheduler.exclude(peer);
+        }
+        self.excluded.insert(peer);
+    }
+
+    // Lets an excluded peer back in, for pieces started from now on.
+    pub fn readmit(&mut self, peer: SocketAddrV4) {
+        self.excluded.remove(&peer);
+    }
+
+    pub fn is_excluded(&self, peer: SocketAddrV4) -> bool {
+        self.excluded.contains(&peer)
+    }
+
+    pub fn get(&self, index: usize) -> Option<Arc<PieceDownload>> {
+        self.active.get(&index).cloned()
+    }
+
+    // Forgets a piece once it's complete (or given up on).
+    pub fn finish(&mut self, index: usize) {
+        self.active.remove(&index);
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &Arc<PieceDownload>> {
+        self.active.values()
+    }
+
+    pub fn paused(&self) -> impl Iterator<Item = usize> {
+        self.active
+            .values()
+            .filter(|download| download.is_paused())
+            .map(|download| download.index)
+    }
+}
+
+impl fmt::Debug for PieceDownloads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PieceDownloads")
+            .field("active", &self.active.keys())
+            .field("excluded", &self.excluded)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::BlockCache;
+    use bytes::Bytes;
+    use std::net::SocketAddrV4;
+    use std::time::Duration;
+
+    const HASH: [u8; 20] = [0; 20];
+
+    // Fetches up to `n` blocks of `download` into `cache`, returning the
+    // assembled piece if that completed it.
+    async fn fetch(download: &PieceDownload, cache: &BlockCache, n: usize) -> Option<Bytes> {
+        let peer: SocketAddrV4 = "127.0.0.1:1".parse().unwrap();
+        let mut assembled = None;
+        for _ in 0..n {
+            let block_i = download.scheduler().next(peer).await?;
+            assert!(download.scheduler().complete(peer, block_i));
+            let begin = block_i * BLOCK_SIZE;
+            let block = Bytes::from(vec![download.index() as u8; BLOCK_SIZE]);
+            assembled = cache.put_block(HASH, download.index(), begin, block, download.length());
+        }
+        assembled
+    }
+
+    #[tokio::test]
+    async fn paused_piece_resumes_where_it_left_off() {
+        let cache = BlockCache::new(16 * BLOCK_SIZE);
+        let mut downloads = PieceDownloads::default();
+        let slow = downloads.start(0, 4 * BLOCK_SIZE);
+        assert!(fetch(&slow, &cache, 2).await.is_none());
+
+        // something more urgent comes up
+        slow.pause();
+        let blocked = tokio::time::timeout(Duration::from_millis(50), fetch(&slow, &cache, 1));
+        assert!(blocked.await.is_err());
+        let urgent = downloads.start(1, 2 * BLOCK_SIZE);
+        assert!(fetch(&urgent, &cache, 2).await.is_some());
+        downloads.finish(1);
+        assert_eq!(downloads.paused().collect::<Vec<_>>(), [0]);
+
+        // picking the piece again doesn't start it over
+        let slow = downloads.start(0, 4 * BLOCK_SIZE);
+        assert_eq!(slow.blocks_done(), 2);
+        assert!(cache.contains(HASH, 0));
+        slow.resume();
+        let piece = fetch(&slow, &cache, 2).await.unwrap();
+        assert_eq!(piece.len(), 4 * BLOCK_SIZE);
+        assert_eq!(
+            slow.scheduler().next("127.0.0.1:1".parse().unwrap()).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn resumes_with_blocks_already_there() {
+        let mut have = BitVec::new(4);
+        have.set(0).unwrap();
+        have.set(2).unwrap();
+        let download = PieceDownload::new(0, 4 * BLOCK_SIZE).with_blocks(&have);
+        assert_eq!(download.blocks_done(), 2);
+        assert_eq!(download.next_missing(), Some(1));
+
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let scheduler = download.scheduler();
+        let first = scheduler.next(peer).await.unwrap();
+        assert!(scheduler.complete(peer, first));
+        let second = scheduler.next(peer).await.unwrap();
+        assert_eq!([first, second], [1, 3]);
+        assert_eq!(download.next_missing(), Some(3));
+        assert!(scheduler.complete(peer, second));
+        assert_eq!(download.next_missing(), None);
+        assert_eq!(download.blocks().as_bytes(), [0b1111_0000]);
+    }
+}
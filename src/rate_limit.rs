@@ -0,0 +1,164 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+
+// Token bucket holding up to a second's worth of bytes.
+#[derive(Debug)]
+struct Bucket {
+    // bytes per second
+    rate: f64,
+    // negative when children of a global bucket spent their own allowance
+    // while it was already empty
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    // How long until `n` bytes are available.
+    fn wait_for(&self, n: f64) -> Duration {
+        Duration::from_secs_f64(((n - self.tokens) / self.rate).max(0.0))
+    }
+}
+
+// Caps a transfer rate in bytes per second. Limiters form a hierarchy: a
+// global one shared by every torrent caps the client's total bandwidth, and
+// each torrent's child gets a guaranteed share of it. A child that has used
+// up its share borrows what the global bucket has left over, so a busy
+// torrent gets the allowance idle ones aren't using.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    parent: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "rate must be positive");
+        Self {
+            bucket: Mutex::new(Bucket::new(rate)),
+            parent: None,
+        }
+    }
+
+    // A limiter guaranteed `rate` of this one's. The shares of all children
+    // should add up to at most this limiter's rate.
+    pub fn child(self: &Arc<Self>, rate: u64) -> RateLimiter {
+        RateLimiter {
+            parent: Some(self.clone()),
+            ..RateLimiter::new(rate)
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate as u64
+    }
+
+    // Waits until `n` bytes may be transferred.
+    pub async fn acquire(&self, n: usize) {
+        while let Err(wait) = self.try_acquire(n) {
+            sleep(wait).await;
+        }
+    }
+
+    // Takes `n` bytes worth of tokens, or says how long to wait before trying
+    // again.
+    fn try_acquire(&self, n: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut own = self.bucket.lock().unwrap();
+        own.refill(now);
+        // a bucket never holds more than a second's worth, so larger
+        // requests go through once it's full
+        let n = (n as f64).min(own.rate);
+        let Some(parent) = &self.parent else {
+            if own.tokens >= n {
+                own.tokens -= n;
+                return Ok(());
+            }
+            return Err(own.wait_for(n));
+        };
+        let mut shared = parent.bucket.lock().unwrap();
+        shared.refill(now);
+        if own.tokens >= n {
+            // within our share, which the global bucket owes us even if
+            // others have borrowed it empty
+            own.tokens -= n;
+            shared.tokens -= n;
+            return Ok(());
+        }
+        let borrowed = n.min(shared.rate);
+        if shared.tokens >= borrowed {
+            shared.tokens -= borrowed;
+            return Ok(());
+        }
+        Err(own.wait_for(n).min(shared.wait_for(borrowed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn busy_torrent_borrows_from_idle_one_under_global_cap() {
+        const GLOBAL: u64 = 200_000;
+        const BLOCK: usize = 1 << 12;
+        let global = Arc::new(RateLimiter::new(GLOBAL));
+        let busy = global.child(GLOBAL / 2);
+        let idle = global.child(GLOBAL / 2);
+
+        let start = Instant::now();
+        let run_for = Duration::from_millis(1500);
+        let busy_bytes = async {
+            let mut total = 0;
+            while start.elapsed() < run_for {
+                busy.acquire(BLOCK).await;
+                total += BLOCK;
+            }
+            total
+        };
+        let idle_bytes = async {
+            let mut total = 0;
+            while start.elapsed() < run_for {
+                idle.acquire(BLOCK).await;
+                total += BLOCK;
+                sleep(Duration::from_millis(100)).await;
+            }
+            total
+        };
+        let (busy_bytes, idle_bytes) = tokio::join!(busy_bytes, idle_bytes);
+        let elapsed = start.elapsed().as_secs_f64();
+
+        // the global bucket starts full, so up to a second's worth on top
+        let cap = GLOBAL as f64 * (elapsed + 1.0);
+        assert!(((busy_bytes + idle_bytes) as f64) <= cap);
+        // more than its own share, borrowed from the idle torrent
+        let share = (GLOBAL / 2) as f64 * (elapsed + 1.0);
+        assert!(busy_bytes as f64 > share);
+        assert!(busy_bytes > idle_bytes);
+    }
+
+    #[tokio::test]
+    async fn standalone_limiter_caps_rate() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+        // a full bucket, then another second's worth
+        for _ in 0..20 {
+            limiter.acquire(10_000).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::BLOCK_SIZE;
+use crate::bit_vec::BitVec;
+use crate::dot_torrent::{DotTorrent, LayoutMode};
+use anyhow::Context;
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+// Which SHA1 code verifies pieces, for diagnostics. The `sha1` crate picks the
+// CPU's SHA extensions at runtime unless the `portable-sha1` feature is on.
+pub fn verification_backend() -> &'static str {
+    #[cfg(not(feature = "portable-sha1"))]
+    {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if is_x86_feature_detected!("sha")
+            && is_x86_feature_detected!("sse2")
+            && is_x86_feature_detected!("ssse3")
+            && is_x86_feature_detected!("sse4.1")
+        {
+            return "sha-ni";
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("sha2") {
+            return "armv8-crypto";
+        }
+        #[cfg(target_arch = "loongarch64")]
+        return "loongarch64-asm";
+    }
+    #[allow(unreachable_code)]
+    "portable"
+}
+
+// Where a file's bytes sit in the torrent's contiguous byte stream.
+pub(crate) struct FileSpan {
+    pub(crate) path: PathBuf,
+    pub(crate) offset: usize,
+    pub(crate) length: usize,
+}
+
+// Where each file of the torrent is stored under `dir`.
+pub(crate) fn data_paths(
+    dot_torrent: &DotTorrent,
+    dir: &Path,
+    mode: LayoutMode,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let paths = dot_torrent.layout_paths(mode)?;
+    Ok(paths.into_iter().map(|path| dir.join(path)).collect())
+}
+
+pub(crate) fn layout(
+    dot_torrent: &DotTorrent,
+    dir: &Path,
+    mode: LayoutMode,
+) -> anyhow::Result<Vec<FileSpan>> {
+    let mut offset = 0;
+    let paths = data_paths(dot_torrent, dir, mode)?;
+    Ok(dot_torrent
+        .files()
+        .into_iter()
+        .zip(paths)
+        .map(|(file, path)| {
+            let span = FileSpan {
+                path,
+                offset,
+                length: file.length,
+            };
+            offset += file.length;
+            span
+        })
+        .collect())
+}
+
+// Verifies the data under `dir`, laid out as `mode` says, against the
+// torrent's piece hashes and returns the intact pieces. Missing or short
+// files just fail their pieces.
+pub async fn recheck(
+    dot_torrent: &DotTorrent,
+    dir: &Path,
+    mode: LayoutMode,
+) -> anyhow::Result<BitVec> {
+    let files = layout(dot_torrent, dir, mode)?;
+    let mut verified = BitVec::new(dot_torrent.info.pieces.0.len());
+    for piece in dot_torrent.pieces() {
+        if let Ok(hash) = hash_range(&files, piece.offset, piece.length).await
+            && hash == piece.hash
+        {
+            verified.set(piece.index)?;
+        }
+    }
+    Ok(verified)
+}
+
+// Reads the blocks in `have` of the piece `index` from the data under `dir`,
+// to pick up a piece that was partially written before a restart. Returns
+// each block with its offset in the piece.
+pub async fn read_blocks(
+    dot_torrent: &DotTorrent,
+    dir: &Path,
+    mode: LayoutMode,
+    index: usize,
+    have: &BitVec,
+) -> anyhow::Result<Vec<(usize, Bytes)>> {
+    let files = layout(dot_torrent, dir, mode)?;
+    let piece = dot_torrent.piece(index).context("no such piece")?;
+    let mut blocks = Vec::new();
+    for block_i in have.ones() {
+        let begin = block_i * BLOCK_SIZE;
+        anyhow::ensure!(begin < piece.length, "piece {index} has no block {block_i}");
+        let length = BLOCK_SIZE.min(piece.length - begin);
+        let mut block = Vec::with_capacity(length);
+        read_range(&files, piece.offset + begin, length, |bytes| {
+            block.extend_from_slice(bytes)
+        })
+        .await
+        .with_context(|| format!("read block {block_i} of piece {index}"))?;
+        blocks.push((begin, Bytes::from(block)));
+    }
+    Ok(blocks)
+}
+
+// Hashes `length` bytes starting at `offset` of the torrent's byte stream,
+// reading a block at a time so huge pieces never sit in memory whole.
+async fn hash_range(files: &[FileSpan], offset: usize, length: usize) -> std::io::Result<[u8; 20]> {
+    let mut hasher = Sha1::new();
+    read_range(files, offset, length, |bytes| hasher.update(bytes)).await?;
+    Ok(hasher.finalize().into())
+}
+
+// Feeds `length` bytes starting at `offset` to `consume`, a block at a time.
+pub(crate) async fn read_range(
+    files: &[FileSpan],
+    offset: usize,
+    length: usize,
+    mut consume: impl FnMut(&[u8]),
+) -> std::io::Result<()> {
+    let end = offset + length;
+    let mut pos = offset;
+    let mut buf = vec![0; BLOCK_SIZE];
+    for file in files {
+        if file.offset + file.length <= pos || file.offset >= end {
+            continue;
+        }
+        let mut handle = File::open(&file.path).await?;
+        handle
+            .seek(SeekFrom::Start((pos - file.offset) as u64))
+            .await?;
+        let file_end = end.min(file.offset + file.length);
+        while pos < file_end {
+            let n = BLOCK_SIZE.min(file_end - pos);
+            handle.read_exact(&mut buf[..n]).await?;
+            consume(&buf[..n]);
+            pos += n;
+        }
+    }
+    if pos < end {
+        // the files end before the range does
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dot_torrent::hashes::Hashes;
+    use crate::dot_torrent::{File as TorrentFile, Info, Key};
+
+    // 5 MiB split over two files, in 4 MiB pieces so the second one is truncated
+    fn pack(data: &[u8]) -> DotTorrent {
+        let piece_length = 4 << 20;
+        let pieces = data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let files = vec![
+            TorrentFile {
+                length: 3 << 20,
+                path: vec!["a".to_string()],
+            },
+            TorrentFile {
+                length: 2 << 20,
+                path: vec!["dir".to_string(), "b".to_string()],
+            },
+        ];
+        DotTorrent::new(Info::new(
+            "pack",
+            piece_length,
+            Hashes(pieces),
+            Key::MultipleFiles { files },
+        ))
+    }
+
+    #[tokio::test]
+    async fn chunked_hash_matches_one_shot_hash() {
+        let data: Vec<u8> = (0..5u32 << 20).map(|i| (i % 253) as u8).collect();
+        let dir = std::env::temp_dir().join("bittorrent_recheck_test");
+        std::fs::create_dir_all(dir.join("pack/dir")).unwrap();
+        std::fs::write(dir.join("pack/a"), &data[..3 << 20]).unwrap();
+        std::fs::write(dir.join("pack/dir/b"), &data[3 << 20..]).unwrap();
+        let dot_torrent = pack(&data);
+
+        // the first piece spans both files
+        let files = layout(&dot_torrent, &dir, LayoutMode::Nested).unwrap();
+        let hash = hash_range(&files, 0, 4 << 20).await.unwrap();
+        assert_eq!(hash, <[u8; 20]>::from(Sha1::digest(&data[..4 << 20])));
+
+        let verified = recheck(&dot_torrent, &dir, LayoutMode::Nested)
+            .await
+            .unwrap();
+        assert_eq!(verified.ones().collect::<Vec<_>>(), [0, 1]);
+
+        // corrupt the truncated last piece, which is the tail of `b`
+        let mut b = data[3 << 20..].to_vec();
+        b[(2 << 20) - 1] ^= 1;
+        std::fs::write(dir.join("pack/dir/b"), b).unwrap();
+        let verified = recheck(&dot_torrent, &dir, LayoutMode::Nested)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(verified.ones().collect::<Vec<_>>(), [0]);
+    }
+
+    #[tokio::test]
+    async fn flat_layout_is_rechecked_where_it_was_saved() {
+        let data: Vec<u8> = (0..5u32 << 20).map(|i| (i % 247) as u8).collect();
+        let dir = std::env::temp_dir().join("bittorrent_recheck_flat_test");
+        let dot_torrent = pack(&data);
+        let paths = data_paths(&dot_torrent, &dir, LayoutMode::Flat).unwrap();
+        std::fs::create_dir_all(paths[0].parent().unwrap()).unwrap();
+        std::fs::write(&paths[0], &data[..3 << 20]).unwrap();
+        std::fs::write(&paths[1], &data[3 << 20..]).unwrap();
+
+        let flat = recheck(&dot_torrent, &dir, LayoutMode::Flat).await.unwrap();
+        // nothing where the nested layout would have put it
+        let nested = recheck(&dot_torrent, &dir, LayoutMode::Nested)
+            .await
+            .unwrap();
+        let have = BitVec::full(2);
+        let blocks = read_blocks(&dot_torrent, &dir, LayoutMode::Flat, 1, &have).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(flat.is_full());
+        assert_eq!(nested.ones().count(), 0);
+        let blocks = blocks.unwrap();
+        assert_eq!(blocks[1].0, BLOCK_SIZE);
+        assert_eq!(blocks[1].1, &data[(4 << 20) + BLOCK_SIZE..][..BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn digest_is_the_same_on_every_backend() {
+        // spans several 64-byte blocks so the compression function runs in a loop
+        let data = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq".repeat(20);
+        let mut hasher = Sha1::new();
+        for chunk in data.chunks(100) {
+            hasher.update(chunk);
+        }
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "d01e46ebd8a844a5fec5cdc6ae7a19f501362ca9",
+            "on {}",
+            verification_backend()
+        );
+    }
+}
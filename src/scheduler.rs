@@ -0,0 +1,419 @@
+use crate::bit_vec::BitVec;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddrV4;
+use std::pin::pin;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+// Hands out the blocks of a piece to the peers participating in its download.
+// Peers pull a block whenever they have room in their pipeline, so fast peers
+// naturally end up with more blocks than slow ones, and no peer may hold more
+// than `max_held` blocks at a time. Once there is nothing left to hand out,
+// an idle peer (one holding no blocks) steals the block that has been held
+// the longest by another peer, so a single slow peer can't keep the whole
+// piece waiting.
+//
+// Optionally no peer may claim (hold or have completed) more than a share of
+// the piece's blocks while other peers are working on it, so a single fast
+// peer doesn't do the whole piece and the others get a chance.
+pub(crate) struct BlockScheduler {
+    state: Mutex<State>,
+    // woken whenever a block becomes available or the piece completes
+    notify: Notify,
+}
+
+struct State {
+    n_blocks: usize,
+    max_held: usize,
+    pending: VecDeque<usize>,
+    // peers currently requesting a block and when they were given it
+    in_flight: HashMap<usize, Vec<(SocketAddrV4, Instant)>>,
+    n_done: usize,
+    // blocks received, or already there when the download started
+    done: BitVec,
+    max_claimed: usize,
+    completed_by: HashMap<SocketAddrV4, usize>,
+    // no blocks are handed out, in flight ones may still complete
+    paused: bool,
+    // blocks may be requested from any number of peers at once
+    endgame: bool,
+    // requests another peer answered first, to be cancelled
+    superseded: Vec<(SocketAddrV4, usize)>,
+    // peers that get no more blocks, see `exclude`
+    excluded: HashSet<SocketAddrV4>,
+}
+
+impl State {
+    fn held_by(&self, peer: SocketAddrV4) -> usize {
+        self.in_flight
+            .values()
+            .filter(|holders| holders.iter().any(|(addr, _)| *addr == peer))
+            .count()
+    }
+
+    fn capped(&self, peer: SocketAddrV4) -> bool {
+        let claimed = self.held_by(peer) + self.completed_by.get(&peer).copied().unwrap_or(0);
+        // the cap only applies while someone else can pick up the slack
+        let others_busy = self
+            .in_flight
+            .values()
+            .any(|holders| holders.iter().any(|(addr, _)| *addr != peer));
+        claimed >= self.max_claimed && others_busy
+    }
+
+    // Oldest block held by other peers only. Blocks are requested from at
+    // most two peers at once, except in endgame where the ones with the
+    // fewest holders go first.
+    fn steal(&self, peer: SocketAddrV4) -> Option<usize> {
+        self.in_flight
+            .iter()
+            .filter(|(_, holders)| {
+                (holders.len() == 1 || self.endgame)
+                    && holders.iter().all(|(addr, _)| *addr != peer)
+            })
+            .min_by_key(|(_, holders)| (holders.len(), holders[0].1))
+            .map(|(&block_i, _)| block_i)
+    }
+}
+
+impl BlockScheduler {
+    pub(crate) fn new(n_blocks: usize, max_held: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                n_blocks,
+                max_held: max_held.max(1),
+                pending: (0..n_blocks).collect(),
+                in_flight: HashMap::new(),
+                n_done: 0,
+                done: BitVec::new(n_blocks),
+                max_claimed: n_blocks,
+                completed_by: HashMap::new(),
+                superseded: Vec::new(),
+                excluded: HashSet::new(),
+                paused: false,
+                endgame: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    // Limits each peer to `share` (0 to 1) of the piece's blocks.
+    pub(crate) fn with_max_share(self, share: f64) -> Self {
+        {
+            let mut state = self.state.lock().expect("mutex was poisoned");
+            state.max_claimed = ((state.n_blocks as f64 * share).ceil() as usize).max(1);
+        }
+        self
+    }
+
+    // Treats the blocks in `have` as received, e.g. ones written to disk
+    // before a restart, so only the rest are handed out.
+    pub(crate) fn with_done(self, have: &BitVec) -> Self {
+        {
+            let mut state = self.state.lock().expect("mutex was poisoned");
+            state.pending.retain(|&block_i| !have.has(block_i));
+            let n_blocks = state.n_blocks;
+            for block_i in have.ones().filter(|&block_i| block_i < n_blocks) {
+                state.done.set(block_i).expect("within the piece");
+            }
+            state.n_done = n_blocks - state.pending.len();
+        }
+        self
+    }
+
+    // Which blocks have been received so far.
+    pub(crate) fn done(&self) -> BitVec {
+        self.state.lock().expect("mutex was poisoned").done.clone()
+    }
+
+    // Waits until there is a block for `peer` to request.
+    // Returns `None` once every block of the piece has been received.
+    pub(crate) async fn next(&self, peer: SocketAddrV4) -> Option<usize> {
+        loop {
+            // register interest before looking at the state so a wakeup
+            // between the check and the await isn't lost
+            let mut notified = pin!(self.notify.notified());
+            notified.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("mutex was poisoned");
+                if state.n_done == state.n_blocks || state.excluded.contains(&peer) {
+                    return None;
+                }
+                if !state.paused
+                    && state.held_by(peer) < state.max_held
+                    && (state.endgame || !state.capped(peer))
+                {
+                    let idle = state.endgame || state.held_by(peer) == 0;
+                    let block_i = state
+                        .pending
+                        .pop_front()
+                        .or_else(|| idle.then(|| state.steal(peer)).flatten());
+                    if let Some(block_i) = block_i {
+                        state
+                            .in_flight
+                            .entry(block_i)
+                            .or_default()
+                            .push((peer, Instant::now()));
+                        return Some(block_i);
+                    }
+                }
+            }
+            notified.await;
+        }
+    }
+
+    // Stops handing out blocks until `resume`. Peers waiting in `next` keep waiting.
+    pub(crate) fn pause(&self) {
+        self.state.lock().expect("mutex was poisoned").paused = true;
+    }
+
+    pub(crate) fn resume(&self) {
+        self.state.lock().expect("mutex was poisoned").paused = false;
+        self.notify.notify_waiters();
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.state.lock().expect("mutex was poisoned").paused
+    }
+
+    // Lets every idle peer request any missing block, whoever else has it,
+    // and lifts the share cap: the piece is needed now.
+    pub(crate) fn set_endgame(&self) {
+        self.state.lock().expect("mutex was poisoned").endgame = true;
+        self.notify.notify_waiters();
+    }
+
+    pub(crate) fn is_endgame(&self) -> bool {
+        self.state.lock().expect("mutex was poisoned").endgame
+    }
+
+    // Peers that delivered at least one block.
+    pub(crate) fn contributors(&self) -> usize {
+        self.state
+            .lock()
+            .expect("mutex was poisoned")
+            .completed_by
+            .len()
+    }
+
+    // Blocks received so far.
+    pub(crate) fn n_done(&self) -> usize {
+        self.state.lock().expect("mutex was poisoned").n_done
+    }
+
+    // Blocks that were requested but haven't arrived, with the peers asked
+    // for them and when.
+    pub(crate) fn in_flight(&self) -> Vec<(usize, SocketAddrV4, Instant)> {
+        let state = self.state.lock().expect("mutex was poisoned");
+        let mut in_flight: Vec<_> = state
+            .in_flight
+            .iter()
+            .flat_map(|(&block_i, holders)| {
+                holders
+                    .iter()
+                    .map(move |&(peer, since)| (block_i, peer, since))
+            })
+            .collect();
+        in_flight.sort_by_key(|&(block_i, _, since)| (block_i, since));
+        in_flight
+    }
+
+    // Takes a block away from every peer it was requested from and hands it
+    // out again. Whatever they still send for it is discarded.
+    pub(crate) fn cancel(&self, block_i: usize) -> bool {
+        let mut state = self.state.lock().expect("mutex was poisoned");
+        if state.in_flight.remove(&block_i).is_none() {
+            return false;
+        }
+        state.pending.push_front(block_i);
+        drop(state);
+        self.notify.notify_waiters();
+        true
+    }
+
+    // Returns a block `peer` failed to get (it choked us, timed out, ...),
+    // so it can be handed out again if no other peer is working on it.
+    pub(crate) fn requeue(&self, peer: SocketAddrV4, block_i: usize) {
+        let mut state = self.state.lock().expect("mutex was poisoned");
+        let Some(holders) = state.in_flight.get_mut(&block_i) else {
+            return;
+        };
+        holders.retain(|(addr, _)| *addr != peer);
+        if holders.is_empty() {
+            state.in_flight.remove(&block_i);
+            // it's been waited on the longest, so it goes first
+            state.pending.push_front(block_i);
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    // Requeues every block `peer` still holds, e.g. when it disconnects.
+    pub(crate) fn release(&self, peer: SocketAddrV4) {
+        let held: Vec<_> = {
+            let state = self.state.lock().expect("mutex was poisoned");
+            state
+                .in_flight
+                .iter()
+                .filter(|(_, holders)| holders.iter().any(|(addr, _)| *addr == peer))
+                .map(|(&block_i, _)| block_i)
+                .collect()
+        };
+        for block_i in held {
+            self.requeue(peer, block_i);
+        }
+        // peers held back by the share cap may be the only ones left
+        self.notify.notify_waiters();
+    }
+
+    // Takes `peer` off the piece: what it holds is requeued and it's handed
+    // nothing more, as if the piece were complete. Whatever it still sends
+    // is discarded.
+    pub(crate) fn exclude(&self, peer: SocketAddrV4) {
+        let mut state = self.state.lock().expect("mutex was poisoned");
+        state.excluded.insert(peer);
+        drop(state);
+        self.release(peer);
+    }
+
+    // Blocks that were also requested from peers other than the one that
+    // delivered them, with those peers. Each is handed out once.
+    pub(crate) fn take_superseded(&self) -> Vec<(SocketAddrV4, usize)> {
+        std::mem::take(&mut self.state.lock().expect("mutex was poisoned").superseded)
+    }
+
+    // Waits until another peer delivers a block `peer` also asked for, and
+    // hands those blocks out, so `peer` can cancel its requests right away.
+    pub(crate) async fn superseded(&self, peer: SocketAddrV4) -> Vec<usize> {
+        loop {
+            let mut notified = pin!(self.notify.notified());
+            notified.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("mutex was poisoned");
+                let (ours, others) = std::mem::take(&mut state.superseded)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|(addr, _)| *addr == peer);
+                state.superseded = others;
+                if !ours.is_empty() {
+                    return ours.into_iter().map(|(_, block_i)| block_i).collect();
+                }
+            }
+            notified.await;
+        }
+    }
+
+    // Marks a block as received from `peer`. Returns `false` if another peer
+    // already delivered it, in which case the data should be discarded.
+    pub(crate) fn complete(&self, peer: SocketAddrV4, block_i: usize) -> bool {
+        let mut state = self.state.lock().expect("mutex was poisoned");
+        let Some(holders) = state.in_flight.remove(&block_i) else {
+            return false;
+        };
+        if !holders.iter().any(|(addr, _)| *addr == peer) {
+            // we took the block away from this peer, and it was already requeued
+            state.in_flight.insert(block_i, holders);
+            return false;
+        }
+        let losers = holders.iter().filter(|(addr, _)| *addr != peer);
+        let losers: Vec<_> = losers.map(|&(addr, _)| (addr, block_i)).collect();
+        state.superseded.extend(losers);
+        state.n_done += 1;
+        state.done.set(block_i).expect("within the piece");
+        *state.completed_by.entry(peer).or_default() += 1;
+        drop(state);
+        self.notify.notify_waiters();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+    }
+
+    async fn work(scheduler: Arc<BlockScheduler>, peer: SocketAddrV4, delay: Duration) -> usize {
+        let mut completed = 0;
+        while let Some(block_i) = scheduler.next(peer).await {
+            sleep(delay).await;
+            if scheduler.complete(peer, block_i) {
+                completed += 1;
+            }
+        }
+        completed
+    }
+
+    #[tokio::test]
+    async fn fast_peer_completes_more_blocks() {
+        let scheduler = Arc::new(BlockScheduler::new(20, 1));
+        let fast = tokio::spawn(work(scheduler.clone(), addr(1), Duration::from_millis(2)));
+        let slow = tokio::spawn(work(scheduler.clone(), addr(2), Duration::from_millis(50)));
+        let (fast, slow) = (fast.await.unwrap(), slow.await.unwrap());
+        assert_eq!(fast + slow, 20);
+        assert!(fast > slow, "fast: {fast}, slow: {slow}");
+    }
+
+    #[tokio::test]
+    async fn share_cap_splits_blocks_between_peers() {
+        let scheduler = Arc::new(BlockScheduler::new(10, 1).with_max_share(0.5));
+        let fast = tokio::spawn(work(scheduler.clone(), addr(1), Duration::from_millis(2)));
+        let slow = tokio::spawn(work(scheduler.clone(), addr(2), Duration::from_millis(20)));
+        assert_eq!((fast.await.unwrap(), slow.await.unwrap()), (5, 5));
+    }
+
+    #[tokio::test]
+    async fn share_cap_is_lifted_for_the_last_peer() {
+        let scheduler = BlockScheduler::new(4, 1).with_max_share(0.5);
+        assert_eq!(scheduler.next(addr(2)).await, Some(0));
+        // peer 2 leaves, so peer 1 has to do everything
+        scheduler.release(addr(2));
+        for _ in 0..4 {
+            let block_i = scheduler.next(addr(1)).await.unwrap();
+            assert!(scheduler.complete(addr(1), block_i));
+        }
+        assert_eq!(scheduler.next(addr(1)).await, None);
+    }
+
+    #[tokio::test]
+    async fn requeued_block_is_handed_out_again() {
+        let scheduler = BlockScheduler::new(2, 1);
+        assert_eq!(scheduler.next(addr(1)).await, Some(0));
+        assert_eq!(scheduler.next(addr(2)).await, Some(1));
+        scheduler.requeue(addr(1), 0);
+        assert_eq!(scheduler.next(addr(1)).await, Some(0));
+        assert!(scheduler.complete(addr(1), 0));
+        assert!(scheduler.complete(addr(2), 1));
+        assert_eq!(scheduler.next(addr(1)).await, None);
+    }
+
+    #[tokio::test]
+    async fn idle_peer_steals_held_block() {
+        let scheduler = BlockScheduler::new(1, 1);
+        assert_eq!(scheduler.next(addr(1)).await, Some(0));
+        // nothing pending, so peer 2 duplicates the request
+        assert_eq!(scheduler.next(addr(2)).await, Some(0));
+        assert!(scheduler.complete(addr(2), 0));
+        assert!(!scheduler.complete(addr(1), 0));
+        assert_eq!(scheduler.next(addr(1)).await, None);
+    }
+
+    #[tokio::test]
+    async fn endgame_requests_blocks_from_every_idle_peer() {
+        let scheduler = BlockScheduler::new(1, 1);
+        assert_eq!(scheduler.next(addr(1)).await, Some(0));
+        assert_eq!(scheduler.next(addr(2)).await, Some(0));
+        // two holders is the limit outside of endgame
+        let third = tokio::time::timeout(Duration::from_millis(50), scheduler.next(addr(3)));
+        assert!(third.await.is_err());
+        scheduler.set_endgame();
+        assert_eq!(scheduler.next(addr(3)).await, Some(0));
+        assert!(scheduler.complete(addr(3), 0));
+    }
+}
@@ -1,9 +1,11 @@
 use crate::bit_vec::BitVec;
 use crate::db::FileDB;
 use crate::dot_torrent::DotTorrent;
-use serde::Deserialize;
-use std::path::PathBuf;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::Mutex;
 
 pub struct State {
@@ -13,8 +15,23 @@ pub struct State {
 }
 
 impl State {
+    // A corrupt state file doesn't keep the client from starting: it's backed
+    // up next to the original and we start without any torrents.
     pub fn new(db: FileDB) -> anyhow::Result<Self> {
-        let data: Vec<Metadata> = serde_json::from_slice(db.data())?;
+        let data: Vec<Metadata> = match serde_json::from_slice(db.data()) {
+            Ok(data) => data,
+            Err(err) => {
+                let backup = backup_path(db.path());
+                std::fs::write(&backup, db.data())
+                    .with_context(|| format!("back up corrupt state to `{}`", backup.display()))?;
+                eprintln!(
+                    "state in `{}` is corrupt ({err}), starting empty; the old state is in `{}`",
+                    db.path().display(),
+                    backup.display()
+                );
+                Vec::new()
+            }
+        };
         let data = data
             .into_iter()
             .map(|value| Arc::new(Mutex::new(value)))
@@ -26,18 +43,99 @@ impl State {
     // }
 }
 
+// `db.json` -> `db.json.corrupt-1700000000`, so earlier backups aren't overwritten.
+fn backup_path(path: &Path) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".corrupt-{secs}"));
+    backup.into()
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Metadata {
     pub id: usize,
     pub path: PathBuf,
     pub dot_torrent: DotTorrent,
-    pub peer_id: [u8; 20],
     pub port: u16,
     pub uploaded: usize,
     pub downloaded: usize,
     pub left: usize,
     pub pieces: BitVec,
+    // pieces with only some of their blocks on disk
+    #[serde(default)]
+    pub partial: Vec<PartialPiece>,
     pub finished: bool,
 }
 
+// Blocks of an unfinished piece that made it to disk, see
+// `PieceDownload::blocks`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub index: usize,
+    // bitmap of the blocks, high bit first
+    #[serde(with = "serde_bytes")]
+    pub blocks: Vec<u8>,
+}
+
 pub type SharedMetadata = Arc<Mutex<Metadata>>;
+
+// Progress of a download in a form that can be moved to another install
+// and picked up there, see `Torrent::export_resume`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    // bitfield of verified pieces, high bit first
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    // where the downloaded data lives
+    pub path: PathBuf,
+    // absent in resume data from before it was tracked
+    #[serde(default)]
+    pub partial: Vec<PartialPiece>,
+}
+
+impl ResumeData {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        serde_bencode::from_bytes(bytes).context("parse resume data")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("bencode resume data")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn corrupt_state_is_backed_up_and_replaced() {
+        let dir = std::env::temp_dir().join("bittorrent_state_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.json");
+        std::fs::write(&path, b"[{\"id\": 1, \"pa").unwrap();
+
+        let state = State::new(FileDB::open(path.clone()).await.unwrap()).unwrap();
+        assert!(state.data.is_empty());
+        let backups: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().contains(".corrupt-"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(std::fs::read(&backups[0]).unwrap(), b"[{\"id\": 1, \"pa");
+
+        // a fresh database isn't mistaken for a corrupt one
+        let fresh = dir.join("fresh.json");
+        let state = State::new(FileDB::open(fresh).await.unwrap()).unwrap();
+        assert!(state.data.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
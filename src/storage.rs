@@ -0,0 +1,72 @@
+use crate::dot_torrent::{DotTorrent, LayoutMode};
+use crate::recheck::{FileSpan, layout, read_range};
+use futures_util::future::BoxFuture;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+// Where a torrent's data is kept, addressed by offset into the torrent's
+// contiguous byte stream. `FileStorage` is the real thing, tests swap in
+// storage that misbehaves.
+pub trait Storage: Send + Sync {
+    fn write<'a>(&'a self, offset: usize, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<()>>;
+
+    fn read(&self, offset: usize, length: usize) -> BoxFuture<'_, std::io::Result<Vec<u8>>>;
+}
+
+// The torrent's files under a directory, laid out as `mode` says.
+pub struct FileStorage {
+    files: Vec<FileSpan>,
+}
+
+impl FileStorage {
+    pub fn new(dot_torrent: &DotTorrent, dir: &Path, mode: LayoutMode) -> anyhow::Result<Self> {
+        Ok(Self {
+            files: layout(dot_torrent, dir, mode)?,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn write<'a>(&'a self, offset: usize, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            let end = offset + data.len();
+            for file in &self.files {
+                let (start, stop) = (offset.max(file.offset), end.min(file.offset + file.length));
+                if start >= stop {
+                    continue;
+                }
+                if let Some(parent) = file.path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let mut handle = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&file.path)
+                    .await?;
+                handle
+                    .seek(SeekFrom::Start((start - file.offset) as u64))
+                    .await?;
+                handle
+                    .write_all(&data[start - offset..stop - offset])
+                    .await?;
+                // on disk before the piece counts as stored
+                handle.sync_data().await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read(&self, offset: usize, length: usize) -> BoxFuture<'_, std::io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut data = Vec::with_capacity(length);
+            read_range(&self.files, offset, length, |bytes| {
+                data.extend_from_slice(bytes)
+            })
+            .await?;
+            Ok(data)
+        })
+    }
+}
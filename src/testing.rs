@@ -0,0 +1,362 @@
+use crate::magnet::{
+    ExtendedHandshake, METADATA_BLOCK, METADATA_DATA, METADATA_REJECT, METADATA_REQUEST,
+    MetadataMessage, extended,
+};
+use crate::peer::{
+    EXTENSION_BIT, Handshake, Message, MessageFramer, MessageType, PieceRequest, PieceResponse,
+};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tokio_util::codec::Framed;
+
+type Responder = dyn Fn(usize, &str) -> (u16, Vec<u8>) + Send + Sync;
+
+// Minimal HTTP tracker for tests. Every request's target (path and query)
+// is forwarded to `requests`, and answered with whatever the `respond`
+// closure returns for the request's index and target.
+pub struct MockTracker {
+    pub url: String,
+    pub requests: UnboundedReceiver<String>,
+}
+
+impl MockTracker {
+    pub async fn start(
+        respond: impl Fn(usize, &str) -> (u16, Vec<u8>) + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (requests_tx, requests) = unbounded_channel();
+        let respond: Arc<Responder> = Arc::new(respond);
+        let mut n_requests = 0;
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let mut buf = Vec::new();
+                let mut chunk = [0; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&buf);
+                let Some(target) = head.split_whitespace().nth(1) else {
+                    continue;
+                };
+                let target = target.to_string();
+                let (status, body) = respond(n_requests, &target);
+                n_requests += 1;
+                let _ = requests_tx.send(target);
+                let head = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        Self { url, requests }
+    }
+}
+
+// Bencoded tracker response with a compact peer list.
+pub fn tracker_response(interval: u64, peers: &[std::net::SocketAddrV4]) -> Vec<u8> {
+    let mut compact = Vec::new();
+    for peer in peers {
+        compact.extend(peer.ip().octets());
+        compact.extend(peer.port().to_be_bytes());
+    }
+    let mut body = format!("d8:intervali{interval}e5:peers{}:", compact.len()).into_bytes();
+    body.extend(compact);
+    body.push(b'e');
+    body
+}
+
+// In-process peer speaking the real wire protocol. It accepts the handshake
+// for `info_hash`, sends `bitfield`, unchokes as soon as we're interested and
+// serves requested blocks out of `data`.
+pub struct MockPeer {
+    pub addr: SocketAddrV4,
+    seed: Arc<Seed>,
+}
+
+struct Seed {
+    addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    data: Vec<u8>,
+    piece_length: usize,
+    bitfield: Vec<u8>,
+    // (piece, begin) of every block anyone asked us for
+    requested: Mutex<BTreeSet<(usize, usize)>>,
+    // pieces in the order they were first asked for
+    order: Mutex<Vec<usize>>,
+    // how long it takes to answer a request
+    delay: Duration,
+    // info dictionary handed out over `ut_metadata`, none if empty
+    metadata: Vec<u8>,
+    // pieces in the bitfield whose requests get rejected (BEP 6), or
+    // metadata pieces for a metadata-only peer
+    rejected: BTreeSet<usize>,
+}
+
+impl MockPeer {
+    pub async fn start(
+        info_hash: [u8; 20],
+        data: Vec<u8>,
+        piece_length: usize,
+        bitfield: Vec<u8>,
+    ) -> Self {
+        Self::start_slow(info_hash, data, piece_length, bitfield, Duration::ZERO).await
+    }
+
+    // A peer that has nothing but the torrent's metadata, which it sends to
+    // whoever asks over the extension protocol (BEP 9).
+    pub async fn start_metadata(info_hash: [u8; 20], metadata: Vec<u8>) -> Self {
+        Self::spawn(Seed {
+            metadata,
+            ..Seed::new(info_hash, Vec::new(), 1, Vec::new(), Duration::ZERO)
+        })
+        .await
+    }
+
+    // Like `start_metadata`, but rejects requests for the `rejected`
+    // metadata pieces.
+    pub async fn start_metadata_rejecting(
+        info_hash: [u8; 20],
+        metadata: Vec<u8>,
+        rejected: &[usize],
+    ) -> Self {
+        Self::spawn(Seed {
+            metadata,
+            rejected: rejected.iter().copied().collect(),
+            ..Seed::new(info_hash, Vec::new(), 1, Vec::new(), Duration::ZERO)
+        })
+        .await
+    }
+
+    // Like `start`, but rejects every request for the `rejected` pieces
+    // although it advertises them.
+    pub async fn start_rejecting(
+        info_hash: [u8; 20],
+        data: Vec<u8>,
+        piece_length: usize,
+        bitfield: Vec<u8>,
+        rejected: &[usize],
+    ) -> Self {
+        Self::spawn(Seed {
+            rejected: rejected.iter().copied().collect(),
+            ..Seed::new(info_hash, data, piece_length, bitfield, Duration::ZERO)
+        })
+        .await
+    }
+
+    // Like `start`, but takes `delay` to answer each request.
+    pub async fn start_slow(
+        info_hash: [u8; 20],
+        data: Vec<u8>,
+        piece_length: usize,
+        bitfield: Vec<u8>,
+        delay: Duration,
+    ) -> Self {
+        Self::spawn(Seed::new(info_hash, data, piece_length, bitfield, delay)).await
+    }
+
+    async fn spawn(seed: Seed) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let seed = Arc::new(Seed { addr, ..seed });
+        let serving = seed.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let seed = serving.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, &seed).await;
+                });
+            }
+        });
+        Self { addr, seed }
+    }
+
+    // Pieces that blocks were requested of so far, sorted.
+    pub fn requested_pieces(&self) -> Vec<usize> {
+        let requested = self.seed.requested.lock().unwrap();
+        let pieces: BTreeSet<_> = requested.iter().map(|&(piece_i, _)| piece_i).collect();
+        pieces.into_iter().collect()
+    }
+
+    // Pieces that blocks were requested of so far, in the order they were
+    // first asked for.
+    pub fn request_order(&self) -> Vec<usize> {
+        self.seed.order.lock().unwrap().clone()
+    }
+
+    // Offsets of the blocks of `piece_i` requested so far, sorted.
+    pub fn requested_blocks(&self, piece_i: usize) -> Vec<usize> {
+        let requested = self.seed.requested.lock().unwrap();
+        requested
+            .range((piece_i, 0)..(piece_i + 1, 0))
+            .map(|&(_, begin)| begin)
+            .collect()
+    }
+}
+
+impl Seed {
+    fn new(
+        info_hash: [u8; 20],
+        data: Vec<u8>,
+        piece_length: usize,
+        bitfield: Vec<u8>,
+        delay: Duration,
+    ) -> Self {
+        Self {
+            // filled in once listening
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            info_hash,
+            data,
+            piece_length,
+            bitfield,
+            requested: Mutex::default(),
+            order: Mutex::default(),
+            delay,
+            metadata: Vec::new(),
+            rejected: BTreeSet::new(),
+        }
+    }
+}
+
+// The id we want `ut_metadata` messages sent to us with, unlike the client's.
+const MOCK_UT_METADATA: u8 = 3;
+
+async fn serve(mut stream: TcpStream, seed: &Seed) -> anyhow::Result<()> {
+    let info_hash = seed.info_hash;
+    let mut their_handshake = [0; 68];
+    stream.read_exact(&mut their_handshake).await?;
+    let their_handshake = Handshake::from_bytes(&their_handshake)?;
+    anyhow::ensure!(their_handshake.info_hash == info_hash, "unknown torrent");
+    // every mock peer gets its own id
+    let peer_id = format!("-MK0001-{:012}", seed.addr.port());
+    let mut handshake = Handshake::new(info_hash, peer_id.as_bytes().try_into()?);
+    if !seed.metadata.is_empty() {
+        handshake.reserved[5] |= EXTENSION_BIT;
+    }
+    stream.write_all(&handshake.to_bytes()).await?;
+
+    let mut stream = Framed::new(stream, MessageFramer::default());
+    stream
+        .send(Message {
+            typ: MessageType::Bitfield,
+            payload: seed.bitfield.clone(),
+        })
+        .await?;
+    if !seed.metadata.is_empty() {
+        let handshake = ExtendedHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), MOCK_UT_METADATA)]),
+            metadata_size: Some(seed.metadata.len()),
+        };
+        stream.send(extended(0, &handshake)?).await?;
+    }
+    // what the other side wants `ut_metadata` messages sent with
+    let mut their_ut_metadata = None;
+    while let Some(msg) = stream.next().await {
+        let msg = msg?;
+        match msg.typ {
+            MessageType::Interested => {
+                stream
+                    .send(Message {
+                        typ: MessageType::Unchoke,
+                        payload: Vec::new(),
+                    })
+                    .await?
+            }
+            MessageType::Request => {
+                let request = PieceRequest::from_bytes(&msg.payload)?;
+                let piece_i = request.index() as usize;
+                let block = (piece_i, request.begin() as usize);
+                seed.requested.lock().unwrap().insert(block);
+                {
+                    let mut order = seed.order.lock().unwrap();
+                    if !order.contains(&piece_i) {
+                        order.push(piece_i);
+                    }
+                }
+                if seed.rejected.contains(&piece_i) {
+                    stream
+                        .send(Message {
+                            typ: MessageType::RejectRequest,
+                            payload: msg.payload,
+                        })
+                        .await?;
+                    continue;
+                }
+                tokio::time::sleep(seed.delay).await;
+                let begin = piece_i * seed.piece_length + request.begin() as usize;
+                let block = &seed.data[begin..begin + request.length() as usize];
+                let payload = PieceResponse::to_bytes(request.index(), request.begin(), block);
+                stream
+                    .send(Message {
+                        typ: MessageType::Piece,
+                        payload,
+                    })
+                    .await?
+            }
+            MessageType::Extended => {
+                let (&id, payload) = msg.payload.split_first().unwrap_or((&0, &[]));
+                if id == 0 {
+                    let theirs: ExtendedHandshake = serde_bencode::from_bytes(payload)?;
+                    their_ut_metadata = theirs.m.get("ut_metadata").copied();
+                    continue;
+                }
+                let Some(their_id) = their_ut_metadata else {
+                    continue;
+                };
+                let request: MetadataMessage = serde_bencode::from_bytes(payload)?;
+                let begin = request.piece * METADATA_BLOCK;
+                if id != MOCK_UT_METADATA
+                    || request.msg_type != METADATA_REQUEST
+                    || begin >= seed.metadata.len()
+                {
+                    continue;
+                }
+                if seed.rejected.contains(&request.piece) {
+                    let header = MetadataMessage {
+                        msg_type: METADATA_REJECT,
+                        piece: request.piece,
+                        total_size: None,
+                    };
+                    stream.send(extended(their_id, &header)?).await?;
+                    continue;
+                }
+                let end = (begin + METADATA_BLOCK).min(seed.metadata.len());
+                let header = MetadataMessage {
+                    msg_type: METADATA_DATA,
+                    piece: request.piece,
+                    total_size: Some(seed.metadata.len()),
+                };
+                let mut reply = extended(their_id, &header)?;
+                reply.payload.extend(&seed.metadata[begin..end]);
+                stream.send(reply).await?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Bitfield with the first `n_pieces` bits set.
+pub fn full_bitfield(n_pieces: usize) -> Vec<u8> {
+    let mut bitfield = vec![0xff; n_pieces.div_ceil(8)];
+    if n_pieces % 8 != 0 {
+        *bitfield.last_mut().expect("at least one piece") = 0xff << (8 - n_pieces % 8);
+    }
+    bitfield
+}
@@ -1,13 +1,37 @@
-use crate::peer::Peer;
+use crate::BLOCK_SIZE;
+use crate::bit_vec::BitVec;
+use crate::cache::BlockCache;
+use crate::choker::{ChokeCandidate, Choker, RECHOKE_INTERVAL};
+use crate::dot_torrent::{DotTorrent, File, LayoutMode};
+use crate::download::{DownloadOptions, download_blocks};
+use crate::ip_filter::IpFilter;
+use crate::peer::{
+    Message, MessageType, Peer, PeerId, PeerStats, ProtocolCheck, cancel_superseded,
+    client_peer_id,
+};
 use crate::piece::Piece;
-use crate::state::SharedMetadata;
-use crate::tracker::{PeerAddrs, query_tracker};
+use crate::piece_download::{PieceDownload, PieceDownloads};
+use crate::recheck::{read_blocks, recheck};
+use crate::state::{PartialPiece, ResumeData, SharedMetadata};
+use crate::storage::{FileStorage, Storage};
+use crate::tracker::{
+    AnnounceMode, AnnounceQueue, AnnounceState, Event, PeerAddrs, Progress, TrackerClient,
+    TrackerTiers,
+};
+use crate::verify::PieceVerifier;
+use anyhow::Context;
+use bytes::Bytes;
 use futures_util::{StreamExt, stream};
-use std::collections::BinaryHeap;
-use std::sync::Arc;
-use std::time::Duration;
+use sha1::{Digest, Sha1};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::net::SocketAddrV4;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, Notify, Semaphore, mpsc};
+use tokio::sync::{Mutex, Notify, Semaphore, mpsc, watch};
 use tokio::time::sleep;
 
 pub struct TorrentManager {
@@ -28,6 +52,17 @@ impl TorrentManager {
     // }
 }
 
+// Writes of a piece that reads back corrupt before giving up, see
+// `Torrent::with_verify_after_write`.
+pub const WRITE_ATTEMPTS: usize = 3;
+
+// Peers connected at once unless changed with `Torrent::set_max_peers`.
+pub const MAX_PEERS: usize = 5;
+
+// How quickly the download rate behind `Torrent::eta` follows changes. A
+// change in rate is about two thirds taken in after this long.
+const RATE_SMOOTHING: Duration = Duration::from_secs(5);
+
 pub struct Torrent {
     pub info_hash: [u8; 20],
     pub metadata: SharedMetadata,
@@ -37,6 +72,158 @@ pub struct Torrent {
     pub max_peers: Arc<Semaphore>,
     // notifies after fetching peer addresses
     notify: Arc<Notify>,
+    // wakes the heartbeat up to re-announce after a network change
+    network_changed: Arc<Notify>,
+    // whether downloading is paused, watched by the heartbeat
+    paused: watch::Sender<bool>,
+    // spreads out the announces of all torrents in a `TorrentList`
+    announce_queue: AnnounceQueue,
+    // which of the torrent's trackers the heartbeat announces to
+    announce_mode: AnnounceMode,
+    // how we introduce ourselves to peers and trackers
+    peer_id: PeerId,
+    // pieces being downloaded, see `inflight`
+    downloads: Arc<std::sync::Mutex<PieceDownloads>>,
+    // when to stop seeding, enforced by the heartbeat
+    seeding: Arc<Seeding>,
+    // set by `start`
+    mode: OnceLock<Mode>,
+    // picks the peers we upload to, see `rechoke`
+    choker: std::sync::Mutex<Choker>,
+    // read pieces back after writing them, see `store_piece`
+    verify_writes: bool,
+    // what `max_peers` is being brought to, see `set_max_peers`
+    peer_limit: Arc<std::sync::Mutex<PeerLimit>>,
+    // hashes downloaded pieces, shared with other torrents to bound CPU
+    verifier: Arc<PieceVerifier>,
+    // see `piece_metrics`
+    piece_metrics: Arc<std::sync::Mutex<BTreeMap<usize, PieceMetric>>>,
+    // peers taken out of `peers` by a piece download, see `lend_peers`
+    lent: std::sync::Mutex<Vec<(PeerId, SocketAddrV4)>>,
+    // addresses not to connect to again, see `disconnect`
+    banned: std::sync::Mutex<HashSet<SocketAddrV4>>,
+    // peers we won't talk to, in either direction
+    ip_filter: Arc<IpFilter>,
+    // fed every block downloaded with `download_options`, see `eta`
+    download_rate: Arc<std::sync::Mutex<DownloadRate>>,
+}
+
+#[derive(Debug)]
+struct PeerLimit {
+    max: usize,
+    // permits still to take away once peers give them back
+    debt: usize,
+}
+
+// What a started torrent is up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Downloading,
+    // complete, only uploading
+    Seeding,
+}
+
+// When to stop seeding a complete torrent, whichever comes first. The ratio
+// is of uploaded to downloaded bytes, counting at least the torrent's size as
+// downloaded so seeding from the start works too.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeedLimit {
+    pub ratio: Option<f32>,
+    pub time: Option<Duration>,
+}
+
+impl SeedLimit {
+    fn reached(&self, progress: &Progress, length: usize, seeding_for: Duration) -> bool {
+        let ratio = progress.uploaded as f32 / progress.downloaded.max(length).max(1) as f32;
+        self.ratio.is_some_and(|limit| ratio >= limit)
+            || self.time.is_some_and(|limit| seeding_for >= limit)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Seeding {
+    limit: SeedLimit,
+    // poked on every upload so the ratio gets checked
+    uploaded: Notify,
+    stopped: AtomicBool,
+}
+
+// Download rate as an exponential moving average, weighted by time so it
+// doesn't matter how often blocks come in. Time without any counts as a
+// rate of zero.
+#[derive(Debug, Default)]
+pub struct DownloadRate {
+    // bytes per second, starting from zero so the first couple of blocks
+    // only count for as long as they took
+    average: f64,
+    last: Option<Instant>,
+}
+
+impl DownloadRate {
+    pub fn record(&mut self, bytes: usize, at: Instant) {
+        if let Some(last) = self.last {
+            let elapsed = at.duration_since(last).as_secs_f64();
+            let rate = bytes as f64 / elapsed.max(f64::EPSILON);
+            self.average += weight(elapsed) * (rate - self.average);
+        }
+        self.last = Some(at);
+    }
+
+    pub fn rate(&self, at: Instant) -> f64 {
+        let Some(last) = self.last else {
+            return 0.0;
+        };
+        self.average * (1.0 - weight(at.duration_since(last).as_secs_f64()))
+    }
+}
+
+// How much a rate measured over `elapsed` seconds moves the average.
+fn weight(elapsed: f64) -> f64 {
+    1.0 - (-elapsed / RATE_SMOOTHING.as_secs_f64()).exp()
+}
+
+// A block to ask a peer for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub piece: usize,
+    // within the piece
+    pub begin: usize,
+    pub length: usize,
+}
+
+// What deciding on requests needs to know about a connected peer.
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub peer_id: PeerId,
+    // the peer unchoked us, so it answers requests
+    pub unchoked: bool,
+    pub pieces: BitVec,
+    pub requests_in_flight: usize,
+}
+
+// A block request the peer hasn't answered yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InflightBlock {
+    pub piece: usize,
+    // within the piece
+    pub block_offset: usize,
+    pub peer: SocketAddrV4,
+    pub elapsed: Duration,
+}
+
+// How downloading a piece went, for telling whether a slow download is held
+// up by peers, the disk or scheduling. Times are since the (latest) attempt
+// at the piece started.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PieceMetric {
+    pub piece: usize,
+    pub first_block: Option<Duration>,
+    // until it passed the hash check, `None` if it didn't (yet)
+    pub complete: Option<Duration>,
+    // peers that sent any of its blocks
+    pub peers: usize,
+    // attempts after the first
+    pub retries: usize,
 }
 
 impl Torrent {
@@ -46,80 +233,2046 @@ impl Torrent {
             metadata,
             peer_addrs: Arc::new(Mutex::new(PeerAddrs(Vec::new()))),
             peers: Arc::new(Mutex::new(Vec::new())),
-            max_peers: Arc::new(Semaphore::new(5)),
+            max_peers: Arc::new(Semaphore::new(MAX_PEERS)),
             notify: Arc::new(Notify::new()),
+            network_changed: Arc::new(Notify::new()),
+            paused: watch::Sender::new(false),
+            announce_queue: AnnounceQueue::default(),
+            announce_mode: AnnounceMode::default(),
+            peer_id: client_peer_id(),
+            downloads: Arc::default(),
+            seeding: Arc::default(),
+            mode: OnceLock::new(),
+            choker: std::sync::Mutex::default(),
+            verify_writes: false,
+            peer_limit: Arc::new(std::sync::Mutex::new(PeerLimit {
+                max: MAX_PEERS,
+                debt: 0,
+            })),
+            verifier: Arc::default(),
+            piece_metrics: Arc::default(),
+            lent: std::sync::Mutex::default(),
+            banned: std::sync::Mutex::default(),
+            ip_filter: Arc::default(),
+            download_rate: Arc::default(),
+        }
+    }
+
+    pub fn with_announce_queue(mut self, announce_queue: AnnounceQueue) -> Self {
+        self.announce_queue = announce_queue;
+        self
+    }
+
+    pub fn with_peer_id(mut self, peer_id: PeerId) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn with_announce_mode(mut self, mode: AnnounceMode) -> Self {
+        self.announce_mode = mode;
+        self
+    }
+
+    pub fn with_seed_limit(mut self, limit: SeedLimit) -> Self {
+        self.seeding = Arc::new(Seeding {
+            limit,
+            ..Default::default()
+        });
+        self
+    }
+
+    pub fn with_choker(mut self, choker: Choker) -> Self {
+        self.choker = std::sync::Mutex::new(choker);
+        self
+    }
+
+    pub fn with_verifier(mut self, verifier: Arc<PieceVerifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    pub fn with_ip_filter(mut self, ip_filter: Arc<IpFilter>) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    // Changes how many peers may be connected at once. Growing takes effect
+    // right away. Shrinking takes back free permits, and the rest as peers
+    // holding them finish, so nobody is dropped mid-piece.
+    pub fn set_max_peers(&self, max: usize) {
+        let mut limit = self.peer_limit.lock().expect("mutex was poisoned");
+        if max >= limit.max {
+            let grow = max - limit.max;
+            // what's still owed is simply not taken back
+            let forgiven = grow.min(limit.debt);
+            limit.debt -= forgiven;
+            self.max_peers.add_permits(grow - forgiven);
+        } else {
+            let shrink = limit.max - max;
+            let owed = shrink - self.max_peers.forget_permits(shrink);
+            if owed > 0 && limit.debt == 0 {
+                tokio::spawn(collect_peer_debt(
+                    self.max_peers.clone(),
+                    self.peer_limit.clone(),
+                ));
+            }
+            limit.debt += owed;
         }
+        limit.max = max;
+    }
+
+    pub fn max_peers(&self) -> usize {
+        self.peer_limit.lock().expect("mutex was poisoned").max
+    }
+
+    // For unreliable storage: every piece written is read back and hashed
+    // again before it counts as done.
+    pub fn with_verify_after_write(mut self, verify: bool) -> Self {
+        self.verify_writes = verify;
+        self
     }
 
-    pub async fn run(&mut self) {
-        tokio::spawn(heartbeat(
-            self.metadata.clone(),
-            self.peer_addrs.clone(),
-            self.notify.clone(),
-        ));
-        let info_hash = self.info_hash.clone();
+    // Writes the verified `piece` to `storage` and marks it as done in the
+    // resume bitmap. With `with_verify_after_write` a piece that reads back
+    // wrong is written again, up to `WRITE_ATTEMPTS` times.
+    pub async fn store_piece(
+        &self,
+        storage: &dyn Storage,
+        index: usize,
+        piece: &[u8],
+    ) -> anyhow::Result<()> {
+        let info = self
+            .metadata
+            .lock()
+            .await
+            .dot_torrent
+            .piece(index)
+            .with_context(|| format!("torrent has no piece {index}"))?;
+        anyhow::ensure!(
+            piece.len() == info.length,
+            "piece {index} has the wrong length"
+        );
+        let mut attempt = 1;
         loop {
-            self.notify.notified().await;
-            let peer_addrs = self.peer_addrs.lock().await;
-            let mut stream = stream::iter(peer_addrs.0.iter())
-                .map(|peer_addr| async move {
-                    let peer = Peer::new(*peer_addr, info_hash).await;
-                    (peer_addr, peer)
-                })
-                .buffer_unordered(self.max_peers.available_permits());
-            while let Some((peer_addr, peer)) = stream.next().await {
-                match peer {
-                    Ok(peer) => {
-                        let mut peers = self.peers.lock().await;
-                        peers.push(peer);
+            storage
+                .write(info.offset, piece)
+                .await
+                .with_context(|| format!("write piece {index}"))?;
+            if !self.verify_writes {
+                break;
+            }
+            let written = storage
+                .read(info.offset, info.length)
+                .await
+                .with_context(|| format!("read back piece {index}"))?;
+            if <[u8; 20]>::from(Sha1::digest(&written)) == info.hash {
+                break;
+            }
+            anyhow::ensure!(
+                attempt < WRITE_ATTEMPTS,
+                "piece {index} keeps reading back corrupt from disk"
+            );
+            println!("piece {index} read back corrupt, writing it again");
+            attempt += 1;
+        }
+        let have = {
+            let mut metadata = self.metadata.lock().await;
+            if !metadata.pieces.has(index) {
+                metadata.pieces.set(index)?;
+                metadata.left = metadata.left.saturating_sub(info.length);
+            }
+            metadata.pieces.clone()
+        };
+        // peers with nothing left for us shouldn't keep an unchoke slot for us
+        for peer in self.peers.lock().await.iter_mut() {
+            if let Err(err) = peer.update_interest(&have).await {
+                println!("failed to update interest in peer {}: {err}", peer.addr());
+            }
+        }
+        Ok(())
+    }
+
+    // Runs a choke round: unchokes the peers the choker picks and chokes the
+    // rest. Once the torrent is complete the choker's seed strategy is used.
+    // Peers are judged by their rates since the previous round, `run` does
+    // one every `RECHOKE_INTERVAL`.
+    pub async fn rechoke(&self) -> Vec<PeerId> {
+        let complete = self.metadata.lock().await.pieces.is_full();
+        let mut peers = self.peers.lock().await;
+        let candidates: Vec<_> = peers
+            .iter_mut()
+            .map(|peer| {
+                let (download_rate, upload_rate) = peer.recent_rates();
+                ChokeCandidate {
+                    peer_id: peer.peer_id(),
+                    interested: peer.peer_interested(),
+                    download_rate,
+                    upload_rate,
+                }
+            })
+            .collect();
+        let unchoked = self
+            .choker
+            .lock()
+            .expect("mutex was poisoned")
+            .unchoke(&candidates, complete);
+        for peer in peers.iter_mut() {
+            let unchoke = unchoked.contains(&peer.peer_id());
+            // only changes are sent
+            if unchoke != peer.am_choking() {
+                continue;
+            }
+            let typ = if unchoke {
+                MessageType::Unchoke
+            } else {
+                MessageType::Choke
+            };
+            let msg = Message {
+                typ,
+                payload: Vec::new(),
+            };
+            if let Err(err) = peer.send(msg).await {
+                println!("failed to {typ:?} peer {}: {err}", peer.addr());
+            }
+        }
+        unchoked
+    }
+
+    // Counts `n` bytes sent to peers.
+    pub async fn record_upload(&self, n: usize) {
+        self.metadata.lock().await.uploaded += n;
+        self.seeding.uploaded.notify_one();
+    }
+
+    // Whether the seed limit was reached and trackers were told we stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.seeding.stopped.load(Ordering::SeqCst)
+    }
+
+    // Uploads whatever `peer` asks for from the files under the torrent's
+    // path, counting it towards the seed limit. Ends when the peer hangs up,
+    // or with the first request after the limit was reached.
+    pub async fn seed(&self, peer: &mut Peer) -> anyhow::Result<()> {
+        let (dot_torrent, have, storage) = {
+            let metadata = self.metadata.lock().await;
+            let storage =
+                FileStorage::new(&metadata.dot_torrent, &metadata.path, LayoutMode::Nested)?;
+            (metadata.dot_torrent.clone(), metadata.pieces.clone(), storage)
+        };
+        let piece_length = |index| dot_torrent.piece(index).map_or(0, |piece| piece.length);
+        peer.serve(&have, piece_length, async |request: BlockRequest| {
+            anyhow::ensure!(!self.is_stopped(), "seed limit reached");
+            let offset = request.piece * dot_torrent.info.piece_length + request.begin;
+            let block = storage.read(offset, request.length).await?;
+            self.record_upload(block.len()).await;
+            Ok(block)
+        })
+        .await
+    }
+
+    pub async fn file_progress(&self) -> Vec<(File, f64)> {
+        let metadata = self.metadata.lock().await;
+        metadata.dot_torrent.file_progress(&metadata.pieces)
+    }
+
+    pub async fn stats(&self) -> TorrentStats {
+        let metadata = self.metadata.lock().await;
+        TorrentStats {
+            eta: self.eta_for(metadata.left),
+            pieces_total: metadata.dot_torrent.info.pieces.0.len(),
+            pieces_complete: metadata.pieces.ones().count(),
+            uploaded: metadata.uploaded,
+            downloaded: metadata.downloaded,
+            left: metadata.left,
+            file_progress: metadata.dot_torrent.file_progress(&metadata.pieces),
+        }
+    }
+
+    // How long the rest of the torrent should take at the current download
+    // rate, `None` if it's complete or nothing is coming in.
+    pub async fn eta(&self) -> Option<Duration> {
+        let left = self.metadata.lock().await.left;
+        self.eta_for(left)
+    }
+
+    fn eta_for(&self, left: usize) -> Option<Duration> {
+        let rate = self
+            .download_rate
+            .lock()
+            .expect("mutex was poisoned")
+            .rate(Instant::now());
+        if left == 0 || rate < 1.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(left as f64 / rate))
+    }
+
+    pub async fn peer_stats(&self) -> Vec<PeerStats> {
+        self.peers.lock().await.iter().map(Peer::stats).collect()
+    }
+
+    // The block requests to send this tick, see `plan_requests`.
+    pub async fn request_targets(&self, pipeline_depth: usize) -> Vec<(PeerId, Vec<BlockRequest>)> {
+        let metadata = self.metadata.lock().await;
+        let peers: Vec<_> = self
+            .peers
+            .lock()
+            .await
+            .iter()
+            .map(|peer| PeerState {
+                peer_id: peer.peer_id(),
+                unchoked: !peer.peer_choking(),
+                pieces: peer.pieces().clone(),
+                requests_in_flight: peer.stats().requests_in_flight,
+            })
+            .collect();
+        let pending = {
+            let downloads = self.downloads.lock().expect("mutex was poisoned");
+            let mut pending = HashSet::new();
+            for download in downloads.active() {
+                let index = download.index();
+                pending.extend(download.blocks().ones().map(|block_i| (index, block_i)));
+                pending.extend(
+                    download
+                        .inflight()
+                        .into_iter()
+                        .map(|(block_i, ..)| (index, block_i)),
+                );
+            }
+            pending
+        };
+        plan_requests(
+            &metadata.dot_torrent,
+            &metadata.pieces,
+            &peers,
+            &pending,
+            pipeline_depth,
+        )
+    }
+
+    // Every outstanding block request, for diagnosing stalls. Read under the
+    // schedulers' own locks, so it's consistent with what they hand out.
+    pub fn inflight(&self) -> Vec<InflightBlock> {
+        let downloads = self.downloads.lock().expect("mutex was poisoned");
+        let now = Instant::now();
+        let mut inflight: Vec<_> = downloads
+            .active()
+            .flat_map(|download| {
+                download
+                    .inflight()
+                    .into_iter()
+                    .map(move |(block_i, peer, since)| InflightBlock {
+                        piece: download.index(),
+                        block_offset: block_i * BLOCK_SIZE,
+                        peer,
+                        elapsed: now.duration_since(since),
+                    })
+            })
+            .collect();
+        inflight.sort_by_key(|block| (block.piece, block.block_offset));
+        inflight
+    }
+
+    // Forcibly requeues the block at `block_offset` of `piece`. Returns
+    // whether it was in flight.
+    pub fn cancel_inflight(&self, piece: usize, block_offset: usize) -> bool {
+        let downloads = self.downloads.lock().expect("mutex was poisoned");
+        downloads
+            .get(piece)
+            .is_some_and(|download| download.cancel(block_offset / BLOCK_SIZE))
+    }
+
+    pub async fn connected_peers(&self) -> Vec<PeerId> {
+        let peers = self.peers.lock().await;
+        self.connected(&peers).map(|(peer_id, _)| peer_id).collect()
+    }
+
+    // Every connected peer, lent ones included. Only consistent while the
+    // `peers` passed in are locked, as lending happens under that lock.
+    fn connected(&self, peers: &[Peer]) -> impl Iterator<Item = (PeerId, SocketAddrV4)> {
+        let lent = self.lent.lock().expect("mutex was poisoned").clone();
+        peers
+            .iter()
+            .map(|peer| (peer.peer_id(), peer.addr()))
+            .chain(lent)
+    }
+
+    // Hangs up on a peer, banning its address for the rest of the session
+    // if `ban`. Pieces being downloaded go on without it right away, its
+    // blocks requeued for the other peers, and so do downloads run with
+    // `download_options`. A peer lent to a piece download is only closed
+    // once the download is done with it. Returns whether the peer was
+    // connected.
+    pub async fn disconnect(&self, peer_id: PeerId, ban: bool) -> bool {
+        let mut peers = self.peers.lock().await;
+        let addr = match peers.iter().position(|peer| peer.peer_id() == peer_id) {
+            Some(i) => peers.swap_remove(i).addr(),
+            None => {
+                let mut lent = self.lent.lock().expect("mutex was poisoned");
+                let Some(i) = lent.iter().position(|&(id, _)| id == peer_id) else {
+                    return false;
+                };
+                lent.swap_remove(i).1
+            }
+        };
+        drop(peers);
+        if ban {
+            self.banned.lock().expect("mutex was poisoned").insert(addr);
+        }
+        self.downloads
+            .lock()
+            .expect("mutex was poisoned")
+            .exclude(addr);
+        println!("disconnected from peer {addr}");
+        true
+    }
+
+    // For downloading the torrent's pieces with `download::from_peers` and
+    // the like: the same peer id and filters, and the pieces are shared, so
+    // `inflight`, `piece_metrics` and `eta` cover them and `disconnect`ed
+    // peers sit them out.
+    pub fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            peer_id: self.peer_id,
+            ip_filter: self.ip_filter.clone(),
+            verifier: self.verifier.clone(),
+            downloads: self.downloads.clone(),
+            piece_metrics: self.piece_metrics.clone(),
+            download_rate: self.download_rate.clone(),
+            ..Default::default()
+        }
+    }
+
+    // Timings of every piece downloaded so far, by index, see `download_options`.
+    pub fn piece_metrics(&self) -> Vec<PieceMetric> {
+        let metrics = self.piece_metrics.lock().expect("mutex was poisoned");
+        metrics.values().cloned().collect()
+    }
+
+    // Downloads and verifies piece `index` from the connected peers that
+    // have it. They're lent to the download until it's done, see `lend_peers`.
+    pub async fn download_piece(&self, index: usize) -> anyhow::Result<Vec<u8>> {
+        let started = Instant::now();
+        let info = self
+            .metadata
+            .lock()
+            .await
+            .dot_torrent
+            .piece(index)
+            .with_context(|| format!("torrent has no piece {index}"))?;
+        let cache = BlockCache::new(info.length);
+        let mut assembled = None;
+        let mut download = PieceDownload::new(index, info.length);
+        if let Some((have, blocks)) = self.take_partial(index).await {
+            for (begin, block) in blocks {
+                assembled = cache.put_block(self.info_hash, index, begin, block, info.length);
+            }
+            download = download.with_blocks(&have);
+        }
+        let download = self
+            .downloads
+            .lock()
+            .expect("mutex was poisoned")
+            .insert(download);
+        let mut lent = self.lend_peers(|peer| peer.has_piece(index)).await;
+        let options = self.download_options();
+        let mut first_block = None;
+        if assembled.is_none() {
+            let mut paused = self.paused.subscribe();
+            let blocks = download_blocks(
+                &download,
+                lent.iter_mut().collect(),
+                Vec::new(),
+                None,
+                &options,
+                |begin, block| {
+                    first_block.get_or_insert_with(|| started.elapsed());
+                    cache.put_block(self.info_hash, index, begin, block, info.length)
+                },
+            );
+            assembled = tokio::select! {
+                assembled = blocks => assembled,
+                // pausing the torrent interrupts the piece
+                _ = paused.wait_for(|paused| *paused) => None,
+            };
+        }
+        cancel_superseded(&mut lent, index, info.length, download.scheduler()).await;
+        self.return_peers(lent).await;
+        self.downloads
+            .lock()
+            .expect("mutex was poisoned")
+            .finish(index);
+        if assembled.is_none() {
+            let blocks = cache.take_blocks(self.info_hash, index);
+            if let Err(err) = self.keep_partial(index, blocks).await {
+                println!("failed to keep the blocks of piece {index}: {err}");
+            }
+        }
+        let complete = assembled.is_some();
+        let verified = match assembled {
+            Some(piece) => self.verifier.verified(piece.to_vec(), info.hash).await,
+            None => None,
+        };
+        {
+            let mut metrics = options.piece_metrics.lock().expect("mutex was poisoned");
+            // only attempts that didn't make it are retried
+            let retries = metrics
+                .get(&index)
+                .filter(|metric| metric.complete.is_none())
+                .map_or(0, |metric| metric.retries + 1);
+            let metric = PieceMetric {
+                piece: index,
+                first_block,
+                complete: verified.as_ref().map(|_| started.elapsed()),
+                peers: download.contributors(),
+                retries,
+            };
+            metrics.insert(index, metric);
+        }
+        anyhow::ensure!(complete, "no peers left to get piece {index}");
+        verified.with_context(|| format!("piece {index} failed the hash check"))
+    }
+
+    // Writes the blocks of an unfinished piece to disk and notes them in
+    // `Metadata::partial`, so the next attempt only asks for the rest.
+    async fn keep_partial(&self, index: usize, blocks: Vec<(usize, Bytes)>) -> anyhow::Result<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let mut metadata = self.metadata.lock().await;
+        let info = metadata
+            .dot_torrent
+            .piece(index)
+            .with_context(|| format!("torrent has no piece {index}"))?;
+        let storage = FileStorage::new(&metadata.dot_torrent, &metadata.path, LayoutMode::Nested)?;
+        let mut have = BitVec::new(info.length.div_ceil(BLOCK_SIZE));
+        for (begin, block) in blocks {
+            storage
+                .write(info.offset + begin, &block)
+                .await
+                .with_context(|| format!("write block at {begin} of piece {index}"))?;
+            have.set(begin / BLOCK_SIZE)?;
+        }
+        metadata.partial.retain(|partial| partial.index != index);
+        metadata.partial.push(PartialPiece {
+            index,
+            blocks: have.as_bytes().to_vec(),
+        });
+        Ok(())
+    }
+
+    // Takes the blocks `keep_partial` left on disk for the piece `index`,
+    // read back along with which ones they are.
+    async fn take_partial(&self, index: usize) -> Option<(BitVec, Vec<(usize, Bytes)>)> {
+        let mut metadata = self.metadata.lock().await;
+        let i = metadata
+            .partial
+            .iter()
+            .position(|partial| partial.index == index)?;
+        let partial = metadata.partial.swap_remove(i);
+        let length = metadata.dot_torrent.piece(index)?.length;
+        let read = async {
+            let have = BitVec::from_bytes(partial.blocks, length.div_ceil(BLOCK_SIZE))?;
+            let blocks = read_blocks(
+                &metadata.dot_torrent,
+                &metadata.path,
+                LayoutMode::Nested,
+                index,
+                &have,
+            )
+            .await?;
+            anyhow::Ok((have, blocks))
+        };
+        match read.await {
+            Ok(read) => Some(read),
+            Err(err) => {
+                println!("downloading piece {index} from scratch: {err}");
+                None
+            }
+        }
+    }
+
+    // Takes the peers `lend` picks out of `peers` for a download, so the
+    // rest of the torrent (rechoking, accepting peers, ...) isn't held up
+    // until it's done. They're still listed as connected meanwhile.
+    async fn lend_peers(&self, lend: impl Fn(&Peer) -> bool) -> Vec<Peer> {
+        let mut peers = self.peers.lock().await;
+        let taken: Vec<_> = peers.extract_if(.., |peer| lend(peer)).collect();
+        let mut lent = self.lent.lock().expect("mutex was poisoned");
+        lent.extend(taken.iter().map(|peer| (peer.peer_id(), peer.addr())));
+        taken
+    }
+
+    // Puts lent peers back, except the ones disconnected meanwhile.
+    async fn return_peers(&self, taken: Vec<Peer>) {
+        let mut peers = self.peers.lock().await;
+        let mut lent = self.lent.lock().expect("mutex was poisoned");
+        for peer in taken {
+            let Some(i) = lent.iter().position(|&(id, _)| id == peer.peer_id()) else {
+                continue;
+            };
+            lent.swap_remove(i);
+            peers.push(peer);
+        }
+    }
+
+    // Bencoded snapshot of the progress, to carry the download over to
+    // another install with `import_resume`.
+    pub async fn export_resume(&self) -> anyhow::Result<Vec<u8>> {
+        let metadata = self.metadata.lock().await;
+        ResumeData {
+            info_hash: self.info_hash.to_vec(),
+            pieces: metadata.pieces.as_bytes().to_vec(),
+            uploaded: metadata.uploaded,
+            downloaded: metadata.downloaded,
+            left: metadata.left,
+            path: metadata.path.clone(),
+            partial: metadata.partial.clone(),
+        }
+        .to_bytes()
+    }
+
+    pub async fn import_resume(&self, resume: ResumeData) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            resume.info_hash == self.info_hash,
+            "resume data is for torrent {}, not {}",
+            hex::encode(&resume.info_hash),
+            hex::encode(self.info_hash)
+        );
+        let mut metadata = self.metadata.lock().await;
+        let n_pieces = metadata.dot_torrent.info.pieces.0.len();
+        metadata.pieces = BitVec::from_bytes(resume.pieces, n_pieces)?;
+        metadata.uploaded = resume.uploaded;
+        metadata.downloaded = resume.downloaded;
+        metadata.left = resume.left;
+        metadata.path = resume.path;
+        metadata.partial = resume.partial;
+        Ok(())
+    }
+
+    // Connects to the given peers, skipping the ones we already have a
+    // connection to. The same peer can come up under several addresses, so
+    // it's checked by peer id as well once we know it, keeping the first connection.
+    async fn connect(&self, peer_addrs: &[SocketAddrV4]) {
+        let info_hash = self.info_hash;
+        let our_id = self.peer_id;
+        let n_pieces = self.metadata.lock().await.dot_torrent.info.pieces.0.len();
+        let connected: HashSet<_> = {
+            let peers = self.peers.lock().await;
+            self.connected(&peers).map(|(_, addr)| addr).collect()
+        };
+        let banned = self.banned.lock().expect("mutex was poisoned").clone();
+        let mut dialing = HashSet::new();
+        let peer_addrs: Vec<SocketAddrV4> = peer_addrs
+            .iter()
+            .copied()
+            .filter(|addr| {
+                !connected.contains(addr) && !banned.contains(addr) && dialing.insert(*addr)
+            })
+            .filter(|addr| {
+                let allowed = self.ip_filter.allows(*addr.ip());
+                if !allowed {
+                    println!("not connecting to filtered peer {addr}");
+                }
+                allowed
+            })
+            .collect();
+        let mut stream = stream::iter(peer_addrs)
+            .map(|peer_addr| async move {
+                let peer = Peer::connect(peer_addr, info_hash, our_id, ProtocolCheck::Strict).await;
+                (peer_addr, peer)
+            })
+            .buffer_unordered(self.max_peers.available_permits());
+        while let Some((peer_addr, peer)) = stream.next().await {
+            match peer {
+                Ok(mut peer) => {
+                    peer.set_piece_count(n_pieces);
+                    let mut peers = self.peers.lock().await;
+                    if self.connected(&peers).any(|(id, _)| id == peer.peer_id()) {
+                        println!("already connected to peer {peer_addr}");
+                        continue;
                     }
-                    Err(err) => println!("failed to connect to peer {peer_addr}: {err}"),
+                    self.readmit(peer_addr);
+                    peers.push(peer);
                 }
+                Err(err) => println!("failed to connect to peer {peer_addr}: {err}"),
             }
-            drop(stream);
+        }
+    }
 
-            let mut available_pieces = BinaryHeap::new();
-            let mut unavailable_pieces = Vec::new();
+    // Takes over a connection a peer opened, unless its address is filtered
+    // or banned. A seeding torrent serves the peer until it's done with us.
+    pub async fn accept(&self, stream: TcpStream) -> anyhow::Result<()> {
+        let std::net::SocketAddr::V4(addr) = stream.peer_addr()? else {
+            anyhow::bail!("IPv6 peers aren't supported");
+        };
+        anyhow::ensure!(self.ip_filter.allows(*addr.ip()), "peer {addr} is filtered");
+        anyhow::ensure!(
+            !self
+                .banned
+                .lock()
+                .expect("mutex was poisoned")
+                .contains(&addr),
+            "peer {addr} is banned"
+        );
+        let n_pieces = self.metadata.lock().await.dot_torrent.info.pieces.0.len();
+        let mut peer =
+            Peer::accept(stream, self.info_hash, self.peer_id, ProtocolCheck::Strict).await?;
+        peer.set_piece_count(n_pieces);
+        if self.mode() == Some(Mode::Seeding) {
+            // there's nothing to get from it, it's only here to download
+            return self.seed(&mut peer).await;
+        }
+        let mut peers = self.peers.lock().await;
+        anyhow::ensure!(
+            self.connected(&peers).all(|(id, _)| id != peer.peer_id()),
+            "already connected to peer {addr}"
+        );
+        self.readmit(addr);
+        peers.push(peer);
+        Ok(())
+    }
+
+    // A peer that was disconnected but not banned may take part in
+    // downloads again once it's reconnected.
+    fn readmit(&self, addr: SocketAddrV4) {
+        self.downloads
+            .lock()
+            .expect("mutex was poisoned")
+            .readmit(addr);
+    }
+
+    // Should be called when the network changes (sleep/resume, Wi-Fi switch).
+    // Rebuilds the tracker client and re-announces immediately instead of
+    // waiting out the interval against a possibly stale connection.
+    pub fn network_changed(&self) {
+        self.network_changed.notify_one();
+    }
+
+    // Stops downloading but keeps seeding what we have. Trackers are told
+    // right away so they can stop handing us out to other partial seeds.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    // Gets a torrent loaded from the state going. A finished one only seeds,
+    // once its data turns out to be intact; if it isn't, or the torrent never
+    // finished, the download picks up from the verified pieces.
+    pub async fn start(self: &Arc<Self>) -> anyhow::Result<Mode> {
+        let mut metadata = self.metadata.lock().await;
+        let mut mode = Mode::Downloading;
+        if metadata.finished {
+            // torrents we manage keep the torrent's own layout
+            let verified =
+                recheck(&metadata.dot_torrent, &metadata.path, LayoutMode::Nested).await?;
+            if verified.is_full() {
+                mode = Mode::Seeding;
+            } else {
+                metadata.left = metadata
+                    .dot_torrent
+                    .pieces()
+                    .filter(|piece| !verified.has(piece.index))
+                    .map(|piece| piece.length)
+                    .sum();
+                metadata.pieces = verified;
+                metadata.finished = false;
+            }
+        }
+        drop(metadata);
+        anyhow::ensure!(self.mode.set(mode).is_ok(), "torrent was already started");
+        match mode {
+            Mode::Seeding => self.spawn_heartbeat(),
+            Mode::Downloading => {
+                let torrent = self.clone();
+                tokio::spawn(async move { torrent.run().await });
+            }
+        }
+        Ok(mode)
+    }
+
+    pub fn mode(&self) -> Option<Mode> {
+        self.mode.get().copied()
+    }
+
+    fn spawn_heartbeat(&self) {
+        tokio::spawn(self.heartbeat());
+    }
+
+    // sends regular requests to the tracker at an interval specified by it
+    fn heartbeat(&self) -> impl Future<Output = ()> + Send + 'static {
+        let metadata = self.metadata.clone();
+        let peer_addrs = self.peer_addrs.clone();
+        let notify = self.notify.clone();
+        let network_changed = self.network_changed.clone();
+        let mut paused = self.paused.subscribe();
+        let announce_queue = self.announce_queue.clone();
+        let seeding = self.seeding.clone();
+        let mode = self.announce_mode;
+        let peer_id = self.peer_id;
+        async move {
+        let client = TrackerClient::new().with_peer_id(peer_id);
+        let mut tiers =
+            TrackerTiers::new(&metadata.lock().await.dot_torrent).with_mode(mode);
+        if tiers.tiers().is_empty() {
+            // trackerless, peers have to come from elsewhere
+            return;
+        }
+        let mut interval = 0;
+        let mut state = AnnounceState::default();
+        let mut known_peers = HashSet::new();
+        let mut fruitless = 0;
+        let mut seeding_since = None;
+        loop {
+            tokio::select! {
+                _ = sleep(Duration::from_secs(interval)) => {}
+                _ = network_changed.notified() => client.reset(),
+                // pausing and resuming are announced straight away
+                Ok(()) = paused.changed() => {}
+                _ = seed_limit(&metadata, &seeding, &mut seeding_since) => {
+                    let _permit = announce_queue.acquire().await;
+                    let metadata = metadata.lock().await;
+                    let progress = Progress {
+                        uploaded: metadata.uploaded,
+                        downloaded: metadata.downloaded,
+                        left: metadata.left,
+                    };
+                    let event = Some(Event::Stopped);
+                    let _ = tiers
+                        .announce(&client, &metadata.dot_torrent, event, progress)
+                        .await;
+                    seeding.stopped.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+            let mut backoff = 1;
+            loop {
+                let permit = announce_queue.acquire().await;
+                let metadata = metadata.lock().await;
+                let progress = Progress {
+                    uploaded: metadata.uploaded,
+                    downloaded: metadata.downloaded,
+                    left: metadata.left,
+                };
+                let event = state.event(&progress, *paused.borrow_and_update());
+                let resp = tiers
+                    .announce(&client, &metadata.dot_torrent, event, progress)
+                    .await;
+                drop(metadata);
+                drop(permit);
+                if let Ok(resp) = resp {
+                    state.announced(event, &progress);
+                    interval = resp.interval;
+                    let mut new_peers = 0;
+                    for addr in &resp.peers.0 {
+                        if known_peers.insert(*addr) {
+                            new_peers += 1;
+                        }
+                    }
+                    fruitless = if new_peers == 0 { fruitless + 1 } else { 0 };
+                    if fruitless >= FRUITLESS_ANNOUNCES {
+                        // the swarm behind this tracker has nothing more for us
+                        tiers.skip_current();
+                        fruitless = 0;
+                    }
+                    let mut peer_addrs = peer_addrs.lock().await;
+                    *peer_addrs = resp.peers;
+                    notify.notify_one();
+                    break;
+                }
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(backoff)) => {}
+                    // no point in backing off against a network we've just left
+                    _ = network_changed.notified() => client.reset(),
+                }
+                backoff *= 2;
+            }
+        }
+        }
+    }
+
+    pub async fn run(&self) {
+        self.spawn_heartbeat();
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = rechoke.tick() => {
+                    self.rechoke().await;
+                    continue;
+                }
+                _ = self.notify.notified() => {}
+            }
+            let peer_addrs = self.peer_addrs.lock().await.0.clone();
+            self.connect(&peer_addrs).await;
+
+            // choke rounds go on while pieces come in
+            let mut download = pin!(self.download_available());
+            loop {
+                tokio::select! {
+                    _ = &mut download => break,
+                    _ = rechoke.tick() => {
+                        self.rechoke().await;
+                    }
+                }
+            }
+        }
+    }
+
+    // Downloads the pieces we miss that connected peers have, in the usual
+    // `Piece` order, and stores them under the torrent's path (see
+    // `store_piece`). Each is tried once, and nothing new is started while
+    // paused.
+    async fn download_available(&self) {
+        let (mut available_pieces, storage) = {
             let metadata = self.metadata.lock().await;
             let peers = self.peers.lock().await;
+            let mut available_pieces = BinaryHeap::new();
             for piece_i in metadata.pieces.zeros() {
                 let piece = Piece::new(piece_i, &metadata.dot_torrent, peers.as_slice());
-                if piece.peers().is_empty() {
-                    unavailable_pieces.push(piece);
-                } else {
-                    // TODO: handle unavailable pieces
+                // TODO: handle unavailable pieces
+                if !piece.peers().is_empty() {
                     available_pieces.push(piece);
                 }
             }
+            let storage =
+                FileStorage::new(&metadata.dot_torrent, &metadata.path, LayoutMode::Nested);
+            (available_pieces, storage)
+        };
+        let storage = match storage {
+            Ok(storage) => storage,
+            Err(err) => {
+                println!("can't store pieces: {err}");
+                return;
+            }
+        };
+        while let Some(piece) = available_pieces.pop() {
+            if self.is_paused() {
+                break;
+            }
+            let index = piece.index();
+            let stored = match self.download_piece(index).await {
+                Ok(data) => self.store_piece(&storage, index, &data).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = stored {
+                println!("failed to get piece {index}: {err}");
+            }
         }
     }
 }
 
+// Snapshot of a torrent's progress for display.
+#[derive(Debug, Clone)]
+pub struct TorrentStats {
+    pub pieces_total: usize,
+    pub pieces_complete: usize,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    pub file_progress: Vec<(File, f64)>,
+    // see `Torrent::eta`
+    pub eta: Option<Duration>,
+}
+
 pub type SharedPeerAddrs = Arc<Mutex<PeerAddrs>>;
 
 pub type SharedPeers = Arc<Mutex<Vec<Peer>>>;
 
 async fn connect_to_peers(addrs: SharedPeerAddrs) {}
 
-// sends regular requests to the tracker at an interval specified by it
-async fn heartbeat(metadata: SharedMetadata, peer_addrs: SharedPeerAddrs, notify: Arc<Notify>) {
-    let mut interval = 0;
+// Forgets permits of `max_peers` as they're released until the debt of
+// `Torrent::set_max_peers` is paid off or forgiven.
+async fn collect_peer_debt(max_peers: Arc<Semaphore>, limit: Arc<std::sync::Mutex<PeerLimit>>) {
     loop {
-        sleep(Duration::from_secs(interval)).await;
-        let mut backoff = 1;
-        loop {
-            let metadata = metadata.lock().await;
-            let resp = query_tracker(&metadata.dot_torrent).await;
-            drop(metadata);
-            if let Ok(resp) = resp {
-                interval = resp.interval;
-                let mut peer_addrs = peer_addrs.lock().await;
-                *peer_addrs = resp.peers;
-                notify.notify_one();
+        let Ok(permit) = max_peers.acquire().await else {
+            return;
+        };
+        let mut limit = limit.lock().expect("mutex was poisoned");
+        if limit.debt == 0 {
+            // grown back meanwhile, the permit goes back too
+            return;
+        }
+        permit.forget();
+        limit.debt -= 1;
+        if limit.debt == 0 {
+            return;
+        }
+    }
+}
+
+// announces in a row without new peers before moving on to another tracker
+const FRUITLESS_ANNOUNCES: usize = 3;
+
+
+// Decides which blocks to request from which peers, without touching the
+// network. Pieces we don't `have` are picked in the usual `Piece` order and
+// their blocks that aren't `pending` (received or requested already) go to
+// the peers that have them and unchoked us, each up to `pipeline_depth`
+// requests in flight. Peers left without requests are left out.
+pub fn plan_requests(
+    dot_torrent: &DotTorrent,
+    have: &BitVec,
+    peers: &[PeerState],
+    pending: &HashSet<(usize, usize)>,
+    pipeline_depth: usize,
+) -> Vec<(PeerId, Vec<BlockRequest>)> {
+    let mut slots: Vec<_> = peers
+        .iter()
+        .map(|peer| match peer.unchoked {
+            true => pipeline_depth.saturating_sub(peer.requests_in_flight),
+            false => 0,
+        })
+        .collect();
+    // how many of the pieces we miss each peer could send us
+    let useful: Vec<_> = peers
+        .iter()
+        .map(|peer| peer.pieces.ones().filter(|&i| !have.has(i)).count())
+        .collect();
+    let mut requests = vec![Vec::new(); peers.len()];
+    let mut pieces: BinaryHeap<_> = have
+        .zeros()
+        .take_while(|&index| index < dot_torrent.info.pieces.0.len())
+        .map(|index| {
+            let has = peers.iter().map(|peer| peer.pieces.has(index));
+            Piece::held_by(index, dot_torrent, has)
+        })
+        .collect();
+    while let Some(piece) = pieces.pop() {
+        if slots.iter().all(|&free| free == 0) {
+            break;
+        }
+        let n_blocks = piece.length().div_ceil(BLOCK_SIZE);
+        for block_i in 0..n_blocks {
+            if pending.contains(&(piece.index(), block_i)) {
+                continue;
+            }
+            // peers with little else to offer first, keeping the others'
+            // room for pieces only they have, then the ones with most room
+            let Some(&peer_i) = piece
+                .peers()
+                .iter()
+                .filter(|&&peer_i| slots[peer_i] > 0)
+                .min_by_key(|&&peer_i| (useful[peer_i], Reverse(slots[peer_i]), peer_i))
+            else {
                 break;
+            };
+            slots[peer_i] -= 1;
+            let begin = block_i * BLOCK_SIZE;
+            requests[peer_i].push(BlockRequest {
+                piece: piece.index(),
+                begin,
+                length: BLOCK_SIZE.min(piece.length() - begin),
+            });
+        }
+    }
+    peers
+        .iter()
+        .zip(requests)
+        .filter(|(_, requests)| !requests.is_empty())
+        .map(|(peer, requests)| (peer.peer_id, requests))
+        .collect()
+}
+
+// Resolves once the torrent is complete and has seeded past its limit.
+async fn seed_limit(
+    metadata: &SharedMetadata,
+    seeding: &Seeding,
+    seeding_since: &mut Option<Instant>,
+) {
+    let limit = seeding.limit;
+    if limit.ratio.is_none() && limit.time.is_none() {
+        return std::future::pending().await;
+    }
+    loop {
+        let metadata = metadata.lock().await;
+        if metadata.left == 0 {
+            let since = *seeding_since.get_or_insert_with(Instant::now);
+            let progress = Progress {
+                uploaded: metadata.uploaded,
+                downloaded: metadata.downloaded,
+                left: 0,
+            };
+            if limit.reached(&progress, metadata.dot_torrent.length(), since.elapsed()) {
+                return;
+            }
+        }
+        drop(metadata);
+        let time_left = seeding_since
+            .zip(limit.time)
+            .map(|(since, time)| time.saturating_sub(since.elapsed()));
+        tokio::select! {
+            _ = seeding.uploaded.notified() => {}
+            _ = sleep(time_left.unwrap_or_default()), if time_left.is_some() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dot_torrent::hashes::Hashes;
+    use crate::dot_torrent::{DotTorrent, Info, Key};
+    use crate::state::Metadata;
+    use crate::testing::{MockPeer, MockTracker, full_bitfield, tracker_response};
+    use std::pin::pin;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::{Instant, timeout};
+
+    fn metadata(announce: &str) -> SharedMetadata {
+        let dot_torrent = DotTorrent {
+            announce: Some(announce.to_string()),
+            ..DotTorrent::new(Info::new(
+                "sample.txt",
+                32768,
+                Hashes(vec![[0; 20]; 3]),
+                Key::SingleFile { length: 92063 },
+            ))
+        };
+        Arc::new(Mutex::new(Metadata {
+            id: 1,
+            path: "sample.txt".into(),
+            dot_torrent,
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 92063,
+            pieces: BitVec::new(3),
+            partial: Vec::new(),
+            finished: false,
+        }))
+    }
+
+    // Flips a byte of the first `corrupt_reads` reads.
+    struct FlakyStorage {
+        data: std::sync::Mutex<Vec<u8>>,
+        writes: AtomicUsize,
+        corrupt_reads: AtomicUsize,
+    }
+
+    impl Storage for FlakyStorage {
+        fn write<'a>(
+            &'a self,
+            offset: usize,
+            data: &'a [u8],
+        ) -> futures_util::future::BoxFuture<'a, std::io::Result<()>> {
+            self.writes.fetch_add(1, SeqCst);
+            self.data.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn read(
+            &self,
+            offset: usize,
+            length: usize,
+        ) -> futures_util::future::BoxFuture<'_, std::io::Result<Vec<u8>>> {
+            let mut data = self.data.lock().unwrap()[offset..offset + length].to_vec();
+            let corrupt = self
+                .corrupt_reads
+                .fetch_update(SeqCst, SeqCst, |n| n.checked_sub(1));
+            if corrupt.is_ok() {
+                data[length / 2] ^= 1;
             }
-            sleep(Duration::from_secs(backoff)).await;
-            backoff *= 2;
+            Box::pin(async { Ok(data) })
+        }
+    }
+
+    #[tokio::test]
+    async fn piece_reading_back_corrupt_is_written_again() {
+        let piece: Vec<u8> = (0..32768u32).map(|i| (i % 253) as u8).collect();
+        let torrent_with = |corrupt_reads| {
+            let metadata = metadata("http://127.0.0.1:8000/announce");
+            metadata.try_lock().unwrap().dot_torrent.info.pieces.0[0] = Sha1::digest(&piece).into();
+            let torrent = Torrent::new([0; 20], metadata).with_verify_after_write(true);
+            let storage = FlakyStorage {
+                data: std::sync::Mutex::new(vec![0; 92063]),
+                writes: AtomicUsize::new(0),
+                corrupt_reads: AtomicUsize::new(corrupt_reads),
+            };
+            (torrent, storage)
+        };
+
+        let (torrent, storage) = torrent_with(1);
+        torrent.store_piece(&storage, 0, &piece).await.unwrap();
+        assert_eq!(storage.writes.load(SeqCst), 2);
+        let metadata = torrent.metadata.lock().await;
+        assert!(metadata.pieces.has(0));
+        assert_eq!(metadata.left, 92063 - 32768);
+        drop(metadata);
+
+        // storage that never gets it right
+        let (torrent, storage) = torrent_with(usize::MAX);
+        assert!(torrent.store_piece(&storage, 0, &piece).await.is_err());
+        assert_eq!(storage.writes.load(SeqCst), WRITE_ATTEMPTS);
+        assert!(!torrent.metadata.lock().await.pieces.has(0));
+    }
+
+    #[tokio::test]
+    async fn max_peers_changes_at_runtime() {
+        let torrent = Torrent::new([0; 20], metadata("http://127.0.0.1:8000/announce"));
+        torrent.set_max_peers(8);
+        assert_eq!(torrent.max_peers(), 8);
+        let mut held: Vec<_> = (0..8)
+            .map(|_| torrent.max_peers.clone().try_acquire_owned().unwrap())
+            .collect();
+        assert!(torrent.max_peers.try_acquire().is_err());
+
+        let available = async |n| {
+            timeout(Duration::from_secs(1), async {
+                while torrent.max_peers.available_permits() != n {
+                    tokio::task::yield_now().await;
+                }
+            })
+            .await
+            .unwrap()
+        };
+
+        // the connected peers keep their permits until they're done
+        torrent.set_max_peers(3);
+        held.truncate(4);
+        available(0).await;
+        held.truncate(2);
+        available(1).await;
+        held.clear();
+        available(3).await;
+
+        // growing forgives what's still owed first
+        let held: Vec<_> = (0..3)
+            .map(|_| torrent.max_peers.clone().try_acquire_owned().unwrap())
+            .collect();
+        torrent.set_max_peers(1);
+        torrent.set_max_peers(2);
+        drop(held);
+        available(2).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
         }
+        assert_eq!(torrent.max_peers.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn stats_include_file_progress() {
+        let torrent = Torrent::new([0; 20], metadata("http://127.0.0.1:8000/announce"));
+        torrent.metadata.lock().await.pieces.set(1).unwrap();
+        let stats = torrent.stats().await;
+        assert_eq!(stats.pieces_total, 3);
+        assert_eq!(stats.pieces_complete, 1);
+        assert_eq!(stats.file_progress.len(), 1);
+        assert!((stats.file_progress[0].1 - 1.0 / 3.0).abs() < f64::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn resume_round_trips_into_a_fresh_torrent() {
+        let info_hash = [4; 20];
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.pieces.set(0).unwrap();
+            metadata.pieces.set(2).unwrap();
+            metadata.downloaded = 65536;
+            metadata.left = 26527;
+            metadata.path = "/data/sample.txt".into();
+        }
+        let exported = torrent.export_resume().await.unwrap();
+
+        let fresh = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        fresh
+            .import_resume(ResumeData::from_bytes(&exported).unwrap())
+            .await
+            .unwrap();
+        let stats = fresh.stats().await;
+        assert_eq!(stats.pieces_complete, 2);
+        assert_eq!((stats.downloaded, stats.left), (65536, 26527));
+        assert_eq!(fresh.export_resume().await.unwrap(), exported);
+
+        let other = Torrent::new([5; 20], metadata("http://127.0.0.1:8000/announce"));
+        let err = other
+            .import_resume(ResumeData::from_bytes(&exported).unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("resume data is for torrent"));
+    }
+
+    #[tokio::test]
+    async fn connects_to_each_peer_once() {
+        let info_hash = [6; 20];
+        let peer = MockPeer::start(info_hash, vec![0; 92063], 32768, full_bitfield(3)).await;
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        torrent.connect(&[peer.addr, peer.addr]).await;
+        assert_eq!(torrent.peers.lock().await.len(), 1);
+        // the tracker handing it out again doesn't open another connection
+        torrent.connect(&[peer.addr]).await;
+        assert_eq!(torrent.peers.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn peer_stats_cover_each_connected_peer() {
+        let info_hash = [6; 20];
+        let seed = MockPeer::start(info_hash, vec![0; 92063], 32768, full_bitfield(3)).await;
+        let partial = MockPeer::start(info_hash, vec![0; 92063], 32768, vec![0b0100_0000]).await;
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        assert!(torrent.peer_stats().await.is_empty());
+        torrent.connect(&[seed.addr, partial.addr]).await;
+        let mut stats = torrent.peer_stats().await;
+        stats.sort_by_key(|peer| peer.pieces_have);
+        let seen: Vec<_> = stats.iter().map(|peer| (peer.addr, peer.pieces_have)).collect();
+        assert_eq!(seen, [(partial.addr, 1), (seed.addr, 3)]);
+        assert!(stats.iter().all(|peer| peer.am_choking && peer.peer_choking));
+    }
+
+    #[tokio::test]
+    async fn peers_and_trackers_see_the_client_peer_id() {
+        let mut tracker = MockTracker::start(|_, _| (200, tracker_response(3600, &[]))).await;
+        let torrent = Torrent::new([0; 20], metadata(&tracker.url));
+        tokio::spawn(torrent.heartbeat());
+        let announce = timeout(Duration::from_secs(5), tracker.requests.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let encoded = crate::tracker::url_encode(&client_peer_id());
+        assert!(announce.contains(&format!("peer_id={encoded}")), "{announce}");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let remote = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            <[u8; 20]>::try_from(&handshake[48..]).unwrap()
+        });
+        torrent.connect(&[addr]).await;
+        assert_eq!(remote.await.unwrap(), client_peer_id());
+    }
+
+    #[tokio::test]
+    async fn network_change_triggers_prompt_announce() {
+        let mut tracker = MockTracker::start(|_, _| (200, tracker_response(3600, &[]))).await;
+        let torrent = Torrent::new([0; 20], metadata(&tracker.url));
+        tokio::spawn(torrent.heartbeat());
+
+        let first = timeout(Duration::from_secs(5), tracker.requests.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(first.contains("event=started"));
+
+        // the tracker asked for an hour between announces
+        torrent.network_changed();
+        let second = timeout(Duration::from_secs(5), tracker.requests.recv())
+            .await
+            .expect("re-announce after network change")
+            .unwrap();
+        assert!(!second.contains("event="));
+    }
+
+    #[tokio::test]
+    async fn moves_on_from_tracker_without_new_peers() {
+        let stale = "127.0.0.1:1".parse().unwrap();
+        let fresh = "127.0.0.1:2".parse().unwrap();
+        let mut first = MockTracker::start(move |_, _| (200, tracker_response(0, &[stale]))).await;
+        let mut second =
+            MockTracker::start(move |_, _| (200, tracker_response(3600, &[fresh]))).await;
+        let torrent = Torrent::new([0; 20], metadata(&first.url));
+        torrent.metadata.lock().await.dot_torrent.announce_list =
+            Some(vec![vec![first.url.clone()], vec![second.url.clone()]]);
+        tokio::spawn(torrent.heartbeat());
+
+        timeout(Duration::from_secs(5), second.requests.recv())
+            .await
+            .expect("rotated to the second tracker")
+            .unwrap();
+        // one announce to learn the peer, then the fruitless ones
+        let mut n_first = 0;
+        while first.requests.try_recv().is_ok() {
+            n_first += 1;
+        }
+        assert_eq!(n_first, 1 + FRUITLESS_ANNOUNCES);
+        timeout(Duration::from_secs(5), async {
+            while torrent.peer_addrs.lock().await.0 != [fresh] {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn heartbeat_can_announce_to_every_tracker() {
+        let a = "127.0.0.1:1".parse().unwrap();
+        let b = "127.0.0.1:2".parse().unwrap();
+        let c = "127.0.0.1:3".parse().unwrap();
+        let mut first = MockTracker::start(move |_, _| (200, tracker_response(3600, &[a, b]))).await;
+        let mut second =
+            MockTracker::start(move |_, _| (200, tracker_response(3600, &[b, c]))).await;
+        let torrent = Torrent::new([0; 20], metadata(&first.url))
+            .with_announce_mode(AnnounceMode::All);
+        // failover would stop at the first tier
+        torrent.metadata.lock().await.dot_torrent.announce_list =
+            Some(vec![vec![first.url.clone()], vec![second.url.clone()]]);
+        tokio::spawn(torrent.heartbeat());
+
+        for tracker in [&mut first, &mut second] {
+            timeout(Duration::from_secs(5), tracker.requests.recv())
+                .await
+                .expect("announced to every tracker")
+                .unwrap();
+        }
+        timeout(Duration::from_secs(5), async {
+            while torrent.peer_addrs.lock().await.0.len() < 3 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut peers = torrent.peer_addrs.lock().await.0.clone();
+        peers.sort();
+        assert_eq!(peers, [a, b, c]);
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_are_announced() {
+        let mut tracker = MockTracker::start(|_, _| (200, tracker_response(3600, &[]))).await;
+        let torrent = Torrent::new([0; 20], metadata(&tracker.url));
+        tokio::spawn(torrent.heartbeat());
+        let mut next = async || {
+            timeout(Duration::from_secs(5), tracker.requests.recv())
+                .await
+                .expect("announce")
+                .unwrap()
+        };
+
+        let started = next().await;
+        assert!(started.contains("event=started"));
+        assert!(started.contains("downloaded=0"));
+        assert!(started.contains("left=92063"));
+
+        // a partial seed
+        {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.downloaded = 32768;
+            metadata.left = 92063 - 32768;
+        }
+        torrent.pause();
+        assert!(torrent.is_paused());
+        let paused = next().await;
+        assert!(paused.contains("event=paused"));
+        assert!(paused.contains("downloaded=32768"));
+        assert!(paused.contains("left=59295"));
+
+        torrent.resume();
+        let resumed = next().await;
+        assert!(!resumed.contains("event="));
+        assert!(resumed.contains("left=59295"));
+
+        // finishing is reported once
+        {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.downloaded = 92063;
+            metadata.left = 0;
+        }
+        torrent.network_changed();
+        let completed = next().await;
+        assert!(completed.contains("event=completed"));
+        assert!(completed.contains("left=0"));
+        torrent.network_changed();
+        assert!(!next().await.contains("event="));
+    }
+
+    #[tokio::test]
+    async fn requests_respect_pipeline_depth_and_choking() {
+        let dot_torrent = metadata("http://127.0.0.1:8000/announce")
+            .lock()
+            .await
+            .dot_torrent
+            .clone();
+        let peer = |id: u8, unchoked, pieces: &[usize], requests_in_flight| {
+            let mut bits = BitVec::new(3);
+            for &piece_i in pieces {
+                bits.set(piece_i).unwrap();
+            }
+            PeerState {
+                peer_id: [id; 20],
+                unchoked,
+                pieces: bits,
+                requests_in_flight,
+            }
+        };
+        let peers = [
+            peer(1, true, &[0, 1, 2], 0),
+            peer(2, false, &[0, 1, 2], 0),
+            peer(3, true, &[1], 1),
+        ];
+        let mut have = BitVec::new(3);
+        have.set(0).unwrap();
+        // block 0 of piece 2 is on its way already
+        let pending = HashSet::from([(2, 0)]);
+
+        for depth in 1..=4 {
+            let plan = plan_requests(&dot_torrent, &have, &peers, &pending, depth);
+            // the choked peer gets nothing and nobody goes over the depth
+            assert!(plan.iter().all(|(id, _)| *id != [2; 20]));
+            for (id, requests) in &plan {
+                let peer = peers.iter().find(|peer| peer.peer_id == *id).unwrap();
+                assert!(requests.len() + peer.requests_in_flight <= depth);
+                assert!(requests.iter().all(|r| peer.pieces.has(r.piece)));
+            }
+            let mut all: Vec<_> = plan.into_iter().flat_map(|(_, r)| r).collect();
+            all.sort_by_key(|request| (request.piece, request.begin));
+            let expected = [
+                (1, 0, BLOCK_SIZE),
+                (1, BLOCK_SIZE, BLOCK_SIZE),
+                (2, BLOCK_SIZE, 26527 - BLOCK_SIZE),
+            ];
+            let expected = expected.map(|(piece, begin, length)| BlockRequest {
+                piece,
+                begin,
+                length,
+            });
+            if depth >= 2 {
+                // every missing block once, the truncated one included
+                assert_eq!(all, expected);
+            } else {
+                // one for the first peer, the other one's pipeline is full
+                assert_eq!(all.len(), 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn seeding_stops_at_the_ratio_limit() {
+        let mut tracker = MockTracker::start(|_, _| (200, tracker_response(3600, &[]))).await;
+        let torrent = Torrent::new([0; 20], metadata(&tracker.url)).with_seed_limit(SeedLimit {
+            ratio: Some(1.5),
+            time: None,
+        });
+        {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.downloaded = 92063;
+            metadata.left = 0;
+        }
+        tokio::spawn(torrent.heartbeat());
+        let started = timeout(Duration::from_secs(5), tracker.requests.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.contains("event=started"));
+
+        // a bit short of 1.5 times the size
+        for _ in 0..8 {
+            torrent.record_upload(BLOCK_SIZE).await;
+        }
+        sleep(Duration::from_millis(50)).await;
+        assert!(!torrent.is_stopped());
+        assert!(tracker.requests.try_recv().is_err());
+
+        torrent.record_upload(BLOCK_SIZE).await;
+        let stopped = timeout(Duration::from_secs(5), tracker.requests.recv())
+            .await
+            .expect("stop announced")
+            .unwrap();
+        assert!(stopped.contains("event=stopped"));
+        assert!(stopped.contains(&format!("uploaded={}", 9 * BLOCK_SIZE)));
+        timeout(Duration::from_secs(5), async {
+            while !torrent.is_stopped() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocks_uploaded_to_peers_count_towards_the_ratio_limit() {
+        let mut tracker = MockTracker::start(|_, _| (200, tracker_response(3600, &[]))).await;
+        let torrent = Torrent::new([0; 20], metadata(&tracker.url)).with_seed_limit(SeedLimit {
+            ratio: Some(0.3),
+            time: None,
+        });
+        let dir = std::env::temp_dir().join("bittorrent_seed_upload_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..92063).map(|i| i as u8).collect();
+        std::fs::write(dir.join("sample.txt"), &data).unwrap();
+        {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.path = dir.clone();
+            metadata.pieces = BitVec::from_bytes(vec![0b1110_0000], 3).unwrap();
+            metadata.downloaded = 92063;
+            metadata.left = 0;
+        }
+        torrent.mode.set(Mode::Seeding).unwrap();
+        tokio::spawn(torrent.heartbeat());
+        tracker.requests.recv().await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let seeder = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            torrent.accept(stream).await
+        };
+        let leecher = async {
+            let mut peer = Peer::connect(addr, [0; 20], client_peer_id(), ProtocolCheck::Strict)
+                .await
+                .unwrap();
+            let request = |begin: usize| Message {
+                typ: MessageType::Request,
+                payload: crate::peer::PieceRequest::new(1, begin as u32, BLOCK_SIZE as u32)
+                    .as_bytes_mut()
+                    .to_vec(),
+            };
+            let interested = Message {
+                typ: MessageType::Interested,
+                payload: Vec::new(),
+            };
+            peer.send(interested).await.unwrap();
+            assert_eq!(peer.recv().await.unwrap().typ, MessageType::Unchoke);
+            // a third of the torrent's size, over the limit
+            for begin in [0, BLOCK_SIZE] {
+                peer.send(request(begin)).await.unwrap();
+                let piece = peer.recv().await.unwrap();
+                let response = crate::peer::PieceResponse::ref_from_bytes(&piece.payload).unwrap();
+                let offset = 32768 + begin;
+                assert_eq!(response.block(), &data[offset..offset + BLOCK_SIZE]);
+            }
+            timeout(Duration::from_secs(5), async {
+                while !torrent.is_stopped() {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("seeding stopped");
+            // not served anymore
+            peer.send(request(0)).await.unwrap();
+            assert!(peer.recv().await.is_err());
+        };
+        let (served, ()) = tokio::join!(seeder, leecher);
+        assert!(served.unwrap_err().to_string().contains("seed limit"));
+        assert_eq!(torrent.metadata.lock().await.uploaded, 2 * BLOCK_SIZE);
+        let stopped = tracker.requests.recv().await.unwrap();
+        assert!(stopped.contains("event=stopped"));
+    }
+
+    #[tokio::test]
+    async fn announces_of_many_torrents_are_throttled() {
+        const MAX_CONCURRENT: usize = 3;
+        const N_TORRENTS: usize = 12;
+        // answers slowly, keeping track of how many announces it's serving
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let active = Arc::new(AtomicUsize::new(0));
+        let most_active = Arc::new(AtomicUsize::new(0));
+        let (served_tx, mut served) = mpsc::unbounded_channel();
+        {
+            let (active, most_active) = (active.clone(), most_active.clone());
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let (active, most_active) = (active.clone(), most_active.clone());
+                    let served_tx = served_tx.clone();
+                    tokio::spawn(async move {
+                        let mut buf = [0; 4096];
+                        let _ = stream.read(&mut buf).await;
+                        let now = active.fetch_add(1, SeqCst) + 1;
+                        most_active.fetch_max(now, SeqCst);
+                        sleep(Duration::from_millis(50)).await;
+                        let body = tracker_response(3600, &[]);
+                        let head = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        );
+                        active.fetch_sub(1, SeqCst);
+                        let _ = stream.write_all(head.as_bytes()).await;
+                        let _ = stream.write_all(&body).await;
+                        let _ = served_tx.send(());
+                    });
+                }
+            });
+        }
+
+        let queue = AnnounceQueue::new(MAX_CONCURRENT, Duration::from_millis(1));
+        let torrents: Vec<_> = (0..N_TORRENTS)
+            .map(|i| Torrent::new([i as u8; 20], metadata(&url)).with_announce_queue(queue.clone()))
+            .collect();
+        for torrent in &torrents {
+            tokio::spawn(torrent.heartbeat());
+        }
+
+        for _ in 0..N_TORRENTS {
+            timeout(Duration::from_secs(5), served.recv())
+                .await
+                .expect("every torrent announces")
+                .unwrap();
+        }
+        let most_active = most_active.load(SeqCst);
+        assert!(
+            most_active <= MAX_CONCURRENT,
+            "{most_active} concurrent announces"
+        );
+        assert!(most_active > 1);
+    }
+
+    #[tokio::test]
+    async fn announce_queue_spreads_announces_out() {
+        let queue = AnnounceQueue::new(8, Duration::from_millis(50));
+        let start = Instant::now();
+        for _ in 0..4 {
+            drop(queue.acquire().await);
+        }
+        // the first goes out right away
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn stalled_block_can_be_inspected_and_requeued() {
+        let info_hash = [8; 20];
+        let data: Vec<u8> = (0..92063u32).map(|i| (i % 253) as u8).collect();
+        let peer = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            32768,
+            full_bitfield(3),
+            Duration::from_millis(300),
+        )
+        .await;
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        torrent.metadata.lock().await.dot_torrent.info.pieces = Hashes(
+            data.chunks(32768)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+        );
+        torrent.connect(&[peer.addr]).await;
+        assert!(torrent.inflight().is_empty());
+
+        let inspect = async {
+            sleep(Duration::from_millis(100)).await;
+            // both blocks were asked for at once
+            let inflight = torrent.inflight();
+            assert_eq!(inflight.len(), 2);
+            assert_eq!((inflight[0].piece, inflight[0].block_offset), (1, 0));
+            assert_eq!(inflight[1].block_offset, BLOCK_SIZE);
+            assert_eq!(inflight[0].peer, peer.addr);
+            assert!(inflight[0].elapsed >= Duration::from_millis(50));
+
+            assert!(torrent.cancel_inflight(1, 0));
+            // back in the queue, the peer is still busy with its old requests
+            let inflight = torrent.inflight();
+            assert_eq!(inflight.len(), 1);
+            assert_eq!(inflight[0].block_offset, BLOCK_SIZE);
+            assert!(!torrent.cancel_inflight(1, 0));
+        };
+        let (piece, ()) = tokio::join!(torrent.download_piece(1), inspect);
+        // the block was asked for again and the piece still completes
+        assert_eq!(piece.unwrap(), data[32768..65536]);
+        assert!(torrent.inflight().is_empty());
+    }
+
+    #[tokio::test]
+    async fn disconnected_peer_leaves_its_blocks_to_the_others() {
+        let info_hash = [9; 20];
+        let data: Vec<u8> = (0..92063u32).map(|i| (i % 251) as u8).collect();
+        let slow = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            32768,
+            full_bitfield(3),
+            Duration::from_secs(10),
+        )
+        .await;
+        let fast = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            32768,
+            full_bitfield(3),
+            Duration::from_millis(300),
+        )
+        .await;
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        torrent.metadata.lock().await.dot_torrent.info.pieces = Hashes(
+            data.chunks(32768)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+        );
+        torrent.connect(&[slow.addr, fast.addr]).await;
+        assert_eq!(torrent.connected_peers().await.len(), 2);
+        let stats = torrent.peer_stats().await;
+        let id = |addr| stats.iter().find(|peer| peer.addr == addr).unwrap().peer_id;
+        let (slow_id, fast_id) = (id(slow.addr), id(fast.addr));
+
+        let kick = async {
+            sleep(Duration::from_millis(100)).await;
+            assert!(
+                torrent
+                    .inflight()
+                    .iter()
+                    .any(|block| block.peer == slow.addr)
+            );
+            // the peer is only lent to the piece, so there's no waiting for it
+            let disconnect = pin!(torrent.disconnect(slow_id, true));
+            assert!(futures_util::poll!(disconnect).is_ready());
+            assert!(
+                torrent
+                    .inflight()
+                    .iter()
+                    .all(|block| block.peer != slow.addr)
+            );
+        };
+        let piece = timeout(Duration::from_secs(5), async {
+            tokio::join!(torrent.download_piece(0), kick).0
+        })
+        .await
+        .expect("the slow peer was still waited for");
+        assert_eq!(piece.unwrap(), data[..32768]);
+        assert_eq!(torrent.connected_peers().await, [fast_id]);
+        assert!(!torrent.disconnect(slow_id, true).await);
+
+        // banned, so it isn't connected to again
+        torrent.connect(&[slow.addr]).await;
+        assert_eq!(torrent.peers.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn filtered_peers_are_never_dialed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(addr) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let filter = IpFilter::default().with_denied("127.0.0.0/8".parse().unwrap());
+        let torrent = Torrent::new([9; 20], metadata("http://127.0.0.1:8000/announce"))
+            .with_ip_filter(Arc::new(filter));
+        torrent.connect(&[addr]).await;
+        assert!(torrent.peers.lock().await.is_empty());
+        let dialed = timeout(Duration::from_millis(200), listener.accept()).await;
+        assert!(dialed.is_err(), "a filtered peer was dialed");
+
+        // and connections from the range are turned away too
+        let (stream, _) = tokio::join!(
+            async { listener.accept().await.unwrap().0 },
+            TcpStream::connect(addr)
+        );
+        let err = torrent.accept(stream).await.unwrap_err();
+        assert!(err.to_string().contains("filtered"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn eta_follows_a_steady_rate() {
+        let torrent = Torrent::new([9; 20], metadata("http://127.0.0.1:8000/announce"));
+        torrent.metadata.lock().await.left = 1_000_000;
+        assert_eq!(torrent.eta().await, None);
+
+        // 100 KB/s for a minute, in uneven blocks
+        let now = std::time::Instant::now();
+        let start = now - Duration::from_secs(60);
+        {
+            let mut rate = torrent.download_rate.lock().unwrap();
+            for tick in 0..=600 {
+                let bytes = if tick % 2 == 0 { 5_000 } else { 15_000 };
+                rate.record(bytes, start + Duration::from_millis(100) * tick);
+            }
+        }
+        let eta = torrent.eta().await.unwrap();
+        assert!(
+            eta.abs_diff(Duration::from_secs(10)) < Duration::from_secs(1),
+            "{eta:?}"
+        );
+        let shown = torrent.stats().await.eta.unwrap();
+        assert!(shown.abs_diff(eta) < Duration::from_millis(100));
+
+        // nothing coming in for long enough
+        let stalled = torrent
+            .download_rate
+            .lock()
+            .unwrap()
+            .rate(now + Duration::from_secs(120));
+        assert!(stalled < 1.0);
+
+        torrent.metadata.lock().await.left = 0;
+        assert_eq!(torrent.eta().await, None);
+    }
+
+    #[tokio::test]
+    async fn paused_piece_resumes_from_the_blocks_on_disk() {
+        let info_hash = [9; 20];
+        let data: Vec<u8> = (0..92063u32).map(|i| (i % 251) as u8).collect();
+        let slow = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            32768,
+            full_bitfield(3),
+            Duration::from_millis(200),
+        )
+        .await;
+        let dir = std::env::temp_dir().join("bittorrent_partial_piece_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.path = dir.clone();
+            metadata.dot_torrent.info.pieces = Hashes(
+                data.chunks(32768)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+            );
+        }
+        torrent.connect(&[slow.addr]).await;
+        // paused after the first block and before the second
+        let (interrupted, ()) = tokio::join!(torrent.download_piece(0), async {
+            sleep(Duration::from_millis(300)).await;
+            torrent.pause();
+        });
+        assert!(interrupted.is_err());
+        let partial = PartialPiece {
+            index: 0,
+            blocks: vec![0b1000_0000],
+        };
+        assert_eq!(torrent.metadata.lock().await.partial, [partial]);
+        let written = std::fs::read(dir.join("sample.txt")).unwrap();
+        assert!(written[..BLOCK_SIZE] == data[..BLOCK_SIZE]);
+
+        let slow_id = torrent.peer_stats().await[0].peer_id;
+        assert!(torrent.disconnect(slow_id, false).await);
+        let fast = MockPeer::start(info_hash, data.clone(), 32768, full_bitfield(3)).await;
+        torrent.connect(&[fast.addr]).await;
+        torrent.resume();
+        let piece = torrent.download_piece(0).await.unwrap();
+        assert!(piece == data[..32768]);
+        assert_eq!(fast.requested_blocks(0), [BLOCK_SIZE]);
+        assert!(torrent.metadata.lock().await.partial.is_empty());
+    }
+
+    #[tokio::test]
+    async fn available_pieces_are_stored_under_the_torrents_path() {
+        let info_hash = [9; 20];
+        let data: Vec<u8> = (0..92063u32).map(|i| (i % 251) as u8).collect();
+        let peer = MockPeer::start(info_hash, data.clone(), 32768, full_bitfield(3)).await;
+        let dir = std::env::temp_dir().join("bittorrent_store_available_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"))
+            .with_verify_after_write(true);
+        {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.path = dir.clone();
+            metadata.dot_torrent.info.pieces = Hashes(
+                data.chunks(32768)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+            );
+        }
+        torrent.connect(&[peer.addr]).await;
+        torrent.download_available().await;
+        assert!(std::fs::read(dir.join("sample.txt")).unwrap() == data);
+        let metadata = torrent.metadata.lock().await;
+        assert!(metadata.pieces.is_full());
+        assert_eq!(metadata.left, 0);
+    }
+
+    #[tokio::test]
+    async fn downloads_run_with_the_torrents_options_feed_the_eta() {
+        let data: Vec<u8> = (0..92063u32).map(|i| (i % 251) as u8).collect();
+        let torrent = Torrent::new([9; 20], metadata("http://127.0.0.1:8000/announce"));
+        let dot_torrent = {
+            let mut metadata = torrent.metadata.lock().await;
+            metadata.dot_torrent.info.pieces = Hashes(
+                data.chunks(32768)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+            );
+            metadata.dot_torrent.clone()
+        };
+        let peer = MockPeer::start_slow(
+            dot_torrent.info_hash().unwrap(),
+            data.clone(),
+            32768,
+            full_bitfield(3),
+            Duration::from_millis(20),
+        )
+        .await;
+        assert_eq!(torrent.eta().await, None);
+        let options = torrent.download_options();
+        crate::download::from_peers(&dot_torrent, &[peer.addr], &options)
+            .await
+            .unwrap();
+        assert!(torrent.eta().await.is_some());
+        assert_eq!(torrent.piece_metrics().len(), 3);
+    }
+
+    #[test]
+    fn back_to_back_blocks_dont_inflate_the_rate() {
+        // two pipelined blocks, a millisecond apart, would be 16 MB/s
+        let start = std::time::Instant::now();
+        let mut rate = DownloadRate::default();
+        rate.record(BLOCK_SIZE, start);
+        rate.record(BLOCK_SIZE, start + Duration::from_millis(1));
+        let early = rate.rate(start + Duration::from_millis(1));
+        assert!(early < 10_000.0, "{early}");
+
+        // a steady 100 KB/s after them is what the rate settles on
+        for tick in 1..=300 {
+            let at = start + Duration::from_millis(1) + Duration::from_millis(100) * tick;
+            rate.record(10_000, at);
+        }
+        let settled = rate.rate(start + Duration::from_millis(30_001));
+        assert!((settled - 100_000.0).abs() < 1_000.0, "{settled}");
+    }
+
+    #[tokio::test]
+    async fn downloaded_pieces_are_timed() {
+        let info_hash = [9; 20];
+        let data: Vec<u8> = (0..92063u32).map(|i| (i % 251) as u8).collect();
+        let peer = MockPeer::start_slow(
+            info_hash,
+            data.clone(),
+            32768,
+            full_bitfield(3),
+            Duration::from_millis(20),
+        )
+        .await;
+        let torrent = Torrent::new(info_hash, metadata("http://127.0.0.1:8000/announce"));
+        torrent.metadata.lock().await.dot_torrent.info.pieces = Hashes(
+            data.chunks(32768)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+        );
+        torrent.connect(&[peer.addr]).await;
+        assert!(torrent.piece_metrics().is_empty());
+
+        torrent.download_piece(2).await.unwrap();
+        torrent.download_piece(0).await.unwrap();
+        let metrics = torrent.piece_metrics();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!((metrics[0].piece, metrics[1].piece), (0, 2));
+        for metric in &metrics {
+            let first_block = metric.first_block.unwrap();
+            assert!(first_block >= Duration::from_millis(20));
+            // two blocks, one after the other
+            assert!(metric.complete.unwrap() >= first_block + Duration::from_millis(20));
+            assert_eq!((metric.peers, metric.retries), (1, 0));
+        }
+
+        // downloading a piece again isn't retrying it
+        torrent.download_piece(0).await.unwrap();
+        assert_eq!(torrent.piece_metrics()[0].retries, 0);
+
+        let hash = std::mem::replace(
+            &mut torrent.metadata.lock().await.dot_torrent.info.pieces.0[1],
+            [0; 20],
+        );
+        assert!(torrent.download_piece(1).await.is_err());
+        assert_eq!(torrent.piece_metrics()[1].complete, None);
+        torrent.metadata.lock().await.dot_torrent.info.pieces.0[1] = hash;
+        torrent.download_piece(1).await.unwrap();
+        let metric = &torrent.piece_metrics()[1];
+        assert_eq!(metric.retries, 1);
+        assert!(metric.complete.is_some());
     }
 }
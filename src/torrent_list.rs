@@ -1,11 +1,17 @@
 use crate::db::FileDB;
-use crate::state::State;
-use crate::torrent::Torrent;
+use crate::dot_torrent::InfoHash;
+use crate::state::{ResumeData, State};
+use crate::torrent::{Mode, Torrent};
+use crate::tracker::AnnounceQueue;
+use anyhow::Context;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 pub struct TorrentList {
     state: State,
-    torrents: HashMap<[u8; 20], Torrent>,
+    torrents: HashMap<InfoHash, Arc<Torrent>>,
+    // shared by all torrents so they don't announce all at once
+    announce_queue: AnnounceQueue,
 }
 
 impl TorrentList {
@@ -13,16 +19,123 @@ impl TorrentList {
         Ok(TorrentList {
             state: State::new(db)?,
             torrents: HashMap::new(),
+            announce_queue: AnnounceQueue::default(),
         })
     }
 
-    pub async fn start(&mut self) -> anyhow::Result<()> {
-        for metadata in &self.state.data {
+    pub fn add(&mut self, torrent: Torrent) {
+        let torrent = torrent.with_announce_queue(self.announce_queue.clone());
+        self.torrents
+            .insert(InfoHash(torrent.info_hash), Arc::new(torrent));
+    }
+
+    pub fn get(&self, info_hash: &InfoHash) -> Option<&Arc<Torrent>> {
+        self.torrents.get(info_hash)
+    }
+
+    // Picks up a download exported with `Torrent::export_resume`. The torrent
+    // itself has to be added first.
+    pub async fn import_resume(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let resume = ResumeData::from_bytes(bytes)?;
+        let info_hash: [u8; 20] = resume.info_hash[..]
+            .try_into()
+            .context("resume data has an invalid info hash")?;
+        let torrent = self
+            .torrents
+            .get(&InfoHash(info_hash))
+            .context("resume data is for a torrent that isn't added")?;
+        torrent.import_resume(resume).await
+    }
+
+    // Starts every torrent in the state, finished ones seeding and the rest
+    // downloading, see `Torrent::start`.
+    pub async fn start(&mut self) -> anyhow::Result<Vec<(InfoHash, Mode)>> {
+        let mut modes = Vec::new();
+        for metadata in self.state.data.clone() {
+            let info_hash = metadata.lock().await.dot_torrent.info_hash()?;
+            if !self.torrents.contains_key(&InfoHash(info_hash)) {
+                self.add(Torrent::new(info_hash, metadata));
+            }
+            let mode = self.torrents[&InfoHash(info_hash)]
+                .start()
+                .await
+                .with_context(|| format!("start torrent {}", hex::encode(info_hash)))?;
+            modes.push((InfoHash(info_hash), mode));
+        }
+        Ok(modes)
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_vec::BitVec;
+    use crate::create::create;
+    use crate::state::Metadata;
+    use std::path::Path;
+    use tokio::sync::Mutex;
+
+    fn metadata(id: usize, file: &Path, finished: bool) -> Metadata {
+        let dot_torrent = create(file).unwrap();
+        let n_pieces = dot_torrent.info.pieces.0.len();
+        let mut pieces = BitVec::new(n_pieces);
+        if finished {
+            for i in 0..n_pieces {
+                pieces.set(i).unwrap();
+            }
+        }
+        Metadata {
+            id,
+            path: file.parent().unwrap().to_path_buf(),
+            left: if finished { 0 } else { dot_torrent.length() },
+            dot_torrent,
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            pieces,
+            partial: Vec::new(),
+            finished,
+        }
+    }
+
+    #[tokio::test]
+    async fn finished_torrents_start_seeding() {
+        let dir = std::env::temp_dir().join("bittorrent_torrent_list_start_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let complete = dir.join("complete.bin");
+        let partial = dir.join("partial.bin");
+        let damaged = dir.join("damaged.bin");
+        for (i, path) in [&complete, &partial, &damaged].into_iter().enumerate() {
+            let data: Vec<u8> = (0..600_000u32).map(|j| (j + i as u32) as u8).collect();
+            std::fs::write(path, data).unwrap();
         }
-        // for (_, torrent) in &mut self.torrents {
-        //     tokio::spawn(async { torrent.run().await });
-        // }
-        Ok(())
+
+        let db = FileDB::open(dir.join("db.json")).await.unwrap();
+        let mut list = TorrentList::new(db).unwrap();
+        let entries = [
+            metadata(1, &complete, true),
+            metadata(2, &partial, false),
+            metadata(3, &damaged, true),
+        ];
+        // the damaged one lost its tail after finishing
+        let data = std::fs::read(&damaged).unwrap();
+        std::fs::write(&damaged, &data[..300_000]).unwrap();
+        for entry in entries {
+            list.state.data.push(Arc::new(Mutex::new(entry)));
+        }
+
+        let modes: Vec<_> = list.start().await.unwrap();
+        let modes: Vec<_> = modes.into_iter().map(|(_, mode)| mode).collect();
+        assert_eq!(modes, [Mode::Seeding, Mode::Downloading, Mode::Downloading]);
+
+        let damaged = list.state.data[2].lock().await;
+        assert!(!damaged.finished);
+        assert!(damaged.pieces.has(0));
+        let last = damaged.dot_torrent.info.pieces.0.len() - 1;
+        assert!(!damaged.pieces.has(last));
+        assert!(damaged.left > 0);
+        drop(damaged);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,10 +1,20 @@
 use crate::dot_torrent::DotTorrent;
+use crate::error::BtError;
+use crate::peer::{PeerId, client_peer_id};
 use anyhow::{Context, anyhow};
+use futures_util::future::join_all;
 use hex;
+use rand::seq::SliceRandom;
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, sleep_until, timeout};
 
 // NOTE: `info_hash` field is not included.
 // Added separately to the URL parameters because
@@ -54,6 +64,86 @@ pub struct TrackerRequest {
     // a compact response unless the request contains
     // "compact=0" (in which case they will refuse the request.)
     pub compact: u8,
+
+    // Setting this to 1 asks the tracker to leave out peer ids
+    // from the peers list. Ignored for compact responses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_peer_id: Option<u8>,
+
+    // If specified, must be one of started, completed, stopped
+    // (or empty which is the same as not being specified).
+    // If not specified, then this request is one performed at regular intervals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    // The first request to the tracker must include the event key with this value.
+    Started,
+    // Must be sent to the tracker when the download completes.
+    Completed,
+    // Must be sent to the tracker if the client is shutting down gracefully.
+    Stopped,
+    // Sent by partial seeds: we have some of the torrent but aren't
+    // downloading the rest (BEP 21).
+    Paused,
+}
+
+// Transfer totals reported in announces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+}
+
+impl Progress {
+    // Nothing transferred yet.
+    pub fn fresh(dot_torrent: &DotTorrent) -> Self {
+        Self {
+            left: dot_torrent.length(),
+            ..Default::default()
+        }
+    }
+}
+
+// Picks the `event` of each announce over a torrent's lifetime: `started`
+// until a tracker acknowledges it, `completed` once when the download
+// finishes, `paused` while we hold part of the torrent without downloading
+// the rest, and nothing otherwise.
+#[derive(Debug, Default)]
+pub struct AnnounceState {
+    started: bool,
+    completed: bool,
+}
+
+impl AnnounceState {
+    pub fn event(&self, progress: &Progress, paused: bool) -> Option<Event> {
+        if !self.started {
+            Some(Event::Started)
+        } else if progress.left == 0 && !self.completed {
+            Some(Event::Completed)
+        } else if paused && progress.left > 0 {
+            Some(Event::Paused)
+        } else {
+            None
+        }
+    }
+
+    // Records that the tracker accepted an announce made with `event`.
+    pub fn announced(&mut self, event: Option<Event>, progress: &Progress) {
+        match event {
+            Some(Event::Started) => {
+                self.started = true;
+                // seeding from the start, there's nothing to complete
+                self.completed = progress.left == 0;
+            }
+            Some(Event::Completed) => self.completed = true,
+            _ => {}
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -66,44 +156,529 @@ pub struct TrackerResponse {
     // First 4 bytes are the IP address and last 2 bytes are
     // the port number. All in network (big endian) notation.
     pub peers: PeerAddrs,
+
+    // Something the tracker wants us to know, the announce still went through.
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponseErr {
+    #[serde(rename = "failure reason", alias = "reason")]
     reason: String,
 }
 
-pub async fn query_tracker(dot_torrent: &DotTorrent) -> anyhow::Result<TrackerResponse> {
-    let info_hash = dot_torrent.info_hash()?;
-    let peer_id = b"00112233445566778899";
-    let request = TrackerRequest {
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: dot_torrent.length(),
-        compact: 1,
-    };
-    let url_params =
-        serde_urlencoded::to_string(&request).context("urlencode tracker parameters")?;
-    let url = format!(
-        "{}?{}&info_hash={}&peer_id={}",
-        dot_torrent.announce,
-        url_params,
-        &url_encode(&info_hash),
-        &url_encode(&peer_id)
-    );
-    let response = reqwest::get(url).await.context("query tracker")?;
-    let status_is_success = response.status().is_success();
-    let response = response.bytes().await.context("fetch tracker response")?;
-    println!("{}", String::from_utf8_lossy(&response.to_vec()));
-    if status_is_success {
-        let response: TrackerResponse =
-            serde_bencode::from_bytes(&response).context("parse tracker response")?;
-        Ok(response)
-    } else {
-        let response: TrackerResponseErr =
-            serde_bencode::from_bytes(&response).context("parse tracker response")?;
-        Err(anyhow!("{}", response.reason))
+// Trackers send `failure reason` with whatever HTTP status they like, 200
+// included, so the body is checked for it before the status.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AnnounceReply {
+    Failure(TrackerResponseErr),
+    Success(TrackerResponse),
+}
+
+pub async fn query_tracker(dot_torrent: &DotTorrent) -> Result<TrackerResponse, BtError> {
+    let announce = dot_torrent
+        .announce
+        .as_deref()
+        .context("torrent is trackerless")
+        .map_err(BtError::Tracker)?;
+    TrackerClient::new()
+        .announce(announce, dot_torrent, None, Progress::fresh(dot_torrent))
+        .await
+        .map_err(BtError::Tracker)
+}
+
+// Holds on to the HTTP client between announces so connections are pooled.
+// After a network change (sleep/resume, Wi-Fi switch) the pooled connections
+// and resolver state may be stale, so the client can be rebuilt with `reset`.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    http: Arc<Mutex<reqwest::Client>>,
+    // UDP trackers get `udp_retries` retransmissions, each waiting twice
+    // as long as the previous one (BEP 15).
+    udp_timeout: Duration,
+    udp_retries: u32,
+    // what we announce ourselves as
+    peer_id: PeerId,
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        Self {
+            http: Arc::default(),
+            udp_timeout: Duration::from_secs(15),
+            udp_retries: 2,
+            peer_id: client_peer_id(),
+        }
+    }
+}
+
+impl TrackerClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_udp_timeout(mut self, timeout: Duration, retries: u32) -> Self {
+        self.udp_timeout = timeout;
+        self.udp_retries = retries;
+        self
+    }
+
+    pub fn with_peer_id(mut self, peer_id: PeerId) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn reset(&self) {
+        *self.http.lock().expect("mutex was poisoned") = reqwest::Client::new();
+    }
+
+    pub async fn announce(
+        &self,
+        tracker_url: &str,
+        dot_torrent: &DotTorrent,
+        event: Option<Event>,
+        progress: Progress,
+    ) -> anyhow::Result<TrackerResponse> {
+        if tracker_url.starts_with("udp://") {
+            self.announce_udp(tracker_url, dot_torrent, event, progress)
+                .await
+        } else {
+            self.announce_http(tracker_url, dot_torrent, event, progress)
+                .await
+        }
+    }
+
+    async fn announce_http(
+        &self,
+        tracker_url: &str,
+        dot_torrent: &DotTorrent,
+        event: Option<Event>,
+        progress: Progress,
+    ) -> anyhow::Result<TrackerResponse> {
+        let info_hash = dot_torrent.info_hash()?;
+        let request = TrackerRequest {
+            port: 6881,
+            uploaded: progress.uploaded,
+            downloaded: progress.downloaded,
+            left: progress.left,
+            compact: 1,
+            no_peer_id: None,
+            event,
+        };
+        let url_params =
+            serde_urlencoded::to_string(&request).context("urlencode tracker parameters")?;
+        let url = format!(
+            "{}?{}&info_hash={}&peer_id={}",
+            tracker_url,
+            url_params,
+            &url_encode(&info_hash),
+            &url_encode(&self.peer_id)
+        );
+        // cloning is cheap, the client is reference counted internally
+        let http = self.http.lock().expect("mutex was poisoned").clone();
+        let response = http.get(url).send().await.context("query tracker")?;
+        let status = response.status();
+        let response = response.bytes().await.context("fetch tracker response")?;
+        println!("{}", String::from_utf8_lossy(&response.to_vec()));
+        let reply = serde_bencode::from_bytes(&response)
+            .with_context(|| format!("parse tracker response (HTTP {status})"))?;
+        match reply {
+            AnnounceReply::Failure(err) => Err(anyhow!("{}", err.reason)),
+            AnnounceReply::Success(response) if status.is_success() => Ok(response),
+            AnnounceReply::Success(_) => Err(anyhow!("tracker answered with HTTP {status}")),
+        }
+    }
+
+    // UDP tracker protocol (BEP 15): obtain a connection id, then announce with it.
+    async fn announce_udp(
+        &self,
+        tracker_url: &str,
+        dot_torrent: &DotTorrent,
+        event: Option<Event>,
+        progress: Progress,
+    ) -> anyhow::Result<TrackerResponse> {
+        let host = tracker_url
+            .trim_start_matches("udp://")
+            .split('/')
+            .next()
+            .context("tracker URL has no host")?;
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .context("bind UDP socket")?;
+        socket
+            .connect(host)
+            .await
+            .with_context(|| format!("resolve UDP tracker {host}"))?;
+
+        let transaction_id: u32 = rand::random();
+        let mut connect = Vec::with_capacity(16);
+        connect.extend(UDP_PROTOCOL_ID.to_be_bytes());
+        connect.extend(UDP_ACTION_CONNECT.to_be_bytes());
+        connect.extend(transaction_id.to_be_bytes());
+        let response = self
+            .udp_exchange(&socket, &connect, UDP_ACTION_CONNECT, transaction_id)
+            .await?;
+        anyhow::ensure!(response.len() >= 8, "connect response is too short");
+        let connection_id = &response[..8];
+
+        let info_hash = dot_torrent.info_hash()?;
+        let transaction_id: u32 = rand::random();
+        let event_id: u32 = match event {
+            // BEP 15 has no paused event
+            None | Some(Event::Paused) => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+        let mut announce = Vec::with_capacity(98);
+        announce.extend(connection_id);
+        announce.extend(UDP_ACTION_ANNOUNCE.to_be_bytes());
+        announce.extend(transaction_id.to_be_bytes());
+        announce.extend(info_hash);
+        announce.extend(self.peer_id);
+        // downloaded, left, uploaded
+        announce.extend((progress.downloaded as u64).to_be_bytes());
+        announce.extend((progress.left as u64).to_be_bytes());
+        announce.extend((progress.uploaded as u64).to_be_bytes());
+        announce.extend(event_id.to_be_bytes());
+        // IP address (0 means use the sender's), key, number of peers wanted (-1 is default)
+        announce.extend(0u32.to_be_bytes());
+        announce.extend(rand::random::<u32>().to_be_bytes());
+        announce.extend((-1i32).to_be_bytes());
+        announce.extend(6881u16.to_be_bytes());
+        let response = self
+            .udp_exchange(&socket, &announce, UDP_ACTION_ANNOUNCE, transaction_id)
+            .await?;
+        // interval, leechers, seeders and then the compact peer list
+        anyhow::ensure!(response.len() >= 12, "announce response is too short");
+        let interval = u32::from_be_bytes(response[..4].try_into().expect("4 bytes"));
+        let peers = PeerAddrsVisitor
+            .visit_bytes::<serde::de::value::Error>(&response[12..])
+            .map_err(|err| anyhow!("invalid peer list: {err}"))?;
+        Ok(TrackerResponse {
+            interval: interval as u64,
+            peers,
+            warning_message: None,
+        })
+    }
+
+    // Sends `request` until a response to it arrives, retransmitting on
+    // timeout. Returns the response without its action and transaction id.
+    async fn udp_exchange(
+        &self,
+        socket: &UdpSocket,
+        request: &[u8],
+        action: u32,
+        transaction_id: u32,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; 2048];
+        for attempt in 0..=self.udp_retries {
+            socket.send(request).await.context("send to UDP tracker")?;
+            let wait = self.udp_timeout * 2u32.pow(attempt);
+            let Ok(received) = timeout(wait, socket.recv(&mut buf)).await else {
+                continue;
+            };
+            let n = received.context("receive from UDP tracker")?;
+            if n < 8 || buf[4..8] != transaction_id.to_be_bytes() {
+                // stale or foreign datagram
+                continue;
+            }
+            let response_action = u32::from_be_bytes(buf[..4].try_into().expect("4 bytes"));
+            if response_action == UDP_ACTION_ERROR {
+                anyhow::bail!("{}", String::from_utf8_lossy(&buf[8..n]));
+            }
+            anyhow::ensure!(
+                response_action == action,
+                "UDP tracker answered with action {response_action}, expected {action}"
+            );
+            return Ok(buf[8..n].to_vec());
+        }
+        anyhow::bail!("UDP tracker did not respond")
+    }
+}
+
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_ACTION_CONNECT: u32 = 0;
+const UDP_ACTION_ANNOUNCE: u32 = 1;
+const UDP_ACTION_ERROR: u32 = 3;
+
+pub const MAX_CONCURRENT_ANNOUNCES: usize = 4;
+pub const ANNOUNCE_SPACING: Duration = Duration::from_millis(100);
+
+// Shared by every torrent of a client so their announces don't all go out at
+// once: at most `max_concurrent` are in flight, and each starts at least
+// `spacing` after the previous one.
+#[derive(Debug, Clone)]
+pub struct AnnounceQueue {
+    permits: Arc<Semaphore>,
+    spacing: Duration,
+    // when the next announce may start
+    next_slot: Arc<Mutex<Instant>>,
+}
+
+impl AnnounceQueue {
+    pub fn new(max_concurrent: usize, spacing: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            spacing,
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    // Waits for this announce's turn. It may go out while the permit is held.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("never closed");
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.spacing;
+            slot
+        };
+        sleep_until(slot).await;
+        permit
+    }
+}
+
+impl Default for AnnounceQueue {
+    fn default() -> Self {
+        Self::new(MAX_CONCURRENT_ANNOUNCES, ANNOUNCE_SPACING)
+    }
+}
+
+// Trackers grouped in tiers as described by BEP 12. Trackers within a tier
+// are shuffled once, then tried in order, and a tracker that answers is moved
+// to the front of its tier so it's tried first next time. Later tiers are
+// only used when every tracker in the earlier ones failed.
+//
+// A tracker that keeps answering without anything new can be skipped, after
+// which it's only used when none of the other trackers answer.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    skipped: HashSet<String>,
+    // the tracker that answered last
+    current: Option<String>,
+    mode: AnnounceMode,
+    // trackers that failed in `AnnounceMode::All`, left alone until then
+    backoff: HashMap<String, Backoff>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnnounceMode {
+    // One tracker at a time, the others only if it fails (BEP 12).
+    #[default]
+    Failover,
+    // Every tracker at once, for public torrents whose trackers each know
+    // part of the swarm.
+    All,
+}
+
+// First wait after a tracker fails in `AnnounceMode::All`, doubled on every
+// further failure up to `MAX_TRACKER_BACKOFF`.
+const TRACKER_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_TRACKER_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    until: Instant,
+    wait: Duration,
+}
+
+// Peer addresses gathered from several trackers, each kept once.
+#[derive(Debug, Clone, Default)]
+pub struct PeerPool {
+    seen: HashSet<SocketAddrV4>,
+    peers: Vec<SocketAddrV4>,
+}
+
+impl PeerPool {
+    // Returns how many of the addresses were new.
+    pub fn extend(&mut self, addrs: impl IntoIterator<Item = SocketAddrV4>) -> usize {
+        let before = self.peers.len();
+        for addr in addrs {
+            if self.seen.insert(addr) {
+                self.peers.push(addr);
+            }
+        }
+        self.peers.len() - before
+    }
+
+    pub fn contains(&self, addr: &SocketAddrV4) -> bool {
+        self.seen.contains(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    // In the order they were first seen.
+    pub fn peers(&self) -> &[SocketAddrV4] {
+        &self.peers
+    }
+}
+
+impl TrackerTiers {
+    pub fn new(dot_torrent: &DotTorrent) -> Self {
+        let mut tiers = match &dot_torrent.announce_list {
+            Some(list) if list.iter().any(|tier| !tier.is_empty()) => list.clone(),
+            // no tiers at all for trackerless torrents
+            _ => dot_torrent
+                .announce
+                .iter()
+                .map(|url| vec![url.clone()])
+                .collect(),
+        };
+        let mut rng = rand::rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+            // UDP is cheaper for both sides, so HTTP trackers are only
+            // used when the UDP ones in the tier don't answer
+            tier.sort_by_key(|url| !url.starts_with("udp://"));
+        }
+        Self::from_tiers(tiers)
+    }
+
+    // Keeps the given order within tiers.
+    pub fn from_tiers(tiers: Vec<Vec<String>>) -> Self {
+        Self {
+            tiers,
+            skipped: HashSet::new(),
+            current: None,
+            mode: AnnounceMode::default(),
+            backoff: HashMap::new(),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: AnnounceMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub async fn announce(
+        &mut self,
+        client: &TrackerClient,
+        dot_torrent: &DotTorrent,
+        event: Option<Event>,
+        progress: Progress,
+    ) -> anyhow::Result<TrackerResponse> {
+        if self.mode == AnnounceMode::All {
+            let mut pool = PeerPool::default();
+            let interval = self
+                .announce_all(client, dot_torrent, event, progress, &mut pool)
+                .await?;
+            return Ok(TrackerResponse {
+                interval,
+                peers: PeerAddrs(pool.peers),
+                warning_message: None,
+            });
+        }
+        let mut last_err = anyhow!("no trackers to announce to");
+        for use_skipped in [false, true] {
+            for tier in &mut self.tiers {
+                for i in 0..tier.len() {
+                    if self.skipped.contains(&tier[i]) != use_skipped {
+                        continue;
+                    }
+                    match client
+                        .announce(&tier[i], dot_torrent, event, progress)
+                        .await
+                    {
+                        Ok(response) => {
+                            let url = tier.remove(i);
+                            self.current = Some(url.clone());
+                            tier.insert(0, url);
+                            return Ok(response);
+                        }
+                        Err(err) => last_err = err.context(format!("announce to {}", tier[i])),
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    // Announces to every tracker not backing off at the same time, adding the
+    // peers they return to `pool`. Returns the shortest interval asked for,
+    // or an error if none of them answered.
+    pub async fn announce_all(
+        &mut self,
+        client: &TrackerClient,
+        dot_torrent: &DotTorrent,
+        event: Option<Event>,
+        progress: Progress,
+        pool: &mut PeerPool,
+    ) -> anyhow::Result<u64> {
+        let now = Instant::now();
+        let urls: Vec<String> = self
+            .tiers
+            .iter()
+            .flatten()
+            .filter(|url| self.backoff.get(*url).is_none_or(|b| b.until <= now))
+            .cloned()
+            .collect();
+        let responses = join_all(
+            urls.iter()
+                .map(|url| client.announce(url, dot_torrent, event, progress)),
+        )
+        .await;
+        let mut interval = None;
+        let mut last_err = anyhow!("all trackers are backing off");
+        for (url, response) in urls.into_iter().zip(responses) {
+            match response {
+                Ok(response) => {
+                    self.backoff.remove(&url);
+                    pool.extend(response.peers.0);
+                    interval = Some(interval.unwrap_or(u64::MAX).min(response.interval));
+                }
+                Err(err) => {
+                    let wait = self
+                        .backoff
+                        .get(&url)
+                        .map_or(TRACKER_BACKOFF, |b| (b.wait * 2).min(MAX_TRACKER_BACKOFF));
+                    let until = now + wait;
+                    last_err = err.context(format!("announce to {url}"));
+                    self.backoff.insert(url, Backoff { until, wait });
+                }
+            }
+        }
+        interval.ok_or(last_err)
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    // Moves on from the tracker that answered last, e.g. because it keeps
+    // handing out peers we already know.
+    pub fn skip_current(&mut self) {
+        let Some(url) = self.current.take() else {
+            return;
+        };
+        self.skipped.insert(url);
+        if self
+            .tiers
+            .iter()
+            .flatten()
+            .all(|url| self.skipped.contains(url))
+        {
+            // tried them all, start over
+            self.skipped.clear();
+        }
     }
 }
 
@@ -173,3 +748,193 @@ impl<'de> Visitor<'de> for PeerAddrsVisitor {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dot_torrent::hashes::Hashes;
+    use crate::dot_torrent::{Info, Key};
+    use crate::testing::{MockTracker, tracker_response};
+
+    fn dot_torrent() -> DotTorrent {
+        DotTorrent::new(Info::new(
+            "sample.txt",
+            32768,
+            Hashes(vec![[0; 20]; 3]),
+            Key::SingleFile { length: 92063 },
+        ))
+    }
+
+    fn fresh() -> Progress {
+        Progress::fresh(&dot_torrent())
+    }
+
+    fn failure() -> (u16, Vec<u8>) {
+        (500, b"d6:reason4:downe".to_vec())
+    }
+
+    #[tokio::test]
+    async fn working_tracker_is_promoted_within_its_tier() {
+        let peer = "127.0.0.2:6881".parse().unwrap();
+        let failing = MockTracker::start(|_, _| failure()).await;
+        let working = MockTracker::start(move |_, _| (200, tracker_response(60, &[peer]))).await;
+        let mut backup = MockTracker::start(|_, _| failure()).await;
+        let mut tiers = TrackerTiers::from_tiers(vec![
+            vec![failing.url.clone(), working.url.clone()],
+            vec![backup.url.clone()],
+        ]);
+
+        let response = tiers
+            .announce(&TrackerClient::new(), &dot_torrent(), None, fresh())
+            .await
+            .unwrap();
+        assert_eq!(response.peers.0, vec![peer]);
+        assert_eq!(
+            tiers.tiers(),
+            &[
+                vec![working.url.clone(), failing.url.clone()],
+                vec![backup.url.clone()]
+            ]
+        );
+        // the second tier was never needed
+        assert!(backup.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_http_when_udp_is_blocked() {
+        // bound but never answers, like a tracker behind a firewall dropping UDP
+        let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_url = format!("udp://{}/announce", blackhole.local_addr().unwrap());
+        let peer = "127.0.0.2:6881".parse().unwrap();
+        let http = MockTracker::start(move |_, _| (200, tracker_response(60, &[peer]))).await;
+        let mut tiers = TrackerTiers::from_tiers(vec![vec![udp_url.clone(), http.url.clone()]]);
+
+        let client = TrackerClient::new().with_udp_timeout(Duration::from_millis(20), 1);
+        let response = tiers
+            .announce(&client, &dot_torrent(), None, fresh())
+            .await
+            .unwrap();
+        assert_eq!(response.peers.0, vec![peer]);
+        assert_eq!(tiers.tiers()[0][0], http.url);
+    }
+
+    #[tokio::test]
+    async fn announces_over_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 16);
+            assert_eq!(buf[..8], UDP_PROTOCOL_ID.to_be_bytes());
+            let mut reply = UDP_ACTION_CONNECT.to_be_bytes().to_vec();
+            reply.extend(&buf[12..16]);
+            reply.extend(42u64.to_be_bytes());
+            socket.send_to(&reply, from).await.unwrap();
+
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 98);
+            assert_eq!(buf[..8], 42u64.to_be_bytes());
+            let mut reply = UDP_ACTION_ANNOUNCE.to_be_bytes().to_vec();
+            reply.extend(&buf[12..16]);
+            reply.extend(1800u32.to_be_bytes());
+            reply.extend([0; 8]);
+            reply.extend([127, 0, 0, 2, 0x1a, 0xe1]);
+            socket.send_to(&reply, from).await.unwrap();
+        });
+
+        let response = TrackerClient::new()
+            .with_udp_timeout(Duration::from_secs(5), 0)
+            .announce(&url, &dot_torrent(), Some(Event::Started), fresh())
+            .await
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.peers.0, vec!["127.0.0.2:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_tier() {
+        let failing = MockTracker::start(|_, _| failure()).await;
+        let working = MockTracker::start(|_, _| (200, tracker_response(60, &[]))).await;
+        let mut tiers =
+            TrackerTiers::from_tiers(vec![vec![failing.url.clone()], vec![working.url.clone()]]);
+        tiers
+            .announce(&TrackerClient::new(), &dot_torrent(), None, fresh())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn seeding_from_the_start_never_completes() {
+        let mut state = AnnounceState::default();
+        let seeding = Progress::default();
+        assert_eq!(state.event(&seeding, false), Some(Event::Started));
+        // not acknowledged, so it's tried again
+        assert_eq!(state.event(&seeding, true), Some(Event::Started));
+        state.announced(Some(Event::Started), &seeding);
+        assert_eq!(state.event(&seeding, false), None);
+        // there's nothing left to download, pausing changes nothing
+        assert_eq!(state.event(&seeding, true), None);
+    }
+
+    #[tokio::test]
+    async fn announcing_to_all_trackers_merges_their_peers() {
+        let a = "127.0.0.2:6881".parse().unwrap();
+        let b = "127.0.0.3:6881".parse().unwrap();
+        let c = "127.0.0.4:6881".parse().unwrap();
+        let first = MockTracker::start(move |_, _| (200, tracker_response(900, &[a, b]))).await;
+        let second = MockTracker::start(move |_, _| (200, tracker_response(600, &[b, c]))).await;
+        let mut failing = MockTracker::start(|_, _| failure()).await;
+        let mut tiers = TrackerTiers::from_tiers(vec![
+            vec![first.url.clone()],
+            vec![second.url.clone(), failing.url.clone()],
+        ])
+        .with_mode(AnnounceMode::All);
+
+        let client = TrackerClient::new();
+        let mut pool = PeerPool::default();
+        let interval = tiers
+            .announce_all(&client, &dot_torrent(), None, fresh(), &mut pool)
+            .await
+            .unwrap();
+        assert_eq!(interval, 600);
+        assert_eq!(pool.len(), 3);
+        assert!([a, b, c].iter().all(|peer| pool.contains(peer)));
+        assert!(failing.requests.try_recv().is_ok());
+
+        // the failed tracker is left alone while the others are asked again
+        let response = tiers
+            .announce(&client, &dot_torrent(), None, fresh())
+            .await
+            .unwrap();
+        assert_eq!(response.peers.0.len(), 3);
+        assert!(failing.requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn failure_reason_is_an_error_whatever_the_status() {
+        let peer = "127.0.0.2:6881".parse().unwrap();
+        let announce = |status: u16, body: Vec<u8>| async move {
+            let tracker = MockTracker::start(move |_, _| (status, body.clone())).await;
+            TrackerClient::new()
+                .announce(&tracker.url, &dot_torrent(), None, fresh())
+                .await
+        };
+        let failure = b"d14:failure reason12:unregisterede".to_vec();
+
+        let err = announce(200, failure.clone()).await.unwrap_err();
+        assert_eq!(err.to_string(), "unregistered");
+        let err = announce(403, failure).await.unwrap_err();
+        assert_eq!(err.to_string(), "unregistered");
+
+        let mut success = b"d8:intervali60e5:peers6:".to_vec();
+        success.extend([127, 0, 0, 2, 0x1a, 0xe1]);
+        success.extend(b"15:warning message4:slowe");
+        let response = announce(200, success.clone()).await.unwrap();
+        assert_eq!(response.interval, 60);
+        assert_eq!(response.peers.0, vec![peer]);
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        // a peer list doesn't make an error status a success
+        assert!(announce(500, success).await.is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use sha1::{Digest, Sha1};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// Hashes pieces on the blocking pool, at most `slots` at a time. A burst of
+// completed pieces would otherwise take over the pool and keep CPU busy
+// with hashing while other blocking work (file IO) waits. Pieces waiting
+// for a slot are just held on to until they get one.
+#[derive(Debug)]
+pub struct PieceVerifier {
+    slots: Arc<Semaphore>,
+}
+
+impl Default for PieceVerifier {
+    fn default() -> Self {
+        Self::new(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl PieceVerifier {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(slots.max(1))),
+        }
+    }
+
+    // `piece` back if it hashes to `hash`.
+    pub async fn verified<P>(&self, piece: P, hash: [u8; 20]) -> Option<P>
+    where
+        P: AsRef<[u8]> + Send + 'static,
+    {
+        self.run(move || (<[u8; 20]>::from(Sha1::digest(piece.as_ref())) == hash).then_some(piece))
+            .await
+    }
+
+    async fn run<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> T {
+        let slot = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("never closed");
+        tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            job()
+        })
+        .await
+        .expect("verification panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn verifications_wait_for_a_slot() {
+        let piece = vec![7; 1000];
+        let hash = Sha1::digest(&piece).into();
+        let verifier = PieceVerifier::new(1);
+        assert!(verifier.verified(piece.clone(), hash).await == Some(piece.clone()));
+        assert!(verifier.verified(piece, [0; 20]).await.is_none());
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let jobs = (0..8).map(|_| {
+            let (running, most) = (running.clone(), most.clone());
+            verifier.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        join_all(jobs).await;
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+
+        // and with more slots they do overlap
+        let verifier = PieceVerifier::new(4);
+        let jobs = (0..8).map(|_| {
+            let (running, most) = (running.clone(), most.clone());
+            verifier.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        join_all(jobs).await;
+        assert!(most.load(Ordering::SeqCst) > 1);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::dot_torrent::PieceInfo;
+use crate::tracker::url_encode;
+use crate::verify::PieceVerifier;
+use anyhow::Context;
+use reqwest::StatusCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+// Times a busy seed (HTTP 503) is asked for the same piece again.
+const BUSY_RETRIES: usize = 3;
+
+// Longest a busy seed is waited for, it's given up on if it asks for more.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// A server from a torrent's `httpseeds` (BEP 17). It's asked for whole
+// pieces by index, `<url>?info_hash=<hash>&piece=<index>`, and may tell us
+// to come back later instead.
+#[derive(Debug, Clone)]
+pub struct HttpSeed {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl HttpSeed {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Piece `piece` of the torrent with `info_hash`, as sent. It still has
+    // to be checked against the piece's hash. A busy seed is asked again
+    // once the time it gives is up.
+    pub async fn piece(&self, info_hash: &[u8; 20], piece: &PieceInfo) -> anyhow::Result<Vec<u8>> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{separator}info_hash={}&piece={}",
+            self.url,
+            url_encode(info_hash),
+            piece.index
+        );
+        let mut retries = 0;
+        let (status, body) = loop {
+            let response = self
+                .http
+                .get(&url)
+                .send()
+                .await
+                .with_context(|| format!("query web seed {}", self.url))?;
+            let status = response.status();
+            let body = response
+                .bytes()
+                .await
+                .context("fetch piece from web seed")?;
+            if status != StatusCode::SERVICE_UNAVAILABLE {
+                break (status, body);
+            }
+            // the body says how many seconds to wait
+            let wait = String::from_utf8_lossy(&body).trim().to_string();
+            let retry_after = wait.parse().map(Duration::from_secs).ok();
+            let Some(retry_after) = retry_after
+                .filter(|&retry_after| retries < BUSY_RETRIES && retry_after <= MAX_RETRY_AFTER)
+            else {
+                anyhow::bail!("web seed {} is busy, retry in {wait}s", self.url);
+            };
+            println!("web seed {} is busy, retrying in {wait}s", self.url);
+            tokio::time::sleep(retry_after).await;
+            retries += 1;
+        };
+        anyhow::ensure!(
+            status.is_success(),
+            "web seed {} answered with HTTP {status}",
+            self.url
+        );
+        anyhow::ensure!(
+            body.len() == piece.length,
+            "web seed {} sent {} bytes for piece {}",
+            self.url,
+            body.len(),
+            piece.index
+        );
+        Ok(body.to_vec())
+    }
+}
+
+// Asks each seed in turn for `piece`, until one sends it intact.
+pub(crate) async fn fetch_verified(
+    seeds: &[HttpSeed],
+    info_hash: &[u8; 20],
+    piece: &PieceInfo,
+    verifier: &PieceVerifier,
+) -> anyhow::Result<Vec<u8>> {
+    for seed in seeds {
+        match seed.piece(info_hash, piece).await {
+            Ok(data) => match verifier.verified(data, piece.hash).await {
+                Some(data) => return Ok(data),
+                None => println!(
+                    "piece {} from web seed {} failed the hash check",
+                    piece.index,
+                    seed.url()
+                ),
+            },
+            Err(err) => println!("{err:#}"),
+        }
+    }
+    anyhow::bail!("no web seed sent piece {}", piece.index)
+}
+
+// Fetches the pieces sent on `pieces` from `seeds` one after the other and
+// sends each back with how it went, until `pieces` is closed. Runs next to
+// the peers, so the seeds are a source of their own.
+pub(crate) async fn fetch_queued(
+    seeds: Vec<HttpSeed>,
+    info_hash: [u8; 20],
+    verifier: Arc<PieceVerifier>,
+    mut pieces: UnboundedReceiver<PieceInfo>,
+    fetched: UnboundedSender<(PieceInfo, anyhow::Result<Vec<u8>>)>,
+) {
+    while let Some(piece) = pieces.recv().await {
+        let result = fetch_verified(&seeds, &info_hash, &piece, &verifier).await;
+        if fetched.send((piece, result)).is_err() {
+            return;
+        }
+    }
+}
@@ -14,6 +14,13 @@ struct ErrMsg {
     reason: String
 }
 
+// What clients expect a refused announce to look like, sent with 200 OK.
+#[derive(Serialize)]
+struct Failure {
+    #[serde(rename = "failure reason")]
+    reason: String,
+}
+
 impl ErrResp {
     pub fn new(status: StatusCode, err: Error) -> Self {
         let error = ErrMsg { reason: err.to_string() };
@@ -29,6 +36,18 @@ impl ErrResp {
     pub fn server_error(err: Error) -> Self {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, err)
     }
+
+    // A request we understood but won't serve.
+    pub fn failure(err: Error) -> Self {
+        let error = Failure {
+            reason: err.to_string(),
+        };
+        let error = serde_bencode::to_bytes(&error).expect("failed to bencode failure reason");
+        Self {
+            status: StatusCode::OK,
+            error,
+        }
+    }
 }
 
 impl IntoResponse for ErrResp {
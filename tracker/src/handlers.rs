@@ -1 +1,2 @@
-pub mod announce;
\ No newline at end of file
+pub mod announce;
+pub mod health;
\ No newline at end of file
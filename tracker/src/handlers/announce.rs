@@ -1,17 +1,18 @@
 use crate::error::ErrResp;
-use crate::state::AppState;
+use crate::state::{AppState, IpRange};
+use crate::torrents::{InfoHash, Peer};
 use crate::utils::percent_decode;
 use anyhow::anyhow;
 use axum::extract::{ConnectInfo, RawQuery, State};
-use axum::http::StatusCode;
-use serde::Serialize;
-use std::collections::VecDeque;
-use std::collections::hash_map::Entry;
-use std::net::SocketAddr;
+use axum::http::{HeaderMap, StatusCode};
+use rand::seq::IteratorRandom;
+use serde::{Serialize, Serializer};
+use std::net::{IpAddr, SocketAddr};
 
 pub async fn get(
     RawQuery(query): RawQuery,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     State(state): State<AppState>,
 ) -> Result<(StatusCode, Vec<u8>), ErrResp> {
     let query = query.ok_or(ErrResp::bad_request(anyhow!("invalid URL query string")))?;
@@ -19,40 +20,102 @@ pub async fn get(
         ErrResp::bad_request(anyhow!(e))
     })?;
     println!("{:?}", params);
-    let peer_addr = SocketAddr::new(addr.ip(), params.port);
-    let mut torrents = state.torrents.lock().expect("mutex was poisoned");
-    let mut peers = Vec::new();
-    match torrents.items.entry(params.info_hash) {
-        Entry::Vacant(entry) => {
-            let mut available_peers = VecDeque::new();
-            available_peers.push_back(peer_addr);
-            entry.insert(available_peers);
-            peers.push(peer_addr)
-        }
-        Entry::Occupied(mut entry) => {
-            let available_peers = entry.get_mut();
-            if let Some(index) = available_peers.iter().position(|&addr| addr == peer_addr) {
-                available_peers.remove(index);
-                available_peers.push_back(peer_addr);
-                peers.extend(available_peers.iter())
-            }
+    if state.force_compact && params.compact == 0 {
+        return Err(ErrResp::failure(anyhow!(
+            "this tracker only sends compact peer lists"
+        )));
+    }
+    let ip = peer_ip(addr.ip(), params.ip, &headers, &state.trusted_proxies);
+    let peer_addr = SocketAddr::new(ip, params.port);
+    let mut torrents = state.torrents();
+    torrents.announces += 1;
+    let mut peers: Vec<Peer> = Vec::new();
+    if params.event.as_deref() == Some("stopped") {
+        torrents.remove(&params.info_hash, peer_addr);
+    } else {
+        let available_peers = torrents.items.entry(params.info_hash).or_default();
+        // (re)announcing peers go to the back
+        available_peers.retain(|peer| peer.addr != peer_addr);
+        let others = available_peers.iter().copied();
+        let want = state.peer_list.for_request(params.numwant);
+        if available_peers.len() <= want {
+            peers.extend(others);
+        } else {
+            peers = others.choose_multiple(&mut rand::rng(), want);
         }
+        available_peers.push_back(Peer {
+            id: params.peer_id,
+            addr: peer_addr,
+        });
+    }
+    let peers = if params.compact == 1 {
+        Peers::Compact(CompactPeers(peers))
+    } else {
+        Peers::Dicts(
+            peers
+                .iter()
+                .map(|peer| PeerDict {
+                    peer_id: (params.no_peer_id != Some(1)).then_some(Bytes(peer.id)),
+                    ip: peer.addr.ip().to_string(),
+                    port: peer.addr.port(),
+                })
+                .collect(),
+        )
+    };
+    let peer_resp = PeersResp {
+        interval: state.interval.next(),
+        min_interval: state.interval.min,
+        peers,
     };
-    let peer_resp = PeersResp { peers };
     let peer_resp =
         serde_bencode::to_bytes(&peer_resp).map_err(|e| ErrResp::server_error(anyhow!(e)))?;
     Ok((StatusCode::OK, peer_resp))
 }
 
+// The address peers should connect to. Only trusted proxies may override the
+// connection's address, with the `ip` parameter taking precedence over
+// `X-Forwarded-For` (whose first entry is the original client), and that
+// over `X-Real-IP`.
+fn peer_ip(
+    conn_ip: IpAddr,
+    ip_param: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpRange],
+) -> IpAddr {
+    if !trusted_proxies.iter().any(|range| range.contains(conn_ip)) {
+        return conn_ip;
+    }
+    let forwarded_for = || {
+        headers
+            .get("x-forwarded-for")?
+            .to_str()
+            .ok()?
+            .split(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    let real_ip = || headers.get("x-real-ip")?.to_str().ok()?.trim().parse().ok();
+    ip_param
+        .or_else(forwarded_for)
+        .or_else(real_ip)
+        .unwrap_or(conn_ip)
+}
+
 #[derive(Debug)]
 pub struct AnnounceParams {
-    pub info_hash: [u8; 20],
+    pub info_hash: InfoHash,
     pub peer_id: [u8; 20],
     pub port: u16,
     pub uploaded: usize,
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    pub no_peer_id: Option<u8>,
+    pub ip: Option<IpAddr>,
+    pub numwant: Option<usize>,
+    pub event: Option<String>,
 }
 
 fn parse_query(s: &str) -> anyhow::Result<AnnounceParams> {
@@ -63,6 +126,10 @@ fn parse_query(s: &str) -> anyhow::Result<AnnounceParams> {
     let mut downloaded = None;
     let mut left = None;
     let mut compact = None;
+    let mut no_peer_id = None;
+    let mut ip = None;
+    let mut numwant = None;
+    let mut event = None;
     for pair in s.split('&') {
         let mut parts = pair.split('=');
         let key = parts.next().ok_or(anyhow!("missing query key"))?;
@@ -113,21 +180,393 @@ fn parse_query(s: &str) -> anyhow::Result<AnnounceParams> {
                         .map_err(|_| anyhow!("invalid query parameter `compact`"))?,
                 )
             }
+            "no_peer_id" => {
+                no_peer_id = Some(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("invalid query parameter `no_peer_id`"))?,
+                )
+            }
+            "ip" => {
+                ip = Some(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("invalid query parameter `ip`"))?,
+                )
+            }
+            "numwant" => {
+                numwant = Some(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("invalid query parameter `numwant`"))?,
+                )
+            }
+            "event" => match value {
+                "started" | "completed" | "stopped" => event = Some(value.to_string()),
+                _ => return Err(anyhow!("invalid query parameter `event`")),
+            },
             _ => return Err(anyhow!("Unknown parameter: {key}")),
         }
     }
     Ok(AnnounceParams {
-        info_hash: info_hash.ok_or(anyhow!("missing query parameter `info_hash`"))?,
+        info_hash: InfoHash(info_hash.ok_or(anyhow!("missing query parameter `info_hash`"))?),
         peer_id: peer_id.ok_or(anyhow!("missing query parameter `peer_id`"))?,
         port: port.ok_or(anyhow!("missing query parameter `port`"))?,
         uploaded: uploaded.ok_or(anyhow!("missing query parameter `uploaded`"))?,
         downloaded: downloaded.ok_or(anyhow!("missing query parameter `downloaded`"))?,
         left: left.ok_or(anyhow!("missing query parameter `left`"))?,
         compact: compact.ok_or(anyhow!("missing query parameter `compact`"))?,
+        no_peer_id,
+        ip,
+        numwant,
+        event,
     })
 }
 
 #[derive(Serialize)]
 pub struct PeersResp {
-    peers: Vec<SocketAddr>,
+    interval: u64,
+    #[serde(rename = "min interval")]
+    min_interval: u64,
+    peers: Peers,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Peers {
+    Compact(CompactPeers),
+    Dicts(Vec<PeerDict>),
+}
+
+// 6 bytes per peer: IPv4 address and port, both big endian.
+// IPv6 peers don't fit and are left out.
+struct CompactPeers(Vec<Peer>);
+
+impl Serialize for CompactPeers {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = Vec::with_capacity(self.0.len() * 6);
+        for peer in &self.0 {
+            if let SocketAddr::V4(addr) = peer.addr {
+                bytes.extend(addr.ip().octets());
+                bytes.extend(addr.port().to_be_bytes());
+            }
+        }
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
+#[derive(Serialize)]
+struct PeerDict {
+    #[serde(rename = "peer id", skip_serializing_if = "Option::is_none")]
+    peer_id: Option<Bytes>,
+    ip: String,
+    port: u16,
+}
+
+// Peer ids are arbitrary bytes, not necessarily UTF-8.
+struct Bytes([u8; 20]);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::router;
+    use crate::state::{AnnounceInterval, AppState};
+    use crate::torrents::{InfoHash, Peer};
+    use axum::body::{Body, to_bytes};
+    use axum::extract::ConnectInfo;
+    use axum::http::{Request, StatusCode};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    const QUERY: &str = "info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01\
+        &peer_id=-TS0001-000000000000&port=6881&uploaded=0&downloaded=0&left=0&compact=1";
+
+    #[derive(serde::Deserialize)]
+    struct Intervals {
+        interval: u64,
+        #[serde(rename = "min interval")]
+        min_interval: u64,
+    }
+
+    async fn announce(
+        state: AppState,
+        from: &str,
+        query: &str,
+        headers: &[(&str, &str)],
+    ) -> Intervals {
+        let body = announce_raw(state, from, query, headers).await;
+        serde_bencode::from_bytes(&body).unwrap()
+    }
+
+    async fn announce_raw(
+        state: AppState,
+        from: &str,
+        query: &str,
+        headers: &[(&str, &str)],
+    ) -> Vec<u8> {
+        let mut request = Request::get(format!("/announce?{query}"));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        let from: SocketAddr = from.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(from));
+        let response = router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    fn stored_peers(state: &AppState) -> Vec<SocketAddr> {
+        let torrents = state.torrents.lock().unwrap();
+        torrents.items[&InfoHash([1; 20])]
+            .iter()
+            .map(|peer| peer.addr)
+            .collect()
+    }
+
+    fn trusting(proxy: &str) -> AppState {
+        AppState {
+            trusted_proxies: Arc::new(vec![proxy.parse().unwrap()]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn trusted_proxy_can_override_peer_ip() {
+        let proxy = "10.0.0.1:40000";
+        let state = trusting("10.0.0.1");
+        let query = format!("{QUERY}&ip=203.0.113.7");
+        announce(state.clone(), proxy, &query, &[]).await;
+        assert_eq!(stored_peers(&state), ["203.0.113.7:6881".parse().unwrap()]);
+
+        let state = trusting("10.0.0.1");
+        let forwarded_for = [("x-forwarded-for", "198.51.100.2, 10.0.0.1")];
+        announce(state.clone(), proxy, QUERY, &forwarded_for).await;
+        assert_eq!(stored_peers(&state), ["198.51.100.2:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn proxies_are_trusted_by_range() {
+        let state = trusting("10.0.0.0/8");
+        let forwarded_for = [("x-forwarded-for", "198.51.100.2")];
+        announce(state.clone(), "10.20.30.40:40000", QUERY, &forwarded_for).await;
+        assert_eq!(stored_peers(&state), ["198.51.100.2:6881".parse().unwrap()]);
+
+        // nginx style
+        let state = trusting("10.0.0.0/8");
+        let real_ip = [("x-real-ip", "198.51.100.3")];
+        announce(state.clone(), "10.20.30.40:40000", QUERY, &real_ip).await;
+        assert_eq!(stored_peers(&state), ["198.51.100.3:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn untrusted_clients_cant_override_peer_ip() {
+        let state = trusting("10.0.0.1");
+        let query = format!("{QUERY}&ip=203.0.113.7");
+        let headers = [
+            ("x-forwarded-for", "198.51.100.2"),
+            ("x-real-ip", "198.51.100.3"),
+        ];
+        announce(state.clone(), "192.0.2.9:40000", &query, &headers).await;
+        assert_eq!(stored_peers(&state), ["192.0.2.9:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn intervals_are_jittered_within_the_band() {
+        let interval = AnnounceInterval {
+            base: 1000,
+            jitter: 0.2,
+            min: 900,
+        };
+        let state = AppState {
+            interval,
+            ..Default::default()
+        };
+        let mut seen = Vec::new();
+        for _ in 0..50 {
+            let response = announce(state.clone(), "192.0.2.9:40000", QUERY, &[]).await;
+            assert!((900..=1200).contains(&response.interval));
+            assert_eq!(response.min_interval, 900);
+            seen.push(response.interval);
+        }
+        // not everyone is sent back at the same time
+        seen.dedup();
+        assert!(seen.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn announces_survive_a_panic_holding_the_lock() {
+        let state = AppState::default();
+        let poisoner = state.clone();
+        let panicked = std::thread::spawn(move || {
+            let _torrents = poisoner.torrents.lock().unwrap();
+            panic!("request went wrong");
+        })
+        .join();
+        assert!(panicked.is_err() && state.torrents.is_poisoned());
+
+        announce(state.clone(), "192.0.2.9:40000", QUERY, &[]).await;
+        announce(state.clone(), "192.0.2.10:40000", QUERY, &[]).await;
+        assert!(!state.torrents.is_poisoned());
+        assert_eq!(stored_peers(&state).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn swarm_is_dropped_when_last_peer_stops() {
+        let state = AppState::default();
+        let stopped = format!("{QUERY}&event=stopped");
+        announce(state.clone(), "192.0.2.9:40000", QUERY, &[]).await;
+        announce(state.clone(), "192.0.2.10:40000", QUERY, &[]).await;
+        announce(state.clone(), "192.0.2.9:40000", &stopped, &[]).await;
+        assert_eq!(stored_peers(&state), ["192.0.2.10:6881".parse().unwrap()]);
+
+        announce(state.clone(), "192.0.2.10:40000", &stopped, &[]).await;
+        let torrents = state.torrents.lock().unwrap();
+        assert!(!torrents.items.contains_key(&InfoHash([1; 20])));
+        assert_eq!(torrents.peer_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn peer_ids_are_left_out_when_asked() {
+        let state = AppState::default();
+        let query = QUERY.replace("compact=1", "compact=0");
+        announce_raw(state.clone(), "192.0.2.10:40000", &query, &[]).await;
+        let body = announce_raw(state.clone(), "192.0.2.9:40000", &query, &[]).await;
+        let with_ids = String::from_utf8_lossy(&body);
+        assert!(with_ids.contains("7:peer id20:-TS0001-000000000000"));
+
+        let query = format!("{query}&no_peer_id=1");
+        let body = announce_raw(state, "192.0.2.9:40000", &query, &[]).await;
+        let without_ids = String::from_utf8_lossy(&body);
+        assert!(!without_ids.contains("peer id"));
+        assert!(without_ids.contains("2:ip10:192.0.2.104:porti6881e"));
+    }
+
+    #[tokio::test]
+    async fn non_compact_announce_is_refused_when_forcing_compact() {
+        let query = QUERY.replace("compact=1", "compact=0");
+        let state = AppState {
+            force_compact: true,
+            ..Default::default()
+        };
+        let body = announce_raw(state.clone(), "192.0.2.9:40000", &query, &[]).await;
+        assert_eq!(
+            body,
+            b"d14:failure reason42:this tracker only sends compact peer listse"
+        );
+        assert!(state.torrents().is_empty());
+        let body = announce_raw(state, "192.0.2.9:40000", QUERY, &[]).await;
+        assert!(returned_peers(&body).is_empty());
+
+        let body = announce_raw(AppState::default(), "192.0.2.9:40000", &query, &[]).await;
+        assert!(String::from_utf8_lossy(&body).contains("5:peersl"));
+    }
+
+    #[tokio::test]
+    async fn compact_response_packs_ipv4_peers() {
+        let state = AppState::default();
+        announce_raw(state.clone(), "192.0.2.9:40000", QUERY, &[]).await;
+        let body = announce_raw(state, "192.0.2.10:40000", QUERY, &[]).await;
+        let peers = [192, 0, 2, 9, 0x1a, 0xe1];
+        assert!(body.windows(8).any(|w| w[..2] == *b"6:" && w[2..] == peers));
+    }
+
+    // Addresses in a compact response.
+    fn returned_peers(body: &[u8]) -> Vec<SocketAddr> {
+        #[derive(serde::Deserialize)]
+        struct Response {
+            peers: serde_bencode::value::Value,
+        }
+        let response: Response = serde_bencode::from_bytes(body).unwrap();
+        let serde_bencode::value::Value::Bytes(peers) = response.peers else {
+            panic!("peers aren't compact");
+        };
+        peers
+            .chunks(6)
+            .map(|peer| {
+                let ip = std::net::Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+                SocketAddr::from((ip, u16::from_be_bytes([peer[4], peer[5]])))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn client_decodes_compact_responses() {
+        let state = AppState::default();
+        for from in ["192.0.2.1:40000", "192.0.2.2:40000", "198.51.100.7:50000"] {
+            announce_raw(state.clone(), from, QUERY, &[]).await;
+        }
+        let body = announce_raw(state, "192.0.2.3:40000", QUERY, &[]).await;
+        let response: bittorrent::tracker::TrackerResponse =
+            serde_bencode::from_bytes(&body).unwrap();
+        assert!((1440..=2160).contains(&response.interval));
+        assert_eq!(
+            response.peers.0,
+            [
+                "192.0.2.1:6881".parse().unwrap(),
+                "192.0.2.2:6881".parse().unwrap(),
+                "198.51.100.7:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn tiny_swarm_gets_every_other_peer() {
+        let state = AppState::default();
+        announce_raw(state.clone(), "192.0.2.1:40000", QUERY, &[]).await;
+        announce_raw(state.clone(), "192.0.2.2:40000", QUERY, &[]).await;
+        let body = announce_raw(state.clone(), "192.0.2.3:40000", QUERY, &[]).await;
+        assert_eq!(
+            returned_peers(&body),
+            [
+                "192.0.2.1:6881".parse().unwrap(),
+                "192.0.2.2:6881".parse().unwrap()
+            ]
+        );
+        // the first one to announce has no one to hear about
+        let body = announce_raw(AppState::default(), "192.0.2.1:40000", QUERY, &[]).await;
+        assert!(returned_peers(&body).is_empty());
+    }
+
+    #[tokio::test]
+    async fn large_swarm_gets_a_capped_random_sample() {
+        let state = AppState::default();
+        {
+            let mut torrents = state.torrents.lock().unwrap();
+            let swarm = torrents.items.entry(InfoHash([1; 20])).or_default();
+            for i in 0..1000u16 {
+                swarm.push_back(Peer {
+                    id: [0; 20],
+                    addr: SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 6881)),
+                });
+            }
+        }
+        let me: SocketAddr = "10.0.0.7:6881".parse().unwrap();
+        let body = announce_raw(state.clone(), "10.0.0.7:40000", QUERY, &[]).await;
+        let first = returned_peers(&body);
+        assert_eq!(first.len(), state.peer_list.default);
+        assert!(!first.contains(&me));
+
+        // numwant is honoured up to the cap
+        let query = format!("{QUERY}&numwant=10");
+        let body = announce_raw(state.clone(), "10.0.0.7:40000", &query, &[]).await;
+        assert_eq!(returned_peers(&body).len(), 10);
+        let query = format!("{QUERY}&numwant=5000");
+        let body = announce_raw(state.clone(), "10.0.0.7:40000", &query, &[]).await;
+        let capped = returned_peers(&body);
+        assert_eq!(capped.len(), state.peer_list.max);
+        assert!(!capped.contains(&me));
+
+        let body = announce_raw(state, "10.0.0.7:40000", QUERY, &[]).await;
+        assert_ne!(returned_peers(&body), first);
+    }
 }
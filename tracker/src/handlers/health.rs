@@ -0,0 +1,78 @@
+use crate::state::AppState;
+use axum::Json;
+use axum::extract::State;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    torrents: usize,
+    peers: usize,
+}
+
+// Liveness probe, also reporting how much the tracker is tracking.
+pub async fn get(State(state): State<AppState>) -> Json<Health> {
+    let torrents = state.torrents();
+    Json(Health {
+        torrents: torrents.len(),
+        peers: torrents.peer_count(),
+    })
+}
+
+// Plain-text counters, one `name value` pair per line.
+pub async fn metrics(State(state): State<AppState>) -> String {
+    let torrents = state.torrents();
+    let active_swarms = torrents
+        .items
+        .values()
+        .filter(|peers| !peers.is_empty())
+        .count();
+    format!(
+        "announces_served {}\nactive_swarms {active_swarms}\n",
+        torrents.announces
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::router;
+    use crate::state::AppState;
+    use crate::torrents::{InfoHash, Peer};
+    use axum::body::{Body, to_bytes};
+    use axum::http::{Request, StatusCode};
+    use std::collections::VecDeque;
+    use tower::ServiceExt;
+
+    async fn fetch(state: AppState, uri: &str) -> (StatusCode, String) {
+        let response = router(state)
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn health_counts_registered_swarms() {
+        let state = AppState::default();
+        {
+            let mut torrents = state.torrents.lock().unwrap();
+            let peers = VecDeque::from([Peer {
+                id: [0; 20],
+                addr: "127.0.0.1:6881".parse().unwrap(),
+            }]);
+            torrents.items.insert(InfoHash([1; 20]), peers.clone());
+            torrents.items.insert(InfoHash([2; 20]), peers);
+            torrents.items.insert(InfoHash([3; 20]), VecDeque::new());
+            torrents.announces = 5;
+        }
+
+        let (status, body) = fetch(state.clone(), "/health").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"torrents":3,"peers":2}"#);
+
+        let (status, body) = fetch(state, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "announces_served 5\nactive_swarms 2\n");
+    }
+}
@@ -2,4 +2,16 @@ pub mod error;
 pub mod handlers;
 pub mod state;
 pub mod torrents;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+
+use axum::{Router, routing::get};
+use handlers::{announce, health};
+use state::AppState;
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/announce", get(announce::get))
+        .route("/health", get(health::get))
+        .route("/metrics", get(health::metrics))
+        .with_state(state)
+}
@@ -1,16 +1,28 @@
-use axum::{Router, routing::get};
 use std::net::SocketAddr;
-use tracker::handlers::announce;
-use tracker::state::AppState;
+use std::sync::Arc;
+use tracker::router;
+use tracker::state::{AppState, IpRange};
 
 const PORT: u16 = 8000;
 
 #[tokio::main]
 async fn main() {
-    let state = AppState::default();
-    let app = Router::new()
-        .route("/announce", get(announce::get))
-        .with_state(state);
+    // comma separated addresses or CIDR blocks of the reverse proxies in front of us
+    let trusted_proxies: Vec<IpRange> = std::env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| range.parse().expect("invalid TRUSTED_PROXIES entry"))
+        .collect();
+    // anything but unset or 0 refuses `compact=0` announces
+    let force_compact = std::env::var("FORCE_COMPACT").is_ok_and(|value| value != "0");
+    let state = AppState {
+        trusted_proxies: Arc::new(trusted_proxies),
+        force_compact,
+        ..Default::default()
+    };
+    let app = router(state);
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{PORT}"))
         .await
         .unwrap();
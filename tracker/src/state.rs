@@ -1,7 +1,161 @@
 use crate::torrents::Torrents;
-use std::sync::{Arc, Mutex};
+use anyhow::Context;
+use rand::Rng;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 #[derive(Default, Clone)]
 pub struct AppState {
+    pub interval: AnnounceInterval,
     pub torrents: Arc<Mutex<Torrents>>,
+    // Proxies allowed to tell us the peer's address via the `ip` parameter,
+    // `X-Forwarded-For` or `X-Real-IP`. Anyone else could use it to register
+    // a victim's address.
+    pub trusted_proxies: Arc<Vec<IpRange>>,
+    pub peer_list: PeerListSize,
+    // Refuse `compact=0` announces instead of sending the much bigger
+    // dictionary peer list.
+    pub force_compact: bool,
+}
+
+impl AppState {
+    // Locks the swarms. A request that panicked while holding the lock
+    // poisons it, but every change to `Torrents` leaves it consistent, so
+    // the tracker carries on instead of failing every request after it.
+    pub fn torrents(&self) -> MutexGuard<'_, Torrents> {
+        self.torrents.lock().unwrap_or_else(|poisoned| {
+            println!("recovering from a request that panicked holding the swarms");
+            self.torrents.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
+// CIDR block such as `10.0.0.0/8`. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address in {s}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max
+        } else {
+            prefix
+                .parse()
+                .with_context(|| format!("invalid prefix in {s}"))?
+        };
+        anyhow::ensure!(prefix <= max, "prefix of {s} is too long");
+        Ok(Self { addr, prefix })
+    }
+}
+
+// How often clients are told to re-announce, in seconds. Each response gets
+// `base` randomly moved by up to `jitter` (a fraction of `base`) so clients
+// don't all come back at the same moment, but never less than `min`.
+#[derive(Debug, Clone, Copy)]
+pub struct AnnounceInterval {
+    pub base: u64,
+    pub jitter: f64,
+    pub min: u64,
+}
+
+impl Default for AnnounceInterval {
+    fn default() -> Self {
+        Self {
+            base: 1800,
+            jitter: 0.2,
+            min: 900,
+        }
+    }
+}
+
+impl AnnounceInterval {
+    pub fn next(&self) -> u64 {
+        let spread = (self.base as f64 * self.jitter) as u64;
+        let interval =
+            rand::rng().random_range(self.base - spread.min(self.base)..=self.base + spread);
+        interval.max(self.min)
+    }
+}
+
+// How many peers an announce gets back. Swarms with fewer peers than that
+// are sent all of them, bigger ones a random sample, so the load spreads
+// over the swarm and responses stay small.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerListSize {
+    // when the client doesn't say (`numwant`)
+    pub default: usize,
+    // most we send, whatever the client asks for
+    pub max: usize,
+}
+
+impl Default for PeerListSize {
+    fn default() -> Self {
+        Self {
+            default: 50,
+            max: 200,
+        }
+    }
+}
+
+impl PeerListSize {
+    pub fn for_request(&self, numwant: Option<usize>) -> usize {
+        numwant.unwrap_or(self.default).min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_ranges_match_by_prefix() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains("10.1.255.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(!range.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let single: IpRange = "192.0.2.1".parse().unwrap();
+        assert!(single.contains("192.0.2.1".parse().unwrap()));
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        let v6: IpRange = "fd00::/8".parse().unwrap();
+        assert!(v6.contains("fd12::1".parse().unwrap()));
+        assert!(
+            "0.0.0.0/0"
+                .parse::<IpRange>()
+                .unwrap()
+                .contains("8.8.8.8".parse().unwrap())
+        );
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("proxy".parse::<IpRange>().is_err());
+    }
 }
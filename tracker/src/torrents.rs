@@ -3,5 +3,44 @@ use std::net::SocketAddr;
 
 #[derive(Debug, Default, Clone)]
 pub struct Torrents {
-    pub items: HashMap<[u8; 20], VecDeque<SocketAddr>>
-}
\ No newline at end of file
+    pub items: HashMap<InfoHash, VecDeque<Peer>>,
+    // number of announces handled since startup
+    pub announces: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InfoHash(pub [u8; 20]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub id: [u8; 20],
+    pub addr: SocketAddr,
+}
+
+impl Torrents {
+    // Number of swarms being tracked.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Number of peers across all swarms.
+    pub fn peer_count(&self) -> usize {
+        self.items.values().map(|peers| peers.len()).sum()
+    }
+
+    // Takes the peer at `addr` out of the swarm, dropping the swarm once nobody
+    // is left so the map doesn't keep growing with dead info hashes.
+    pub fn remove(&mut self, info_hash: &InfoHash, addr: SocketAddr) {
+        let Some(peers) = self.items.get_mut(info_hash) else {
+            return;
+        };
+        peers.retain(|peer| peer.addr != addr);
+        if peers.is_empty() {
+            self.items.remove(info_hash);
+        }
+    }
+}